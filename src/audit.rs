@@ -0,0 +1,176 @@
+use std::{
+    fs::{OpenOptions, create_dir_all, read_dir, remove_file},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde_json::{Value, json, to_vec_pretty};
+use tracing::{debug, warn};
+
+use crate::{config::CONFIG, redaction::redact};
+
+/// Directory holding the audit log, relative to the `.jj` (or, without jj, `.git`) directory
+const LOGS_DIR: &str = "ccc-jj/logs";
+
+static LOG_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// A prompt and response file in the audit log
+#[derive(Debug, Clone)]
+pub struct LogFile {
+    pub path: PathBuf,
+    pub modified: SystemTime,
+    pub size: u64,
+}
+
+/// The audit log directory of the jj workspace or git repository containing `start_dir`:
+/// `.jj/ccc-jj/logs`, or `.git/ccc-jj/logs` in a repository without jj
+pub fn log_dir(start_dir: &Path) -> Option<PathBuf> {
+    let metadata_dir = |name| {
+        start_dir
+            .ancestors()
+            .map(|dir| dir.join(name))
+            .find(|dir| dir.is_dir())
+    };
+    metadata_dir(".jj")
+        .or_else(|| metadata_dir(".git"))
+        .map(|dir| dir.join(LOGS_DIR))
+}
+
+/// Sets where [`record`] writes for the rest of the run
+pub fn set_log_dir(dir: PathBuf) {
+    let _ = LOG_DIR.set(dir);
+}
+
+/// With `audit.enabled`, writes a prompt sent to `model` and the raw response (`None` if the call
+/// failed) to `<timestamp>.json` in the audit log, masking secrets with `audit.redact`, then prunes
+/// logs past `audit.retention_days`. Failures are logged rather than returned, so that auditing
+/// never stops a commit.
+pub(crate) fn record(model: &str, prompt: &str, response: Option<&str>) {
+    let config = &CONFIG.audit;
+    let Some(dir) = LOG_DIR.get().filter(|_| config.enabled) else {
+        return;
+    };
+    let mask = |text: &str| if config.redact { redact(text).0 } else { text.to_string() };
+    let entry = json!({
+        "timestamp": Utc::now().to_rfc3339(),
+        "model": model,
+        "backend": CONFIG.generator.backend,
+        "redacted": config.redact,
+        "prompt": mask(prompt),
+        "response": response.map(mask),
+    });
+    match write_log(dir, &entry) {
+        Ok(path) => debug!(path = %path.display(), "Wrote audit log"),
+        Err(e) => warn!(error = %e, "Failed to write the audit log"),
+    }
+    if let Err(e) = prune(dir, config.retention_days) {
+        warn!(error = %e, "Failed to prune the audit log");
+    }
+}
+
+/// Writes the entry to a new file named after the current time, with a counter appended when
+/// calls made in parallel finish within the same microsecond
+fn write_log(dir: &Path, entry: &Value) -> Result<PathBuf> {
+    create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let timestamp = Utc::now().format("%Y%m%dT%H%M%S%.6fZ").to_string();
+    let contents = to_vec_pretty(entry)?;
+    for n in 0.. {
+        let name = match n {
+            0 => format!("{timestamp}.json"),
+            n => format!("{timestamp}-{n}.json"),
+        };
+        let path = dir.join(name);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                file.write_all(&contents)
+                    .with_context(|| format!("Failed to write {}", path.display()))?;
+                return Ok(path);
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to create {}", path.display()));
+            }
+        }
+    }
+    unreachable!("the counter runs until a free name is found")
+}
+
+/// The audit log files, oldest first. A missing directory has none.
+pub fn list(dir: &Path) -> Result<Vec<LogFile>> {
+    let entries = match read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+    let mut logs: Vec<LogFile> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            Some(LogFile {
+                path: entry.path(),
+                modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                size: metadata.len(),
+            })
+        })
+        .collect();
+    logs.sort_by(|a, b| (a.modified, &a.path).cmp(&(b.modified, &b.path)));
+    Ok(logs)
+}
+
+/// Removes the logs older than `retention_days` (0 keeps them all) and returns how many were
+/// removed
+pub fn prune(dir: &Path, retention_days: u64) -> Result<usize> {
+    if retention_days == 0 {
+        return Ok(0);
+    }
+    let retention = Duration::from_secs(retention_days * 24 * 60 * 60);
+    let cutoff = SystemTime::now()
+        .checked_sub(retention)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    let mut removed = 0;
+    for log in list(dir)?.into_iter().take_while(|log| log.modified < cutoff) {
+        debug!(path = %log.path.display(), "Pruning old audit log");
+        match remove_file(&log.path) {
+            Ok(()) => removed += 1,
+            Err(e) => warn!(path = %log.path.display(), error = %e, "Failed to prune audit log"),
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{File, remove_dir_all};
+
+    use super::*;
+
+    #[test]
+    fn test_write_list_and_prune() {
+        let dir = std::env::temp_dir().join(format!("ccc-jj-audit-test-{}", std::process::id()));
+        let _ = remove_dir_all(&dir);
+        assert!(list(&dir).unwrap().is_empty());
+
+        let first = write_log(&dir, &json!({ "prompt": "a" })).unwrap();
+        let second = write_log(&dir, &json!({ "prompt": "b" })).unwrap();
+        assert_ne!(first, second);
+        let old = SystemTime::now() - Duration::from_secs(3 * 24 * 60 * 60);
+        File::options()
+            .write(true)
+            .open(&first)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        let logs = list(&dir).unwrap();
+        assert_eq!(logs.iter().map(|log| &log.path).collect::<Vec<_>>(), [&first, &second]);
+        assert_eq!(prune(&dir, 0).unwrap(), 0);
+        assert_eq!(prune(&dir, 2).unwrap(), 1);
+        assert_eq!(list(&dir).unwrap().len(), 1);
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,356 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use jj_lib::{
+    backend::CommitId,
+    commit::Commit,
+    git::{GitImportOptions, export_refs, import_refs},
+    op_store::RefTarget,
+    ref_name::{RefName, RemoteName},
+    repo::{ReadonlyRepo, Repo},
+    workspace::Workspace,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    Error, Result,
+    bookmark_generator::{BookmarkGenerator, summarize_diff},
+    diff::summarize_unified_diff,
+    pipeline::{blocking, evaluate_revset, generator_error, jj, load_repo, resolve_single_commit},
+    pr_description::aggregate_diff,
+};
+
+/// Bookmarks that track the trunk and are never advanced automatically
+const TRUNK_BOOKMARKS: [&str; 3] = ["main", "master", "trunk"];
+
+/// What happened to a bookmark
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BookmarkAction {
+    Created,
+    Moved,
+    /// The bookmark already pointed at the commit
+    Kept,
+    /// The bookmark replaced the one of the given name
+    Renamed(String),
+}
+
+/// A bookmark that was set
+#[derive(Debug)]
+pub struct Bookmark {
+    pub name: String,
+    pub commit: Commit,
+    pub action: BookmarkAction,
+}
+
+/// A bookmark named by [`plan_bookmark`], not yet set
+pub struct BookmarkPlan {
+    repo: Arc<ReadonlyRepo>,
+    pub name: String,
+    pub commit: Commit,
+    /// The bookmark in the range that this one replaces when renaming
+    replacing: Option<String>,
+}
+
+impl BookmarkPlan {
+    /// Sets the bookmark, deleting the one it replaces in the same operation
+    pub fn apply(self) -> Result<Bookmark> {
+        let existed =
+            set_bookmark(&self.repo, &self.name, &self.commit, self.replacing.as_deref())?;
+        let action = match self.replacing {
+            Some(old) => BookmarkAction::Renamed(old),
+            None if existed => BookmarkAction::Moved,
+            None => BookmarkAction::Created,
+        };
+        Ok(Bookmark { name: self.name, commit: self.commit, action })
+    }
+}
+
+/// Names the bookmark for the commits in `from..to` and picks the commit it goes on.
+///
+/// `from` defaults to [`default_base`], and an empty `@` is skipped (see
+/// [`resolve_bookmark_target`]). A bookmark already in the range is moved to the head of it,
+/// unless `rename` asks for a new name; otherwise a name is generated from the descriptions of the
+/// commits, or from their diff if none is described. Names get the `prefix`, if any.
+pub async fn plan_bookmark(
+    workspace: &Workspace,
+    model: &str,
+    from: Option<&str>,
+    to: &str,
+    prefix: Option<&str>,
+    rename: bool,
+) -> Result<BookmarkPlan> {
+    let repo = load_repo(workspace)?;
+    debug!("Loaded repository at head");
+
+    let from = match from {
+        Some(rev) => rev.to_string(),
+        None => default_base(&repo)?,
+    };
+
+    // Resolve target revision, skipping empty @ if needed
+    let to = resolve_bookmark_target(&repo, workspace, to)?;
+    let commit = resolve_single_commit(&repo, workspace, &to)?;
+
+    // Check if any commit in the range already has a bookmark - if so, move it (or rename it below)
+    let existing = find_existing_bookmark_in_range(&repo, workspace, &from, &to)?;
+    if let Some(existing_name) = existing.as_ref().filter(|_| !rename) {
+        let name = match prefix {
+            Some(p) if !existing_name.starts_with(&format!("{p}/")) => {
+                format!("{p}/{existing_name}")
+            }
+            _ => existing_name.clone(),
+        };
+        return Ok(BookmarkPlan { repo, name, commit, replacing: None });
+    }
+
+    // No existing bookmark, or renaming it - generate a new name
+    info!(from = %from, to = %to, "Resolving revset range");
+
+    let mut commit_summaries = get_commit_summaries(&repo, workspace, &from, &to)?;
+    if commit_summaries.is_empty() {
+        let commits = range_commits(&repo, workspace, &from, &to)?;
+        // Revsets list the newest commit first
+        let (Some(head), Some(oldest)) = (commits.first(), commits.last()) else {
+            return Err(Error::EmptyRange { from, to });
+        };
+        info!("No described commits, naming the bookmark after the diff");
+        commit_summaries = diff_summary(&repo, oldest, head).await?;
+    }
+    debug!(commit_count = commit_summaries.lines().count(), "Found commits");
+
+    let bookmark_name = generate_name(model, commit_summaries).await?;
+    let name = match prefix {
+        Some(p) => format!("{p}/{bookmark_name}"),
+        None => bookmark_name,
+    };
+    let replacing = existing.filter(|existing| *existing != name);
+    Ok(BookmarkPlan { repo, name, commit, replacing })
+}
+
+/// Points a bookmark at a freshly described commit.
+///
+/// A bookmark already on the commit is kept, and one on its parent is advanced to it (unless it
+/// is a trunk bookmark). Otherwise a new name is generated from the commit's summary.
+pub async fn bookmark_commit(
+    workspace: &Workspace,
+    model: &str,
+    revision: &str,
+) -> Result<Bookmark> {
+    let repo = load_repo(workspace)?;
+    let commit = resolve_single_commit(&repo, workspace, revision)?;
+
+    let points_at =
+        |target: &RefTarget, ids: &[CommitId]| target.added_ids().any(|id| ids.contains(id));
+    let view = repo.view();
+    if let Some((name, _)) = view
+        .local_bookmarks()
+        .find(|(_, target)| points_at(target, std::slice::from_ref(commit.id())))
+    {
+        let name = name.as_str().to_string();
+        return Ok(Bookmark { name, commit, action: BookmarkAction::Kept });
+    }
+    let advanced = view
+        .local_bookmarks()
+        .find(|(name, target)| {
+            !TRUNK_BOOKMARKS.contains(&name.as_str()) && points_at(target, commit.parent_ids())
+        })
+        .map(|(name, _)| name.as_str().to_string());
+
+    let name = match advanced {
+        Some(name) => name,
+        None => {
+            let summary = match commit.description().lines().next() {
+                Some(subject) if !subject.trim().is_empty() => format!("- {subject}"),
+                _ => diff_summary(&repo, &commit, &commit).await?,
+            };
+            generate_name(model, summary).await?
+        }
+    };
+
+    BookmarkPlan { repo, name, commit, replacing: None }.apply()
+}
+
+/// Generates a bookmark name from the summaries of the commits it goes on
+async fn generate_name(model: &str, commit_summaries: String) -> Result<String> {
+    info!(model = %model, "Generating bookmark name with Claude");
+    let generator = BookmarkGenerator::new(model);
+    blocking(move || {
+        generator
+            .generate(&commit_summaries)
+            .map_err(generator_error)?
+            .ok_or(Error::BookmarkNameFailed)
+    })
+    .await
+}
+
+/// Summarizes the net change from the parent of `oldest` to `head` for naming a bookmark, for when
+/// the commits have no descriptions to go by
+async fn diff_summary(repo: &ReadonlyRepo, oldest: &Commit, head: &Commit) -> Result<String> {
+    let (diff, _masked) = aggregate_diff(repo, oldest, head).await?;
+    Ok(summarize_diff(&diff, &summarize_unified_diff(&diff)))
+}
+
+/// The commits in `from..to`, newest first
+pub fn range_commits(
+    repo: &ReadonlyRepo,
+    workspace: &Workspace,
+    from: &str,
+    to: &str,
+) -> Result<Vec<Commit>> {
+    evaluate_revset(repo, workspace, &format!("{from}..{to}"))?
+        .iter()
+        .map(|id| {
+            repo.store()
+                .get_commit(id)
+                .map_err(jj("Failed to read a commit in the range"))
+        })
+        .collect()
+}
+
+/// Find an existing local bookmark anywhere in the given revset range
+fn find_existing_bookmark_in_range(
+    repo: &ReadonlyRepo,
+    workspace: &Workspace,
+    from: &str,
+    to: &str,
+) -> Result<Option<String>> {
+    let revset_str = format!("{from}..{to}");
+    let commit_ids: HashSet<_> =
+        evaluate_revset(repo, workspace, &revset_str)?.into_iter().collect();
+
+    for (name, target) in repo.view().local_bookmarks() {
+        if target.added_ids().any(|id| commit_ids.contains(id)) {
+            return Ok(Some(name.as_str().to_string()));
+        }
+    }
+    Ok(None)
+}
+
+/// Resolve bookmark target, using @- if @ is empty (idiomatic jj behavior)
+pub fn resolve_bookmark_target(
+    repo: &ReadonlyRepo,
+    workspace: &Workspace,
+    to: &str,
+) -> Result<String> {
+    if to != "@" {
+        return Ok(to.to_string());
+    }
+
+    let commit = resolve_single_commit(repo, workspace, "@")?;
+
+    // Check if @ is empty (no description and tree matches parent)
+    let is_empty = commit.description().is_empty() && {
+        if let Some(parent_id) = commit.parent_ids().first() {
+            let parent = repo
+                .store()
+                .get_commit(parent_id)
+                .map_err(jj("Failed to read the parent commit"))?;
+            commit.tree_ids() == parent.tree_ids()
+        } else {
+            false
+        }
+    };
+
+    if is_empty {
+        debug!("@ is empty, using @- as bookmark target");
+        Ok("@-".to_string())
+    } else {
+        Ok("@".to_string())
+    }
+}
+
+/// The base of a range when `--from` isn't given: `main@origin`, or else `main`
+pub fn default_base(repo: &ReadonlyRepo) -> Result<String> {
+    let view = repo.view();
+    let remote_name = RemoteName::new("origin");
+    let main_ref = RefName::new("main");
+
+    let remote_symbol = main_ref.to_remote_symbol(remote_name);
+    let remote_ref = view.get_remote_bookmark(remote_symbol);
+    if remote_ref.target.is_present() {
+        debug!("Using main@origin as base");
+        return Ok("main@origin".to_string());
+    }
+
+    let local_ref = view.get_local_bookmark(main_ref);
+    if local_ref.is_present() {
+        debug!("Using main as base");
+        return Ok("main".to_string());
+    }
+
+    Err(Error::NoBaseBookmark)
+}
+
+fn get_commit_summaries(
+    repo: &ReadonlyRepo,
+    workspace: &Workspace,
+    from: &str,
+    to: &str,
+) -> Result<String> {
+    let mut summaries = Vec::new();
+    for commit in range_commits(repo, workspace, from, to)? {
+        let desc = commit.description().trim();
+        if !desc.is_empty() {
+            summaries.push(format!("- {}", desc.lines().next().unwrap_or("")));
+        }
+    }
+
+    Ok(summaries.join("\n"))
+}
+
+/// Set bookmark to point to commit, deleting the `replacing` bookmark in the same operation when
+/// renaming. Returns true if bookmark already existed (moved), false if created. Also exports the
+/// bookmarks to git refs.
+fn set_bookmark(
+    repo: &Arc<ReadonlyRepo>,
+    name: &str,
+    commit: &Commit,
+    replacing: Option<&str>,
+) -> Result<bool> {
+    let ref_name = RefName::new(name);
+    let existed = repo.view().get_local_bookmark(ref_name).is_present();
+
+    let mut tx = repo.start_transaction();
+    let mut_repo = tx.repo_mut();
+
+    // Import git refs first to sync state (prevents compare-and-swap failures)
+    let import_options = GitImportOptions {
+        auto_local_bookmark: false,
+        abandon_unreachable_commits: true,
+        remote_auto_track_bookmarks: HashMap::new(),
+    };
+    if let Err(e) = import_refs(mut_repo, &import_options) {
+        warn!(error = %e, "Failed to import git refs");
+    }
+
+    let target = RefTarget::normal(commit.id().clone());
+    mut_repo.set_local_bookmark_target(ref_name, target);
+    if let Some(old) = replacing {
+        mut_repo.set_local_bookmark_target(RefName::new(old), RefTarget::absent());
+    }
+
+    // Export to git refs - now should succeed since we imported first
+    match export_refs(mut_repo) {
+        Ok(stats) => {
+            if !stats.failed_bookmarks.is_empty() {
+                for (ref_name, reason) in &stats.failed_bookmarks {
+                    warn!(bookmark = %ref_name, reason = ?reason, "Failed to export bookmark");
+                }
+            }
+        }
+        Err(e) => {
+            warn!(error = %e, "Failed to export refs to git");
+        }
+    }
+
+    let action = match replacing {
+        Some(old) => format!("rename bookmark '{old}' to"),
+        None if existed => "move bookmark".to_string(),
+        None => "create bookmark".to_string(),
+    };
+    tx.commit(format!("{action} '{name}' via ccc-jj"))
+        .map_err(jj("Failed to commit the transaction"))?;
+    Ok(existed)
+}
@@ -1,11 +1,16 @@
 use std::sync::LazyLock;
 
+use anyhow::Result;
 use regex::Regex;
+use serde_json::Value;
 use tracing::{debug, trace, warn};
 
 use crate::{
-    claude_client::{ClaudeRequest, invoke_claude},
+    claude_client::{extract_json_object, strip_code_fence},
     config::CONFIG,
+    diff::FileChangeSummary,
+    generator::{GenerationRequest, Generator, backend_from_config, invoke},
+    offline::offline_message,
 };
 
 static VALID_BOOKMARK_RE: LazyLock<Regex> = LazyLock::new(|| {
@@ -14,10 +19,22 @@ static VALID_BOOKMARK_RE: LazyLock<Regex> = LazyLock::new(|| {
 
 const JSON_SCHEMA: &str = r#"{"type":"object","properties":{"bookmark":{"type":"string","description":"Bookmark name: 2-6 lowercase words separated by hyphens, e.g. 'add-user-auth'"}},"required":["bookmark"]}"#;
 
+/// Stands in for the commit summaries when no commit is described yet, e.g. on the first commit of
+/// a series: a subject guessed from the paths by [`offline_message`], followed by the changed files
+/// with their line counts
+pub fn summarize_diff(diff: &str, file_changes: &FileChangeSummary) -> String {
+    let message = offline_message(diff, file_changes);
+    let (subject, files) = message.split_once("\n\n").unwrap_or((&message, ""));
+    let mut summary = format!("- {subject} (not yet described)");
+    for line in files.lines() {
+        summary.push_str(&format!("\n  {line}"));
+    }
+    summary
+}
+
 pub struct BookmarkGenerator {
     prompt_template: String,
-    command: String,
-    args: Vec<String>,
+    backend: Box<dyn Generator + Send + Sync>,
     model: String,
 }
 
@@ -25,15 +42,14 @@ impl BookmarkGenerator {
     pub fn new(model: &str) -> Self {
         Self {
             prompt_template: CONFIG.bookmark.prompt_template.clone(),
-            command: CONFIG.generator.command.clone(),
-            args: CONFIG.generator.args.clone(),
+            backend: backend_from_config(&CONFIG.generator),
             model: model.to_string(),
         }
     }
 
-    pub fn generate(&self, commit_summaries: &str) -> Option<String> {
+    pub fn generate(&self, commit_summaries: &str) -> Result<Option<String>> {
         debug!(summaries_len = commit_summaries.len(), "Starting bookmark name generation");
-        self.try_generate(commit_summaries).and_then(|name| {
+        Ok(self.try_generate(commit_summaries)?.and_then(|name| {
             let name = name.trim().to_lowercase();
             if VALID_BOOKMARK_RE.is_match(&name) {
                 debug!(bookmark = %name, "Generated valid bookmark name");
@@ -42,36 +58,68 @@ impl BookmarkGenerator {
                 warn!(bookmark = %name, "Generated bookmark name doesn't match expected format");
                 None
             }
-        })
+        }))
     }
 
-    fn try_generate(&self, commit_summaries: &str) -> Option<String> {
+    fn try_generate(&self, commit_summaries: &str) -> Result<Option<String>> {
         let prompt = self.prompt_template.replace("{commit_summaries}", commit_summaries);
         trace!(prompt_len = prompt.len(), "Prepared prompt for Claude");
 
-        let request = ClaudeRequest {
-            command: &self.command,
-            args: &self.args,
+        let request = GenerationRequest {
             model: &self.model,
             json_schema: JSON_SCHEMA,
             prompt: &prompt,
+            stable_prefix: 0,
             spinner_message: "Generating bookmark name with Claude...",
         };
 
-        let structured = invoke_claude(&request)?;
+        let Some(response) = invoke(self.backend.as_ref(), &request)? else {
+            return Ok(None);
+        };
+        let structured = match response.structured {
+            Value::String(text) => extract_json_object(&text).unwrap_or(Value::String(text)),
+            value => value,
+        };
 
-        let bookmark = structured
-            .get("bookmark")
-            .and_then(|v| v.as_str())
-            .unwrap_or("")
-            .trim();
+        let bookmark = match &structured {
+            // The model ignored the schema: take the first line of its answer
+            Value::String(text) => strip_code_fence(text)
+                .lines()
+                .map(|line| line.trim().trim_matches(['`', '"', '\'']))
+                .find(|line| !line.is_empty())
+                .unwrap_or(""),
+            _ => structured
+                .get("bookmark")
+                .and_then(|v| v.as_str())
+                .unwrap_or("")
+                .trim(),
+        };
 
         if bookmark.is_empty() {
             warn!("Claude CLI returned empty bookmark");
-            return None;
+            return Ok(None);
         }
 
         trace!(bookmark = %bookmark, "Claude CLI output");
-        Some(bookmark.to_string())
+        Ok(Some(bookmark.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summarize_diff() {
+        let diff = "diff --git a/src/auth.rs b/src/auth.rs\n--- a/src/auth.rs\n+++ b/src/auth.rs\n\
+            @@ -1 +1,2 @@\n-old\n+new\n+more\n";
+        let file_changes = FileChangeSummary {
+            modified: vec!["src/auth.rs".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            summarize_diff(diff, &file_changes),
+            "- chore(src): update auth.rs (not yet described)\n  - src/auth.rs (+2 -1)"
+        );
     }
 }
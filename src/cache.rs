@@ -0,0 +1,143 @@
+use std::{
+    cmp::Reverse,
+    fs::{self, create_dir_all, read_dir, read_to_string, remove_file},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use jj_lib::{merged_tree::MergedTree, object_id::ObjectId};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_str, to_string};
+use tracing::{debug, warn};
+
+use crate::{
+    commit_message_generator::GeneratedMessage,
+    provenance::{Provenance, fnv1a},
+};
+
+/// Directory holding cached messages, relative to the workspace's `.jj` directory
+const CACHE_DIR: &str = "ccc-jj/cache";
+
+/// Number of cached messages kept; the oldest are pruned beyond this
+const MAX_ENTRIES: usize = 100;
+
+/// Identifies a generated message: the trees it describes, the model, and a hash of everything
+/// else that goes into the prompt
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheKey {
+    from_tree: String,
+    to_tree: String,
+    model: String,
+    prompt_hash: String,
+}
+
+impl CacheKey {
+    /// # Arguments
+    /// - `prompt_inputs` - The diff, options, and configuration the prompt is built from
+    pub fn new(
+        from_tree: &MergedTree,
+        to_tree: &MergedTree,
+        model: &str,
+        prompt_inputs: &[String],
+    ) -> Self {
+        Self {
+            from_tree: tree_id(from_tree),
+            to_tree: tree_id(to_tree),
+            model: model.to_string(),
+            prompt_hash: format!("{:016x}", fnv1a(prompt_inputs)),
+        }
+    }
+
+    fn file_name(&self) -> String {
+        let key = [&*self.from_tree, &self.to_tree, &self.model, &self.prompt_hash];
+        format!("{:016x}.json", fnv1a(&key))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    key: CacheKey,
+    message: String,
+    raw_output: String,
+    /// Absent in entries written before provenance was recorded
+    #[serde(default)]
+    provenance: Option<Provenance>,
+}
+
+/// Returns the message cached under `.jj/ccc-jj/cache/` for the key, if any
+pub fn read(workspace_root: &Path, key: &CacheKey) -> Option<GeneratedMessage> {
+    let path = cache_dir(workspace_root).join(key.file_name());
+    let entry: Entry = from_str(&read_to_string(&path).ok()?)
+        .inspect_err(
+            |e| warn!(path = %path.display(), error = %e, "Ignoring unreadable cache entry"),
+        )
+        .ok()?;
+    // File names are hashes, so make sure the entry is really for this key
+    (entry.key == *key).then(|| {
+        debug!(path = %path.display(), "Found cached message");
+        GeneratedMessage {
+            message: entry.message,
+            raw_output: entry.raw_output,
+            provenance: entry.provenance,
+        }
+    })
+}
+
+/// Stores the message under the key, then prunes the oldest entries beyond the cache size
+pub fn write(workspace_root: &Path, key: CacheKey, generated: &GeneratedMessage) -> Result<()> {
+    let dir = cache_dir(workspace_root);
+    create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+
+    let path = dir.join(key.file_name());
+    let entry = Entry {
+        key,
+        message: generated.message.clone(),
+        raw_output: generated.raw_output.clone(),
+        provenance: generated.provenance.clone(),
+    };
+    fs::write(&path, to_string(&entry)?)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    debug!(path = %path.display(), "Cached message");
+
+    prune(&dir, MAX_ENTRIES)
+}
+
+fn cache_dir(workspace_root: &Path) -> PathBuf {
+    workspace_root.join(".jj").join(CACHE_DIR)
+}
+
+fn tree_id(tree: &MergedTree) -> String {
+    tree.tree_ids()
+        .iter()
+        .map(ObjectId::hex)
+        .collect::<Vec<_>>()
+        .join("+")
+}
+
+/// Remove the oldest cache entries so that at most `max_entries` remain
+fn prune(dir: &Path, max_entries: usize) -> Result<()> {
+    let mut entries: Vec<(SystemTime, PathBuf)> = read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let modified = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            (modified, entry.path())
+        })
+        .collect();
+
+    if entries.len() <= max_entries {
+        return Ok(());
+    }
+
+    entries.sort_by_key(|(modified, _)| Reverse(*modified));
+    for (_, path) in entries.into_iter().skip(max_entries) {
+        debug!(path = %path.display(), "Pruning old cache entry");
+        if let Err(e) = remove_file(&path) {
+            warn!(path = %path.display(), error = %e, "Failed to prune cache entry");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,246 @@
+use std::{
+    path::Path,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    thread,
+};
+
+use anyhow::Result;
+use serde_json::Value;
+use tracing::{info, trace, warn};
+
+use crate::{
+    claude_client::{extract_json_object, strip_code_fence},
+    config::CONFIG,
+    diff::{estimate_tokens, split_file_diffs},
+    generator::{GenerationRequest, Generator, backend_from_config, invoke},
+};
+
+const JSON_SCHEMA: &str = r#"{"type":"object","properties":{"summary":{"type":"string","description":"Short summary of what the changes in this part of the diff do"}},"required":["summary"]}"#;
+
+/// Part of a diff summarized with one model call
+#[derive(Debug, PartialEq, Eq)]
+pub struct Chunk {
+    /// The directories of the files in the chunk, e.g. `src/cli, tests`
+    pub directories: String,
+    pub paths: Vec<String>,
+    pub diff: String,
+}
+
+/// Describes a diff too large for one prompt: it is split into per-directory chunks, each is
+/// summarized by the model, and the summaries stand in for the diff when the commit message is
+/// generated
+pub struct ChunkSummarizer {
+    prompt_template: String,
+    backend: Box<dyn Generator + Send + Sync>,
+    language: String,
+    model: String,
+}
+
+impl ChunkSummarizer {
+    /// Creates a new chunk summarizer
+    ///
+    /// # Arguments
+    /// - `language` - The language to write the summaries in
+    /// - `model` - The model to use for generation
+    pub fn new(language: &str, model: &str) -> Self {
+        Self {
+            prompt_template: CONFIG.summarize.prompt_template.clone(),
+            backend: backend_from_config(&CONFIG.generator),
+            language: language.to_string(),
+            model: model.to_string(),
+        }
+    }
+
+    /// Summarizes the chunks of the diff, up to `generator.parallel_calls` at a time, and returns
+    /// the summaries as a list to generate the commit message from in place of the diff. Chunks
+    /// the model can't summarize are listed by their paths.
+    ///
+    /// # Returns
+    /// The list of summaries, or an error if the call budget is exhausted.
+    pub fn summarize(&self, diff: &str) -> Result<String> {
+        let chunks = split_into_chunks(diff, CONFIG.summarize.chunk_tokens);
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let workers = CONFIG.generator.parallel_calls.clamp(1, chunks.len().max(1));
+        info!(chunks = chunks.len(), workers, "Summarizing the diff in chunks");
+
+        let mut results: Vec<(usize, Result<Option<String>>)> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut results = Vec::new();
+                        while !failed.load(Ordering::Relaxed) {
+                            let i = next.fetch_add(1, Ordering::Relaxed);
+                            let Some(chunk) = chunks.get(i) else {
+                                break;
+                            };
+                            let summary = self.summarize_chunk(chunk);
+                            if summary.is_err() {
+                                failed.store(true, Ordering::Relaxed);
+                            }
+                            results.push((i, summary));
+                        }
+                        results
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().expect("chunk summarization panicked"))
+                .collect()
+        });
+        results.sort_by_key(|(i, _)| *i);
+
+        let mut summaries = String::from(
+            "The diff is too large to show in full. These are summaries of its parts, by \
+             directory:\n\n",
+        );
+        for (i, summary) in results {
+            let chunk = &chunks[i];
+            let summary = summary?.unwrap_or_else(|| {
+                warn!(directories = %chunk.directories, "No summary for a chunk, listing its files");
+                format!("Changes {}", chunk.paths.join(", "))
+            });
+            summaries.push_str(&format!("- {}: {}\n", chunk.directories, summary.trim()));
+        }
+        Ok(summaries)
+    }
+
+    fn summarize_chunk(&self, chunk: &Chunk) -> Result<Option<String>> {
+        let prompt = self
+            .prompt_template
+            .replace("{language}", &self.language)
+            .replace("{directories}", &chunk.directories)
+            .replace("{diff_content}", &chunk.diff);
+        trace!(prompt_len = prompt.len(), directories = %chunk.directories, "Prepared chunk prompt");
+
+        let spinner_message = format!("Summarizing the changes in {}...", chunk.directories);
+        let request = GenerationRequest {
+            model: &self.model,
+            json_schema: JSON_SCHEMA,
+            prompt: &prompt,
+            stable_prefix: 0,
+            spinner_message: &spinner_message,
+        };
+        Ok(invoke(self.backend.as_ref(), &request)?
+            .and_then(|response| parse_summary(response.structured)))
+    }
+}
+
+/// Splits a diff into chunks of about `max_tokens` each, keeping the files of a directory together
+/// where they fit and filling chunks with whole directories in path order. A file larger than a
+/// chunk is cut off.
+pub fn split_into_chunks(diff: &str, max_tokens: usize) -> Vec<Chunk> {
+    let max_tokens = max_tokens.max(1);
+    let mut files = split_file_diffs(diff);
+    files.sort_by(|(a, _), (b, _)| directory(a).cmp(directory(b)).then(a.cmp(b)));
+
+    let mut chunks: Vec<Chunk> = Vec::new();
+    let mut tokens = 0;
+    for (path, file_diff) in files {
+        let file_diff = truncate_to_tokens(&file_diff, max_tokens);
+        let file_tokens = estimate_tokens(&file_diff);
+        let dir = directory(&path);
+        match chunks.last_mut() {
+            Some(chunk) if tokens + file_tokens <= max_tokens => {
+                if !chunk.directories.split(", ").any(|d| d == dir) {
+                    chunk.directories.push_str(&format!(", {dir}"));
+                }
+                chunk.paths.push(path);
+                chunk.diff.push_str(&file_diff);
+                tokens += file_tokens;
+            }
+            _ => {
+                chunks.push(Chunk {
+                    directories: dir.to_string(),
+                    paths: vec![path],
+                    diff: file_diff,
+                });
+                tokens = file_tokens;
+            }
+        }
+    }
+    chunks
+}
+
+/// The directory of a path, `.` for files at the root
+fn directory(path: &str) -> &str {
+    Path::new(path)
+        .parent()
+        .and_then(Path::to_str)
+        .filter(|dir| !dir.is_empty())
+        .unwrap_or(".")
+}
+
+/// Cuts a file's diff off at the last whole line within `max_tokens`
+fn truncate_to_tokens(file_diff: &str, max_tokens: usize) -> String {
+    if estimate_tokens(file_diff) <= max_tokens {
+        return file_diff.to_string();
+    }
+    let mut truncated = String::new();
+    for line in file_diff.split_inclusive('\n') {
+        if estimate_tokens(&truncated) + estimate_tokens(line) > max_tokens {
+            break;
+        }
+        truncated.push_str(line);
+    }
+    truncated.push_str("[... rest of the file's diff cut off ...]\n");
+    truncated
+}
+
+/// Reads the summary from structured output, or takes the whole answer when the model ignored the
+/// schema
+fn parse_summary(structured: Value) -> Option<String> {
+    let structured = match structured {
+        Value::String(text) => extract_json_object(&text).unwrap_or(Value::String(text)),
+        value => value,
+    };
+    let summary = match &structured {
+        Value::String(text) => strip_code_fence(text),
+        _ => structured.get("summary").and_then(Value::as_str).unwrap_or(""),
+    };
+    let summary = summary.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!summary.is_empty()).then_some(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn file_diff(path: &str, lines: usize) -> String {
+        let mut diff = format!("diff --git a/{path} b/{path}\n--- a/{path}\n+++ b/{path}\n");
+        for i in 0..lines {
+            diff.push_str(&format!("+line {i}\n"));
+        }
+        diff
+    }
+
+    #[test]
+    fn test_split_into_chunks() {
+        let diff = [
+            file_diff("src/b.rs", 2),
+            file_diff("README.md", 2),
+            file_diff("src/a.rs", 2),
+            file_diff("tests/big.rs", 400),
+        ]
+        .concat();
+        let chunks = split_into_chunks(&diff, 100);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].directories, "., src");
+        assert_eq!(chunks[0].paths, ["README.md", "src/a.rs", "src/b.rs"]);
+        assert_eq!(chunks[1].directories, "tests");
+        assert!(estimate_tokens(&chunks[1].diff) <= 100 + 12);
+        assert!(chunks[1].diff.ends_with("cut off ...]\n"));
+    }
+
+    #[test]
+    fn test_parse_summary() {
+        assert_eq!(
+            parse_summary(json!({"summary": "Adds the\nlogin form. "})),
+            Some("Adds the login form.".to_string())
+        );
+        assert_eq!(parse_summary(json!({"summary": ""})), None);
+    }
+}
@@ -1,113 +1,498 @@
+#[cfg(windows)]
 use std::{
-    io::Write,
-    process::{Command, Stdio},
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    process::{Child, Command, Output, Stdio},
+    thread::{self, JoinHandle},
 };
 
-use indicatif::{ProgressBar, ProgressStyle};
 use serde_json::{Value, from_str};
 use tracing::{debug, trace, warn};
 
-/// Configuration for Claude CLI invocation
-pub struct ClaudeRequest<'a> {
-    pub command: &'a str,
-    pub args: &'a [String],
-    pub model: &'a str,
-    pub json_schema: &'a str,
-    pub prompt: &'a str,
-    pub spinner_message: &'a str,
+use crate::generator::{GenerationRequest, Generator, GeneratorResponse};
+
+/// Generates with the Claude CLI in headless mode, passing the prompt on stdin
+pub struct ClaudeCli {
+    pub command: String,
+    pub args: Vec<String>,
+    pub shell: bool,
 }
 
-/// Invokes Claude CLI and returns the structured output JSON value.
-///
-/// Handles spinner display, subprocess spawning, and JSON parsing.
-/// Returns `None` if the command fails or output cannot be parsed.
-pub fn invoke_claude(request: &ClaudeRequest<'_>) -> Option<Value> {
-    let spinner = ProgressBar::new_spinner();
-    spinner.set_style(
-        ProgressStyle::default_spinner()
-            .tick_chars("✶✸✹✺✹✷")
-            .template("{spinner:.yellow} {msg}")
-            .ok()?,
-    );
-    spinner.set_message(request.spinner_message.to_string());
-    spinner.enable_steady_tick(std::time::Duration::from_millis(200));
-
-    debug!(
-        command = %request.command,
-        args = ?request.args,
-        model = %request.model,
-        prompt_len = request.prompt.len(),
-        "Executing Claude CLI via stdin"
-    );
-
-    let result = Command::new(request.command)
+impl Generator for ClaudeCli {
+    fn generate(&self, request: &GenerationRequest<'_>) -> Option<GeneratorResponse> {
+        let raw_output = self.run(request, &self.args, |_| {})?;
+        parse_structured_output(&raw_output).map(|structured| GeneratorResponse {
+            structured,
+            raw_output,
+            model: request.model.to_string(),
+        })
+    }
+
+    fn generate_streaming(
+        &self,
+        request: &GenerationRequest<'_>,
+        on_text: &mut dyn FnMut(&str),
+    ) -> Option<GeneratorResponse> {
+        let Some(args) = streaming_args(&self.args) else {
+            return self.generate(request);
+        };
+        let raw_output = self.run(request, &args, |line| {
+            if let Some(text) = from_str::<Value>(line).ok().as_ref().and_then(stream_event_text) {
+                on_text(text);
+            }
+        })?;
+
+        // The last event holds the result, in the same shape as `--output-format json`
+        let result = raw_output
+            .lines()
+            .rfind(|line| from_str::<Value>(line).is_ok_and(|event| event["type"] == "result"))
+            .unwrap_or_default();
+        parse_structured_output(result).map(|structured| GeneratorResponse {
+            structured,
+            raw_output,
+            model: request.model.to_string(),
+        })
+    }
+}
+
+impl ClaudeCli {
+    /// Runs the CLI with `args`, passing each line of its output to `on_line` as it is printed,
+    /// and returns the whole output, or `None` if it fails
+    fn run(
+        &self,
+        request: &GenerationRequest<'_>,
+        args: &[String],
+        on_line: impl FnMut(&str),
+    ) -> Option<String> {
+        debug!(
+            command = %self.command,
+            args = ?args,
+            shell = self.shell,
+            model = %request.model,
+            prompt_len = request.prompt.len(),
+            "Executing Claude CLI via stdin"
+        );
+
+        let mut args = args.to_vec();
+        args.extend([
+            "--model".to_string(),
+            request.model.to_string(),
+            "--json-schema".to_string(),
+            request.json_schema.to_string(),
+        ]);
+        run_process(&self.command, &args, self.shell, request.prompt, on_line)
+    }
+}
+
+/// Generates with any CLI that reads a prompt on stdin and prints the answer, such as
+/// `llm -m gpt-4o`, `aichat`, or `ollama run llama3.2`. The model is part of the command, and the
+/// JSON schema is spelled out in the prompt, since such tools have no standard flags for either.
+pub struct CommandCli {
+    pub command: String,
+    pub args: Vec<String>,
+    pub shell: bool,
+}
+
+impl Generator for CommandCli {
+    fn generate(&self, request: &GenerationRequest<'_>) -> Option<GeneratorResponse> {
+        self.generate_streaming(request, &mut |_| {})
+    }
+
+    fn generate_streaming(
+        &self,
+        request: &GenerationRequest<'_>,
+        on_text: &mut dyn FnMut(&str),
+    ) -> Option<GeneratorResponse> {
+        debug!(
+            command = %self.command,
+            args = ?self.args,
+            shell = self.shell,
+            prompt_len = request.prompt.len(),
+            "Executing generator command via stdin"
+        );
+        let prompt = with_schema_instructions(request.prompt, request.json_schema);
+        let raw_output = run_process(&self.command, &self.args, self.shell, &prompt, |line| {
+            on_text(&format!("{line}\n"))
+        })?;
+        Some(GeneratorResponse {
+            structured: Value::String(raw_output.clone()),
+            raw_output,
+            model: request.model.to_string(),
+        })
+    }
+}
+
+/// Appends the JSON schema to a prompt for a backend that can't be given it separately
+fn with_schema_instructions(prompt: &str, json_schema: &str) -> String {
+    format!(
+        "{prompt}\n\nAnswer with only a JSON object matching this JSON schema, without any other \
+         text:\n{json_schema}\n"
+    )
+}
+
+/// Runs the generator process with `input` on stdin, passing each line of its output to `on_line`
+/// as it is printed, and returns the whole output, or `None` if it fails
+fn run_process(
+    command: &str,
+    args: &[String],
+    shell: bool,
+    input: &str,
+    on_line: impl FnMut(&str),
+) -> Option<String> {
+    let result = build_command(command, args, shell)
         .env_remove("CLAUDECODE")
-        .args(request.args)
-        .arg("--model")
-        .arg(request.model)
-        .arg("--json-schema")
-        .arg(request.json_schema)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
-        .and_then(|mut child| {
-            if let Some(mut stdin) = child.stdin.take() {
-                stdin.write_all(request.prompt.as_bytes())?;
-            }
-            child.wait_with_output()
-        });
+        .and_then(|child| stream_lines(child, input.as_bytes(), on_line));
 
-    let result = match result {
+    match result {
         Ok(output) => {
             debug!(
                 status = %output.status,
                 stdout_len = output.stdout.len(),
                 stderr_len = output.stderr.len(),
-                "Claude CLI completed"
+                "Generator command completed"
             );
             if !output.status.success() {
                 let stderr = String::from_utf8_lossy(&output.stderr);
-                warn!(status = %output.status, stderr = %stderr, "Claude CLI failed");
+                warn!(command, status = %output.status, stderr = %stderr, "Generator command failed");
                 None
             } else {
-                let raw_output = String::from_utf8_lossy(&output.stdout);
-                trace!(raw_output = %raw_output, "Claude CLI raw output");
-                parse_structured_output(&raw_output)
+                let raw_output = String::from_utf8_lossy(&output.stdout).into_owned();
+                trace!(raw_output = %raw_output, "Generator command raw output");
+                Some(raw_output)
             }
         }
         Err(e) => {
-            warn!(error = %e, "Failed to execute Claude CLI");
+            warn!(command, error = %e, "Failed to execute generator command");
             None
         }
+    }
+}
+
+/// Writes `input` to the child's stdin, then passes each line of its stdout to `on_line` as it is
+/// printed. Returns the exit status with the complete stdout and stderr.
+pub(crate) fn stream_lines(
+    mut child: Child,
+    input: &[u8],
+    mut on_line: impl FnMut(&str),
+) -> io::Result<Output> {
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input)?;
+    }
+    // Drain stderr alongside stdout so a chatty child can't block on a full pipe
+    let stderr = child.stderr.take().map(|mut stderr| {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            stderr.read_to_end(&mut buf).map(|_| buf)
+        })
+    });
+
+    let mut stdout = Vec::new();
+    if let Some(out) = child.stdout.take() {
+        for line in BufReader::new(out).split(b'\n') {
+            let line = line?;
+            on_line(&String::from_utf8_lossy(&line));
+            stdout.extend_from_slice(&line);
+            stdout.push(b'\n');
+        }
+    }
+    let status = child.wait()?;
+    let stderr = match stderr.map(JoinHandle::join) {
+        Some(Ok(stderr)) => stderr?,
+        _ => Vec::new(),
     };
+    Ok(Output { status, stdout, stderr })
+}
+
+/// Switches `--output-format json` to `stream-json` with partial messages, or returns `None` if
+/// the arguments ask for another output format
+fn streaming_args(args: &[String]) -> Option<Vec<String>> {
+    let at = args
+        .windows(2)
+        .position(|pair| pair[0] == "--output-format" && pair[1] == "json")?;
+    let mut args = args.to_vec();
+    args[at + 1] = "stream-json".to_string();
+    args.extend(["--verbose".to_string(), "--include-partial-messages".to_string()]);
+    Some(args)
+}
+
+/// The piece of the answer in a `stream-json` event: text, or partial input of the tool that
+/// returns the structured output
+fn stream_event_text(event: &Value) -> Option<&str> {
+    let delta = event.pointer("/event/delta")?;
+    delta.get("text").or_else(|| delta.get("partial_json"))?.as_str()
+}
+
+/// Builds the generator process.
+///
+/// With `shell` unset the command is executed directly, found with `where` on Windows (see
+/// [`resolve_program`]). Otherwise it is interpreted by `sh -c` (`cmd /C` on Windows) so it may
+/// use env var prefixes or wrappers; the arguments are still passed as positional parameters
+/// (`"$@"`) rather than spliced into the script on Unix.
+pub(crate) fn build_command(command: &str, args: &[String], shell: bool) -> Command {
+    if !shell {
+        let mut cmd = Command::new(resolve_program(command));
+        cmd.args(args);
+        return cmd;
+    }
+
+    if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(command).args(args);
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(format!("{command} \"$@\"")).arg("sh").args(args);
+        cmd
+    }
+}
+
+/// The program to execute for `command`. On Windows a bare name like `claude` is looked up with
+/// `where`, as npm installs the Claude CLI as a `claude.cmd` wrapper that `Command` doesn't find on
+/// its own; std runs `.cmd` and `.bat` files through `cmd.exe` with the arguments escaped. Lookups
+/// are cached for the run.
+#[cfg(windows)]
+fn resolve_program(command: &str) -> PathBuf {
+    static RESOLVED: Mutex<BTreeMap<String, PathBuf>> = Mutex::new(BTreeMap::new());
+
+    let path = Path::new(command);
+    if path.extension().is_some() || path.components().count() > 1 {
+        return path.to_path_buf();
+    }
+    if let Some(resolved) = RESOLVED.lock().ok().and_then(|r| r.get(command).cloned()) {
+        return resolved;
+    }
+    let resolved = Command::new("where")
+        .arg(command)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| executable_match(&String::from_utf8_lossy(&output.stdout)))
+        .unwrap_or_else(|| path.to_path_buf());
+    debug!(command, resolved = %resolved.display(), "Resolved the generator command");
+    if let Ok(mut cache) = RESOLVED.lock() {
+        cache.insert(command.to_string(), resolved.clone());
+    }
+    resolved
+}
+
+#[cfg(not(windows))]
+fn resolve_program(command: &str) -> &str {
+    command
+}
 
-    spinner.finish_and_clear();
-    result
+/// The first match in the output of `where` that Windows can execute. npm puts an extensionless
+/// shell script for Git Bash next to the `.cmd` wrapper, and `where` may list it first.
+#[cfg(windows)]
+fn executable_match(where_output: &str) -> Option<PathBuf> {
+    where_output.lines().map(str::trim).map(PathBuf::from).find(|path| {
+        path.extension().and_then(|ext| ext.to_str()).is_some_and(|ext| {
+            ["exe", "cmd", "bat", "com"].contains(&ext.to_ascii_lowercase().as_str())
+        })
+    })
 }
 
 /// Parse Claude CLI JSON output and extract the structured_output field.
+///
+/// Models that ignore `--json-schema` answer in prose instead. In that case the free-text result
+/// (or the raw output when it isn't JSON at all) is returned as a `Value::String` so callers can
+/// attempt their own extraction.
 fn parse_structured_output(raw_output: &str) -> Option<Value> {
     match from_str::<Value>(raw_output) {
         Ok(json) => {
-            let structured = if let Some(arr) = json.as_array() {
+            let result = if let Some(arr) = json.as_array() {
                 arr.iter()
                     .rfind(|obj| obj.get("type").and_then(|v| v.as_str()) == Some("result"))
-                    .and_then(|obj| obj.get("structured_output"))
             } else {
-                json.get("structured_output")
+                Some(&json)
             };
 
-            if let Some(structured) = structured {
-                Some(structured.clone())
-            } else {
-                warn!("Claude CLI JSON missing 'structured_output' field");
-                None
+            if let Some(structured) = result.and_then(|obj| obj.get("structured_output")) {
+                return Some(structured.clone());
+            }
+
+            match result.and_then(|obj| obj.get("result")).and_then(|v| v.as_str()) {
+                Some(text) if !text.trim().is_empty() => {
+                    warn!(
+                        "Claude CLI JSON missing 'structured_output' field, using free-text result"
+                    );
+                    Some(Value::String(text.trim().to_string()))
+                }
+                _ => {
+                    warn!("Claude CLI JSON missing 'structured_output' field");
+                    None
+                }
             }
         }
         Err(e) => {
-            warn!(error = %e, raw = %raw_output, "Failed to parse Claude CLI JSON output");
-            None
+            let text = raw_output.trim();
+            if text.is_empty() {
+                warn!(error = %e, "Claude CLI returned empty output");
+                None
+            } else {
+                warn!(error = %e, "Failed to parse Claude CLI JSON output, using raw text");
+                Some(Value::String(text.to_string()))
+            }
         }
     }
 }
+
+/// Recovers a JSON object embedded in free text, e.g. inside a markdown code fence.
+pub fn extract_json_object(text: &str) -> Option<Value> {
+    let start = text.find('{')?;
+    let end = text.rfind('}')?;
+    if end < start {
+        return None;
+    }
+    from_str::<Value>(&text[start..=end]).ok().filter(Value::is_object)
+}
+
+/// Strips a surrounding markdown code fence (```` ```lang ... ``` ````) from free text.
+pub fn strip_code_fence(text: &str) -> &str {
+    let text = text.trim();
+    match text.strip_prefix("```").and_then(|rest| rest.strip_suffix("```")) {
+        // Drop the optional language tag on the opening fence line
+        Some(inner) => inner.split_once('\n').map_or(inner, |(_, body)| body).trim(),
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_build_command_through_shell() {
+        let args = vec!["a b".to_string(), "$HOME".to_string()];
+        let output = build_command("GREETING=hi; printf '%s|' \"$GREETING\"", &args, true)
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "hi|a b|$HOME|");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_build_command_direct_does_not_use_shell() {
+        let args = vec!["$HOME".to_string()];
+        let output = build_command("echo", &args, false).output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "$HOME\n");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_executable_match() {
+        let output = "C:\\Users\\me\\AppData\\Roaming\\npm\\claude\r\n\
+                      C:\\Users\\me\\AppData\\Roaming\\npm\\claude.CMD\r\n";
+        assert_eq!(
+            executable_match(output),
+            Some(PathBuf::from("C:\\Users\\me\\AppData\\Roaming\\npm\\claude.CMD"))
+        );
+        assert_eq!(executable_match("C:\\tools\\claude\r\n"), None);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_resolve_program_keeps_paths_and_extensions() {
+        assert_eq!(resolve_program("claude.exe"), PathBuf::from("claude.exe"));
+        assert_eq!(resolve_program("C:\\tools\\claude"), PathBuf::from("C:\\tools\\claude"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_cli_reads_prompt_on_stdin() {
+        let cli = CommandCli {
+            command: "sh".to_string(),
+            args: vec!["-c".to_string(), "head -n 1; echo '{\"bookmark\": \"x\"}'".to_string()],
+            shell: false,
+        };
+        let request = GenerationRequest {
+            model: "unused",
+            json_schema: "{}",
+            prompt: "Name the bookmark",
+            stable_prefix: 0,
+            spinner_message: "",
+        };
+        let mut streamed = String::new();
+        let response = cli
+            .generate_streaming(&request, &mut |text| streamed.push_str(text))
+            .unwrap();
+        assert_eq!(response.raw_output, "Name the bookmark\n{\"bookmark\": \"x\"}\n");
+        assert_eq!(streamed, response.raw_output);
+        assert!(matches!(response.structured, Value::String(_)));
+    }
+
+    #[test]
+    fn test_parse_structured_output() {
+        let raw = r#"{"type":"result","result":"","structured_output":{"bookmark":"add-auth"}}"#;
+        let value = parse_structured_output(raw).unwrap();
+        assert_eq!(value["bookmark"], "add-auth");
+    }
+
+    #[test]
+    fn test_parse_structured_output_array() {
+        let raw = r#"[{"type":"system"},{"type":"result","structured_output":{"title":"x"}}]"#;
+        let value = parse_structured_output(raw).unwrap();
+        assert_eq!(value["title"], "x");
+    }
+
+    #[test]
+    fn test_parse_falls_back_to_free_text_result() {
+        let raw = r#"{"type":"result","result":"  fix: handle empty input\n"}"#;
+        let value = parse_structured_output(raw).unwrap();
+        assert_eq!(value, Value::String("fix: handle empty input".to_string()));
+    }
+
+    #[test]
+    fn test_parse_falls_back_to_raw_text() {
+        let value = parse_structured_output("add-user-auth\n").unwrap();
+        assert_eq!(value, Value::String("add-user-auth".to_string()));
+    }
+
+    #[test]
+    fn test_parse_empty_output() {
+        assert!(parse_structured_output("  ").is_none());
+        assert!(parse_structured_output(r#"{"type":"result","result":""}"#).is_none());
+    }
+
+    #[test]
+    fn test_streaming_args() {
+        let args = ["-p", "--output-format", "json"].map(String::from);
+        assert_eq!(
+            streaming_args(&args).unwrap(),
+            ["-p", "--output-format", "stream-json", "--verbose", "--include-partial-messages"]
+        );
+        assert!(streaming_args(&["-p".to_string()]).is_none());
+    }
+
+    #[test]
+    fn test_stream_event_text() {
+        let text = r#"{"type":"stream_event","event":{"delta":{"type":"text_delta","text":"fe"}}}"#;
+        assert_eq!(stream_event_text(&from_str(text).unwrap()), Some("fe"));
+        let tool = r#"{"event":{"delta":{"type":"input_json_delta","partial_json":"{\"a"}}}"#;
+        assert_eq!(stream_event_text(&from_str(tool).unwrap()), Some("{\"a"));
+        assert_eq!(stream_event_text(&from_str(r#"{"type":"result"}"#).unwrap()), None);
+    }
+
+    #[test]
+    fn test_extract_json_object() {
+        let text = "Here you go:\n```json\n{\"bookmark\": \"fix-login\"}\n```";
+        assert_eq!(extract_json_object(text).unwrap()["bookmark"], "fix-login");
+        assert!(extract_json_object("no json here").is_none());
+        assert!(extract_json_object("} {").is_none());
+    }
+
+    #[test]
+    fn test_strip_code_fence() {
+        assert_eq!(strip_code_fence("```text\nfeat: add x\n```"), "feat: add x");
+        assert_eq!(strip_code_fence("```\nfeat: add x\n```"), "feat: add x");
+        assert_eq!(strip_code_fence("  feat: add x  "), "feat: add x");
+    }
+}
@@ -1,12 +1,20 @@
-use std::sync::LazyLock;
+use std::{collections::BTreeMap, sync::LazyLock};
 
+use anyhow::{Context, Result};
 use regex::Regex;
-use tracing::{debug, error, trace, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, from_value};
+use tracing::{debug, error, info, trace, warn};
 
 use crate::{
-    claude_client::{ClaudeRequest, invoke_claude},
-    config::CONFIG,
-    text_formatter::format_text,
+    claude_client::{extract_json_object, strip_code_fence},
+    config::{CONFIG, CommitStyle, FallbackStrategy, LongSubject},
+    conventions::format_recent_commits,
+    generator::{GenerationRequest, Generator, backend_from_config, invoke, stable_prefix_len},
+    prompt_template::{PromptContext, render},
+    provenance::Provenance,
+    text_formatter::{format_text, hard_wrap, is_cjk},
+    trailers::{dedupe_trailer_block, split_trailers},
 };
 
 static CONVENTIONAL_COMMIT_RE: LazyLock<Regex> = LazyLock::new(|| {
@@ -14,15 +22,94 @@ static CONVENTIONAL_COMMIT_RE: LazyLock<Regex> = LazyLock::new(|| {
         .expect("Failed to compile conventional commit regex")
 });
 
-const JSON_SCHEMA: &str = r#"{"type":"object","properties":{"commit_type":{"type":"string","enum":["feat","fix","refactor","docs","test","chore","style","perf","build","ci"],"description":"Conventional commit type"},"title":{"type":"string","description":"Commit description without type prefix, max 50 chars, imperative mood"},"body":{"type":"string","description":"Optional commit body explaining what and why"}},"required":["commit_type","title"]}"#;
+const JSON_SCHEMA: &str = r#"{"type":"object","properties":{"commit_type":{"type":"string","enum":["feat","fix","refactor","docs","test","chore","style","perf","build","ci"],"description":"Conventional commit type"},"subject":{"type":"string","description":"Commit description without type prefix, max 50 chars, imperative mood"},"body":{"type":"string","description":"Optional commit body explaining what and why"},"breaking_change":{"type":"string","description":"Description of the breaking change and how to migrate; empty if there is none"},"issues":{"type":"array","items":{"type":"string"},"description":"Issue references found in the diff, such as #123; empty if there are none"}},"required":["commit_type","subject"]}"#;
+
+/// Variables available to `prompt.template` and `dependency_update.prompt_template`
+pub const PROMPT_VARIABLES: &[&str] = &[
+    "diff_content",
+    "language",
+    "files",
+    "branch",
+    "recent_commits",
+    "repo_name",
+    "style_guide",
+    "project_type",
+    "frameworks",
+    "issue",
+    "suggested_type",
+    "dependency_changes",
+];
+
+/// What the commit message prompt is rendered with
+#[derive(Serialize)]
+struct PromptVars<'a> {
+    diff_content: &'a str,
+    language: &'a str,
+    files: &'a [String],
+    branch: &'a str,
+    recent_commits: &'a str,
+    repo_name: &'a str,
+    style_guide: &'a str,
+    project_type: &'a str,
+    frameworks: &'a str,
+    issue: &'a str,
+    suggested_type: &'a str,
+    dependency_changes: &'a str,
+}
+
+/// A generated commit message along with the raw model output it was derived from
+pub struct GeneratedMessage {
+    pub message: String,
+    pub raw_output: String,
+    /// The model and prompt behind the message; `None` for messages written without a model
+    pub provenance: Option<Provenance>,
+}
+
+/// How the final commit message is laid out
+#[derive(Clone, Copy, Debug)]
+pub struct FormatOptions {
+    /// Reflow body paragraphs to 72 columns
+    pub wrap: bool,
+    /// Break lines longer than this at whitespace, even without `wrap`
+    pub hard_wrap_width: Option<usize>,
+    /// Apply `wrap` and `hard_wrap_width` to bodies in Chinese, Japanese, or Korean
+    pub wrap_cjk: bool,
+    pub style: CommitStyle,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            wrap: true,
+            hard_wrap_width: Some(CONFIG.format.hard_wrap_width),
+            wrap_cjk: CONFIG.format.wrap_cjk,
+            style: CONFIG.format.style,
+        }
+    }
+}
 
 /// Generates commit messages using Claude CLI based on diff content
 pub struct CommitMessageGenerator {
     prompt_template: String,
-    command: String,
-    args: Vec<String>,
+    conflicts_template: String,
+    conflict_markers_template: String,
+    feedback_template: String,
+    amend_template: String,
+    examples_template: String,
+    shorten_template: String,
+    backend: Box<dyn Generator + Send + Sync>,
     language: String,
     model: String,
+    files: Vec<String>,
+    context: PromptContext,
+    dependency_changes: String,
+    conflicts: Option<String>,
+    conflict_markers: Option<String>,
+    feedback: Option<String>,
+    existing_description: Option<String>,
+    examples: Option<String>,
+    suggested_type: Option<String>,
+    format_options: FormatOptions,
 }
 
 impl CommitMessageGenerator {
@@ -34,102 +121,738 @@ impl CommitMessageGenerator {
     pub fn new(language: &str, model: &str) -> Self {
         Self {
             prompt_template: CONFIG.prompt.template.clone(),
-            command: CONFIG.generator.command.clone(),
-            args: CONFIG.generator.args.clone(),
+            conflicts_template: CONFIG.prompt.conflicts_template.clone(),
+            conflict_markers_template: CONFIG.prompt.conflict_markers_template.clone(),
+            feedback_template: CONFIG.prompt.feedback_template.clone(),
+            amend_template: CONFIG.prompt.amend_template.clone(),
+            examples_template: CONFIG.prompt.examples_template.clone(),
+            shorten_template: CONFIG.prompt.shorten_template.clone(),
+            backend: backend_from_config(&CONFIG.generator),
             language: language.to_string(),
             model: model.to_string(),
+            files: Vec::new(),
+            context: PromptContext::default(),
+            dependency_changes: String::new(),
+            conflicts: None,
+            conflict_markers: None,
+            feedback: None,
+            existing_description: None,
+            examples: None,
+            suggested_type: None,
+            format_options: FormatOptions::default(),
         }
     }
 
+    /// Switches to the dependency update prompt for changes that only touch manifests and lock
+    /// files
+    ///
+    /// # Arguments
+    /// - `dependency_changes` - A formatted list of dependency version changes
+    pub fn for_dependency_update(mut self, dependency_changes: &str) -> Self {
+        self.prompt_template = CONFIG.dependency_update.prompt_template.clone();
+        self.dependency_changes = dependency_changes.to_string();
+        self
+    }
+
+    /// Makes the changed files and facts about the repository available to the prompt template
+    ///
+    /// # Arguments
+    /// - `files` - Paths of the changed files
+    /// - `context` - The branch, recent commits, and repository name
+    pub fn with_prompt_context(mut self, files: Vec<String>, context: PromptContext) -> Self {
+        self.files = files;
+        self.context = context;
+        self
+    }
+
+    /// Asks the model to note the given unresolved conflicts in the message
+    ///
+    /// # Arguments
+    /// - `conflicts` - A formatted list of conflicted paths
+    pub fn with_conflicts(mut self, conflicts: &str) -> Self {
+        self.conflicts = Some(conflicts.to_string());
+        self
+    }
+
+    /// Tells the model which files hold conflict markers, so it doesn't describe them as changes
+    ///
+    /// # Arguments
+    /// - `conflicts` - A formatted list of conflicted paths
+    pub fn with_conflict_markers(mut self, conflicts: &str) -> Self {
+        self.conflict_markers = Some(conflicts.to_string());
+        self
+    }
+
+    /// Passes the user's feedback on a rejected message along with the prompt
+    ///
+    /// # Arguments
+    /// - `feedback` - What the user wants changed
+    pub fn with_feedback(mut self, feedback: &str) -> Self {
+        self.feedback = Some(feedback.to_string());
+        self
+    }
+
+    /// Shows the model recent commit messages of the repository whose language and style to match
+    ///
+    /// # Arguments
+    /// - `examples` - The formatted messages
+    pub fn with_examples(mut self, examples: &str) -> Self {
+        self.examples = Some(examples.to_string());
+        self
+    }
+
+    /// Asks the model to update the commit's existing description rather than write a new one
+    ///
+    /// # Arguments
+    /// - `description` - The description the commit already has
+    pub fn with_existing_description(mut self, description: &str) -> Self {
+        self.existing_description = Some(description.to_string());
+        self
+    }
+
+    /// Tells the model which commit type the changed paths call for (see `[types]`), and puts that
+    /// type on the generated message if the model picks another
+    ///
+    /// # Arguments
+    /// - `commit_type` - The type, e.g. `docs`
+    pub fn with_suggested_type(mut self, commit_type: &str) -> Self {
+        self.suggested_type = Some(commit_type.to_string());
+        self
+    }
+
+    /// Sets how the generated message is formatted. The gitmoji style switches to its own prompt.
+    pub fn with_format_options(mut self, format_options: FormatOptions) -> Self {
+        if format_options.style == CommitStyle::Gitmoji {
+            self.prompt_template = CONFIG.gitmoji.prompt_template.clone();
+        }
+        self.format_options = format_options;
+        self
+    }
+
     /// Generates a commit message from the provided diff content
     ///
     /// # Arguments
     /// - `diff_content` - The diff content to analyze for message generation
     ///
     /// # Returns
-    /// `Some(message)` if generation succeeds, `None` if it fails.
-    /// If the generated message doesn't follow conventional commit format, the default
-    /// commit message prefix is prepended.
-    pub fn generate(&self, diff_content: &str) -> Option<String> {
+    /// `Some(generated)` if generation succeeds, `None` if it fails, or an error if the Claude
+    /// call budget is exhausted.
+    /// If the generated message doesn't follow conventional commit format, it is fixed up
+    /// according to `generator.fallback_strategy`. In the gitmoji style, a message that already
+    /// starts with one of the `[gitmoji]` emojis is kept, and the type prefix of any other is
+    /// replaced with its emoji. A subject longer than `format.max_subject_length` is handled as
+    /// `format.long_subject` says.
+    pub fn generate(&self, diff_content: &str) -> Result<Option<GeneratedMessage>> {
         debug!(diff_len = diff_content.len(), "Starting commit message generation");
-        self.try_generate(diff_content).map(|message| {
-            let first_line = message.lines().next().unwrap_or("").trim();
-            let message = if CONVENTIONAL_COMMIT_RE.is_match(first_line) {
-                debug!("Generated message follows conventional commit format");
-                message
-            } else {
-                error!(first_line = %first_line, "Generated message does not follow conventional commit format, prepending default");
-                format!("{}\n\n{message}", CONFIG.generator.default_commit_message)
-            };
-            format_text(&message, 72)
+        let gitmoji = self.format_options.style == CommitStyle::Gitmoji;
+        let emojis = &CONFIG.gitmoji.emojis;
+        let Some(generated) = self.try_generate(diff_content, None)? else {
+            return Ok(None);
+        };
+        let generated = self.fit_subject_length(diff_content, generated)?;
+        let message = if gitmoji && is_gitmoji(&generated.message, emojis) {
+            debug!("Generated message starts with a gitmoji");
+            generated.message
+        } else if is_conventional(&generated.message) {
+            debug!("Generated message follows conventional commit format");
+            match &self.suggested_type {
+                Some(commit_type) => replace_type(&generated.message, commit_type),
+                None => generated.message,
+            }
+        } else {
+            let first_line = generated.message.lines().next().unwrap_or("").trim();
+            error!(first_line = %first_line, strategy = ?CONFIG.generator.fallback_strategy, "Generated message does not follow conventional commit format, applying fallback");
+            apply_fallback(
+                &generated.message,
+                &CONFIG.generator.default_commit_message,
+                CONFIG.generator.fallback_strategy,
+            )
+        };
+        Ok(Some(GeneratedMessage {
+            message: format_message(
+                &apply_style(&message, self.format_options.style),
+                &self.format_options,
+            ),
+            ..generated
+        }))
+    }
+
+    /// Keeps the subject within `format.max_subject_length`, asking the model for a shorter one
+    /// first with `format.long_subject = "shorten"`, then cutting it and moving the rest into the
+    /// body
+    fn fit_subject_length(
+        &self,
+        diff_content: &str,
+        generated: GeneratedMessage,
+    ) -> Result<GeneratedMessage> {
+        let max = CONFIG.format.max_subject_length;
+        let subject = generated.message.lines().next().unwrap_or("").trim();
+        let length = subject.chars().count();
+        if max == 0 || length <= max {
+            return Ok(generated);
+        }
+
+        let generated = if CONFIG.format.long_subject == LongSubject::Shorten {
+            info!(length, max, "Subject too long, asking the model for a shorter one");
+            let shorten = self
+                .shorten_template
+                .replace("{subject}", subject)
+                .replace("{max_length}", &max.to_string());
+            self.try_generate(diff_content, Some(&shorten))?.unwrap_or(generated)
+        } else {
+            generated
+        };
+        Ok(GeneratedMessage {
+            message: move_subject_overflow(&generated.message, max),
+            ..generated
         })
     }
 
-    fn try_generate(&self, diff_content: &str) -> Option<String> {
-        let prompt = self
-            .prompt_template
-            .replace("{language}", &self.language)
-            .replace("{diff_content}", diff_content);
+    /// Generates a message, with `extra` appended to the prompt
+    /// The prompt that [`Self::generate`] first sends for the diff, with the template rendered and
+    /// the conflict, example, description, and feedback sections added
+    pub fn prompt(&self, diff_content: &str) -> Result<String> {
+        self.build_prompt(diff_content, None)
+    }
+
+    fn build_prompt(&self, diff_content: &str, extra: Option<&str>) -> Result<String> {
+        let recent_commits =
+            format_recent_commits(&self.context.recent_commits, CONFIG.prompt.recent_commit_bodies);
+        let vars = PromptVars {
+            diff_content,
+            language: &self.language,
+            files: &self.files,
+            branch: &self.context.branch,
+            recent_commits: &recent_commits,
+            repo_name: &self.context.repo_name,
+            style_guide: &self.context.style_guide,
+            project_type: &self.context.project_type,
+            frameworks: &self.context.frameworks,
+            issue: &self.context.issue,
+            suggested_type: self.suggested_type.as_deref().unwrap_or_default(),
+            dependency_changes: &self.dependency_changes,
+        };
+        let mut prompt = render(&self.prompt_template, PROMPT_VARIABLES, vars, &self.context.root)
+            .context("Failed to render the prompt template")?;
+        if let Some(conflicts) = &self.conflicts {
+            prompt.push_str(&self.conflicts_template.replace("{conflicts}", conflicts));
+        }
+        if let Some(conflicts) = &self.conflict_markers {
+            prompt.push_str(&self.conflict_markers_template.replace("{conflicts}", conflicts));
+        }
+        if let Some(examples) = &self.examples {
+            prompt.push_str(&self.examples_template.replace("{examples}", examples));
+        }
+        if let Some(description) = &self.existing_description {
+            prompt.push_str(&self.amend_template.replace("{description}", description));
+        }
+        if let Some(feedback) = &self.feedback {
+            prompt.push_str(&self.feedback_template.replace("{feedback}", feedback));
+        }
+        if let Some(extra) = extra {
+            prompt.push_str(extra);
+        }
+        Ok(prompt)
+    }
+
+    fn try_generate(
+        &self,
+        diff_content: &str,
+        extra: Option<&str>,
+    ) -> Result<Option<GeneratedMessage>> {
+        let prompt = match self.build_prompt(diff_content, extra) {
+            Ok(prompt) => prompt,
+            Err(e) => {
+                error!(error = %e.root_cause(), "Failed to render the prompt template");
+                return Ok(None);
+            }
+        };
         trace!(prompt_len = prompt.len(), "Prepared prompt for Claude");
+        let recent_commits =
+            format_recent_commits(&self.context.recent_commits, CONFIG.prompt.recent_commit_bodies);
 
-        let request = ClaudeRequest {
-            command: &self.command,
-            args: &self.args,
+        // Everything before the diff and the recent commits is the same for every commit, so
+        // watch mode in particular can reuse it from the prompt cache
+        let request = GenerationRequest {
             model: &self.model,
             json_schema: JSON_SCHEMA,
             prompt: &prompt,
+            stable_prefix: stable_prefix_len(&prompt, &[diff_content, &recent_commits]),
             spinner_message: "Generating commit message with Claude...",
         };
 
-        let structured = invoke_claude(&request)?;
+        let Some(response) = invoke(self.backend.as_ref(), &request)? else {
+            return Ok(None);
+        };
+        // The subject length is enforced by `generate`, which may ask for a shorter one
+        let Some(message) = parse_output(response.structured, usize::MAX) else {
+            return Ok(None);
+        };
+        trace!(message = %message, "Claude CLI output");
+        Ok(Some(GeneratedMessage {
+            message,
+            raw_output: response.raw_output,
+            provenance: Some(Provenance::new(&response.model, &prompt)),
+        }))
+    }
+}
 
-        let commit_type = structured
-            .get("commit_type")
-            .and_then(|v| v.as_str())
-            .unwrap_or("")
-            .trim();
-        let title = structured.get("title").and_then(|v| v.as_str()).unwrap_or("").trim();
-        let body = structured.get("body").and_then(|v| v.as_str()).unwrap_or("").trim();
+impl Default for CommitMessageGenerator {
+    fn default() -> Self {
+        Self::new("English", "haiku")
+    }
+}
 
-        if title.is_empty() {
-            warn!("Claude CLI returned empty title");
+/// The fields of a commit message as returned by the model
+#[derive(Debug, Default, Deserialize)]
+pub struct StructuredMessage {
+    #[serde(default)]
+    pub commit_type: String,
+    /// Accepts `title` too, the field name used before the schema had a subject
+    #[serde(default, alias = "title")]
+    pub subject: String,
+    #[serde(default)]
+    pub body: String,
+    /// Description of the breaking change, if any
+    #[serde(default)]
+    pub breaking_change: String,
+    /// Issue references such as `#123`
+    #[serde(default)]
+    pub issues: Vec<String>,
+}
+
+impl StructuredMessage {
+    /// Assembles the message: `type!: subject`, the body, then `BREAKING CHANGE:` and `Refs:`
+    /// footers. A subject line longer than `max_subject_length` is cut at a word boundary with
+    /// the rest moved into the body.
+    ///
+    /// Returns `None` when the subject is empty.
+    pub fn assemble(&self, max_subject_length: usize) -> Option<String> {
+        // Strip any accidental type prefix the model may have included in the subject
+        let subject = strip_type_prefix(self.subject.trim());
+        if subject.is_empty() {
+            warn!("Claude CLI returned empty subject");
             return None;
         }
 
-        // Strip any accidental type prefix the model may have included in the title
-        let title = strip_type_prefix(title);
-
-        let full_title = if commit_type.is_empty() {
-            debug!("commit_type field is empty, using 'chore' as fallback");
-            format!("chore: {title}")
-        } else {
-            format!("{commit_type}: {title}")
+        let commit_type = match self.commit_type.trim() {
+            "" => {
+                debug!("commit_type field is empty, using 'chore' as fallback");
+                "chore"
+            }
+            commit_type => commit_type,
         };
+        let breaking_change = self.breaking_change.trim();
+        let bang = if breaking_change.is_empty() { "" } else { "!" };
+        let prefix = format!("{commit_type}{bang}: ");
+        let mut message = format!("{prefix}{subject}");
 
-        let message =
-            if body.is_empty() { full_title } else { format!("{full_title}\n\n{body}") };
-        trace!(message = %message, "Claude CLI output");
-        Some(message)
+        let body = self.body.trim();
+        if !body.is_empty() {
+            message.push_str(&format!("\n\n{body}"));
+        }
+
+        let mut footers = Vec::new();
+        if !breaking_change.is_empty() {
+            footers.push(format!("BREAKING CHANGE: {breaking_change}"));
+        }
+        for issue in self
+            .issues
+            .iter()
+            .map(|issue| issue.trim())
+            .filter(|issue| !issue.is_empty())
+        {
+            footers.push(format!("Refs: {issue}"));
+        }
+        if !footers.is_empty() {
+            message.push_str(&format!("\n\n{}", footers.join("\n")));
+        }
+        Some(move_subject_overflow(&message, max_subject_length))
     }
 }
 
-impl Default for CommitMessageGenerator {
-    fn default() -> Self {
-        Self::new("English", "haiku")
+/// Cuts a subject line longer than `max` characters at a word boundary, marking the cut with `…`,
+/// and starts the body with the rest of it
+pub(crate) fn move_subject_overflow(message: &str, max: usize) -> String {
+    let (subject, rest) = message.split_once('\n').unwrap_or((message, ""));
+    let subject = subject.trim();
+    if subject.chars().count() <= max {
+        return message.to_string();
+    }
+    let cut = fit_subject(subject, max.saturating_sub(1));
+    let overflow = subject[cut.len()..].trim();
+    let rest = rest.trim_start_matches('\n');
+    if rest.is_empty() {
+        format!("{cut}…\n\n…{overflow}")
+    } else {
+        format!("{cut}…\n\n…{overflow}\n\n{rest}")
+    }
+}
+
+/// Shortens the subject to at most `max` characters, cutting at a word boundary when there is one
+pub(crate) fn fit_subject(subject: &str, max: usize) -> &str {
+    if subject.chars().count() <= max {
+        return subject;
+    }
+    let end = subject.char_indices().nth(max).map_or(subject.len(), |(i, _)| i);
+    let cut = match subject[..end].rfind(char::is_whitespace) {
+        Some(space) if space > 0 => &subject[..space],
+        _ => &subject[..end],
+    };
+    warn!(subject = %subject, max, "Subject too long, shortening it");
+    cut.trim_end()
+}
+
+/// Assembles a commit message from the structured (or free-text) model output, keeping the
+/// subject within `format.max_subject_length`
+pub fn message_from_output(structured: Value) -> Option<String> {
+    parse_output(structured, CONFIG.format.max_subject_length)
+}
+
+fn parse_output(structured: Value, max_subject_length: usize) -> Option<String> {
+    let structured = match structured {
+        Value::String(text) => match extract_json_object(&text) {
+            Some(object) => object,
+            None => {
+                // The model ignored the schema: use its answer as the message verbatim and let
+                // the conventional commit check in `generate` fix up the subject if needed
+                debug!("Using free-text response as commit message");
+                let message = strip_code_fence(&text);
+                return (!message.is_empty()).then(|| message.to_string());
+            }
+        },
+        value => value,
+    };
+
+    match from_value::<StructuredMessage>(structured) {
+        Ok(message) => message.assemble(max_subject_length),
+        Err(e) => {
+            warn!(error = %e, "Claude CLI output does not match the commit message schema");
+            None
+        }
+    }
+}
+
+/// Maximum length of a first line that `FallbackStrategy::Wrap` treats as a subject
+const MAX_WRAPPED_SUBJECT_LEN: usize = 72;
+
+/// Fixes up a message that doesn't follow the conventional commit format
+///
+/// # Arguments
+/// - `message` - The non-conventional message
+/// - `default_message` - The configured default commit message, e.g. `chore: update changes`
+/// - `strategy` - Whether to prepend the default message or wrap a subject-like first line
+fn apply_fallback(message: &str, default_message: &str, strategy: FallbackStrategy) -> String {
+    let message = message.trim();
+    let (first_line, rest) = message.split_once('\n').unwrap_or((message, ""));
+    let first_line = first_line.trim();
+    let looks_like_subject = !first_line.is_empty()
+        && first_line.chars().count() <= MAX_WRAPPED_SUBJECT_LEN
+        && !first_line.ends_with('.');
+
+    match (strategy, default_message.split_once(':')) {
+        (FallbackStrategy::Wrap, Some((commit_type, _))) if looks_like_subject => {
+            let subject = format!("{}: {first_line}", commit_type.trim());
+            let rest = rest.trim_start_matches('\n');
+            if rest.is_empty() { subject } else { format!("{subject}\n\n{rest}") }
+        }
+        _ => format!("{default_message}\n\n{message}"),
+    }
+}
+
+/// Builds a deterministic message from a configured template, such as
+/// `diff.trivial_message_template`, without calling Claude
+///
+/// # Arguments
+/// - `template` - The message template, with an optional `{files}` placeholder
+/// - `paths` - The changed file paths
+/// - `format_options` - How to format the message
+pub fn templated_message<'a>(
+    template: &str,
+    paths: impl Iterator<Item = &'a str>,
+    format_options: &FormatOptions,
+) -> GeneratedMessage {
+    let files = paths.collect::<Vec<_>>().join(", ");
+    let message = template.replace("{files}", &files);
+    GeneratedMessage {
+        message: format_message(&apply_style(&message, format_options.style), format_options),
+        raw_output: String::new(),
+        provenance: None,
+    }
+}
+
+/// Returns true if the subject line of the message follows the conventional commit format
+pub fn is_conventional(message: &str) -> bool {
+    let first_line = message.lines().next().unwrap_or("").trim();
+    CONVENTIONAL_COMMIT_RE.is_match(first_line)
+}
+
+/// Gives a conventional commit message another type, keeping its scope and breaking change marker
+pub fn replace_type(message: &str, commit_type: &str) -> String {
+    let Some(caps) = CONVENTIONAL_PREFIX_RE.captures(message) else {
+        return message.to_string();
+    };
+    let found = &caps["type"];
+    if found == commit_type {
+        return message.to_string();
+    }
+    warn!(found, expected = %commit_type, "Generated type doesn't match the changed paths, replacing it");
+    format!("{commit_type}{}", &message[found.len()..])
+}
+
+/// Puts a conventional commit message into `style`
+pub fn apply_style(message: &str, style: CommitStyle) -> String {
+    match style {
+        CommitStyle::Conventional => message.to_string(),
+        CommitStyle::Gitmoji => to_gitmoji(message, &CONFIG.gitmoji.emojis),
+    }
+}
+
+/// Returns true if the subject line of the message starts with one of `emojis` and a space
+pub fn is_gitmoji(message: &str, emojis: &BTreeMap<String, String>) -> bool {
+    let first_line = message.lines().next().unwrap_or("").trim();
+    strip_gitmoji(first_line, emojis)
+        .is_some_and(|rest| rest.starts_with(' ') && !rest.trim().is_empty())
+}
+
+/// The subject line without a leading emoji from `emojis`. The emoji presentation selector (U+FE0F)
+/// is optional, as models and editors often drop it.
+fn strip_gitmoji<'a>(subject: &'a str, emojis: &BTreeMap<String, String>) -> Option<&'a str> {
+    emojis.values().find_map(|emoji| {
+        let rest = subject.strip_prefix(emoji.trim_end_matches('\u{fe0f}'))?;
+        Some(rest.strip_prefix('\u{fe0f}').unwrap_or(rest))
+    })
+}
+
+/// Replaces the `type(scope)!: ` prefix of a conventional subject with the emoji of the type, or
+/// the `breaking` emoji for a breaking change. Messages without a prefix, or whose type has no
+/// emoji, are returned as they are.
+pub fn to_gitmoji(message: &str, emojis: &BTreeMap<String, String>) -> String {
+    let Some(caps) = CONVENTIONAL_PREFIX_RE.captures(message) else {
+        return message.to_string();
+    };
+    let key = if caps.name("bang").is_some() && emojis.contains_key("breaking") {
+        "breaking"
+    } else {
+        &caps["type"]
+    };
+    match emojis.get(key) {
+        Some(emoji) => format!("{emoji} {}", &message[caps[0].len()..]),
+        None => {
+            warn!(commit_type = %key, "No gitmoji for the commit type, keeping the type prefix");
+            message.to_string()
+        }
     }
 }
 
+static CONVENTIONAL_PREFIX_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(?P<type>[a-z]+)(?:\([^)]+\))?(?P<bang>!)?:\s+")
+        .expect("Failed to compile conventional prefix regex")
+});
+
+/// Applies the final formatting shared by generated and user-supplied messages.
+///
+/// The result always ends with a single newline, as jj descriptions conventionally do.
+///
+/// The body is wrapped (or, without wrapping, only pathologically long lines are broken), while the
+/// trailer block is kept line-by-line with duplicates removed. A CJK body is left as written when
+/// `wrap_cjk` is unset.
+pub fn format_message(message: &str, options: &FormatOptions) -> String {
+    let (body, trailers) = split_trailers(message);
+    let wrap_body = options.wrap_cjk || !is_cjk(body);
+    let formatted =
+        if options.wrap && wrap_body { format_text(body, 72) } else { body.to_string() };
+    let formatted = match options.hard_wrap_width.filter(|_| wrap_body) {
+        Some(width) => hard_wrap(&formatted, width),
+        None => formatted,
+    };
+    let message = match trailers {
+        Some(block) => format!("{formatted}\n\n{}", dedupe_trailer_block(block)),
+        None => formatted,
+    };
+    normalize_message(&message)
+}
+
+/// Normalizes whitespace so descriptions compare and render consistently: trailing whitespace is
+/// stripped from every line, runs of blank lines collapse into one, and the message ends with
+/// exactly one newline.
+fn normalize_message(message: &str) -> String {
+    let mut normalized = String::with_capacity(message.len() + 1);
+    let mut previous_blank = true;
+    for line in message.lines().map(str::trim_end) {
+        let blank = line.is_empty();
+        if blank && previous_blank {
+            continue;
+        }
+        normalized.push_str(line);
+        normalized.push('\n');
+        previous_blank = blank;
+    }
+    while normalized.ends_with("\n\n") {
+        normalized.pop();
+    }
+    normalized
+}
+
 /// Strips a conventional commit type prefix if the model redundantly included one in the title.
 /// e.g., "feat: add login" -> "add login", "add login" -> "add login"
 fn strip_type_prefix(title: &str) -> &str {
-    if let Some(m) = TYPE_PREFIX_RE.find(title) {
-        title[m.end()..].trim_start()
-    } else {
-        title
-    }
+    if let Some(m) = TYPE_PREFIX_RE.find(title) { title[m.end()..].trim_start() } else { title }
 }
 
 static TYPE_PREFIX_RE: LazyLock<Regex> = LazyLock::new(|| {
-    Regex::new(r"^(?:feat|fix|refactor|docs|test|chore|style|perf|build|ci)(?:\([^)]+\))?(?:!)?:\s*")
-        .expect("Failed to compile type prefix regex")
+    Regex::new(
+        r"^(?:feat|fix|refactor|docs|test|chore|style|perf|build|ci)(?:\([^)]+\))?(?:!)?:\s*",
+    )
+    .expect("Failed to compile type prefix regex")
 });
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULT: &str = "chore: update changes";
+
+    #[test]
+    fn test_format_message_keeps_cjk_body_without_wrap_cjk() {
+        let body = "ログイン画面".repeat(10);
+        let message = format!("feat: ログインを追加\n\n{body}\n");
+        let options = |wrap_cjk| FormatOptions {
+            wrap: true,
+            hard_wrap_width: Some(40),
+            wrap_cjk,
+            style: CommitStyle::Conventional,
+        };
+        assert_eq!(format_message(&message, &options(false)), message);
+        assert_ne!(format_message(&message, &options(true)), message);
+    }
+
+    #[test]
+    fn test_prompt_includes_diff_and_feedback() {
+        let diff = "diff --git a/src/lib.rs b/src/lib.rs\n+fn added() {}\n";
+        let prompt = CommitMessageGenerator::new("English", "test-model")
+            .with_feedback("Mention the new function")
+            .prompt(diff)
+            .unwrap();
+        assert!(prompt.contains("+fn added() {}"));
+        assert!(prompt.contains("Mention the new function"));
+    }
+
+    #[test]
+    fn test_normalize_adds_trailing_newline() {
+        assert_eq!(normalize_message("feat: add x"), "feat: add x\n");
+        assert_eq!(normalize_message("feat: add x\n\nBody."), "feat: add x\n\nBody.\n");
+    }
+
+    #[test]
+    fn test_normalize_strips_trailing_whitespace() {
+        assert_eq!(
+            normalize_message("feat: add x  \n\t\nBody.\t\n\n \n"),
+            "feat: add x\n\nBody.\n"
+        );
+    }
+
+    #[test]
+    fn test_normalize_collapses_blank_lines() {
+        assert_eq!(
+            normalize_message("\nfeat: add x\n\n\n\nBody.\n\n\nMore.\n"),
+            "feat: add x\n\nBody.\n\nMore.\n"
+        );
+    }
+
+    fn structured(value: Value) -> StructuredMessage {
+        from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_assemble_with_footers() {
+        let message = structured(serde_json::json!({
+            "commit_type": "feat",
+            "subject": "feat: drop the v1 API",
+            "body": "The v1 API has been deprecated for a year.",
+            "breaking_change": "Clients must use /v2.",
+            "issues": ["#12", " "],
+        }));
+        assert_eq!(
+            message.assemble(72).unwrap(),
+            "feat!: drop the v1 API\n\nThe v1 API has been deprecated for a year.\n\n\
+            BREAKING CHANGE: Clients must use /v2.\nRefs: #12"
+        );
+    }
+
+    #[test]
+    fn test_assemble_enforces_subject_length() {
+        let message = structured(
+            serde_json::json!({"commit_type": "fix", "title": "handle empty input in the parser"}),
+        );
+        assert_eq!(message.assemble(24).unwrap(), "fix: handle empty…\n\n…input in the parser");
+        assert_eq!(fit_subject("abcdef", 3), "abc");
+        assert_eq!(
+            move_subject_overflow("feat: add a very long subject\n\nBody.", 20),
+            "feat: add a very…\n\n…long subject\n\nBody."
+        );
+        assert_eq!(move_subject_overflow("feat: short\n", 20), "feat: short\n");
+        assert!(structured(serde_json::json!({"subject": " "})).assemble(72).is_none());
+    }
+
+    #[test]
+    fn test_replace_type() {
+        assert_eq!(
+            replace_type("feat(api)!: drop v1\n\nBody.", "docs"),
+            "docs(api)!: drop v1\n\nBody."
+        );
+        assert_eq!(replace_type("docs: fix typo", "docs"), "docs: fix typo");
+        assert_eq!(replace_type("Fix typo", "docs"), "Fix typo");
+    }
+
+    #[test]
+    fn test_to_gitmoji() {
+        let emojis: BTreeMap<String, String> =
+            [("feat", "✨"), ("refactor", "♻️"), ("breaking", "💥")]
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+        assert_eq!(to_gitmoji("feat: add login\n\nBody.\n", &emojis), "✨ add login\n\nBody.\n");
+        assert_eq!(to_gitmoji("refactor(cli)!: drop --foo", &emojis), "💥 drop --foo");
+        assert_eq!(to_gitmoji("docs: fix typo", &emojis), "docs: fix typo");
+        assert_eq!(to_gitmoji("✨ add login", &emojis), "✨ add login");
+
+        assert!(is_gitmoji("✨ add login\n", &emojis));
+        // With or without the emoji presentation selector
+        assert!(is_gitmoji("♻️ split parser", &emojis));
+        assert!(is_gitmoji("\u{267b} split parser", &emojis));
+        assert!(!is_gitmoji("🚀 ship it", &emojis));
+        assert!(!is_gitmoji("✨", &emojis));
+        assert!(!is_gitmoji("feat: add login", &emojis));
+    }
+
+    #[test]
+    fn test_fallback_prepend() {
+        assert_eq!(
+            apply_fallback("Add login form", DEFAULT, FallbackStrategy::Prepend),
+            "chore: update changes\n\nAdd login form"
+        );
+    }
+
+    #[test]
+    fn test_fallback_wrap_subject() {
+        assert_eq!(
+            apply_fallback("Add login form\n\nWith validation.", DEFAULT, FallbackStrategy::Wrap),
+            "chore: Add login form\n\nWith validation."
+        );
+    }
+
+    #[test]
+    fn test_fallback_wrap_prepends_non_subject() {
+        let sentence = "This change adds a login form.";
+        assert_eq!(
+            apply_fallback(sentence, DEFAULT, FallbackStrategy::Wrap),
+            format!("{DEFAULT}\n\n{sentence}")
+        );
+        let long = "a".repeat(MAX_WRAPPED_SUBJECT_LEN + 1);
+        assert_eq!(
+            apply_fallback(&long, DEFAULT, FallbackStrategy::Wrap),
+            format!("{DEFAULT}\n\n{long}")
+        );
+    }
+}
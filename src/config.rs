@@ -1,43 +1,988 @@
-use std::sync::LazyLock;
+use std::{
+    collections::BTreeMap,
+    fs::read_to_string,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{LazyLock, Mutex, OnceLock},
+};
 
-use serde::Deserialize;
-use toml::from_str;
+use anyhow::{Context, Result, bail};
+use dirs::{config_dir, home_dir};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use toml::{Table, Value, from_str, to_string_pretty};
+use tracing::{debug, warn};
 
-#[derive(Deserialize)]
+use crate::prompt_template;
+
+/// Embedded defaults that every configuration layer is merged over
+const EMBEDDED_CONFIG: &str = include_str!("../assets/commit-config.toml");
+
+/// Name of the repository-local configuration file at the workspace root
+const REPO_CONFIG_FILE: &str = ".ccc-jj.toml";
+
+/// Environment variable naming a config file to use instead of the user config files
+const CONFIG_ENV: &str = "CCC_JJ_CONFIG";
+
+/// The tables a repository's `.ccc-jj.toml` may set. It comes with the clone, so anything that
+/// runs commands, chooses where requests and API keys go, or turns off secret masking
+/// (`generator`, `redaction`, `audit`, ...) is only read from user config files.
+const REPO_TABLES: &[&str] = &[
+    "prompt",
+    "format",
+    "diff",
+    "types",
+    "ticket",
+    "conventions",
+    "lint",
+    "gitmoji",
+    "trailers",
+    "bookmark",
+    "pr_description",
+    "explain",
+    "split",
+    "summarize",
+    "dependency_update",
+];
+
+/// Keys of [`REPO_TABLES`] that a repository still can't set: files read from outside the
+/// workspace, and where issue lookups send tokens
+const REPO_DENIED_KEYS: &[(&str, &str)] = &[
+    ("prompt", "style_file"),
+    ("ticket", "fetch_issues"),
+    ("ticket", "github_token_env"),
+    ("ticket", "gitlab_token_env"),
+    ("ticket", "gitlab_host"),
+];
+
+#[derive(Deserialize, Serialize)]
 pub struct Config {
     pub prompt: PromptConfig,
     pub generator: GeneratorConfig,
     pub bookmark: BookmarkConfig,
+    pub pr_description: PrDescriptionConfig,
+    pub explain: ExplainConfig,
     pub diff: DiffConfig,
+    pub summarize: SummarizeConfig,
+    pub dependency_update: DependencyUpdateConfig,
+    pub records: RecordsConfig,
+    pub audit: AuditConfig,
+    pub format: FormatConfig,
+    pub split: SplitConfig,
+    pub redaction: RedactionConfig,
+    pub trailers: TrailersConfig,
+    pub ticket: TicketConfig,
+    pub conventions: ConventionsConfig,
+    pub snapshot: SnapshotConfig,
+    pub lint: LintConfig,
+    pub gitmoji: GitmojiConfig,
+    /// Glob patterns of paths mapped to the commit type they call for
+    pub types: BTreeMap<String, String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct PromptConfig {
     pub template: String,
+    pub conflicts_template: String,
+    pub conflict_markers_template: String,
+    pub feedback_template: String,
+    pub amend_template: String,
+    pub examples_template: String,
+    pub shorten_template: String,
+    pub recent_commits: usize,
+    pub recent_commit_bodies: bool,
+    pub style_file: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct GeneratorConfig {
+    pub backend: Backend,
     pub command: String,
     pub args: Vec<String>,
+    pub shell: bool,
     pub default_commit_message: String,
+    pub fallback_strategy: FallbackStrategy,
+    pub retries: usize,
+    pub retry_backoff_ms: u64,
+    pub fallback_models: Vec<String>,
+    pub offline_fallback: bool,
+    /// Commit with `default_commit_message` when every model fails and `offline_fallback` is off
+    pub default_message_fallback: bool,
+    pub stream: bool,
+    pub parallel_calls: usize,
+    pub rate_limit: RateLimitConfig,
+    pub anthropic: AnthropicConfig,
+    pub openai: OpenAiConfig,
+    pub ollama: OllamaConfig,
+}
+
+/// Where commit messages and bookmark names are generated
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// The Claude CLI (`command` and `args`)
+    ClaudeCli,
+    /// Any other CLI (`command` and `args`) that reads the prompt on stdin and prints the answer
+    Command,
+    /// The Anthropic Messages API
+    Anthropic,
+    /// An OpenAI-compatible chat completions endpoint
+    Openai,
+    /// A local Ollama server
+    Ollama,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
+pub struct AnthropicConfig {
+    pub url: String,
+    pub api_key_env: String,
+    pub prompt_caching: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct OpenAiConfig {
+    pub url: String,
+    #[serde(default)]
+    pub api_key_env: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct OllamaConfig {
+    pub url: String,
+}
+
+/// Limits on model calls shared by everything a run generates, e.g. the commits of `--revset` or
+/// watch mode
+#[derive(Deserialize, Serialize)]
+pub struct RateLimitConfig {
+    /// Most calls started per minute to each model; 0 for no limit
+    pub requests_per_minute: u32,
+    /// Most calls under way at the same time; 0 for no limit
+    pub max_in_flight: usize,
+}
+
+/// How a generated message that isn't in conventional commit format is fixed up
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FallbackStrategy {
+    /// Put `default_commit_message` on its own line above the original message
+    Prepend,
+    /// Turn a subject-like first line into `<type>: <first line>`, using the type of
+    /// `default_commit_message`; falls back to prepending otherwise
+    Wrap,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct LintConfig {
+    pub on_failure: LintAction,
+    pub subject_max_length: usize,
+    pub imperative_mood: bool,
+    pub no_trailing_period: bool,
+    pub types: Vec<String>,
+    pub scopes: Vec<String>,
+}
+
+/// What happens to a generated message that breaks a `[lint]` rule
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LintAction {
+    /// Don't check generated messages
+    Off,
+    /// Fix what can be fixed and warn about the rest
+    Fix,
+    /// Ask the model once more with the violations listed, then fix what is left
+    Regenerate,
+    /// Fail without committing
+    Abort,
+}
+
+#[derive(Deserialize, Serialize)]
 pub struct BookmarkConfig {
     pub prompt_template: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
+pub struct PrDescriptionConfig {
+    pub prompt_template: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ExplainConfig {
+    pub prompt_template: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct SummarizeConfig {
+    pub enabled: bool,
+    /// Approximate number of tokens of the diff in each chunk
+    pub chunk_tokens: usize,
+    pub prompt_template: String,
+}
+
+#[derive(Deserialize, Serialize)]
 pub struct DiffConfig {
     pub collapse_patterns: Vec<String>,
-    pub max_diff_lines: usize,
-    pub max_diff_bytes: usize,
+    pub linguist_attributes: bool,
+    pub context_lines: usize,
+    pub max_file_lines: usize,
+    pub token_budget: usize,
     pub max_total_diff_lines: usize,
     pub max_total_diff_bytes: usize,
+    pub min_lines_for_claude: usize,
+    pub trivial_message_template: String,
+    pub rename_similarity: u8,
+    pub priority: DiffPriorityConfig,
+    pub word_diff: WordDiffConfig,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct DiffPriorityConfig {
+    pub first: Vec<String>,
+    pub last: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct WordDiffConfig {
+    pub patterns: Vec<String>,
+    pub max_bytes: usize,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct DependencyUpdateConfig {
+    pub patterns: Vec<String>,
+    pub prompt_template: String,
+    pub lockfiles: Vec<String>,
+    pub lockfile_message_template: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct FormatConfig {
+    pub hard_wrap_width: usize,
+    /// Wrap message bodies written in Chinese, Japanese, or Korean
+    pub wrap_cjk: bool,
+    pub max_subject_length: usize,
+    pub long_subject: LongSubject,
+    pub style: CommitStyle,
+    /// Put generated messages into the description template from the jj config
+    pub description_template: bool,
+}
+
+/// What is done with a generated subject line longer than `format.max_subject_length`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LongSubject {
+    /// Cut it at a word boundary and move the rest into the body
+    Truncate,
+    /// Ask the model once more for a shorter one, truncating if it is still too long
+    Shorten,
+}
+
+/// How the subject line of a generated message starts
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommitStyle {
+    /// `type: subject`
+    #[default]
+    Conventional,
+    /// `<emoji> subject`, with the emoji for the type from `[gitmoji]`
+    Gitmoji,
+}
+
+impl FromStr for CommitStyle {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "conventional" => Ok(Self::Conventional),
+            "gitmoji" => Ok(Self::Gitmoji),
+            _ => bail!("unknown commit style '{s}' (expected conventional or gitmoji)"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct GitmojiConfig {
+    pub prompt_template: String,
+    /// The emoji for each commit type, plus `breaking` for breaking changes. Generated subjects
+    /// must start with one of these.
+    pub emojis: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct SplitConfig {
+    pub prompt_template: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RedactionConfig {
+    pub patterns: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct TrailersConfig {
+    pub signoff: bool,
+    pub extra: Vec<String>,
+    pub generated_by: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ConventionsConfig {
+    pub sample_size: usize,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct SnapshotConfig {
+    /// A size as accepted by [`parse_size`]
+    pub max_new_file_size: String,
+    pub progress: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct TicketConfig {
+    pub pattern: String,
+    pub placement: TicketPlacement,
+    /// Look up the linked issue for the prompt; needs the `net` feature
+    pub fetch_issues: bool,
+    /// Finds an issue number in bookmark or branch names, in its first capture group
+    pub issue_pattern: String,
+    pub github_token_env: String,
+    pub gitlab_token_env: String,
+    /// The GitLab instance the `gitlab_token_env` token is for; other GitLab hosts get no token
+    pub gitlab_host: String,
+}
+
+/// Where a ticket ID found in the bookmark name goes in the message
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TicketPlacement {
+    /// Leave messages alone
+    None,
+    /// At the start of the subject, after the type prefix
+    Prefix,
+    /// At the end of the subject, in parentheses
+    Suffix,
+    /// As a `Refs:` trailer
+    Trailer,
 }
 
+#[derive(Deserialize, Serialize)]
+pub struct RecordsConfig {
+    pub max_records: usize,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    /// Mask secrets in the logged prompts and responses
+    pub redact: bool,
+    /// Days to keep logs for; 0 keeps them forever
+    pub retention_days: u64,
+}
+
+impl Config {
+    /// Renders the effective configuration as TOML, prefixed with the sources it was built from.
+    pub fn dump(&self) -> Result<String> {
+        let mut output = String::from("# Sources (lowest to highest precedence):\n");
+        output.push_str("#   - embedded assets/commit-config.toml\n");
+        for layer in LAYERS.get().into_iter().flatten() {
+            output.push_str(&format!("#   - {}\n", layer.path.display()));
+        }
+        output.push('\n');
+        output.push_str(&to_string_pretty(self)?);
+        Ok(output)
+    }
+
+    /// Checks that the HTTP backends point at `http://` or `https://` URLs, which is all curl is
+    /// meant to be handed
+    fn check_urls(&self) -> Result<()> {
+        for (name, url) in [
+            ("generator.anthropic.url", &self.generator.anthropic.url),
+            ("generator.openai.url", &self.generator.openai.url),
+            ("generator.ollama.url", &self.generator.ollama.url),
+        ] {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                bail!("{name} must be an http:// or https:// URL, got '{url}'");
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks every prompt template for missing required placeholders and unknown placeholders
+    /// (e.g. a typo'd `{diff_contents}`) that would otherwise be sent to Claude literally.
+    pub fn check_placeholders(&self) -> Result<()> {
+        let optional = [
+            "language",
+            "files",
+            "branch",
+            "recent_commits",
+            "repo_name",
+            "style_guide",
+            "project_type",
+            "frameworks",
+            "issue",
+            "suggested_type",
+        ];
+        prompt_template::check(
+            "prompt.template",
+            &self.prompt.template,
+            &["diff_content"],
+            &optional,
+        )?;
+        check_template(
+            "prompt.conflicts_template",
+            &self.prompt.conflicts_template,
+            &["conflicts"],
+            &[],
+        )?;
+        check_template(
+            "prompt.conflict_markers_template",
+            &self.prompt.conflict_markers_template,
+            &["conflicts"],
+            &[],
+        )?;
+        check_template(
+            "prompt.feedback_template",
+            &self.prompt.feedback_template,
+            &["feedback"],
+            &[],
+        )?;
+        check_template(
+            "prompt.amend_template",
+            &self.prompt.amend_template,
+            &["description"],
+            &[],
+        )?;
+        check_template(
+            "prompt.examples_template",
+            &self.prompt.examples_template,
+            &["examples"],
+            &[],
+        )?;
+        check_template(
+            "prompt.shorten_template",
+            &self.prompt.shorten_template,
+            &["subject"],
+            &["max_length"],
+        )?;
+        prompt_template::check(
+            "gitmoji.prompt_template",
+            &self.gitmoji.prompt_template,
+            &["diff_content"],
+            &optional,
+        )?;
+        prompt_template::check(
+            "dependency_update.prompt_template",
+            &self.dependency_update.prompt_template,
+            &["diff_content"],
+            &[&optional[..], &["dependency_changes"]].concat(),
+        )?;
+        check_template(
+            "diff.trivial_message_template",
+            &self.diff.trivial_message_template,
+            &[],
+            &["files"],
+        )?;
+        check_template(
+            "dependency_update.lockfile_message_template",
+            &self.dependency_update.lockfile_message_template,
+            &[],
+            &["files"],
+        )?;
+        check_template(
+            "split.prompt_template",
+            &self.split.prompt_template,
+            &["files", "diff_content"],
+            &["language"],
+        )?;
+        check_template(
+            "bookmark.prompt_template",
+            &self.bookmark.prompt_template,
+            &["commit_summaries"],
+            &[],
+        )?;
+        check_template(
+            "pr_description.prompt_template",
+            &self.pr_description.prompt_template,
+            &["commit_messages", "diff_content"],
+            &["language"],
+        )?;
+        check_template(
+            "explain.prompt_template",
+            &self.explain.prompt_template,
+            &["diff_content"],
+            &["language"],
+        )?;
+        check_template(
+            "summarize.prompt_template",
+            &self.summarize.prompt_template,
+            &["diff_content"],
+            &["language", "directories"],
+        )
+    }
+}
+
+static PLACEHOLDER_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\{([A-Za-z_][A-Za-z0-9_]*)\}").expect("Failed to compile placeholder regex")
+});
+
+/// Ensures a template contains all `required` placeholders and nothing outside `required` and
+/// `optional`.
+fn check_template(name: &str, template: &str, required: &[&str], optional: &[&str]) -> Result<()> {
+    let found: Vec<&str> = PLACEHOLDER_RE
+        .captures_iter(template)
+        .filter_map(|c| c.get(1))
+        .map(|m| m.as_str())
+        .collect();
+
+    if let Some(missing) = required.iter().find(|p| !found.contains(p)) {
+        bail!("{name} is missing the required placeholder {{{missing}}}");
+    }
+    if let Some(unknown) = found.iter().find(|p| !required.contains(p) && !optional.contains(p)) {
+        let known: Vec<String> =
+            required.iter().chain(optional).map(|p| format!("{{{p}}}")).collect();
+        bail!("{name} contains unknown placeholder {{{unknown}}} (known: {})", known.join(", "));
+    }
+    Ok(())
+}
+
+/// Parses a file size such as `100MiB`, `1.5GB`, or a plain number of bytes. `KiB`, `MiB`, and
+/// `GiB` (or just `K`, `M`, `G`) are powers of 1024, `KB`, `MB`, and `GB` powers of 1000.
+pub fn parse_size(text: &str) -> Result<u64> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        _ => bail!("Unknown size unit in '{text}' (use B, KiB, MiB, GiB, KB, MB, or GB)"),
+    };
+    let number: f64 = number.parse().with_context(|| format!("Invalid size '{text}'"))?;
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// A configuration file merged over the embedded defaults
+struct Layer {
+    path: PathBuf,
+    table: Table,
+}
+
+/// Configuration files found by `load`, lowest precedence first
+static LAYERS: OnceLock<Vec<Layer>> = OnceLock::new();
+
+/// Settings given on the command line (`override_*`), merged over every file in the order given
+static OVERRIDES: Mutex<Vec<Table>> = Mutex::new(Vec::new());
+
+/// The effective configuration: the embedded defaults with the user and repository config files
+/// from `load` merged over them. Uses the embedded defaults alone if `load` was never called.
 pub static CONFIG: LazyLock<Config> = LazyLock::new(|| {
-    from_str(include_str!("../assets/commit-config.toml"))
-        .expect("Failed to parse embedded commit-config.toml")
+    let layers = LAYERS.get().map(Vec::as_slice).unwrap_or_default();
+    let mut merged = merge_layers(layers);
+    for overrides in OVERRIDES.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+        merge_tables(&mut merged, overrides.clone());
+    }
+    Value::Table(merged)
+        .try_into()
+        .expect("Layers are validated when loaded")
 });
+
+/// Finds the user and repository configuration files for a run started in `start_dir`. Must be
+/// called before `CONFIG` is first used to take effect.
+///
+/// Layers, from lowest to highest precedence:
+/// - `~/.config/ccc-jj/config.toml` (and the platform config directory, if different), or the file
+///   `CCC_JJ_CONFIG` points to instead, e.g. in a container without a home directory
+/// - `.ccc-jj.toml` at the root of the workspace containing `start_dir`, limited to [`REPO_TABLES`]
+///
+/// Files that can't be read, parsed, or that produce an invalid configuration are skipped with a
+/// warning, matching how jj config files are handled.
+pub fn load(start_dir: &Path) {
+    if let Some(path) = env_config_path().filter(|path| !path.exists()) {
+        warn!(path = %path.display(), "{CONFIG_ENV} points to a missing file, ignoring it");
+    }
+    let _ = LAYERS.set(read_layers(&config_paths(start_dir)));
+}
+
+/// Replaces `generator.command` and `generator.args` with a command line such as `llm -m gpt-4o`,
+/// split into words by [`split_command_line`]. It is run with the `command` backend, unless it is
+/// the Claude CLI, which keeps the `claude-cli` backend (and the configured arguments when none
+/// are given). Must be called before `CONFIG` is first used to take effect.
+pub fn override_generator_command(command_line: &str) -> Result<()> {
+    let words = split_command_line(command_line)?;
+    let Some((command, args)) = words.split_first() else {
+        bail!("The generator command is empty");
+    };
+    let is_claude = Path::new(command).file_stem().is_some_and(|stem| stem == "claude");
+    let mut generator = Table::new();
+    let backend = if is_claude { "claude-cli" } else { "command" };
+    generator.insert("backend".into(), backend.into());
+    generator.insert("command".into(), command.as_str().into());
+    generator.insert("shell".into(), false.into());
+    if !is_claude || !args.is_empty() {
+        generator.insert("args".into(), args.to_vec().into());
+    }
+    debug!(command, ?args, backend, "Overriding the generator command");
+    add_override("generator", generator);
+    Ok(())
+}
+
+/// Sets `diff.context_lines` and `diff.max_file_lines` to the values given, e.g. from
+/// `--context-lines`. Must be called before `CONFIG` is first used to take effect.
+pub fn override_diff_lines(context_lines: Option<usize>, max_file_lines: Option<usize>) {
+    let mut diff = Table::new();
+    for (key, value) in [("context_lines", context_lines), ("max_file_lines", max_file_lines)] {
+        if let Some(value) = value {
+            diff.insert(key.into(), Value::Integer(value.try_into().unwrap_or(i64::MAX)));
+        }
+    }
+    if !diff.is_empty() {
+        debug!(?context_lines, ?max_file_lines, "Overriding the diff lines");
+        add_override("diff", diff);
+    }
+}
+
+fn add_override(table: &str, settings: Table) {
+    let mut overrides = OVERRIDES.lock().unwrap_or_else(|e| e.into_inner());
+    overrides.push(Table::from_iter([(table.into(), settings.into())]));
+}
+
+/// Splits a command line into words like a POSIX shell, without expanding or running anything:
+/// words are separated by whitespace, single quotes keep their content as is, double quotes allow
+/// `\"` and `\\` escapes, and a backslash outside quotes escapes whitespace, a quote, or another
+/// backslash. Other backslashes are kept, so Windows paths such as `C:\tools\llm.exe` need no
+/// quoting.
+pub fn split_command_line(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_default();
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => bail!("Unterminated single quote in '{line}'"),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_default();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') if matches!(chars.peek(), Some('"' | '\\')) => {
+                            word.extend(chars.next());
+                        }
+                        Some(c) => word.push(c),
+                        None => bail!("Unterminated double quote in '{line}'"),
+                    }
+                }
+            }
+            '\\' if chars
+                .peek()
+                .is_some_and(|&c| c.is_whitespace() || "'\"\\".contains(c)) =>
+            {
+                word.get_or_insert_default().extend(chars.next());
+            }
+            c => word.get_or_insert_default().push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Checks each existing configuration file that `load` would use for `start_dir` on its own over
+/// the embedded defaults, reporting why any of them would be skipped
+pub fn check_files(start_dir: &Path) -> Vec<(PathBuf, Result<()>)> {
+    let explicit = env_config_path();
+    config_paths(start_dir)
+        .into_iter()
+        .filter(|path| path.exists() || explicit.as_ref() == Some(path))
+        .map(|path| {
+            let result = read_to_string(&path)
+                .context("Failed to read file")
+                .and_then(|text| from_str::<Table>(&text).context("Failed to parse TOML"))
+                .and_then(|table| {
+                    let table = restrict_if_repo_config(&path, table);
+                    let layer = Layer { path: path.clone(), table };
+                    parse_config(merge_layers(&[layer]))
+                })
+                .map(drop);
+            (path, result)
+        })
+        .collect()
+}
+
+/// Candidate configuration files for a run started in `start_dir`, lowest precedence first
+fn config_paths(start_dir: &Path) -> Vec<PathBuf> {
+    let mut paths = match env_config_path() {
+        Some(path) => vec![path],
+        None => {
+            let (home, config_dir) = (home_dir(), config_dir());
+            if home.is_none() && config_dir.is_none() {
+                debug!("No home or config directory, set {CONFIG_ENV} to use a user config file");
+            }
+            #[cfg_attr(not(windows), allow(unused_mut))]
+            let mut paths = user_config_paths(home.as_deref(), config_dir.as_deref());
+            // `%APPDATA%` is normally the config directory already, but may be redirected
+            #[cfg(windows)]
+            if let Some(app_data) = std::env::var_os("APPDATA").filter(|dir| !dir.is_empty()) {
+                let path = PathBuf::from(app_data).join("ccc-jj").join("config.toml");
+                if !paths.contains(&path) {
+                    paths.push(path);
+                }
+            }
+            paths
+        }
+    };
+    if let Some(root) = start_dir.ancestors().find(|dir| dir.join(".jj").is_dir()) {
+        paths.push(root.join(REPO_CONFIG_FILE));
+    }
+    paths
+}
+
+/// The config file set with `CCC_JJ_CONFIG`, if any
+fn env_config_path() -> Option<PathBuf> {
+    std::env::var_os(CONFIG_ENV)
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Candidate user configuration files, without duplicates
+fn user_config_paths(home: Option<&Path>, config_dir: Option<&Path>) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = [
+        home.map(|h| h.join(".config/ccc-jj/config.toml")),
+        config_dir.map(|c| c.join("ccc-jj/config.toml")),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    paths.dedup();
+    paths
+}
+
+/// Drops the settings a repository's own config file may not make, if `path` is one
+fn restrict_if_repo_config(path: &Path, table: Table) -> Table {
+    let is_repo_config = path.file_name().is_some_and(|name| name == REPO_CONFIG_FILE)
+        && env_config_path().as_deref() != Some(path);
+    if !is_repo_config {
+        return table;
+    }
+    let (table, dropped) = restrict_repo_table(table);
+    if !dropped.is_empty() {
+        warn!(
+            path = %path.display(),
+            settings = %dropped.join(", "),
+            "Ignoring settings a repository config file can't make; set them in the user config"
+        );
+    }
+    table
+}
+
+/// Keeps the settings of [`REPO_TABLES`] but not [`REPO_DENIED_KEYS`], returning the names of
+/// those dropped
+fn restrict_repo_table(mut table: Table) -> (Table, Vec<String>) {
+    let mut dropped = Vec::new();
+    table.retain(|name, _| {
+        let allowed = REPO_TABLES.contains(&name);
+        if !allowed {
+            dropped.push(name.to_string());
+        }
+        allowed
+    });
+    for (name, key) in REPO_DENIED_KEYS {
+        if let Some(Value::Table(section)) = table.get_mut(*name)
+            && section.remove(*key).is_some()
+        {
+            dropped.push(format!("{name}.{key}"));
+        }
+    }
+    (table, dropped)
+}
+
+/// Reads the existing files among `paths` as layers, skipping any that can't be used
+fn read_layers(paths: &[PathBuf]) -> Vec<Layer> {
+    let mut layers: Vec<Layer> = Vec::new();
+    for path in paths.iter().filter(|path| path.exists()) {
+        let table = match read_to_string(path)
+            .context("Failed to read file")
+            .and_then(|text| from_str::<Table>(&text).context("Failed to parse TOML"))
+        {
+            Ok(table) => restrict_if_repo_config(path, table),
+            Err(e) => {
+                warn!(path = %path.display(), error = %e, "Skipping unreadable config file");
+                continue;
+            }
+        };
+
+        layers.push(Layer { path: path.clone(), table });
+        if let Err(e) = parse_config(merge_layers(&layers)) {
+            warn!(path = %path.display(), error = %e, "Skipping invalid config file");
+            layers.pop();
+            continue;
+        }
+        debug!(path = %path.display(), "Loaded config file");
+    }
+    layers
+}
+
+/// Deserializes merged layers, rejecting settings that parse but can't be used
+fn parse_config(merged: Table) -> Result<Config> {
+    let config: Config = Value::Table(merged).try_into().context("Invalid configuration")?;
+    config.check_urls().context("Invalid configuration")?;
+    Ok(config)
+}
+
+/// Merges the layers over the embedded defaults
+fn merge_layers(layers: &[Layer]) -> Table {
+    let mut merged: Table =
+        from_str(EMBEDDED_CONFIG).expect("Failed to parse embedded commit-config.toml");
+    for layer in layers {
+        merge_tables(&mut merged, layer.table.clone());
+    }
+    merged
+}
+
+/// Recursively merges `overlay` into `base`. Tables are merged key by key; any other value
+/// (including arrays) replaces the base value.
+fn merge_tables(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base_table)), Value::Table(overlay_table)) => {
+                merge_tables(base_table, overlay_table)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_tables() {
+        let mut base: Table = from_str(
+            "[diff]\ntoken_budget = 10\nmax_total_diff_bytes = 20\ncollapse_patterns = [\"a\"]\n",
+        )
+        .unwrap();
+        let overlay: Table =
+            from_str("[diff]\ntoken_budget = 5\ncollapse_patterns = [\"b\"]\n").unwrap();
+        merge_tables(&mut base, overlay);
+        assert_eq!(
+            base,
+            from_str::<Table>(
+                "[diff]\ntoken_budget = 5\nmax_total_diff_bytes = 20\ncollapse_patterns = [\"b\"]\n"
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_read_layers_skips_unusable_files() {
+        let dir = std::env::temp_dir().join(format!("ccc-jj-layers-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let user = dir.join("user.toml");
+        std::fs::write(&user, "[generator]\ncommand = \"my-claude\"\n").unwrap();
+        let malformed = dir.join("malformed.toml");
+        std::fs::write(&malformed, "[generator\n").unwrap();
+        let invalid = dir.join("invalid.toml");
+        std::fs::write(&invalid, "[diff]\ntoken_budget = \"many\"\n").unwrap();
+        let bad_url = dir.join("bad_url.toml");
+        std::fs::write(&bad_url, "[generator.openai]\nurl = \"-o/tmp/x\"\n").unwrap();
+        let repo = dir.join("repo.toml");
+        std::fs::write(&repo, "[diff]\nmin_lines_for_claude = 3\n").unwrap();
+
+        let layers =
+            read_layers(&[user, malformed, invalid, bad_url, dir.join("missing.toml"), repo]);
+        let config: Config = Value::Table(merge_layers(&layers)).try_into().unwrap();
+        assert_eq!(layers.len(), 2);
+        assert_eq!(config.generator.command, "my-claude");
+        assert_eq!(config.diff.min_lines_for_claude, 3);
+        assert_eq!(config.diff.token_budget, CONFIG.diff.token_budget);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_restrict_repo_table() {
+        let table: Table = from_str(
+            "[generator]\ncommand = \"sh\"\n[redaction]\npatterns = []\n[diff]\ntoken_budget = 5\n\
+             [ticket]\nplacement = \"prefix\"\ngitlab_host = \"evil.example\"\n",
+        )
+        .unwrap();
+        let (table, dropped) = restrict_repo_table(table);
+        assert_eq!(dropped, ["generator", "redaction", "ticket.gitlab_host"]);
+        assert_eq!(
+            table,
+            from_str::<Table>("[diff]\ntoken_budget = 5\n[ticket]\nplacement = \"prefix\"\n")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_split_command_line() {
+        let words = |line| split_command_line(line).unwrap();
+        assert_eq!(words("  llm -m gpt-4o "), ["llm", "-m", "gpt-4o"]);
+        assert_eq!(
+            words(r#"aichat --prompt 'a "b" c' "d \"e\" \n" f\ g ''"#),
+            ["aichat", "--prompt", r#"a "b" c"#, r#"d "e" \n"#, "f g", ""]
+        );
+        assert_eq!(words(r"C:\tools\llm.exe -m x"), [r"C:\tools\llm.exe", "-m", "x"]);
+        assert!(words("").is_empty());
+        assert!(split_command_line("llm 'unterminated").is_err());
+        assert!(split_command_line("llm \"unterminated").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("100MiB").unwrap(), 100 * 1024 * 1024);
+        assert_eq!(parse_size("1.5 GB").unwrap(), 1_500_000_000);
+        assert_eq!(parse_size("2k").unwrap(), 2048);
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert!(parse_size("10 parsecs").is_err());
+        assert!(parse_size("MiB").is_err());
+    }
+
+    #[test]
+    fn test_embedded_templates_pass_strict_check() {
+        CONFIG.check_placeholders().unwrap();
+    }
+
+    #[test]
+    fn test_check_template_unknown_placeholder() {
+        let err = check_template("t", "{language} {diff_contents}", &[], &["language"]);
+        assert!(
+            err.unwrap_err()
+                .to_string()
+                .contains("unknown placeholder {diff_contents}")
+        );
+    }
+
+    #[test]
+    fn test_check_template_missing_placeholder() {
+        let err = check_template("t", "Diff: {language}", &["diff_content"], &["language"]);
+        assert!(
+            err.unwrap_err()
+                .to_string()
+                .contains("missing the required placeholder")
+        );
+    }
+
+    #[test]
+    fn test_check_template_ignores_literal_braces() {
+        check_template("t", r#"{"a": 1} {diff_content} { x }"#, &["diff_content"], &[]).unwrap();
+    }
+}
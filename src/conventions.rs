@@ -0,0 +1,253 @@
+use std::{cmp::Reverse, collections::BTreeMap, fs, path::Path};
+
+use globset::{Glob, GlobMatcher};
+use tracing::{debug, warn};
+
+use crate::config::CONFIG;
+
+/// `--language` value that matches the language of the repository's recent commit messages
+pub const AUTO_LANGUAGE: &str = "auto";
+
+/// Stands in for the language when the script of the recent messages doesn't settle it (e.g.
+/// English vs. German), leaving it to the model to match the examples
+pub const MATCH_EXAMPLES: &str = "the same language as the recent commit messages below";
+
+/// Scripts that identify a language on their own, checked in order. Japanese comes first since
+/// it mixes kana with Han characters.
+const SCRIPTS: &[(&str, &[(char, char)])] = &[
+    ("Japanese", &[('\u{3040}', '\u{30ff}')]),
+    ("Korean", &[('\u{ac00}', '\u{d7af}'), ('\u{1100}', '\u{11ff}')]),
+    ("Chinese", &[('\u{4e00}', '\u{9fff}')]),
+    ("Russian", &[('\u{0400}', '\u{04ff}')]),
+    ("Greek", &[('\u{0370}', '\u{03ff}')]),
+    ("Arabic", &[('\u{0600}', '\u{06ff}')]),
+    ("Hebrew", &[('\u{0590}', '\u{05ff}')]),
+    ("Thai", &[('\u{0e00}', '\u{0e7f}')]),
+];
+
+/// Files that document a project's commit conventions, looked for in the workspace root,
+/// `.github/`, and `docs/` when `prompt.style_file` is not set. A dedicated conventions file is
+/// used whole; a contributing guide only for its section about commits.
+const STYLE_FILES: &[(&str, bool)] = &[("COMMIT_CONVENTION.md", true), ("CONTRIBUTING.md", false)];
+
+/// Directories searched for [`STYLE_FILES`], relative to the workspace root
+const STYLE_DIRS: &[&str] = &["", ".github", "docs"];
+
+/// Longest style guide put in the prompt, in characters
+const MAX_STYLE_GUIDE_CHARS: usize = 4000;
+
+/// Guesses the language of commit messages from the script they are written in.
+///
+/// Returns `None` for messages mostly in Latin script, whose language the script doesn't tell.
+pub fn detect_language(messages: &[String]) -> Option<&'static str> {
+    let letters: Vec<char> = messages
+        .iter()
+        .flat_map(|message| message.chars())
+        .filter(|c| c.is_alphabetic())
+        .collect();
+    if letters.is_empty() {
+        return None;
+    }
+    SCRIPTS.iter().find_map(|(language, ranges)| {
+        let count = letters
+            .iter()
+            .filter(|c| ranges.iter().any(|(start, end)| (start..=end).contains(c)))
+            .count();
+        // Identifiers and type prefixes are Latin even in messages written in another script
+        (count * 5 >= letters.len()).then_some(*language)
+    })
+}
+
+/// Formats recent commit messages as examples for the prompt, separated by `---` lines
+pub fn format_examples(messages: &[String]) -> String {
+    messages
+        .iter()
+        .map(|message| message.trim())
+        .collect::<Vec<_>>()
+        .join("\n---\n")
+}
+
+/// Formats the messages of recent commits for the prompt: their subjects as a list, or with
+/// `bodies` the whole messages separated by `---` lines
+pub fn format_recent_commits(messages: &[String], bodies: bool) -> String {
+    if bodies {
+        return format_examples(messages);
+    }
+    messages
+        .iter()
+        .filter_map(|message| message.lines().next())
+        .map(|subject| format!("- {}", subject.trim()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The commit type the changed paths call for according to `types`, which maps glob patterns to
+/// types: the type of the most specific (longest) pattern matching each path, if every path has
+/// one and they all agree. Patterns mapped to an empty type are ignored, so a config layer can turn
+/// a default off.
+pub fn suggested_type<'a>(
+    paths: impl IntoIterator<Item = &'a str>,
+    types: &BTreeMap<String, String>,
+) -> Option<String> {
+    let mut rules: Vec<(&str, GlobMatcher, &str)> = types
+        .iter()
+        .filter(|(_, commit_type)| !commit_type.trim().is_empty())
+        .filter_map(|(pattern, commit_type)| match Glob::new(pattern) {
+            Ok(glob) => Some((pattern.as_str(), glob.compile_matcher(), commit_type.trim())),
+            Err(e) => {
+                warn!(pattern = %pattern, error = %e, "Invalid type pattern, skipping");
+                None
+            }
+        })
+        .collect();
+    rules.sort_by_key(|(pattern, ..)| Reverse(pattern.len()));
+
+    let mut suggested = None;
+    for path in paths {
+        let (_, _, commit_type) = rules.iter().find(|(_, matcher, _)| matcher.is_match(path))?;
+        match suggested {
+            None => suggested = Some(*commit_type),
+            Some(previous) if previous != *commit_type => return None,
+            Some(_) => {}
+        }
+    }
+    suggested.map(str::to_string)
+}
+
+/// The project's documented commit message rules: `prompt.style_file` if set, otherwise the first
+/// of [`STYLE_FILES`] found that has something to say about commits. Empty if there is none.
+pub fn find_style_guide(root: &Path) -> String {
+    let configured = &CONFIG.prompt.style_file;
+    if !configured.is_empty() {
+        let path = root.join(configured);
+        return match fs::read_to_string(&path) {
+            Ok(text) => style_guide(&text, true),
+            Err(e) => {
+                warn!(path = %path.display(), error = %e, "Failed to read prompt.style_file");
+                String::new()
+            }
+        };
+    }
+
+    for dir in STYLE_DIRS {
+        for (name, whole) in STYLE_FILES {
+            let path = root.join(dir).join(name);
+            let Ok(text) = fs::read_to_string(&path) else {
+                continue;
+            };
+            let guide = style_guide(&text, *whole);
+            if !guide.is_empty() {
+                debug!(path = %path.display(), len = guide.len(), "Found a commit style guide");
+                return guide;
+            }
+        }
+    }
+    String::new()
+}
+
+/// The section of a markdown document about commit messages, or with `whole` the whole document
+/// when it has no such section, cut to [`MAX_STYLE_GUIDE_CHARS`]
+fn style_guide(markdown: &str, whole: bool) -> String {
+    let text = match commit_section(markdown) {
+        Some(section) => section,
+        None if whole => markdown,
+        None => "",
+    };
+    let text = text.trim();
+    match text.char_indices().nth(MAX_STYLE_GUIDE_CHARS) {
+        Some((end, _)) => format!("{}\n...", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+/// The first section whose heading mentions commits, up to the next heading of the same or a
+/// higher level. Headings inside code blocks don't count.
+fn commit_section(markdown: &str) -> Option<&str> {
+    let mut in_code = false;
+    let mut section: Option<(usize, usize)> = None;
+    let mut offset = 0;
+    for line in markdown.split_inclusive('\n') {
+        let start = offset;
+        offset += line.len();
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_code = !in_code;
+        }
+        let level = trimmed.chars().take_while(|c| *c == '#').count();
+        if in_code || level == 0 || !trimmed[level..].starts_with(' ') {
+            continue;
+        }
+        match section {
+            Some((from, section_level)) if level <= section_level => {
+                return Some(&markdown[from..start]);
+            }
+            None if trimmed.to_lowercase().contains("commit") => section = Some((start, level)),
+            _ => {}
+        }
+    }
+    section.map(|(from, _)| &markdown[from..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suggested_type() {
+        let types: BTreeMap<String, String> = [
+            ("docs/**", "docs"),
+            ("**/*.md", "docs"),
+            ("**/*_test.rs", "test"),
+            ("src/**", "feat"),
+            (".github/**", ""),
+        ]
+        .map(|(pattern, commit_type)| (pattern.to_string(), commit_type.to_string()))
+        .into();
+        assert_eq!(suggested_type(["docs/a.md", "README.md"], &types), Some("docs".to_string()));
+        // The longer pattern wins over `src/**`
+        assert_eq!(suggested_type(["src/parser_test.rs"], &types), Some("test".to_string()));
+        assert_eq!(suggested_type(["docs/a.md", "src/lib.rs"], &types), None);
+        assert_eq!(suggested_type(["Cargo.toml"], &types), None);
+        assert_eq!(suggested_type([".github/ci.yml"], &types), None);
+        assert_eq!(suggested_type([], &types), None);
+    }
+
+    #[test]
+    fn test_detect_language() {
+        let japanese = vec!["feat: ログイン画面を追加する".to_string()];
+        assert_eq!(detect_language(&japanese), Some("Japanese"));
+        let chinese = vec!["fix: 修复登录问题".to_string()];
+        assert_eq!(detect_language(&chinese), Some("Chinese"));
+        let english = vec!["feat: add login screen".to_string()];
+        assert_eq!(detect_language(&english), None);
+        assert_eq!(detect_language(&[]), None);
+    }
+
+    #[test]
+    fn test_format_examples() {
+        let messages = vec!["feat: a\n".to_string(), "fix: b\n\nBody.\n".to_string()];
+        assert_eq!(format_examples(&messages), "feat: a\n---\nfix: b\n\nBody.");
+    }
+
+    #[test]
+    fn test_format_recent_commits() {
+        let messages = vec!["feat: a\n".to_string(), "fix: b\n\nBody.\n".to_string()];
+        assert_eq!(format_recent_commits(&messages, false), "- feat: a\n- fix: b");
+        assert_eq!(format_recent_commits(&messages, true), "feat: a\n---\nfix: b\n\nBody.");
+        assert_eq!(format_recent_commits(&[], false), "");
+    }
+
+    #[test]
+    fn test_style_guide() {
+        let contributing = "# Contributing\n\n## Setup\n\nRun it.\n\n## Commit Messages\n\n\
+            Use `type: subject`.\n\n```\n# not a heading\n```\n\n### Types\n\nfeat, fix\n\n\
+            ## Tests\n\nRun them.\n";
+        assert_eq!(
+            style_guide(contributing, false),
+            "## Commit Messages\n\nUse `type: subject`.\n\n```\n# not a heading\n```\n\n\
+            ### Types\n\nfeat, fix"
+        );
+        assert_eq!(style_guide("# Contributing\n\nBe nice.\n", false), "");
+        assert_eq!(style_guide("Subjects are lower case.\n", true), "Subjects are lower case.");
+    }
+}
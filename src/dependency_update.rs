@@ -0,0 +1,185 @@
+use std::{collections::BTreeMap, fmt, sync::LazyLock};
+
+use globset::GlobSet;
+use regex::Regex;
+
+/// Manifest files whose diffs are parsed for dependency version changes
+const MANIFEST_FILES: &[&str] = &["Cargo.toml", "package.json", "pyproject.toml"];
+
+/// Keys describing the package itself rather than a dependency
+const IGNORED_KEYS: &[&str] = &["name", "version", "edition", "description", "license"];
+
+/// `serde = "1.0"` or `serde = { version = "1.0", features = [...] }`
+static TOML_DEPENDENCY_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"^\s*([A-Za-z0-9_.-]+)\s*=\s*(?:"([^"]+)"|\{.*\bversion\s*=\s*"([^"]+)".*\})"#)
+        .expect("Failed to compile TOML dependency regex")
+});
+
+/// `"serde": "^1.0.0",`
+static JSON_DEPENDENCY_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"^\s*"([^"]+)"\s*:\s*"([^"]+)",?\s*$"#)
+        .expect("Failed to compile JSON dependency regex")
+});
+
+/// A dependency whose version requirement changed in a manifest
+#[derive(Debug, PartialEq, Eq)]
+pub struct DependencyChange {
+    pub name: String,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+impl fmt::Display for DependencyChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.from, &self.to) {
+            (Some(from), Some(to)) => write!(f, "{}: {from} -> {to}", self.name),
+            (None, Some(to)) => write!(f, "{}: added ({to})", self.name),
+            (Some(from), None) => write!(f, "{}: removed ({from})", self.name),
+            (None, None) => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// Returns true if there are changed paths and every one of them is a dependency manifest or lock
+/// file
+pub fn is_dependency_update<'a>(
+    mut paths: impl Iterator<Item = &'a str>,
+    manifests: &GlobSet,
+) -> bool {
+    let mut any = false;
+    let all = paths.all(|path| {
+        any = true;
+        manifests.is_match(path)
+    });
+    any && all
+}
+
+/// Extract dependency version changes from the manifest sections of a unified diff
+pub fn parse_dependency_changes(diff: &str) -> Vec<DependencyChange> {
+    let mut removed = BTreeMap::new();
+    let mut added = BTreeMap::new();
+    let mut in_manifest = false;
+
+    for line in diff.lines() {
+        if let Some(header) = line.strip_prefix("diff --git ") {
+            let path = header.rsplit(" b/").next().unwrap_or(header);
+            let file_name = path.rsplit('/').next().unwrap_or(path);
+            in_manifest = MANIFEST_FILES.contains(&file_name);
+            continue;
+        }
+        if !in_manifest || line.starts_with("+++") || line.starts_with("---") {
+            continue;
+        }
+
+        let (target, content) = if let Some(content) = line.strip_prefix('-') {
+            (&mut removed, content)
+        } else if let Some(content) = line.strip_prefix('+') {
+            (&mut added, content)
+        } else {
+            continue;
+        };
+
+        if let Some((name, version)) = parse_dependency_line(content)
+            && !IGNORED_KEYS.contains(&name.as_str())
+        {
+            target.insert(name, version);
+        }
+    }
+
+    let mut changes = Vec::new();
+    for (name, to) in &added {
+        match removed.get(name) {
+            Some(from) if from == to => {}
+            from => changes.push(DependencyChange {
+                name: name.clone(),
+                from: from.cloned(),
+                to: Some(to.clone()),
+            }),
+        }
+    }
+    for (name, from) in removed {
+        if !added.contains_key(&name) {
+            changes.push(DependencyChange { name, from: Some(from), to: None });
+        }
+    }
+    changes.sort_by(|a, b| a.name.cmp(&b.name));
+    changes
+}
+
+/// Format dependency changes as a bullet list for the prompt
+pub fn format_dependency_changes(changes: &[DependencyChange]) -> String {
+    if changes.is_empty() {
+        return "(none detected)".to_string();
+    }
+    changes
+        .iter()
+        .map(|c| format!("- {c}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn parse_dependency_line(line: &str) -> Option<(String, String)> {
+    if let Some(caps) = TOML_DEPENDENCY_RE.captures(line) {
+        let version = caps.get(2).or_else(|| caps.get(3))?;
+        return Some((caps[1].to_string(), version.as_str().to_string()));
+    }
+    JSON_DEPENDENCY_RE
+        .captures(line)
+        .map(|caps| (caps[1].to_string(), caps[2].to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::build_collapse_matcher;
+
+    fn manifests() -> GlobSet {
+        build_collapse_matcher(&["**/Cargo.toml".to_string(), "**/Cargo.lock".to_string()]).unwrap()
+    }
+
+    #[test]
+    fn test_is_dependency_update() {
+        let manifests = manifests();
+        assert!(is_dependency_update(["Cargo.toml", "Cargo.lock"].into_iter(), &manifests));
+        assert!(is_dependency_update(["crates/a/Cargo.toml"].into_iter(), &manifests));
+        assert!(!is_dependency_update(["Cargo.toml", "src/main.rs"].into_iter(), &manifests));
+        assert!(!is_dependency_update([].into_iter(), &manifests));
+    }
+
+    #[test]
+    fn test_parse_cargo_changes() {
+        let diff = "diff --git a/Cargo.toml b/Cargo.toml\n--- a/Cargo.toml\n+++ b/Cargo.toml\n@@ -1,4 +1,4 @@\n-version = \"0.1.0\"\n+version = \"0.2.0\"\n-serde = \"1.0.1\"\n+serde = \"1.0.2\"\n-tokio = { version = \"1.47\", features = [\"macros\"] }\n+tokio = { version = \"1.48\", features = [\"macros\"] }\n+regex = \"1\"\n-old = \"0.1\"\n";
+        let changes = parse_dependency_changes(diff);
+        let lines: Vec<String> = changes.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "old: removed (0.1)",
+                "regex: added (1)",
+                "serde: 1.0.1 -> 1.0.2",
+                "tokio: 1.47 -> 1.48",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_package_json_changes() {
+        let diff = "diff --git a/web/package.json b/web/package.json\n@@ -3,3 +3,3 @@\n-    \"react\": \"^18.2.0\",\n+    \"react\": \"^18.3.1\",\n";
+        assert_eq!(
+            parse_dependency_changes(diff),
+            vec![DependencyChange {
+                name: "react".to_string(),
+                from: Some("^18.2.0".to_string()),
+                to: Some("^18.3.1".to_string()),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_lock_files_are_not_parsed() {
+        let diff =
+            "diff --git a/Cargo.lock b/Cargo.lock\n-version = \"1.0.1\"\n+checksum = \"abc\"\n";
+        assert!(parse_dependency_changes(diff).is_empty());
+        assert_eq!(format_dependency_changes(&[]), "(none detected)");
+    }
+}
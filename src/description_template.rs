@@ -0,0 +1,101 @@
+use crate::trailers::{append_trailers, is_trailer_line, split_trailers};
+
+/// Prefix of the instruction lines jj puts in descriptions being edited, which are not kept
+const JJ_COMMENT_PREFIX: &str = "JJ:";
+
+/// The text of a jj template that is a single string literal, e.g. the value of
+/// `templates.draft_commit_description = '"\n\nChecklist:\n- [ ] tests\n"'`. Templates that compute
+/// the description (from `description`, `concat()`, ...) are `None`, as they need jj's template
+/// engine.
+pub fn template_literal(template: &str) -> Option<String> {
+    let template = template.trim();
+    if let Some(raw) = template.strip_prefix('\'').and_then(|rest| rest.strip_suffix('\'')) {
+        return (!raw.contains('\'')).then(|| raw.to_string());
+    }
+    let mut chars = template.strip_prefix('"')?.chars();
+    let mut text = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return chars.as_str().is_empty().then_some(text),
+            '\\' => text.push(match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '0' => '\0',
+                'e' => '\x1b',
+                c @ ('"' | '\\') => c,
+                _ => return None,
+            }),
+            c => text.push(c),
+        }
+    }
+}
+
+/// Puts a generated message into the user's description template so its boilerplate survives: the
+/// message comes first, followed by the template's text, with the template's trailers joined to the
+/// message's trailer block. `JJ:` instruction lines are dropped, and template text the message
+/// already contains (e.g. when updating a description) is not repeated.
+pub fn merge_into_template(message: &str, template: &str) -> String {
+    let template: Vec<&str> = template
+        .lines()
+        .filter(|line| !line.starts_with(JJ_COMMENT_PREFIX))
+        .collect();
+    let template = template.join("\n");
+    let template = template.trim();
+    if template.is_empty() {
+        return message.to_string();
+    }
+    let is_trailer_block = |paragraph: &str| paragraph.lines().all(is_trailer_line);
+    let (boilerplate, template_trailers) = match template.rsplit_once("\n\n") {
+        Some((text, last)) if is_trailer_block(last) => (text.trim_end(), last),
+        None if is_trailer_block(template) => ("", template),
+        _ => (template, ""),
+    };
+
+    let (body, message_trailers) = split_trailers(message);
+    let mut merged = body.to_string();
+    if !boilerplate.is_empty() && !body.contains(boilerplate) {
+        merged = format!("{merged}\n\n{boilerplate}");
+    }
+    let trailers: Vec<String> = message_trailers
+        .into_iter()
+        .chain([template_trailers])
+        .flat_map(str::lines)
+        .map(String::from)
+        .collect();
+    if trailers.is_empty() {
+        return format!("{merged}\n");
+    }
+    append_trailers(&merged, &trailers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_template_literal() {
+        assert_eq!(
+            template_literal(r#""\n\nChecklist:\n- [ ] \"tests\"\n""#),
+            Some("\n\nChecklist:\n- [ ] \"tests\"\n".to_string())
+        );
+        assert_eq!(template_literal("'raw\\n'"), Some("raw\\n".to_string()));
+        assert_eq!(template_literal(r#"concat(description, "\n")"#), None);
+        assert_eq!(template_literal(r#""a" ++ "b""#), None);
+    }
+
+    #[test]
+    fn test_merge_into_template() {
+        let message = "feat: add login\n\nUsers can sign in.\n\nRefs: JIRA-1\n";
+        let template =
+            "\n\nChecklist:\n- [ ] tests\n\nJJ: Lines starting with JJ: are removed\nTeam: auth\n";
+        assert_eq!(
+            merge_into_template(message, template),
+            "feat: add login\n\nUsers can sign in.\n\nChecklist:\n- [ ] tests\n\nRefs: JIRA-1\nTeam: auth\n"
+        );
+        let merged = merge_into_template(message, "Checklist:\n- [ ] tests\n");
+        assert_eq!(merge_into_template(&merged, "Checklist:\n- [ ] tests\n"), merged);
+        assert_eq!(merge_into_template("fix: x\n", "Team: auth\n"), "fix: x\n\nTeam: auth\n");
+        assert_eq!(merge_into_template("fix: x\n", "JJ: only instructions\n"), "fix: x\n");
+    }
+}
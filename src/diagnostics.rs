@@ -0,0 +1,289 @@
+use std::{
+    env::{split_paths, var, var_os},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use serde::Serialize;
+
+use crate::{
+    claude_client::build_command,
+    config::{self, Backend, CONFIG},
+    workspace::find_workspace,
+};
+
+/// Oldest curl that the HTTP backends work with, for `--variable` and `--expand-header`
+const MIN_CURL_VERSION: (u32, u32) = (8, 3);
+
+/// How a check turned out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    /// Works, but not everything is available
+    Warning,
+    /// Commits can't be made until this is fixed
+    Error,
+}
+
+/// The result of checking one thing ccc-jj depends on
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+    /// What to do about a warning or error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fix: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Ok,
+            detail: detail.into(),
+            fix: None,
+        }
+    }
+
+    fn warning(name: &'static str, detail: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Warning,
+            detail: detail.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    fn error(name: &'static str, detail: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Error,
+            detail: detail.into(),
+            fix: Some(fix.into()),
+        }
+    }
+}
+
+/// Checks the environment of a run started in `start_dir`: the config files, the prompt
+/// templates, the jj workspace, the configured backend and its credentials, and git. Expects
+/// [`config::load`] to have been called for `start_dir`.
+pub fn run_checks(start_dir: &Path) -> Vec<Check> {
+    let mut checks =
+        vec![check_config_files(start_dir), check_templates(), check_workspace(start_dir)];
+    checks.extend(check_backend());
+    checks.push(check_git());
+    checks
+}
+
+fn check_config_files(start_dir: &Path) -> Check {
+    const NAME: &str = "config";
+    let files = config::check_files(start_dir);
+    if let Some((path, Err(e))) = files.iter().find(|(_, result)| result.is_err()) {
+        return Check::error(
+            NAME,
+            format!("{} can't be used: {e:#}", path.display()),
+            "Fix or remove the file; it is skipped until then",
+        );
+    }
+    if files.is_empty() {
+        return Check::ok(NAME, "no config files, using the defaults");
+    }
+    let paths: Vec<String> = files.iter().map(|(path, _)| path.display().to_string()).collect();
+    Check::ok(NAME, format!("loaded {}", paths.join(", ")))
+}
+
+fn check_templates() -> Check {
+    const NAME: &str = "prompt templates";
+    match CONFIG.check_placeholders() {
+        Ok(()) => Check::ok(NAME, "all templates are valid"),
+        Err(e) => Check::error(
+            NAME,
+            format!("{e:#}"),
+            "Correct the template in your config, or pass --no-strict-placeholders",
+        ),
+    }
+}
+
+fn check_workspace(start_dir: &Path) -> Check {
+    const NAME: &str = "jj workspace";
+    match find_workspace(start_dir) {
+        Ok(workspace) => {
+            Check::ok(NAME, format!("found at {}", workspace.workspace_root().display()))
+        }
+        Err(_) if start_dir.ancestors().any(|dir| dir.join(".git").exists()) => Check::warning(
+            NAME,
+            "none found, but this is a git repository, so commits are made with git",
+            "Run `jj git init --colocate` to use jj here",
+        ),
+        Err(e) => Check::error(
+            NAME,
+            e.to_string(),
+            "Run ccc-jj inside a jj workspace, or create one with `jj git init`",
+        ),
+    }
+}
+
+/// Checks the configured backend: the Claude CLI or another command, or curl and the API key for
+/// the HTTP backends
+fn check_backend() -> Vec<Check> {
+    let config = &CONFIG.generator;
+    let key = |env: &str| {
+        const NAME: &str = "API key";
+        if var_os(env).is_some_and(|value| !value.is_empty()) {
+            Check::ok(NAME, format!("${env} is set"))
+        } else {
+            Check::error(
+                NAME,
+                format!("${env} is not set"),
+                format!("export {env}=<your key>, or point api_key_env at another variable"),
+            )
+        }
+    };
+    match config.backend {
+        Backend::ClaudeCli => vec![check_claude_cli(&config.command, config.shell)],
+        Backend::Command => vec![check_command(&config.command, config.shell)],
+        Backend::Anthropic => vec![check_curl(), key(&config.anthropic.api_key_env)],
+        Backend::Openai => {
+            let mut checks = vec![check_curl()];
+            checks.extend(config.openai.api_key_env.as_deref().map(key));
+            checks
+        }
+        Backend::Ollama => vec![check_curl()],
+    }
+}
+
+fn check_claude_cli(command: &str, shell: bool) -> Check {
+    const NAME: &str = "Claude CLI";
+    let Some(version) = run(&mut build_command(command, &["--version".to_string()], shell)) else {
+        return Check::error(
+            NAME,
+            format!("`{command}` could not be run"),
+            "Install the Claude CLI, or set generator.command to its path",
+        );
+    };
+    let version = version.lines().next().unwrap_or_default().trim().to_string();
+    // Structured output needs a CLI that knows `--json-schema`
+    let help = run(&mut build_command(command, &["--help".to_string()], shell)).unwrap_or_default();
+    if !help.contains("--json-schema") {
+        return Check::error(
+            NAME,
+            format!("{version} doesn't support --json-schema"),
+            "Update the Claude CLI with `claude update`",
+        );
+    }
+    Check::ok(NAME, version)
+}
+
+/// Checks that a command other than the Claude CLI can be found. It isn't run, as it would wait
+/// for a prompt or call a model.
+fn check_command(command: &str, shell: bool) -> Check {
+    const NAME: &str = "Generator command";
+    if shell {
+        return Check::ok(NAME, format!("`{command}` is run through the shell"));
+    }
+    match find_program(command) {
+        Some(path) => Check::ok(NAME, path.display().to_string()),
+        None => Check::error(
+            NAME,
+            format!("`{command}` was not found"),
+            "Install it, or set generator.command (or --generator-cmd) to its path",
+        ),
+    }
+}
+
+/// Where `program` would be run from: the path itself when it names a directory, or else the first
+/// directory on `PATH` holding it, with one of the `PATHEXT` extensions on Windows
+fn find_program(program: &str) -> Option<PathBuf> {
+    let path = Path::new(program);
+    if path.components().count() > 1 {
+        return path.is_file().then(|| path.to_path_buf());
+    }
+    let extensions: Vec<String> = if cfg!(windows) && path.extension().is_none() {
+        let pathext = var("PATHEXT").unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".to_string());
+        pathext.split(';').map(String::from).collect()
+    } else {
+        vec![String::new()]
+    };
+    split_paths(&var_os("PATH")?)
+        .flat_map(|dir| extensions.iter().map(move |ext| dir.join(format!("{program}{ext}"))))
+        .find(|candidate| candidate.is_file())
+}
+
+fn check_curl() -> Check {
+    const NAME: &str = "curl";
+    let Some(output) = run(Command::new("curl").arg("--version")) else {
+        return Check::error(
+            NAME,
+            "curl could not be run",
+            "Install curl, which the HTTP backends post requests with",
+        );
+    };
+    let (major, minor) = MIN_CURL_VERSION;
+    match curl_version(&output) {
+        Some(version) if version >= MIN_CURL_VERSION => {
+            Check::ok(NAME, format!("curl {}.{}", version.0, version.1))
+        }
+        version => Check::error(
+            NAME,
+            match version {
+                Some((found_major, found_minor)) => {
+                    format!("curl {found_major}.{found_minor} is too old")
+                }
+                None => "the curl version could not be read".to_string(),
+            },
+            format!("Install curl {major}.{minor} or later"),
+        ),
+    }
+}
+
+fn check_git() -> Check {
+    const NAME: &str = "git";
+    match run(Command::new("git").arg("--version")) {
+        Some(version) => Check::ok(NAME, version.trim()),
+        None => Check::warning(
+            NAME,
+            "git could not be run, so core.excludesFile is not read and only \
+             ~/.config/git/ignore is honored as the global excludes file",
+            "Install git",
+        ),
+    }
+}
+
+/// The stdout of a command that succeeded
+fn run(command: &mut Command) -> Option<String> {
+    let output = command.output().ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The major and minor version from the first line of `curl --version`, e.g. `curl 8.5.0 (...)`
+fn curl_version(output: &str) -> Option<(u32, u32)> {
+    let version = output.split_whitespace().nth(1)?;
+    let mut parts = version.split('.').map(str::parse::<u32>);
+    Some((parts.next()?.ok()?, parts.next()?.ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_find_program() {
+        assert!(find_program("sh").is_some_and(|path| path.ends_with("sh")));
+        assert_eq!(find_program("/bin/sh"), Some(PathBuf::from("/bin/sh")));
+        assert_eq!(find_program("ccc-jj-no-such-program"), None);
+    }
+
+    #[test]
+    fn test_curl_version() {
+        assert_eq!(curl_version("curl 8.5.0 (x86_64-pc-linux-gnu) libcurl/8.5.0\n"), Some((8, 5)));
+        assert_eq!(curl_version("curl 7.81.0 (x86_64-pc-linux-gnu)\n"), Some((7, 81)));
+        assert_eq!(curl_version(""), None);
+        assert!((7, 81) < MIN_CURL_VERSION && (8, 10) > MIN_CURL_VERSION);
+    }
+}
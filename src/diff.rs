@@ -1,28 +1,136 @@
-use std::fmt::Write;
+use std::{
+    borrow::Cow,
+    cmp::{Ordering, Reverse},
+    fmt::Write,
+    iter::once,
+};
 
-use anyhow::Result;
-use futures::StreamExt;
+use anyhow::{Context, Result};
+use futures::{StreamExt, TryStreamExt, stream};
 use globset::{Glob, GlobSet, GlobSetBuilder};
 use jj_lib::{
     backend::{FileId, TreeValue},
+    merge::{Diff, MergedTreeValue},
     merged_tree::MergedTree,
+    object_id::ObjectId,
     repo::{ReadonlyRepo, Repo},
-    repo_path::RepoPath,
+    repo_path::{RepoPath, RepoPathBuf},
 };
-use similar::TextDiff;
+use similar::{ChangeTag, DiffTag, TextDiff};
 use tokio::{io::AsyncReadExt, try_join};
 use tracing::{debug, trace, warn};
 
+use crate::{
+    metrics::{self, Stage},
+    renames::{self, Detected, Kind, Source, Target},
+};
+
 /// Summary of file changes between two trees
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct FileChangeSummary {
     pub added: Vec<String>,
     pub deleted: Vec<String>,
     pub modified: Vec<String>,
+    pub conflicted: Vec<String>,
+}
+
+impl FileChangeSummary {
+    /// Iterates over all added, deleted, and modified paths
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.added
+            .iter()
+            .chain(&self.deleted)
+            .chain(&self.modified)
+            .map(String::as_str)
+    }
+
+    /// Keeps only the entries for the given paths
+    pub fn subset(&self, files: &[String]) -> Self {
+        let keep = |paths: &[String]| -> Vec<String> {
+            paths.iter().filter(|path| files.contains(path)).cloned().collect()
+        };
+        Self {
+            added: keep(&self.added),
+            deleted: keep(&self.deleted),
+            modified: keep(&self.modified),
+            conflicted: keep(&self.conflicted),
+        }
+    }
+}
+
+/// A path that is still conflicted in a tree
+#[derive(Debug)]
+pub struct ConflictSummary {
+    pub path: String,
+    pub sides: usize,
 }
 
+/// Lines shown of an added or deleted file unless `diff.max_file_lines` says otherwise
 const MAX_LINES: usize = 50;
+/// Unchanged lines around each change unless `diff.context_lines` says otherwise
 const CONTEXT_LINES: usize = 2;
+/// Number of files whose contents are read at the same time while building a diff
+const MAX_CONCURRENT_READS: usize = 16;
+
+/// Selects the paths to commit from `--only` and `--exclude` globs
+pub struct PathFilter {
+    only: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+}
+
+impl PathFilter {
+    /// Builds the filter, or returns `None` when there are no patterns and every path is
+    /// selected. Unlike configured patterns, an invalid glob given on the command line is an error.
+    pub fn new(only: &[String], exclude: &[String]) -> Result<Option<Self>> {
+        if only.is_empty() && exclude.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            only: build_strict_globset(only)?,
+            exclude: build_strict_globset(exclude)?,
+        }))
+    }
+
+    /// Whether the changes to `path` are committed
+    pub fn matches(&self, path: &str) -> bool {
+        self.only.as_ref().is_none_or(|only| only.is_match(path))
+            && !self.exclude.as_ref().is_some_and(|exclude| exclude.is_match(path))
+    }
+}
+
+fn build_strict_globset(patterns: &[String]) -> Result<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).with_context(|| format!("Invalid glob '{pattern}'"))?);
+    }
+    Ok(Some(builder.build()?))
+}
+
+/// The path as shown to the model. Control characters (newlines, tabs, terminal escapes) and the
+/// invisible characters that reorder or hide text are written as Rust escapes such as `\n` or
+/// `\u{202e}`, so an unusual file name can't break the lines of the diff or disguise itself. Other
+/// characters, including non-ASCII letters, are kept as they are.
+pub fn escape_path(path: &str) -> Cow<'_, str> {
+    let needs_escape = |c: char| {
+        c.is_control()
+            || matches!(c, '\u{200b}'..='\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}' | '\u{feff}')
+    };
+    if !path.chars().any(needs_escape) {
+        return Cow::Borrowed(path);
+    }
+    let mut escaped = String::with_capacity(path.len() + 8);
+    for c in path.chars() {
+        if needs_escape(c) {
+            escaped.extend(c.escape_default());
+        } else {
+            escaped.push(c);
+        }
+    }
+    Cow::Owned(escaped)
+}
 
 /// Build a GlobSet from pattern strings
 pub fn build_collapse_matcher(patterns: &[String]) -> Option<GlobSet> {
@@ -51,6 +159,89 @@ pub fn build_collapse_matcher(patterns: &[String]) -> Option<GlobSet> {
     }
 }
 
+/// Where a file's diff goes in the prompt, and which files get the token budget first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    First,
+    Normal,
+    Last,
+}
+
+/// Sorts files into [`Priority`] classes by the `diff.priority` patterns
+#[derive(Default)]
+pub struct DiffPriority {
+    first: Option<GlobSet>,
+    last: Option<GlobSet>,
+}
+
+impl DiffPriority {
+    /// Files matching `first` come first and `last` last; a file matching both comes first
+    pub fn new(first: &[String], last: &[String]) -> Self {
+        Self {
+            first: build_collapse_matcher(first),
+            last: build_collapse_matcher(last),
+        }
+    }
+
+    pub fn of(&self, path: &str) -> Priority {
+        let matches = |set: &Option<GlobSet>| set.as_ref().is_some_and(|set| set.is_match(path));
+        if matches(&self.first) {
+            Priority::First
+        } else if matches(&self.last) {
+            Priority::Last
+        } else {
+            Priority::Normal
+        }
+    }
+}
+
+/// How the diffs of text files are shown
+pub struct DiffStyle {
+    /// Added files at least this percent similar to a deleted or modified file are shown as
+    /// renames or copies of it; `0` disables detection
+    pub rename_similarity: u8,
+    /// The modified files shown as word diffs
+    pub word_diff: WordDiff,
+    /// Unchanged lines shown around each change
+    pub context_lines: usize,
+    /// Lines shown of an added or deleted file, with the rest only counted
+    pub max_file_lines: usize,
+}
+
+impl Default for DiffStyle {
+    fn default() -> Self {
+        Self {
+            rename_similarity: 0,
+            word_diff: WordDiff::default(),
+            context_lines: CONTEXT_LINES,
+            max_file_lines: MAX_LINES,
+        }
+    }
+}
+
+/// Picks the modified files shown as word diffs by the `diff.word_diff` settings
+#[derive(Default)]
+pub struct WordDiff {
+    patterns: Option<GlobSet>,
+    max_bytes: usize,
+}
+
+impl WordDiff {
+    /// Files matching `patterns`, or no larger than `max_bytes` before and after the change, get
+    /// word diffs; `0` leaves size out of it
+    pub fn new(patterns: &[String], max_bytes: usize) -> Self {
+        Self {
+            patterns: build_collapse_matcher(patterns),
+            max_bytes,
+        }
+    }
+
+    pub fn applies(&self, path: &str, size: usize) -> bool {
+        self.patterns.as_ref().is_some_and(|set| set.is_match(path))
+            || (self.max_bytes > 0 && size <= self.max_bytes)
+    }
+}
+
 /// Read file content from store
 async fn read_file_content(repo: &ReadonlyRepo, path: &RepoPath, id: &FileId) -> Result<Vec<u8>> {
     let mut content = Vec::new();
@@ -62,15 +253,30 @@ async fn read_file_content(repo: &ReadonlyRepo, path: &RepoPath, id: &FileId) ->
     Ok(content)
 }
 
-/// Format file diff (added/removed) with line truncation
-async fn format_added_removed_diff(
-    repo: &ReadonlyRepo,
-    path: &RepoPath,
+/// The `.gitattributes` file at the root of the tree, if there is one without conflicts
+pub async fn read_gitattributes(repo: &ReadonlyRepo, tree: &MergedTree) -> Option<String> {
+    let path = RepoPath::from_internal_string(".gitattributes").ok()?;
+    let value = tree.path_value(path).ok()?;
+    let Some(Some(TreeValue::File { id, .. })) = value.as_resolved() else {
+        return None;
+    };
+    match read_file_content(repo, path, id).await {
+        Ok(content) => Some(String::from_utf8_lossy(&content).into_owned()),
+        Err(e) => {
+            warn!(error = %e, "Failed to read .gitattributes");
+            None
+        }
+    }
+}
+
+/// Format file diff (added/removed) with line truncation; `max_lines` 0 shows every line
+fn format_added_removed_diff(
     path_str: &str,
-    id: &FileId,
+    content: Vec<u8>,
     is_added: bool,
     max_lines: usize,
-) -> Result<String> {
+) -> String {
+    let max_lines = if max_lines == 0 { usize::MAX } else { max_lines };
     let (status, from, to) = if is_added {
         ("new file", "/dev/null".to_string(), format!("b/{path_str}"))
     } else {
@@ -79,7 +285,6 @@ async fn format_added_removed_diff(
 
     let mut output =
         format!("diff --git a/{path_str} b/{path_str}\n{status}\n--- {from}\n+++ {to}\n");
-    let content = read_file_content(repo, path, id).await?;
 
     match String::from_utf8(content) {
         Ok(text) => {
@@ -94,32 +299,105 @@ async fn format_added_removed_diff(
                 let _ = writeln!(output, "... ({} more lines)", lines.len() - max_lines);
             }
         }
-        Err(_) => writeln!(output, "(binary file)")?,
+        Err(e) => {
+            let content = e.into_bytes();
+            let (before, after) =
+                if is_added { (None, Some(&content[..])) } else { (Some(&content[..]), None) };
+            let _ = writeln!(output, "(binary file)\n{}", describe_binary(path_str, before, after));
+        }
     }
 
-    Ok(output)
+    output
 }
 
-/// Determine the collapse reason based on limits
-fn collapse_reason(
-    pattern_match: bool,
-    line_count: usize,
-    byte_size: usize,
-    max_lines: usize,
-    max_bytes: usize,
-) -> &'static str {
-    if pattern_match {
-        "collapsed: matches pattern"
-    } else if line_count > max_lines {
-        "collapsed: exceeds line limit"
-    } else if byte_size > max_bytes {
-        "collapsed: exceeds size limit"
-    } else {
-        "collapsed"
+/// Diffs a modified text file with its changed words marked inline, `[-old-]{+new+}` as in
+/// `git diff --word-diff=plain`. Hunks are found line by line; within a hunk only the words that
+/// changed are marked, which takes far fewer tokens than whole lines when prose is reworded.
+/// Every body line starts with a space, so nothing in it reads as an added or removed line.
+fn format_word_diff(path_str: &str, before: &str, after: &str, context_lines: usize) -> String {
+    let lines = TextDiff::from_lines(before, after);
+    let mut output = format!(
+        "diff --git a/{path_str} b/{path_str}\n{WORD_DIFF_HEADER}\n--- a/{path_str}\n+++ b/{path_str}\n"
+    );
+    for group in lines.grouped_ops(context_lines) {
+        let (Some(first), Some(last)) = (group.first(), group.last()) else {
+            continue;
+        };
+        let old = first.old_range().start..last.old_range().end;
+        let new = first.new_range().start..last.new_range().end;
+        let _ = writeln!(
+            output,
+            "@@ -{},{} +{},{} @@",
+            old.start + 1,
+            old.len(),
+            new.start + 1,
+            new.len()
+        );
+        for op in &group {
+            let old_lines = &lines.old_slices()[op.old_range()];
+            let new_lines = &lines.new_slices()[op.new_range()];
+            // Lines only removed or only added are marked whole, one by one
+            let marked: Vec<String> = match op.tag() {
+                DiffTag::Equal => new_lines.iter().map(|line| line.to_string()).collect(),
+                DiffTag::Delete => old_lines
+                    .iter()
+                    .map(|line| format!("[-{}-]", line.trim_end()))
+                    .collect(),
+                DiffTag::Insert => new_lines
+                    .iter()
+                    .map(|line| format!("{{+{}+}}", line.trim_end()))
+                    .collect(),
+                DiffTag::Replace => {
+                    vec![mark_changed_words(&old_lines.concat(), &new_lines.concat())]
+                }
+            };
+            for line in marked.iter().flat_map(|text| text.lines()) {
+                let _ = writeln!(output, " {line}");
+            }
+        }
+    }
+    output
+}
+
+/// The new text with removed words put back as `[-...-]` and added words marked `{+...+}`
+fn mark_changed_words(old: &str, new: &str) -> String {
+    let words = TextDiff::from_words(old, new);
+    let mut output = String::new();
+    let (mut removed, mut added) = (String::new(), String::new());
+    for change in words.iter_all_changes() {
+        match change.tag() {
+            ChangeTag::Delete => removed.push_str(change.value()),
+            ChangeTag::Insert => added.push_str(change.value()),
+            ChangeTag::Equal => {
+                push_changed_words(&mut output, &mut removed, &mut added);
+                output.push_str(change.value());
+            }
+        }
     }
+    push_changed_words(&mut output, &mut removed, &mut added);
+    output
 }
 
-/// Format a collapsed summary for files matching collapse patterns or size limits
+/// Appends the pending removed and added words, keeping a line break that ends them outside the
+/// markers
+fn push_changed_words(output: &mut String, removed: &mut String, added: &mut String) {
+    let mut newline = false;
+    for (text, open, close) in [(&*removed, "[-", "-]"), (&*added, "{+", "+}")] {
+        if text.is_empty() {
+            continue;
+        }
+        let trimmed = text.strip_suffix('\n');
+        newline |= trimmed.is_some();
+        let _ = write!(output, "{open}{}{close}", trimmed.unwrap_or(text));
+    }
+    if newline {
+        output.push('\n');
+    }
+    removed.clear();
+    added.clear();
+}
+
+/// Format a collapsed summary for files matching collapse patterns or left out of the token budget
 fn format_collapsed_summary(
     path_str: &str,
     added: usize,
@@ -132,138 +410,672 @@ fn format_collapsed_summary(
     )
 }
 
+/// Rough token count of text sent to the model. BPE tokenizers average about four bytes per
+/// token on code and English prose, which is close enough for budgeting.
+pub(crate) fn estimate_tokens(text: &str) -> usize {
+    text.len().div_ceil(4)
+}
+
+/// One file's part of the diff
+struct FileDiff {
+    full: String,
+    /// Summary shown instead of `full` when the token budget runs out, or `None` when `full` is
+    /// already as short as it gets
+    collapsed: Option<String>,
+}
+
+impl FileDiff {
+    fn fixed(text: String) -> Self {
+        Self { full: text, collapsed: None }
+    }
+
+    /// A file that is shown in full if the budget allows, or summarized by `summary` (called with
+    /// the collapse reason). Files matching a collapse pattern are always summarized.
+    fn collapsible(
+        pattern_match: bool,
+        full: impl FnOnce() -> String,
+        summary: impl Fn(&str) -> String,
+    ) -> Self {
+        if pattern_match {
+            Self::fixed(summary(PATTERN_REASON))
+        } else {
+            Self {
+                full: full(),
+                collapsed: Some(summary(BUDGET_REASON)),
+            }
+        }
+    }
+}
+
+impl FileDiff {
+    /// The text put in the diff: `full`, or the summary if it isn't `expanded`
+    fn shown(&self, expanded: bool) -> &str {
+        match &self.collapsed {
+            Some(collapsed) if !expanded => collapsed,
+            _ => &self.full,
+        }
+    }
+
+    /// Notes a change of the executable bit right after the `diff --git` line
+    fn with_mode_change(mut self, mode_change: Option<String>) -> Self {
+        if let Some(mode_change) = mode_change {
+            for text in once(&mut self.full).chain(self.collapsed.as_mut()) {
+                let at = text.find('\n').map_or(text.len(), |at| at + 1);
+                text.insert_str(at, &format!("{mode_change}\n"));
+            }
+        }
+        self
+    }
+}
+
+const PATTERN_REASON: &str = "collapsed: matches pattern";
+const BUDGET_REASON: &str = "collapsed: exceeds token budget";
+const SIZE_REASON: &str = "collapsed: diff too large";
+
+/// Header line of a file shown by [`format_word_diff`], which also tells the model how to read it
+const WORD_DIFF_HEADER: &str = "word diff: [-removed-] {+added+}";
+
+/// How large the diff built by [`get_tree_diff`] may get
+#[derive(Debug, Clone, Copy)]
+pub struct DiffLimits {
+    /// Estimated tokens the diff should take up; files are summarized beyond it
+    pub token_budget: usize,
+    /// Lines and bytes the whole diff may have; more files are summarized to stay within them
+    pub max_lines: usize,
+    pub max_bytes: usize,
+}
+
+impl DiffLimits {
+    /// Every file in full
+    pub const UNLIMITED: Self = Self {
+        token_budget: usize::MAX,
+        max_lines: usize::MAX,
+        max_bytes: usize::MAX,
+    };
+}
+
+/// Decides which files are shown in full.
+///
+/// Every file contributes at least its summary. The remaining budget is then spent expanding
+/// files class by class in `priorities` order, smallest diffs first within a class, so many small
+/// focused changes win over one huge one.
+fn pack(files: &[FileDiff], priorities: &[Priority], token_budget: usize) -> Vec<bool> {
+    let base: usize = files
+        .iter()
+        .map(|file| estimate_tokens(file.collapsed.as_ref().unwrap_or(&file.full)))
+        .sum();
+    let mut remaining = token_budget.saturating_sub(base);
+
+    let mut expanded: Vec<bool> = files.iter().map(|file| file.collapsed.is_none()).collect();
+    let mut candidates: Vec<(usize, &str, &str)> = files
+        .iter()
+        .enumerate()
+        .filter_map(|(i, file)| Some((i, file.full.as_str(), file.collapsed.as_deref()?)))
+        .collect();
+    candidates.sort_by_key(|&(i, full, _)| (priorities[i], estimate_tokens(full)));
+    for (i, full, collapsed) in candidates {
+        let extra = estimate_tokens(full).saturating_sub(estimate_tokens(collapsed));
+        if extra <= remaining {
+            remaining -= extra;
+            expanded[i] = true;
+        }
+    }
+    expanded
+}
+
+/// Summarizes files that `pack` expanded until the whole diff has at most `max_lines` lines and
+/// `max_bytes` bytes, lower priorities first and the largest diffs first within a class.
+///
+/// Returns the summarized files. The diff may still be over the limits if every file is already as
+/// short as it gets.
+fn fit_totals(
+    files: &[FileDiff],
+    priorities: &[Priority],
+    expanded: &mut [bool],
+    max_lines: usize,
+    max_bytes: usize,
+) -> Vec<usize> {
+    let size = |text: &str| (text.lines().count(), text.len());
+    let (mut lines, mut bytes) =
+        files.iter().zip(expanded.iter()).fold((0, 0), |total, (file, full)| {
+            let (l, b) = size(file.shown(*full));
+            (total.0 + l, total.1 + b)
+        });
+
+    let mut candidates: Vec<usize> = (0..files.len())
+        .filter(|&i| expanded[i] && files[i].collapsed.is_some())
+        .collect();
+    candidates.sort_by_key(|&i| (Reverse(priorities[i]), Reverse(files[i].full.len())));
+    let mut degraded = Vec::new();
+    for i in candidates {
+        if lines <= max_lines && bytes <= max_bytes {
+            break;
+        }
+        let (full_lines, full_bytes) = size(&files[i].full);
+        let (summary_lines, summary_bytes) = size(files[i].shown(false));
+        lines = lines - full_lines + summary_lines;
+        bytes = bytes - full_bytes + summary_bytes;
+        expanded[i] = false;
+        degraded.push(i);
+    }
+    degraded
+}
+
+/// Summarize a modified file where at least one side isn't valid UTF-8, keeping track of whether
+/// it changed between text and binary
+fn format_binary_change(path_str: &str, before: &[u8], after: &[u8]) -> String {
+    let change = match (str::from_utf8(before).is_ok(), str::from_utf8(after).is_ok()) {
+        (true, false) => "text file replaced by binary",
+        (false, true) => "binary file replaced by text",
+        _ => "binary file modified",
+    };
+    let description = describe_binary(path_str, Some(before), Some(after));
+    format!("diff --git a/{path_str} b/{path_str}\n({change})\n{description}\n")
+}
+
+/// File types recognized by their leading bytes, checked before the extension
+const MAGIC_BYTES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "PNG image"),
+    (b"\xff\xd8\xff", "JPEG image"),
+    (b"GIF8", "GIF image"),
+    (b"%PDF-", "PDF document"),
+    (b"PK\x03\x04", "ZIP archive"),
+    (b"\x1f\x8b", "gzip archive"),
+    (b"7z\xbc\xaf\x27\x1c", "7z archive"),
+    (b"\x7fELF", "ELF binary"),
+    (b"\xcf\xfa\xed\xfe", "Mach-O binary"),
+    (b"MZ", "Windows executable"),
+    (b"\0asm", "WebAssembly module"),
+    (b"SQLite format 3\0", "SQLite database"),
+    (b"wOFF", "WOFF font"),
+    (b"wOF2", "WOFF2 font"),
+    (b"OTTO", "OpenType font"),
+    (b"\0\x01\0\0", "TrueType font"),
+    (b"\0\0\x01\0", "ICO image"),
+    (b"ID3", "MP3 audio"),
+    (b"OggS", "Ogg media"),
+    (b"fLaC", "FLAC audio"),
+];
+
+/// File types recognized by extension when the leading bytes don't tell
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("png", "PNG image"),
+    ("jpg", "JPEG image"),
+    ("jpeg", "JPEG image"),
+    ("gif", "GIF image"),
+    ("webp", "WebP image"),
+    ("bmp", "BMP image"),
+    ("ico", "ICO image"),
+    ("tif", "TIFF image"),
+    ("tiff", "TIFF image"),
+    ("psd", "Photoshop image"),
+    ("pdf", "PDF document"),
+    ("zip", "ZIP archive"),
+    ("jar", "Java archive"),
+    ("gz", "gzip archive"),
+    ("tar", "tar archive"),
+    ("woff", "WOFF font"),
+    ("woff2", "WOFF2 font"),
+    ("ttf", "TrueType font"),
+    ("otf", "OpenType font"),
+    ("mp3", "MP3 audio"),
+    ("wav", "WAV audio"),
+    ("mp4", "MP4 video"),
+    ("mov", "QuickTime video"),
+    ("webm", "WebM video"),
+    ("wasm", "WebAssembly module"),
+    ("so", "shared library"),
+    ("dylib", "shared library"),
+    ("dll", "Windows library"),
+    ("exe", "Windows executable"),
+    ("sqlite", "SQLite database"),
+];
+
+/// Guesses a binary file's type from its leading bytes, then from its extension
+fn file_type(path_str: &str, content: &[u8]) -> Option<&'static str> {
+    // RIFF containers share their first bytes and name the format at offset 8
+    let riff = match content.get(8..12) {
+        Some(b"WEBP") if content.starts_with(b"RIFF") => Some("WebP image"),
+        Some(b"WAVE") if content.starts_with(b"RIFF") => Some("WAV audio"),
+        _ => None,
+    };
+    riff.or_else(|| {
+        MAGIC_BYTES
+            .iter()
+            .find(|(magic, _)| content.starts_with(magic))
+            .map(|(_, file_type)| *file_type)
+    })
+    .or_else(|| {
+        let (_, extension) = path_str.rsplit_once('.')?;
+        EXTENSIONS
+            .iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(extension))
+            .map(|(_, file_type)| *file_type)
+    })
+}
+
+/// Formats a byte count with binary units, e.g. `12.3 KiB`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 3] = ["KiB", "MiB", "GiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Describes a binary file by size and type, e.g. `icon.png: 12.3 KiB -> 15.1 KiB (+2.8 KiB, PNG
+/// image)`, giving only the size of the side that exists for an added or deleted file
+fn describe_binary(path_str: &str, before: Option<&[u8]>, after: Option<&[u8]>) -> String {
+    let size = |content: &[u8]| content.len() as u64;
+    let (sizes, delta) = match (before.map(size), after.map(size)) {
+        (Some(before), Some(after)) => {
+            let delta = match after.cmp(&before) {
+                Ordering::Greater => format!("+{}", format_size(after - before)),
+                Ordering::Less => format!("-{}", format_size(before - after)),
+                Ordering::Equal => "same size".to_string(),
+            };
+            (format!("{} -> {}", format_size(before), format_size(after)), Some(delta))
+        }
+        (Some(size), None) | (None, Some(size)) => (format_size(size), None),
+        (None, None) => (String::new(), None),
+    };
+    let file_type = after.or(before).and_then(|content| file_type(path_str, content));
+    let details: Vec<&str> = delta.as_deref().into_iter().chain(file_type).collect();
+    if details.is_empty() {
+        format!("{path_str}: {sizes}")
+    } else {
+        format!("{path_str}: {sizes} ({})", details.join(", "))
+    }
+}
+
+/// `mode change 100644 -> 100755` when the executable bit changed
+fn mode_change(before_executable: bool, after_executable: bool) -> Option<String> {
+    let mode = |executable| if executable { "100755" } else { "100644" };
+    (before_executable != after_executable)
+        .then(|| format!("mode change {} -> {}", mode(before_executable), mode(after_executable)))
+}
+
+/// Describes a change involving a symlink or submodule, or one that replaces an entry with one of
+/// another kind, e.g. `symlink changed: old -> new`. Returns `None` for anything else.
+async fn format_special_change(
+    repo: &ReadonlyRepo,
+    path: &RepoPath,
+    before: Option<&TreeValue>,
+    after: Option<&TreeValue>,
+) -> Result<Option<String>> {
+    let path_str = path.as_internal_file_string();
+    let change = match (before, after) {
+        (None, Some(TreeValue::Symlink(id))) => {
+            format!("new symlink -> {}", escape_path(&repo.store().read_symlink(path, id).await?))
+        }
+        (Some(TreeValue::Symlink(id)), None) => {
+            format!(
+                "deleted symlink -> {}",
+                escape_path(&repo.store().read_symlink(path, id).await?)
+            )
+        }
+        (Some(TreeValue::Symlink(before_id)), Some(TreeValue::Symlink(after_id))) => {
+            let (before, after) = try_join!(
+                repo.store().read_symlink(path, before_id),
+                repo.store().read_symlink(path, after_id)
+            )?;
+            format!("symlink changed: {} -> {}", escape_path(&before), escape_path(&after))
+        }
+        (None, Some(TreeValue::GitSubmodule(id))) => format!("new submodule at {}", short_hex(id)),
+        (Some(TreeValue::GitSubmodule(id)), None) => {
+            format!("deleted submodule at {}", short_hex(id))
+        }
+        (Some(TreeValue::GitSubmodule(before)), Some(TreeValue::GitSubmodule(after))) => {
+            format!("submodule updated: {} -> {}", short_hex(before), short_hex(after))
+        }
+        (Some(before), Some(after))
+            if std::mem::discriminant(before) != std::mem::discriminant(after) =>
+        {
+            format!(
+                "type change: {} -> {}",
+                describe_entry(repo, path, before).await?,
+                describe_entry(repo, path, after).await?
+            )
+        }
+        _ => return Ok(None),
+    };
+    let path_str = escape_path(path_str);
+    Ok(Some(format!("diff --git a/{path_str} b/{path_str}\n{change}\n")))
+}
+
+/// Names the kind of a tree entry, with the target of a symlink or the commit of a submodule
+async fn describe_entry(repo: &ReadonlyRepo, path: &RepoPath, value: &TreeValue) -> Result<String> {
+    Ok(match value {
+        TreeValue::File { executable: true, .. } => "executable file".to_string(),
+        TreeValue::File { .. } => "file".to_string(),
+        TreeValue::Symlink(id) => {
+            format!("symlink -> {}", escape_path(&repo.store().read_symlink(path, id).await?))
+        }
+        TreeValue::GitSubmodule(id) => format!("submodule at {}", short_hex(id)),
+        TreeValue::Tree(_) => "directory".to_string(),
+    })
+}
+
+/// The first 12 hex digits of a submodule commit, as `git log --abbrev=12` shows them
+fn short_hex(id: &impl ObjectId) -> String {
+    let mut hex = id.hex();
+    hex.truncate(12);
+    hex
+}
+
+fn format_conflicted_file(path_str: &str, sides: usize) -> String {
+    format!(
+        "diff --git a/{path_str} b/{path_str}\n(unresolved {sides}-sided conflict, conflict markers present)\n"
+    )
+}
+
+/// Added files paired with the deleted or modified files they were renamed or copied from
+struct Renames {
+    sources: Vec<Source>,
+    targets: Vec<Target>,
+    detected: Vec<Detected>,
+}
+
+impl Renames {
+    /// Reads the candidate files and detects renames and copies among them
+    async fn detect(
+        repo: &ReadonlyRepo,
+        entries: &[(RepoPathBuf, Diff<MergedTreeValue>)],
+        min_similarity: u8,
+    ) -> Result<Self> {
+        let mut sources = Vec::new();
+        let mut targets = Vec::new();
+        if min_similarity > 0 {
+            for (path, values) in entries {
+                let path_str = path.as_internal_file_string().to_string();
+                match (values.before.as_resolved(), values.after.as_resolved()) {
+                    (Some(None), Some(Some(TreeValue::File { id, .. }))) => targets.push(Target {
+                        path: path_str,
+                        content: read_file_content(repo, path, id).await?,
+                    }),
+                    (Some(Some(TreeValue::File { id, .. })), after) => {
+                        let deleted = matches!(after, Some(None));
+                        let modified = matches!(after, Some(Some(TreeValue::File { .. })));
+                        if deleted || modified {
+                            sources.push(Source {
+                                path: path_str,
+                                content: read_file_content(repo, path, id).await?,
+                                deleted,
+                            });
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let detected = renames::detect(&sources, &targets, min_similarity);
+        Ok(Self { sources, targets, detected })
+    }
+
+    fn for_target(&self, path: &str) -> Option<&Detected> {
+        self.detected.iter().find(|d| self.targets[d.target].path == path)
+    }
+
+    /// Whether the deleted file is shown as renamed, together with its new path
+    fn is_renamed_source(&self, path: &str) -> bool {
+        self.detected
+            .iter()
+            .any(|d| d.kind == Kind::Rename && self.sources[d.source].path == path)
+    }
+
+    /// Formats a rename or copy like `git diff -M -C`, with only the changed portion inline
+    fn format(&self, detected: &Detected, pattern_match: bool, context_lines: usize) -> FileDiff {
+        let source = &self.sources[detected.source];
+        let target = &self.targets[detected.target];
+        let verb = match detected.kind {
+            Kind::Rename => "rename",
+            Kind::Copy => "copy",
+        };
+        let header = format!(
+            "diff --git a/{0} b/{1}\nsimilarity index {2}%\n{3} from {0}\n{3} to {1}\n",
+            escape_path(&source.path),
+            escape_path(&target.path),
+            detected.similarity,
+            verb
+        );
+        if detected.similarity == 100 {
+            return FileDiff::fixed(header);
+        }
+
+        // Similarity below 100% implies both sides are text
+        let before = String::from_utf8_lossy(&source.content);
+        let after = String::from_utf8_lossy(&target.content);
+        let diff = TextDiff::from_lines(before.as_ref(), after.as_ref());
+        let added = diff
+            .iter_all_changes()
+            .filter(|c| c.tag() == similar::ChangeTag::Insert)
+            .count();
+        let removed = diff
+            .iter_all_changes()
+            .filter(|c| c.tag() == similar::ChangeTag::Delete)
+            .count();
+        FileDiff::collapsible(
+            pattern_match,
+            || {
+                format!(
+                    "{header}{}",
+                    diff.unified_diff()
+                        .context_radius(context_lines)
+                        .header(&format!("a/{}", source.path), &format!("b/{}", target.path))
+                )
+            },
+            |reason| format!("{header}(+{added} -{removed} lines, {reason})\n"),
+        )
+    }
+}
+
 /// Get the diff between two trees using jj-lib
+///
+/// Added files similar enough to a deleted or modified file are shown as renames or copies of it,
+/// and modified files picked by the word diff settings of `style` as word diffs. Files are ordered
+/// by `priority`, by path within a class, and shown in full as long as the diff fits in the token
+/// budget of `limits`, higher priorities first, and summarized otherwise. If the diff is still over
+/// the line or byte limit, the largest files shown in full are summarized until it fits.
 pub async fn get_tree_diff(
     repo: &ReadonlyRepo,
     from_tree: &MergedTree,
     to_tree: &MergedTree,
     collapse_matcher: Option<&GlobSet>,
-    max_diff_lines: usize,
-    max_diff_bytes: usize,
+    priority: &DiffPriority,
+    limits: DiffLimits,
+    style: &DiffStyle,
 ) -> Result<String> {
     debug!("Starting tree diff");
-    let mut output = String::new();
+    let _timer = metrics::time(Stage::Diff);
     let mut stream = from_tree.diff_stream(to_tree, &jj_lib::matchers::EverythingMatcher);
-    let mut file_count = 0;
-    let mut collapsed_count = 0;
-
+    let mut entries = Vec::new();
     while let Some(entry) = stream.next().await {
-        let path_str = entry.path.as_internal_file_string();
-        let values = entry.values?;
-
-        // Check if this file should be collapsed
-        let should_collapse = collapse_matcher.map(|m| m.is_match(path_str)).unwrap_or(false);
-
-        let diff_output = match (values.before.as_resolved(), values.after.as_resolved()) {
-            (Some(None), Some(Some(TreeValue::File { id, .. }))) => {
-                let content = read_file_content(repo, &entry.path, id).await?;
-                let byte_size = content.len();
-                let line_count = String::from_utf8_lossy(&content).lines().count();
-                let should_collapse_size =
-                    line_count > max_diff_lines || byte_size > max_diff_bytes;
-                trace!(path = %path_str, collapsed = should_collapse, collapsed_size = should_collapse_size, lines = line_count, bytes = byte_size, "Processing added file");
-                if should_collapse || should_collapse_size {
-                    collapsed_count += 1;
-                    let reason = collapse_reason(
-                        should_collapse,
-                        line_count,
-                        byte_size,
-                        max_diff_lines,
-                        max_diff_bytes,
-                    );
-                    format_collapsed_summary(path_str, line_count, 0, "new file", reason)
-                } else {
-                    format_added_removed_diff(repo, &entry.path, path_str, id, true, MAX_LINES)
-                        .await?
-                }
+        entries.push((entry.path, entry.values?));
+    }
+    let renames = Renames::detect(repo, &entries, style.rename_similarity).await?;
+    // Entries come sorted by path, and `buffered` yields results in that order
+    let files: Vec<Option<FileDiff>> = stream::iter(&entries)
+        .map(|(path, values)| diff_entry(repo, path, values, collapse_matcher, &renames, style))
+        .buffered(MAX_CONCURRENT_READS)
+        .try_collect()
+        .await?;
+    let mut files: Vec<(Priority, FileDiff)> = files
+        .into_iter()
+        .zip(&entries)
+        .filter_map(|(file, (path, _))| Some((priority.of(path.as_internal_file_string()), file?)))
+        .collect();
+    files.sort_by_key(|(priority, _)| *priority);
+    let (priorities, files): (Vec<Priority>, Vec<FileDiff>) = files.into_iter().unzip();
+
+    let mut expanded = pack(&files, &priorities, limits.token_budget);
+    let over_budget = expanded.iter().filter(|&&full| !full).count();
+    let over_size =
+        fit_totals(&files, &priorities, &mut expanded, limits.max_lines, limits.max_bytes);
+    if !over_size.is_empty() {
+        warn!(
+            files = over_size.len(),
+            "Summarized the largest files to fit diff.max_total_diff_lines and max_total_diff_bytes"
+        );
+    }
+    let mut output = String::new();
+    for (i, (file, full)) in files.iter().zip(expanded).enumerate() {
+        match &file.collapsed {
+            Some(collapsed) if over_size.contains(&i) => {
+                output.push_str(&collapsed.replacen(BUDGET_REASON, SIZE_REASON, 1));
             }
+            _ => output.push_str(file.shown(full)),
+        }
+    }
 
-            (Some(Some(TreeValue::File { id, .. })), Some(None)) => {
-                let content = read_file_content(repo, &entry.path, id).await?;
-                let byte_size = content.len();
-                let line_count = String::from_utf8_lossy(&content).lines().count();
-                let should_collapse_size =
-                    line_count > max_diff_lines || byte_size > max_diff_bytes;
-                trace!(path = %path_str, collapsed = should_collapse, collapsed_size = should_collapse_size, lines = line_count, bytes = byte_size, "Processing deleted file");
-                if should_collapse || should_collapse_size {
-                    collapsed_count += 1;
-                    let reason = collapse_reason(
-                        should_collapse,
-                        line_count,
-                        byte_size,
-                        max_diff_lines,
-                        max_diff_bytes,
-                    );
-                    format_collapsed_summary(path_str, 0, line_count, "deleted file", reason)
-                } else {
-                    format_added_removed_diff(repo, &entry.path, path_str, id, false, MAX_LINES)
-                        .await?
-                }
+    debug!(
+        file_count = files.len(),
+        over_budget,
+        output_len = output.len(),
+        estimated_tokens = estimate_tokens(&output),
+        "Tree diff complete"
+    );
+    Ok(output)
+}
+
+/// Builds one file's part of the diff, or `None` for entries that aren't shown on their own
+async fn diff_entry(
+    repo: &ReadonlyRepo,
+    path: &RepoPath,
+    values: &Diff<MergedTreeValue>,
+    collapse_matcher: Option<&GlobSet>,
+    renames: &Renames,
+    style: &DiffStyle,
+) -> Result<Option<FileDiff>> {
+    let path_str = path.as_internal_file_string();
+    // Matching uses the real path, the diff the escaped one
+    let shown = escape_path(path_str);
+
+    // Check if this file should be collapsed
+    let should_collapse = collapse_matcher.map(|m| m.is_match(path_str)).unwrap_or(false);
+
+    if renames.is_renamed_source(path_str) {
+        trace!(path = %path_str, "Deleted file shown as rename");
+        return Ok(None);
+    }
+    if let Some(detected) = renames.for_target(path_str) {
+        trace!(path = %path_str, kind = ?detected.kind, similarity = detected.similarity, "Processing renamed or copied file");
+        return Ok(Some(renames.format(detected, should_collapse, style.context_lines)));
+    }
+
+    let file_diff = match (values.before.as_resolved(), values.after.as_resolved()) {
+        (Some(None), Some(Some(TreeValue::File { id, .. }))) => {
+            let content = read_file_content(repo, path, id).await?;
+            let line_count = String::from_utf8_lossy(&content).lines().count();
+            trace!(path = %path_str, collapsed = should_collapse, lines = line_count, bytes = content.len(), "Processing added file");
+            FileDiff::collapsible(
+                should_collapse,
+                || format_added_removed_diff(&shown, content, true, style.max_file_lines),
+                |reason| format_collapsed_summary(&shown, line_count, 0, "new file", reason),
+            )
+        }
+
+        (Some(Some(TreeValue::File { id, .. })), Some(None)) => {
+            let content = read_file_content(repo, path, id).await?;
+            let line_count = String::from_utf8_lossy(&content).lines().count();
+            trace!(path = %path_str, collapsed = should_collapse, lines = line_count, bytes = content.len(), "Processing deleted file");
+            FileDiff::collapsible(
+                should_collapse,
+                || format_added_removed_diff(&shown, content, false, style.max_file_lines),
+                |reason| format_collapsed_summary(&shown, 0, line_count, "deleted file", reason),
+            )
+        }
+
+        (
+            Some(Some(TreeValue::File { id: before_id, executable: before_executable, .. })),
+            Some(Some(TreeValue::File { id: after_id, executable: after_executable, .. })),
+        ) => {
+            let mode_change = mode_change(*before_executable, *after_executable);
+            if before_id == after_id {
+                trace!(path = %path_str, "Only the executable bit changed");
+                let header = format!("diff --git a/{shown} b/{shown}\n");
+                return Ok(Some(FileDiff::fixed(header).with_mode_change(mode_change)));
             }
+            let (before_content, after_content) = try_join!(
+                read_file_content(repo, path, before_id),
+                read_file_content(repo, path, after_id)
+            )?;
 
-            (
-                Some(Some(TreeValue::File { id: before_id, .. })),
-                Some(Some(TreeValue::File { id: after_id, .. })),
-            ) => {
-                let (before_content, after_content) = try_join!(
-                    read_file_content(repo, &entry.path, before_id),
-                    read_file_content(repo, &entry.path, after_id)
-                )?;
-
-                // Compute byte_size before consuming the buffers
-                let byte_size = before_content.len().max(after_content.len());
-
-                match (String::from_utf8(before_content), String::from_utf8(after_content)) {
-                    (Ok(before_text), Ok(after_text)) => {
-                        let diff = TextDiff::from_lines(&before_text, &after_text);
-                        let added = diff
-                            .iter_all_changes()
-                            .filter(|c| c.tag() == similar::ChangeTag::Insert)
-                            .count();
-                        let removed = diff
-                            .iter_all_changes()
-                            .filter(|c| c.tag() == similar::ChangeTag::Delete)
-                            .count();
-                        let should_collapse_size =
-                            added + removed > max_diff_lines || byte_size > max_diff_bytes;
-                        trace!(path = %path_str, collapsed = should_collapse, collapsed_size = should_collapse_size, lines = added + removed, bytes = byte_size, "Processing modified file");
-                        if should_collapse || should_collapse_size {
-                            collapsed_count += 1;
-                            let reason = collapse_reason(
-                                should_collapse,
-                                added + removed,
-                                byte_size,
-                                max_diff_lines,
-                                max_diff_bytes,
-                            );
-                            format_collapsed_summary(path_str, added, removed, "modified", reason)
-                        } else {
+            let file_diff = match (
+                String::from_utf8(before_content),
+                String::from_utf8(after_content),
+            ) {
+                (Ok(before_text), Ok(after_text)) => {
+                    let diff = TextDiff::from_lines(&before_text, &after_text);
+                    let added = diff
+                        .iter_all_changes()
+                        .filter(|c| c.tag() == similar::ChangeTag::Insert)
+                        .count();
+                    let removed = diff
+                        .iter_all_changes()
+                        .filter(|c| c.tag() == similar::ChangeTag::Delete)
+                        .count();
+                    let words = style
+                        .word_diff
+                        .applies(path_str, before_text.len().max(after_text.len()));
+                    trace!(path = %path_str, collapsed = should_collapse, lines = added + removed, words, "Processing modified file");
+                    FileDiff::collapsible(
+                        should_collapse,
+                        || {
+                            if words {
+                                return format_word_diff(
+                                    &shown,
+                                    &before_text,
+                                    &after_text,
+                                    style.context_lines,
+                                );
+                            }
                             format!(
                                 "diff --git a/{0} b/{0}\n{1}",
-                                path_str,
+                                shown,
                                 diff.unified_diff()
-                                    .context_radius(CONTEXT_LINES)
-                                    .header(&format!("a/{path_str}"), &format!("b/{path_str}"))
+                                    .context_radius(style.context_lines)
+                                    .header(&format!("a/{shown}"), &format!("b/{shown}"))
                             )
-                        }
-                    }
-                    _ => {
-                        trace!(path = %path_str, "Binary file modified");
-                        format!("diff --git a/{path_str} b/{path_str}\n(binary file modified)\n")
-                    }
+                        },
+                        |reason| {
+                            format_collapsed_summary(&shown, added, removed, "modified", reason)
+                        },
+                    )
                 }
+                (before, after) => {
+                    trace!(path = %path_str, before_text = before.is_ok(), after_text = after.is_ok(), "Binary file modified");
+                    let before = before.map_or_else(|e| e.into_bytes(), String::into_bytes);
+                    let after = after.map_or_else(|e| e.into_bytes(), String::into_bytes);
+                    FileDiff::fixed(format_binary_change(&shown, &before, &after))
+                }
+            };
+            file_diff.with_mode_change(mode_change)
+        }
+        (Some(before), Some(after)) => {
+            match format_special_change(repo, path, before.as_ref(), after.as_ref()).await? {
+                Some(text) => {
+                    trace!(path = %path_str, "Processing symlink, submodule, or type change");
+                    FileDiff::fixed(text)
+                }
+                None => return Ok(None),
             }
-            _ => String::new(),
-        };
-
-        if !diff_output.is_empty() {
-            file_count += 1;
-            output.push_str(&diff_output);
         }
-    }
+        // Unresolved: the file holds jj's conflict markers rather than content to describe
+        (_, None) => {
+            let sides = values.after.num_sides();
+            trace!(path = %path_str, sides, "Conflicted file");
+            FileDiff::fixed(format_conflicted_file(&shown, sides))
+        }
 
-    debug!(file_count, collapsed_count, output_len = output.len(), "Tree diff complete");
-    Ok(output)
+        _ => return Ok(None),
+    };
+    Ok(Some(file_diff))
 }
 
 /// Get summary of file changes between two trees
@@ -283,20 +1095,506 @@ pub async fn get_file_change_summary(
 
         match (values.before.as_resolved(), values.after.as_resolved()) {
             // Added: before is None, after is Some
-            (Some(None), Some(Some(TreeValue::File { .. }))) => {
+            (Some(None), Some(Some(_))) => {
                 summary.added.push(path_str);
             }
             // Deleted: before is Some, after is None
-            (Some(Some(TreeValue::File { .. })), Some(None)) => {
+            (Some(Some(_)), Some(None)) => {
                 summary.deleted.push(path_str);
             }
-            // Modified: both before and after are Some
-            (Some(Some(TreeValue::File { .. })), Some(Some(TreeValue::File { .. }))) => {
+            // Modified: both before and after are Some, including mode and type changes
+            (Some(Some(_)), Some(Some(_))) => {
                 summary.modified.push(path_str);
             }
+            // Conflicted: after is an unresolved merge
+            (_, None) => {
+                summary.conflicted.push(path_str);
+            }
             _ => {}
         }
     }
 
     summary
 }
+
+/// Collect the paths that are still conflicted in the tree, with their number of sides
+pub fn get_conflict_summaries(tree: &MergedTree) -> Vec<ConflictSummary> {
+    tree.conflicts()
+        .filter_map(|(path, value)| match value {
+            Ok(value) => Some(ConflictSummary {
+                path: path.as_internal_file_string().to_string(),
+                sides: value.num_sides(),
+            }),
+            Err(e) => {
+                warn!(path = %path.as_internal_file_string(), error = %e, "Failed to read conflict");
+                None
+            }
+        })
+        .collect()
+}
+
+/// Format conflict summaries as a bullet list for the prompt
+pub fn format_conflicts(conflicts: &[ConflictSummary]) -> String {
+    conflicts
+        .iter()
+        .map(|c| format!("- {} ({}-sided conflict)", escape_path(&c.path), c.sides))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Count the added and removed lines in a unified diff, ignoring file headers and lines that are
+/// blank apart from whitespace. In the word diff of a file, lines with changed words count.
+pub fn count_changed_lines(diff: &str) -> usize {
+    let mut word_diff = false;
+    // Between `diff --git` and the first hunk, `---`/`+++` name the files; inside a hunk they are
+    // removed `--` or added `++` lines
+    let mut in_header = true;
+    diff.lines()
+        .filter(|line| {
+            if line.starts_with("diff --git ") {
+                word_diff = false;
+                in_header = true;
+            } else if *line == WORD_DIFF_HEADER {
+                word_diff = true;
+                return false;
+            } else if line.starts_with("@@") {
+                in_header = false;
+            }
+            if in_header && (line.starts_with("+++") || line.starts_with("---")) {
+                return false;
+            }
+            match line.strip_prefix('+').or_else(|| line.strip_prefix('-')) {
+                Some(content) => !content.trim().is_empty(),
+                None => word_diff && changed_words(line) != (false, false),
+            }
+        })
+        .count()
+}
+
+/// Summarizes the files of a `git diff` whose paths match `collapse_matcher`, the way
+/// [`get_tree_diff`] shows collapsed files
+pub fn collapse_git_diff(diff: &str, collapse_matcher: &GlobSet) -> String {
+    let mut output = String::with_capacity(diff.len());
+    let mut sections = diff.split_inclusive('\n').peekable();
+    while let Some(header) = sections.next() {
+        let mut section = vec![header];
+        while let Some(line) = sections.next_if(|line| !line.starts_with("diff --git a/")) {
+            section.push(line);
+        }
+
+        let path = header
+            .trim_end()
+            .strip_prefix("diff --git a/")
+            .and_then(|paths| paths.rsplit_once(" b/"))
+            .map(|(_, path)| path)
+            .filter(|path| collapse_matcher.is_match(path));
+        let Some(path) = path else {
+            output.extend(section);
+            continue;
+        };
+
+        let status = if section.iter().any(|line| line.starts_with("new file mode")) {
+            "new file"
+        } else if section.iter().any(|line| line.starts_with("deleted file mode")) {
+            "deleted file"
+        } else {
+            "modified"
+        };
+        let hunks = section.iter().skip_while(|line| !line.starts_with("@@"));
+        let (added, removed) = hunks.fold((0, 0), |(added, removed), line| match line.as_bytes() {
+            [b'+', ..] => (added + 1, removed),
+            [b'-', ..] => (added, removed + 1),
+            _ => (added, removed),
+        });
+        output.push_str(&format_collapsed_summary(path, added, removed, status, PATTERN_REASON));
+    }
+    output
+}
+
+/// Lists the files that a diff from [`get_tree_diff`] only shows as a collapsed summary
+pub fn collapsed_files(diff: &str) -> Vec<String> {
+    let mut files = Vec::new();
+    let mut current = None;
+    for line in diff.lines() {
+        if let Some(paths) = line.strip_prefix("diff --git a/") {
+            current = paths.rsplit_once(" b/").map(|(_, path)| path);
+        } else if let Some(path) = current
+            && !line.starts_with(['+', '-', ' ', '@'])
+            && line.contains(" lines, collapsed")
+        {
+            files.push(path.to_string());
+            current = None;
+        }
+    }
+    files
+}
+
+/// Reads the changed files from the `---`/`+++` headers and `Binary files ... differ` lines of a
+/// unified diff from any tool, e.g. `git diff`, `hg diff`, or `diff -u`. Renames count as a
+/// deletion and an addition, as in a jj diff.
+pub fn summarize_unified_diff(diff: &str) -> FileChangeSummary {
+    // `a/path\t2024-01-01 ...` -> `path`; `None` for /dev/null
+    let path = |header: &str| {
+        let path = header.split('\t').next().unwrap_or(header).trim();
+        (path != "/dev/null")
+            .then(|| {
+                path.strip_prefix("a/")
+                    .or_else(|| path.strip_prefix("b/"))
+                    .unwrap_or(path)
+            })
+            .map(String::from)
+    };
+    let mut summary = FileChangeSummary::default();
+    let mut lines = diff.lines().peekable();
+    while let Some(line) = lines.next() {
+        // Skip hunk bodies, whose removed `-- x` and added `++ y` lines look like headers
+        if let Some((mut old_lines, mut new_lines)) = hunk_lengths(line) {
+            while old_lines + new_lines > 0
+                && let Some(line) = lines.next()
+            {
+                match line.chars().next() {
+                    Some('-') => old_lines = old_lines.saturating_sub(1),
+                    Some('+') => new_lines = new_lines.saturating_sub(1),
+                    Some('\\') => {}
+                    _ => {
+                        old_lines = old_lines.saturating_sub(1);
+                        new_lines = new_lines.saturating_sub(1);
+                    }
+                }
+            }
+            continue;
+        }
+        let (old, new) = if let Some(old) = line.strip_prefix("--- ")
+            && let Some(new) = lines.peek().and_then(|next| next.strip_prefix("+++ "))
+        {
+            lines.next();
+            (path(old), path(new))
+        } else if let Some(paths) = line
+            .strip_prefix("Binary files ")
+            .and_then(|rest| rest.strip_suffix(" differ"))
+            .and_then(|paths| paths.split_once(" and "))
+        {
+            (path(paths.0), path(paths.1))
+        } else {
+            continue;
+        };
+        match (old, new) {
+            (None, Some(new)) => summary.added.push(new),
+            (Some(old), None) => summary.deleted.push(old),
+            (Some(old), Some(new)) if old == new => summary.modified.push(new),
+            (Some(old), Some(new)) => {
+                summary.deleted.push(old);
+                summary.added.push(new);
+            }
+            (None, None) => {}
+        }
+    }
+    summary
+}
+
+/// The old and new line counts of a `@@ -1,5 +1,6 @@` hunk header
+fn hunk_lengths(line: &str) -> Option<(usize, usize)> {
+    let mut ranges = line.strip_prefix("@@ -")?.split(' ');
+    let count = |range: &str| match range.split_once(',') {
+        Some((_, count)) => count.parse().ok(),
+        None => Some(1),
+    };
+    let old = count(ranges.next()?)?;
+    let new = count(ranges.next()?.strip_prefix('+')?)?;
+    Some((old, new))
+}
+
+/// Splits a diff from [`get_tree_diff`] into the part of each file, along with the file's path
+pub fn split_file_diffs(diff: &str) -> Vec<(String, String)> {
+    let mut files: Vec<(String, String)> = Vec::new();
+    for line in diff.split_inclusive('\n') {
+        let path = line
+            .trim_end()
+            .strip_prefix("diff --git a/")
+            .and_then(|paths| paths.rsplit_once(" b/"))
+            .map(|(_, path)| path);
+        match (path, files.last_mut()) {
+            (Some(path), _) => files.push((path.to_string(), line.to_string())),
+            (None, Some((_, section))) => section.push_str(line),
+            (None, None) => {}
+        }
+    }
+    files
+}
+
+/// Counts the added and removed lines in one file's part of a diff; in a word diff, the lines
+/// with added and with removed words
+pub fn added_removed_lines(file_diff: &str) -> (usize, usize) {
+    // Everything up to the `+++` line is header, even lines starting with `-`
+    let Some((_, body)) = file_diff.split_once("\n+++ ") else {
+        return (0, 0);
+    };
+    let (mut added, mut removed) = (0, 0);
+    if file_diff.lines().any(|line| line == WORD_DIFF_HEADER) {
+        for (adds, removes) in body.lines().skip(1).map(changed_words) {
+            added += usize::from(adds);
+            removed += usize::from(removes);
+        }
+        return (added, removed);
+    }
+    for line in body.lines().skip(1) {
+        match line.chars().next() {
+            Some('+') => added += 1,
+            Some('-') => removed += 1,
+            _ => {}
+        }
+    }
+    (added, removed)
+}
+
+/// Whether a line of a word diff adds words and whether it removes any
+fn changed_words(line: &str) -> (bool, bool) {
+    (line.contains("{+"), line.contains("[-"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_changed_lines() {
+        let diff = "diff --git a/a.rs b/a.rs\n--- a/a.rs\n+++ b/a.rs\n@@ -1,3 +1,3 @@\n fn a() {\n-    old();\n+    new();\n+\n }\n";
+        assert_eq!(count_changed_lines(diff), 2);
+        assert_eq!(count_changed_lines(""), 0);
+
+        let diff = "diff --git a/a.md b/a.md\n--- a/a.md\n+++ b/a.md\n@@ -1,2 +1,2 @@\n---- old rule\n+++ new counter\n";
+        assert_eq!(count_changed_lines(diff), 2);
+    }
+
+    #[test]
+    fn test_summarize_unified_diff() {
+        let diff = "diff --git a/src/a.rs b/src/a.rs\n--- a/src/a.rs\n+++ b/src/a.rs\n@@ -1 +1 @@\n\
+            --- old\n+++ new\n\
+            --- /dev/null\t2024-01-01 00:00:00\n+++ b/new.rs\t2024-01-01 00:00:00\n@@ -0,0 +1 @@\n+x\n\
+            --- old.txt\n+++ /dev/null\n@@ -1 +0,0 @@\n-x\n\
+            Binary files a/logo.png and b/logo.png differ\n";
+        let summary = summarize_unified_diff(diff);
+        assert_eq!(summary.modified, ["src/a.rs", "logo.png"]);
+        assert_eq!(summary.added, ["new.rs"]);
+        assert_eq!(summary.deleted, ["old.txt"]);
+    }
+
+    #[test]
+    fn test_split_file_diffs() {
+        let diff = "diff --git a/a.rs b/a.rs\n--- a/a.rs\n+++ b/a.rs\n@@ -1,2 +1,2 @@\n-old\n+new\n\
+            +--x\ndiff --git a/b.png b/b.png\n(binary file)\n";
+        let files = split_file_diffs(diff);
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].0, "a.rs");
+        assert_eq!(files[1].0, "b.png");
+        assert_eq!(files[1].1, "diff --git a/b.png b/b.png\n(binary file)\n");
+        assert_eq!(added_removed_lines(&files[0].1), (2, 1));
+        assert_eq!(added_removed_lines(&files[1].1), (0, 0));
+    }
+
+    #[test]
+    fn test_collapsed_files() {
+        let diff = format!(
+            "{}diff --git a/src/a.rs b/src/a.rs\n--- a/src/a.rs\n+++ b/src/a.rs\n@@ -1 +1 @@\n-x\n+modified (+1 -1 lines, collapsed: fake)\n{}",
+            format_collapsed_summary("Cargo.lock", 3, 1, "modified", PATTERN_REASON),
+            format_collapsed_summary("big.rs", 900, 0, "new file", BUDGET_REASON),
+        );
+        assert_eq!(collapsed_files(&diff), vec!["Cargo.lock", "big.rs"]);
+    }
+
+    #[test]
+    fn test_path_filter() {
+        let strings =
+            |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        assert!(PathFilter::new(&[], &[]).unwrap().is_none());
+
+        let filter = PathFilter::new(&strings(&["src/**"]), &strings(&["**/*.snap"]))
+            .unwrap()
+            .unwrap();
+        assert!(filter.matches("src/main.rs"));
+        assert!(!filter.matches("src/tests/output.snap"));
+        assert!(!filter.matches("README.md"));
+
+        let filter = PathFilter::new(&[], &strings(&["*.lock"])).unwrap().unwrap();
+        assert!(filter.matches("README.md"));
+        assert!(!filter.matches("Cargo.lock"));
+
+        assert!(PathFilter::new(&strings(&["src/[a"]), &[]).is_err());
+    }
+
+    #[test]
+    fn test_pack_expands_smallest_files_first() {
+        let file = |full_len: usize| FileDiff {
+            full: "x".repeat(full_len),
+            collapsed: Some("s".repeat(8)),
+        };
+        // Summaries take 2 tokens each; 6 tokens are left to expand the 3- and 5-token files
+        let files = [file(40), file(12), file(20), FileDiff::fixed("f".repeat(8))];
+        let normal = [Priority::Normal; 4];
+        assert_eq!(pack(&files, &normal, 14), vec![false, true, true, true]);
+        assert_eq!(pack(&files, &normal, 0), vec![false, false, false, true]);
+        assert_eq!(pack(&files, &normal, 100), vec![true, true, true, true]);
+
+        // The budget goes to higher priorities first, even to a larger file
+        let priorities = [Priority::First, Priority::Last, Priority::Normal, Priority::Last];
+        assert_eq!(pack(&files, &priorities, 16), vec![true, false, false, true]);
+    }
+
+    #[test]
+    fn test_fit_totals() {
+        let file = |lines: usize| FileDiff {
+            full: "+x\n".repeat(lines),
+            collapsed: Some("summary\n".to_string()),
+        };
+        let files = [file(10), file(30), file(20), FileDiff::fixed("f\n".repeat(5))];
+        let priorities = [Priority::Last, Priority::Normal, Priority::Normal, Priority::Normal];
+
+        // 66 lines: the low-priority file goes first, then the largest
+        let mut expanded = vec![true; 4];
+        assert_eq!(fit_totals(&files, &priorities, &mut expanded, 40, usize::MAX), [0, 1]);
+        assert_eq!(expanded, [false, false, true, true]);
+
+        let mut expanded = vec![true; 4];
+        assert!(fit_totals(&files, &priorities, &mut expanded, 66, usize::MAX).is_empty());
+
+        // Files that are already summarized or can't be are left alone
+        let mut expanded = vec![true, false, true, true];
+        assert_eq!(fit_totals(&files, &priorities, &mut expanded, 0, 0), [0, 2]);
+    }
+
+    #[test]
+    fn test_diff_priority() {
+        let priority = DiffPriority::new(&["src/**".to_string()], &["**/*.lock".to_string()]);
+        assert_eq!(priority.of("src/main.rs"), Priority::First);
+        assert_eq!(priority.of("src/Cargo.lock"), Priority::First);
+        assert_eq!(priority.of("Cargo.lock"), Priority::Last);
+        assert_eq!(priority.of("README.md"), Priority::Normal);
+        assert_eq!(DiffPriority::default().of("src/main.rs"), Priority::Normal);
+    }
+
+    #[test]
+    fn test_format_word_diff() {
+        let before = "# Title\n\nThe quick brown fox.\n- one\n- two\n";
+        let after = "# Title\n\nThe slow brown fox.\n- one\n";
+        let diff = format_word_diff("README.md", before, after, CONTEXT_LINES);
+        assert_eq!(
+            diff,
+            "diff --git a/README.md b/README.md\nword diff: [-removed-] {+added+}\n\
+            --- a/README.md\n+++ b/README.md\n@@ -1,5 +1,4 @@\n # Title\n \n \
+            The [-quick-]{+slow+} brown fox.\n - one\n [-- two-]\n"
+        );
+        assert_eq!(count_changed_lines(&diff), 2);
+        assert_eq!(added_removed_lines(&diff), (1, 2));
+
+        let diff = format_word_diff("README.md", before, after, 0);
+        assert!(diff.contains("@@ -3,1 +3,1 @@\n The [-quick-]{+slow+} brown fox.\n@@"));
+    }
+
+    #[test]
+    fn test_format_added_removed_diff_max_lines() {
+        let content = b"a\nb\nc\n".to_vec();
+        let diff = format_added_removed_diff("f.txt", content.clone(), true, 2);
+        assert!(diff.ends_with("+a\n+b\n... (1 more lines)\n"));
+        let diff = format_added_removed_diff("f.txt", content, true, 0);
+        assert!(diff.ends_with("+a\n+b\n+c\n"));
+    }
+
+    #[test]
+    fn test_escape_path() {
+        assert!(matches!(escape_path("src/日本語.rs"), Cow::Borrowed("src/日本語.rs")));
+        assert_eq!(escape_path("a\nb\tc.txt"), "a\\nb\\tc.txt");
+        assert_eq!(escape_path("\u{1b}[31mred"), "\\u{1b}[31mred");
+        assert_eq!(escape_path("evil\u{202e}txt.exe"), "evil\\u{202e}txt.exe");
+    }
+
+    #[test]
+    fn test_word_diff_applies() {
+        let word_diff = WordDiff::new(&["*.md".to_string()], 100);
+        assert!(word_diff.applies("docs/guide.md", 10_000));
+        assert!(word_diff.applies("src/main.rs", 100));
+        assert!(!word_diff.applies("src/main.rs", 101));
+        assert!(!WordDiff::default().applies("README.md", 0));
+    }
+
+    #[test]
+    fn test_format_binary_change_text_to_binary() {
+        assert_eq!(
+            format_binary_change("logo.svg", b"<svg/>", b"\xff\xfe"),
+            "diff --git a/logo.svg b/logo.svg\n(text file replaced by binary)\n\
+             logo.svg: 6 B -> 2 B (-4 B)\n"
+        );
+    }
+
+    #[test]
+    fn test_format_binary_change_binary_to_text() {
+        assert_eq!(
+            format_binary_change("data.bin", b"\xff", b"text"),
+            "diff --git a/data.bin b/data.bin\n(binary file replaced by text)\ndata.bin: 1 B -> 4 B (+3 B)\n"
+        );
+    }
+
+    #[test]
+    fn test_format_binary_change_binary_to_binary() {
+        let before = [b"\x89PNG\r\n\x1a\n".as_slice(), &[0; 12_587]].concat();
+        let after = [b"\x89PNG\r\n\x1a\n".as_slice(), &[0; 15_454]].concat();
+        assert_eq!(
+            format_binary_change("icon.png", &before, &after),
+            "diff --git a/icon.png b/icon.png\n(binary file modified)\n\
+             icon.png: 12.3 KiB -> 15.1 KiB (+2.8 KiB, PNG image)\n"
+        );
+    }
+
+    #[test]
+    fn test_mode_change() {
+        assert_eq!(mode_change(false, true).as_deref(), Some("mode change 100644 -> 100755"));
+        assert_eq!(mode_change(true, true), None);
+
+        let file = FileDiff {
+            full: "diff --git a/x b/x\n--- a/x\n".to_string(),
+            collapsed: Some("diff --git a/x b/x\nmodified (+1 -0 lines)\n".to_string()),
+        }
+        .with_mode_change(mode_change(true, false));
+        assert_eq!(file.full, "diff --git a/x b/x\nmode change 100755 -> 100644\n--- a/x\n");
+        assert_eq!(
+            file.collapsed.unwrap(),
+            "diff --git a/x b/x\nmode change 100755 -> 100644\nmodified (+1 -0 lines)\n"
+        );
+    }
+
+    #[test]
+    fn test_describe_binary() {
+        assert_eq!(
+            describe_binary("font.woff2", None, Some(b"wOF2\0\0")),
+            "font.woff2: 6 B (WOFF2 font)"
+        );
+        assert_eq!(describe_binary("a.mp4", Some(&[0; 2048]), None), "a.mp4: 2.0 KiB (MP4 video)");
+        assert_eq!(
+            describe_binary("blob", Some(b"\0\0"), Some(b"\x01\x01")),
+            "blob: 2 B -> 2 B (same size)"
+        );
+        assert_eq!(format_size(3 * 1024 * 1024), "3.0 MiB");
+    }
+
+    #[test]
+    fn test_collapse_git_diff() {
+        let matcher = build_collapse_matcher(&["*.lock".to_string()]).unwrap();
+        let kept = "diff --git a/src/main.rs b/src/main.rs\n--- a/src/main.rs\n+++ b/src/main.rs\n@@ -1 +1 @@\n-a\n+b\n";
+        let lock = "diff --git a/Cargo.lock b/Cargo.lock\n--- a/Cargo.lock\n+++ b/Cargo.lock\n@@ -1,2 +1,3 @@\n x\n+y\n+z\n";
+        let collapsed = collapse_git_diff(&format!("{kept}{lock}"), &matcher);
+        assert_eq!(
+            collapsed,
+            format!(
+                "{kept}diff --git a/Cargo.lock b/Cargo.lock\nmodified (+2 -0 lines, collapsed: matches pattern)\n"
+            )
+        );
+        assert_eq!(collapsed_files(&collapsed), ["Cargo.lock"]);
+    }
+
+    #[test]
+    fn test_format_conflicted_file() {
+        assert_eq!(
+            format_conflicted_file("src/lib.rs", 2),
+            "diff --git a/src/lib.rs b/src/lib.rs\n(unresolved 2-sided conflict, conflict markers present)\n"
+        );
+    }
+}
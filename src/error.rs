@@ -0,0 +1,94 @@
+use std::process::ExitCode;
+
+use anyhow::Error;
+use ccc_jj_lib::pipeline;
+
+/// How a run failed, each class with its own exit code so that scripts and the watch daemon can
+/// react differently to each
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CcError {
+    /// Anything not listed below
+    Other,
+    /// No jj workspace was found, or none of the name given with `--workspace`
+    NoWorkspace,
+    /// There were no changes to commit
+    NothingToCommit,
+    /// No model produced a usable message, or the call budget ran out
+    GeneratorFailed,
+    /// Another process held the working copy lock, or moved the working copy during the run
+    LockContention,
+    /// The generated message broke the lint rules with `lint.on_failure = "abort"`
+    LintFailed,
+    /// The working copy has conflicts and `--allow-conflicts` wasn't given
+    Conflicts,
+    /// The commit was recorded, but the working copy wasn't updated to it
+    WorkingCopyNotUpdated,
+}
+
+impl CcError {
+    /// Classifies an error by the first cause in its chain that has a class
+    pub fn of(error: &Error) -> Self {
+        error
+            .chain()
+            .find_map(|cause| match cause.downcast_ref::<pipeline::Error>()? {
+                pipeline::Error::WorkspaceNotFound(_) | pipeline::Error::UnknownWorkspace(_) => {
+                    Some(Self::NoWorkspace)
+                }
+                pipeline::Error::NothingToCommit(_) => Some(Self::NothingToCommit),
+                pipeline::Error::GenerationFailed | pipeline::Error::CallBudgetExhausted(_) => {
+                    Some(Self::GeneratorFailed)
+                }
+                pipeline::Error::WorkingCopyLocked(_)
+                | pipeline::Error::WorkingCopyMoved { .. } => Some(Self::LockContention),
+                pipeline::Error::Lint(_) => Some(Self::LintFailed),
+                pipeline::Error::UnresolvedConflicts(_) => Some(Self::Conflicts),
+                pipeline::Error::WorkingCopyNotUpdated { .. } => Some(Self::WorkingCopyNotUpdated),
+                _ => None,
+            })
+            .unwrap_or(Self::Other)
+    }
+
+    pub fn exit_code(self) -> ExitCode {
+        ExitCode::from(match self {
+            Self::Other => 1,
+            Self::NoWorkspace => 2,
+            Self::NothingToCommit => 3,
+            Self::GeneratorFailed => 4,
+            Self::LockContention => 5,
+            Self::LintFailed => 6,
+            Self::Conflicts => 7,
+            Self::WorkingCopyNotUpdated => 8,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use anyhow::{Context, anyhow};
+    use ccc_jj_lib::generator::CallBudgetExhausted;
+
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let error = Error::from(pipeline::Error::WorkspaceNotFound(PathBuf::from("/tmp")));
+        assert_eq!(CcError::of(&error), CcError::NoWorkspace);
+        let error = Error::from(pipeline::Error::GenerationFailed).context("Failed to commit");
+        assert_eq!(CcError::of(&error), CcError::GeneratorFailed);
+        let error = Error::from(pipeline::Error::from(CallBudgetExhausted { max: 3 }));
+        assert_eq!(CcError::of(&error), CcError::GeneratorFailed);
+        let error = Error::from(pipeline::Error::WorkingCopyNotUpdated {
+            operation: "0123456789ab".to_string(),
+            previous: "ba9876543210".to_string(),
+            source: anyhow!("disk full").into(),
+        });
+        assert_eq!(CcError::of(&error), CcError::WorkingCopyNotUpdated);
+        assert!(error.to_string().contains("jj op restore ba9876543210"));
+        let error = Error::from(pipeline::Error::NothingToCommit("No changes".to_string()));
+        assert_eq!(CcError::of(&error), CcError::NothingToCommit);
+        let error: Result<(), _> = Err(anyhow!("curl exited with 7"));
+        assert_eq!(CcError::of(&error.context("Failed").unwrap_err()), CcError::Other);
+    }
+}
@@ -0,0 +1,104 @@
+use anyhow::Result;
+use serde_json::Value;
+use tracing::{trace, warn};
+
+use crate::{
+    claude_client::{extract_json_object, strip_code_fence},
+    config::CONFIG,
+    generator::{GenerationRequest, Generator, backend_from_config, invoke},
+    text_formatter::format_markdown,
+};
+
+const JSON_SCHEMA: &str = r#"{"type":"object","properties":{"explanation":{"type":"string","description":"Plain-language explanation of the change in markdown"}},"required":["explanation"]}"#;
+
+/// Width the explanation is wrapped at
+const WIDTH: usize = 80;
+
+/// Explains a diff in plain language, e.g. to prepare a review, rather than writing a commit
+/// message for it
+pub struct ExplanationGenerator {
+    prompt_template: String,
+    backend: Box<dyn Generator + Send + Sync>,
+    language: String,
+    model: String,
+}
+
+impl ExplanationGenerator {
+    /// Creates a new explanation generator
+    ///
+    /// # Arguments
+    /// - `language` - The language to write the explanation in
+    /// - `model` - The model to use for generation
+    pub fn new(language: &str, model: &str) -> Self {
+        Self {
+            prompt_template: CONFIG.explain.prompt_template.clone(),
+            backend: backend_from_config(&CONFIG.generator),
+            language: language.to_string(),
+            model: model.to_string(),
+        }
+    }
+
+    /// Generates the explanation of the diff as markdown wrapped at 80 columns
+    ///
+    /// # Returns
+    /// `Some(explanation)` if generation succeeds, `None` if it fails, or an error if the call
+    /// budget is exhausted.
+    pub fn generate(&self, diff_content: &str) -> Result<Option<String>> {
+        let prompt = self
+            .prompt_template
+            .replace("{language}", &self.language)
+            .replace("{diff_content}", diff_content);
+        trace!(prompt_len = prompt.len(), "Prepared explanation prompt");
+
+        let request = GenerationRequest {
+            model: &self.model,
+            json_schema: JSON_SCHEMA,
+            prompt: &prompt,
+            stable_prefix: 0,
+            spinner_message: "Explaining the changes with Claude...",
+        };
+        let Some(response) = invoke(self.backend.as_ref(), &request)? else {
+            return Ok(None);
+        };
+        let explanation = parse_explanation(response.structured);
+        if explanation.is_none() {
+            warn!("Claude returned an empty explanation");
+        }
+        Ok(explanation.map(|text| format_markdown(&text, WIDTH)))
+    }
+}
+
+/// Reads the explanation from structured output, or takes the whole answer when the model ignored
+/// the schema
+fn parse_explanation(structured: Value) -> Option<String> {
+    let structured = match structured {
+        Value::String(text) => extract_json_object(&text).unwrap_or(Value::String(text)),
+        value => value,
+    };
+    let explanation = match &structured {
+        Value::String(text) => strip_code_fence(text),
+        _ => structured.get("explanation").and_then(Value::as_str).unwrap_or(""),
+    };
+    let explanation = explanation.trim();
+    (!explanation.is_empty()).then(|| explanation.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_explanation() {
+        assert_eq!(
+            parse_explanation(json!({"explanation": "Adds a command.\n"})),
+            Some("Adds a command.".to_string())
+        );
+        assert_eq!(
+            parse_explanation(Value::String("Renames a field.".to_string())),
+            Some("Renames a field.".to_string())
+        );
+        assert_eq!(parse_explanation(json!({"explanation": " "})), None);
+    }
+}
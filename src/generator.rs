@@ -0,0 +1,395 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Condvar, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde_json::Value;
+use thiserror::Error;
+use tracing::{debug, warn};
+
+use crate::{
+    audit,
+    claude_client::{ClaudeCli, CommandCli},
+    config::{Backend, CONFIG, GeneratorConfig},
+    diff::estimate_tokens,
+    http_backends::{AnthropicApi, Ollama, OpenAiCompatible},
+    metrics::{self, Stage},
+    progress::Progress,
+    stream_preview::StreamPreview,
+};
+
+/// Default for the maximum number of model invocations per run
+pub const DEFAULT_MAX_CLAUDE_CALLS: usize = 20;
+
+/// Budget shared by every `invoke` call in the process
+static BUDGET: CallBudget = CallBudget::new(DEFAULT_MAX_CLAUDE_CALLS);
+
+/// The run made as many model calls as it is allowed
+#[derive(Debug, Error)]
+#[error(
+    "Reached the limit of {max} model calls for this run; raise it with --max-claude-calls if \
+     this is expected"
+)]
+pub struct CallBudgetExhausted {
+    pub max: usize,
+}
+
+/// Counts model invocations against a limit
+struct CallBudget {
+    max: AtomicUsize,
+    used: AtomicUsize,
+}
+
+impl CallBudget {
+    const fn new(max: usize) -> Self {
+        Self {
+            max: AtomicUsize::new(max),
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// Counts a call, failing if it would exceed the limit
+    fn reserve(&self) -> std::result::Result<(), CallBudgetExhausted> {
+        let max = self.max.load(Ordering::Relaxed);
+        let used = self.used.fetch_add(1, Ordering::Relaxed);
+        if used >= max {
+            return Err(CallBudgetExhausted { max });
+        }
+        Ok(())
+    }
+}
+
+/// Sets the maximum number of model invocations allowed for this run
+pub fn set_max_claude_calls(max: usize) {
+    BUDGET.max.store(max, Ordering::Relaxed);
+}
+
+/// Starts a fresh budget, e.g. for each commit made in watch mode
+pub fn reset_claude_calls() {
+    BUDGET.used.store(0, Ordering::Relaxed);
+}
+
+/// Calls under way in the process, for `generator.rate_limit.max_in_flight`
+static IN_FLIGHT: InFlight = InFlight::new();
+
+/// Counts the calls under way, making callers wait while a limit is reached
+struct InFlight {
+    count: Mutex<usize>,
+    finished: Condvar,
+}
+
+impl InFlight {
+    const fn new() -> Self {
+        Self { count: Mutex::new(0), finished: Condvar::new() }
+    }
+
+    /// Waits until fewer than `max` calls are under way (0 for no limit) and counts one more until
+    /// the returned guard is dropped
+    fn acquire(&self, max: usize) -> InFlightGuard<'_> {
+        let mut count = self.count.lock().unwrap_or_else(|e| e.into_inner());
+        if max > 0 && *count >= max {
+            debug!(max, "Waiting for a call under way to finish");
+            count = self
+                .finished
+                .wait_while(count, |count| *count >= max)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        *count += 1;
+        InFlightGuard(self)
+    }
+}
+
+struct InFlightGuard<'a>(&'a InFlight);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        let mut count = self.0.count.lock().unwrap_or_else(|e| e.into_inner());
+        *count -= 1;
+        self.0.finished.notify_one();
+    }
+}
+
+/// When the latest call to each model was scheduled to start, for
+/// `generator.rate_limit.requests_per_minute`
+static SCHEDULED: Mutex<BTreeMap<String, Instant>> = Mutex::new(BTreeMap::new());
+
+/// Waits until a call to `model` fits within `generator.rate_limit.requests_per_minute`. Each
+/// caller takes the next free slot, so calls made in parallel are spaced out rather than started
+/// together.
+fn wait_for_rate_limit(model: &str) {
+    let per_minute = CONFIG.generator.rate_limit.requests_per_minute;
+    if per_minute == 0 {
+        return;
+    }
+    let now = Instant::now();
+    let slot = {
+        let mut scheduled = SCHEDULED.lock().unwrap_or_else(|e| e.into_inner());
+        let slot =
+            next_slot(scheduled.get(model).copied(), now, Duration::from_secs(60) / per_minute);
+        scheduled.insert(model.to_string(), slot);
+        slot
+    };
+    let delay = slot - now;
+    if !delay.is_zero() {
+        debug!(model, delay_ms = delay.as_millis(), "Waiting for the model's rate limit");
+        std::thread::sleep(delay);
+    }
+}
+
+/// The start of the call after one scheduled at `previous`, at least `interval` later
+fn next_slot(previous: Option<Instant>, now: Instant, interval: Duration) -> Instant {
+    previous.map_or(now, |previous| (previous + interval).max(now))
+}
+
+/// A single request for structured output from a model
+pub struct GenerationRequest<'a> {
+    pub model: &'a str,
+    pub json_schema: &'a str,
+    pub prompt: &'a str,
+    /// Length in bytes of the start of `prompt` that stays the same from one change to the next,
+    /// from [`stable_prefix_len`]. Backends with prompt caching cache it; 0 caches nothing.
+    pub stable_prefix: usize,
+    pub spinner_message: &'a str,
+}
+
+/// The length of the start of `prompt` before the first of the `varying` parts, such as the diff,
+/// that appears in it. 0 if none does.
+pub fn stable_prefix_len(prompt: &str, varying: &[&str]) -> usize {
+    varying
+        .iter()
+        .filter(|part| !part.is_empty())
+        .filter_map(|part| prompt.find(part))
+        .min()
+        .unwrap_or(0)
+}
+
+/// Output of a successful generation
+pub struct GeneratorResponse {
+    /// The structured output, or the free-text answer as a `Value::String`
+    pub structured: Value,
+    /// The raw output of the backend
+    pub raw_output: String,
+    /// The model that answered, which is a fallback model if the requested one failed
+    pub model: String,
+}
+
+/// A backend that can answer a prompt with output matching a JSON schema
+pub trait Generator {
+    /// Runs the request, returning `None` if the backend fails or its output can't be used
+    fn generate(&self, request: &GenerationRequest<'_>) -> Option<GeneratorResponse>;
+
+    /// Like `generate`, but passes the answer text to `on_text` piece by piece as it arrives.
+    /// Backends that can't stream answer all at once without calling it.
+    fn generate_streaming(
+        &self,
+        request: &GenerationRequest<'_>,
+        _on_text: &mut dyn FnMut(&str),
+    ) -> Option<GeneratorResponse> {
+        self.generate(request)
+    }
+}
+
+/// Creates the backend selected by `generator.backend`
+pub fn backend_from_config(config: &GeneratorConfig) -> Box<dyn Generator + Send + Sync> {
+    match config.backend {
+        Backend::ClaudeCli => Box::new(ClaudeCli {
+            command: config.command.clone(),
+            args: config.args.clone(),
+            shell: config.shell,
+        }),
+        Backend::Command => Box::new(CommandCli {
+            command: config.command.clone(),
+            args: config.args.clone(),
+            shell: config.shell,
+        }),
+        Backend::Anthropic => Box::new(AnthropicApi {
+            url: config.anthropic.url.clone(),
+            api_key_env: config.anthropic.api_key_env.clone(),
+            prompt_caching: config.anthropic.prompt_caching,
+        }),
+        Backend::Openai => Box::new(OpenAiCompatible {
+            url: config.openai.url.clone(),
+            api_key_env: config.openai.api_key_env.clone(),
+        }),
+        Backend::Ollama => Box::new(Ollama { url: config.ollama.url.clone() }),
+    }
+}
+
+/// One try of a request: the model to use and how long to wait before calling it
+#[derive(Debug, PartialEq, Eq)]
+struct Attempt<'a> {
+    model: &'a str,
+    delay: Duration,
+}
+
+/// Lists the attempts for a request: the requested model followed by the fallback models, each
+/// tried up to `retries + 1` times with exponential backoff between tries of the same model
+fn plan_attempts<'a>(
+    model: &'a str,
+    fallback_models: &'a [String],
+    retries: usize,
+    backoff: Duration,
+) -> Vec<Attempt<'a>> {
+    let mut models = vec![model];
+    for fallback in fallback_models {
+        if !models.contains(&fallback.as_str()) {
+            models.push(fallback);
+        }
+    }
+    models
+        .into_iter()
+        .flat_map(|model| {
+            (0..=retries).map(move |retry| Attempt {
+                model,
+                delay: match retry {
+                    0 => Duration::ZERO,
+                    _ => backoff.saturating_mul(1 << (retry - 1).min(16)),
+                },
+            })
+        })
+        .collect()
+}
+
+/// Runs a request against a backend while showing a spinner, or progress lines without a
+/// terminal.
+///
+/// Failed calls are retried with exponential backoff (`generator.retries`,
+/// `generator.retry_backoff_ms`), then the request moves on to the models in
+/// `generator.fallback_models`. Every call counts against the run's call budget.
+///
+/// Returns `Ok(None)` if every attempt fails or its output cannot be parsed, and an error if the
+/// run's call budget is exhausted.
+pub fn invoke(
+    backend: &dyn Generator,
+    request: &GenerationRequest<'_>,
+) -> Result<Option<GeneratorResponse>> {
+    let config = &CONFIG.generator;
+    let attempts = plan_attempts(
+        request.model,
+        &config.fallback_models,
+        config.retries,
+        Duration::from_millis(config.retry_backoff_ms),
+    );
+    for (i, attempt) in attempts.iter().enumerate() {
+        if i > 0 {
+            warn!(
+                model = %attempt.model,
+                delay_ms = attempt.delay.as_millis(),
+                "Generation failed, retrying"
+            );
+            std::thread::sleep(attempt.delay);
+        }
+        let request = GenerationRequest { model: attempt.model, ..*request };
+        if let Some(response) = invoke_once(backend, &request)? {
+            return Ok(Some(response));
+        }
+    }
+    Ok(None)
+}
+
+/// Makes a single call to the backend
+fn invoke_once(
+    backend: &dyn Generator,
+    request: &GenerationRequest<'_>,
+) -> Result<Option<GeneratorResponse>> {
+    BUDGET.reserve()?;
+    let _in_flight = IN_FLIGHT.acquire(CONFIG.generator.rate_limit.max_in_flight);
+    wait_for_rate_limit(request.model);
+
+    let progress = Progress::start(request.spinner_message);
+
+    debug!(model = %request.model, prompt_len = request.prompt.len(), "Invoking generator");
+    metrics::record_prompt(request.prompt.len(), estimate_tokens(request.prompt));
+    let timer = metrics::time(Stage::Model);
+    // Without a terminal there is no spinner to show a preview under
+    let response = match progress.spinner() {
+        Some(spinner) if CONFIG.generator.stream => {
+            let mut preview = StreamPreview::new(spinner, request.spinner_message);
+            backend.generate_streaming(request, &mut |text| preview.push(text))
+        }
+        _ => backend.generate(request),
+    };
+    drop(timer);
+    audit::record(
+        request.model,
+        request.prompt,
+        response.as_ref().map(|response| response.raw_output.as_str()),
+    );
+
+    progress.finish();
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_slot() {
+        let now = Instant::now();
+        let interval = Duration::from_secs(6);
+        assert_eq!(next_slot(None, now, interval), now);
+        assert_eq!(next_slot(Some(now), now, interval), now + interval);
+        if let Some(long_ago) = now.checked_sub(Duration::from_secs(60)) {
+            assert_eq!(next_slot(Some(long_ago), now, interval), now);
+        }
+    }
+
+    #[test]
+    fn test_in_flight_limit() {
+        let in_flight = InFlight::new();
+        let started = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            for _ in 0..6 {
+                scope.spawn(|| {
+                    let _guard = in_flight.acquire(2);
+                    let now = started.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(10));
+                    started.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        assert!(most.load(Ordering::SeqCst) <= 2);
+        assert_eq!(*in_flight.count.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_stable_prefix_len() {
+        let prompt = "Rules\n\nRecent commits:\nfix: a\n\nDiff:\n+b";
+        assert_eq!(stable_prefix_len(prompt, &["+b", "fix: a"]), prompt.find("fix: a").unwrap());
+        assert_eq!(stable_prefix_len(prompt, &["+b", ""]), prompt.find("+b").unwrap());
+        assert_eq!(stable_prefix_len(prompt, &["missing"]), 0);
+    }
+
+    #[test]
+    fn test_call_budget() {
+        let budget = CallBudget::new(2);
+        budget.reserve().unwrap();
+        budget.reserve().unwrap();
+        let err = budget.reserve().unwrap_err();
+        assert!(err.to_string().contains("limit of 2 model calls"));
+    }
+
+    #[test]
+    fn test_plan_attempts() {
+        let fallback = vec!["sonnet".to_string(), "haiku".to_string()];
+        let second = Duration::from_secs(1);
+        let plan = plan_attempts("haiku", &fallback, 2, second);
+        let expected =
+            [("haiku", 0), ("haiku", 1), ("haiku", 2), ("sonnet", 0), ("sonnet", 1), ("sonnet", 2)]
+                .map(|(model, secs)| Attempt { model, delay: second * secs });
+        assert_eq!(plan, expected);
+
+        assert_eq!(
+            plan_attempts("haiku", &[], 0, second),
+            vec![Attempt { model: "haiku", delay: Duration::ZERO }]
+        );
+    }
+}
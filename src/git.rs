@@ -0,0 +1,229 @@
+use std::{
+    fs::read_to_string,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context, Result, bail};
+use tracing::debug;
+
+use crate::{
+    config::CONFIG,
+    conventions::find_style_guide,
+    diff::{FileChangeSummary, build_collapse_matcher, collapse_git_diff},
+    gitattributes::collapse_patterns,
+    project::detect_stack,
+    prompt_template::PromptContext,
+};
+
+// Everything here runs the `git` command instead of using a library such as gix, so that commits
+// get the user's hooks, signing, and other git configuration exactly as `git commit` would
+
+/// Which changes a commit in a plain git repository takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// What is staged in the index
+    Staged,
+    /// Every change to tracked files, as `git commit -a` does, when nothing is staged
+    Tracked,
+}
+
+/// The changes a commit would take
+pub struct PendingChanges {
+    pub scope: Scope,
+    pub diff: String,
+    pub file_changes: FileChangeSummary,
+}
+
+/// The top-level directory of the git repository containing `start`, if any
+pub fn find_root(start: &Path) -> Option<PathBuf> {
+    let root = run_git(start, &["rev-parse", "--show-toplevel"], None).ok()?;
+    Some(PathBuf::from(root.trim()))
+}
+
+/// Collects the staged changes, or every change to tracked files if nothing is staged. Files
+/// matching `diff.collapse_patterns` or marked as generated or vendored in `.gitattributes` are
+/// summarized.
+pub fn pending_changes(root: &Path) -> Result<PendingChanges> {
+    let staged = run_git(root, &["diff", "--cached", "--name-status", "-M"], None)?;
+    let scope =
+        if staged.trim().is_empty() && has_head(root) { Scope::Tracked } else { Scope::Staged };
+    collect_changes(root, scope, Some(staged))
+}
+
+/// Collects the staged changes, which is what a commit being made takes by the time git runs its
+/// hooks (`git commit -a` stages the tracked files first)
+pub fn staged_changes(root: &Path) -> Result<PendingChanges> {
+    collect_changes(root, Scope::Staged, None)
+}
+
+/// The diff and changed files of `scope`. `name_status` is the `--name-status` output for the
+/// scope if it was already read.
+fn collect_changes(
+    root: &Path,
+    scope: Scope,
+    name_status: Option<String>,
+) -> Result<PendingChanges> {
+    debug!(?scope, "Collecting git changes");
+    let base = match scope {
+        Scope::Staged => "--cached",
+        Scope::Tracked => "HEAD",
+    };
+    let name_status = match name_status {
+        Some(name_status) => name_status,
+        None => run_git(root, &["diff", base, "--name-status", "-M"], None)?,
+    };
+    let diff = run_git(root, &["diff", "--no-color", "--no-ext-diff", "-M", base], None)?;
+    let gitattributes = read_to_string(root.join(".gitattributes")).ok();
+    let diff = match build_collapse_matcher(&collapse_patterns(gitattributes.as_deref())) {
+        Some(matcher) => collapse_git_diff(&diff, &matcher),
+        None => diff,
+    };
+    Ok(PendingChanges {
+        scope,
+        diff,
+        file_changes: parse_name_status(&name_status),
+    })
+}
+
+/// Commits the changes in `scope` with the message and returns the new commit's hash
+pub fn commit(root: &Path, scope: Scope, message: &str) -> Result<String> {
+    let mut args = vec!["commit", "--quiet", "--file", "-"];
+    if scope == Scope::Tracked {
+        args.push("--all");
+    }
+    run_git(root, &args, Some(message))?;
+    Ok(run_git(root, &["rev-parse", "HEAD"], None)?.trim().to_string())
+}
+
+/// `user.name` and `user.email` from the git config, empty when unset
+pub fn user(root: &Path) -> (String, String) {
+    let get = |key| run_git(root, &["config", "--get", key], None).unwrap_or_default();
+    (get("user.name").trim().to_string(), get("user.email").trim().to_string())
+}
+
+/// The checked-out branch, or `None` on a detached HEAD
+pub fn current_branch(root: &Path) -> Option<String> {
+    let branch = run_git(root, &["symbolic-ref", "--quiet", "--short", "HEAD"], None).ok()?;
+    Some(branch.trim().to_string())
+}
+
+/// The messages of up to `count` of the most recent commits on HEAD, newest first
+pub fn recent_messages(root: &Path, count: usize) -> Vec<String> {
+    if count == 0 {
+        return Vec::new();
+    }
+    let count = format!("--max-count={count}");
+    let log = run_git(root, &["log", &count, "--format=%B%x00"], None).unwrap_or_default();
+    log.split('\0')
+        .map(str::trim)
+        .filter(|message| !message.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// The URL of the `origin` remote, if there is one
+pub fn remote_url(root: &Path) -> Option<String> {
+    let url = run_git(root, &["remote", "get-url", "origin"], None).ok()?;
+    Some(url.trim().to_string()).filter(|url| !url.is_empty())
+}
+
+/// The prompt context of the repository at `root`
+pub fn prompt_context(root: &Path) -> PromptContext {
+    let stack = detect_stack(root);
+    PromptContext {
+        branch: current_branch(root).unwrap_or_default(),
+        recent_commits: recent_messages(root, CONFIG.prompt.recent_commits),
+        repo_name: root
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        style_guide: find_style_guide(root),
+        project_type: stack.project_type,
+        frameworks: stack.frameworks,
+        remote_url: remote_url(root).unwrap_or_default(),
+        root: root.to_path_buf(),
+        issue: String::new(),
+    }
+}
+
+/// The directory git runs hooks from, following `core.hooksPath`
+pub fn hooks_dir(root: &Path) -> Result<PathBuf> {
+    let path = run_git(root, &["rev-parse", "--git-path", "hooks"], None)?;
+    Ok(root.join(path.trim()))
+}
+
+fn has_head(root: &Path) -> bool {
+    run_git(root, &["rev-parse", "--verify", "--quiet", "HEAD"], None).is_ok()
+}
+
+/// Runs git in `dir`, feeding it `input` on stdin, and returns its stdout
+fn run_git(dir: &Path, args: &[&str], input: Option<&str>) -> Result<String> {
+    debug!(?args, "Running git");
+    let mut child = Command::new("git")
+        .current_dir(dir)
+        .args(args)
+        .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run git; is it installed and on PATH?")?;
+    if let Some(input) = input
+        && let Some(mut stdin) = child.stdin.take()
+    {
+        stdin.write_all(input.as_bytes()).context("Failed to write to git")?;
+    }
+    let output = child.wait_with_output().context("Failed to wait for git")?;
+    if !output.status.success() {
+        bail!(
+            "`git {}` failed with {}: {}",
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Reads `git diff --name-status` output. Renames count as a deletion and an addition, as in a
+/// jj diff.
+fn parse_name_status(output: &str) -> FileChangeSummary {
+    let mut summary = FileChangeSummary::default();
+    for line in output.lines() {
+        let mut fields = line.split('\t');
+        let (Some(status), Some(path)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        let path = path.to_string();
+        match status.chars().next() {
+            Some('A') => summary.added.push(path),
+            Some('D') => summary.deleted.push(path),
+            Some('M' | 'T') => summary.modified.push(path),
+            Some('U') => summary.conflicted.push(path),
+            Some('R') => {
+                summary.deleted.push(path);
+                summary.added.extend(fields.next().map(String::from));
+            }
+            Some('C') => summary.added.extend(fields.next().map(String::from)),
+            _ => {}
+        }
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_name_status() {
+        let summary = parse_name_status(
+            "M\tsrc/main.rs\nA\tsrc/git.rs\nR087\told.rs\tnew.rs\nU\tCargo.toml\n",
+        );
+        assert_eq!(summary.modified, ["src/main.rs"]);
+        assert_eq!(summary.added, ["src/git.rs", "new.rs"]);
+        assert_eq!(summary.deleted, ["old.rs"]);
+        assert_eq!(summary.conflicted, ["Cargo.toml"]);
+    }
+}
@@ -0,0 +1,73 @@
+use tracing::debug;
+
+use crate::config::CONFIG;
+
+/// The attributes GitHub's linguist uses to mark files that aren't written by hand
+const LINGUIST_ATTRIBUTES: &[&str] = &["linguist-generated", "linguist-vendored"];
+
+/// `diff.collapse_patterns` together with the paths the root `.gitattributes` marks as
+/// `linguist-generated` or `linguist-vendored`, unless `diff.linguist_attributes` is off
+pub fn collapse_patterns(gitattributes: Option<&str>) -> Vec<String> {
+    let mut patterns = CONFIG.diff.collapse_patterns.clone();
+    if let Some(text) = gitattributes.filter(|_| CONFIG.diff.linguist_attributes) {
+        let marked = linguist_patterns(text);
+        debug!(patterns = ?marked, "Collapsing files marked in .gitattributes");
+        patterns.extend(marked);
+    }
+    patterns
+}
+
+/// Globs for the patterns of a `.gitattributes` file that set `linguist-generated` or
+/// `linguist-vendored`, e.g. `*.min.js` becomes `**/*.min.js` and `/dist/**` becomes `dist/**`.
+///
+/// As in git, a pattern without a slash matches at any depth and one with a slash is relative to
+/// the repository root. Lines that unset an attribute (`-linguist-generated`,
+/// `linguist-vendored=false`) are ignored, as are macros and quoted patterns.
+pub fn linguist_patterns(gitattributes: &str) -> Vec<String> {
+    gitattributes
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let pattern = fields.next()?;
+            if pattern.starts_with(['#', '[', '"']) {
+                return None;
+            }
+            fields.any(marks_linguist_file).then(|| to_glob(pattern))
+        })
+        .collect()
+}
+
+/// Whether an attribute of a `.gitattributes` line sets one of [`LINGUIST_ATTRIBUTES`]
+fn marks_linguist_file(attribute: &str) -> bool {
+    let (name, value) = attribute.split_once('=').unwrap_or((attribute, "true"));
+    LINGUIST_ATTRIBUTES.contains(&name) && value != "false"
+}
+
+fn to_glob(pattern: &str) -> String {
+    match pattern.strip_prefix('/') {
+        Some(anchored) => anchored.to_string(),
+        None if pattern.contains('/') => pattern.to_string(),
+        None => format!("**/{pattern}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_linguist_patterns() {
+        let gitattributes = "\
+# Build output
+*.min.js linguist-generated
+/dist/** linguist-generated=true -diff
+third_party/** linguist-vendored
+docs/** linguist-documentation
+vendor/ours/** -linguist-vendored
+legacy/** linguist-vendored=false
+[attr]generated linguist-generated
+*.rs text eol=lf
+";
+        assert_eq!(linguist_patterns(gitattributes), ["**/*.min.js", "dist/**", "third_party/**"]);
+    }
+}
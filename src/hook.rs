@@ -0,0 +1,114 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use tracing::debug;
+
+/// Line that marks a hook as ours, so that reinstalling replaces it without `--force`
+const MARKER: &str = "# Installed by ccc-jj: fills in empty commit messages";
+
+/// Values of the hook's second argument for commits whose message git already has: `-m`/`-F`,
+/// merges, squashes, and `-c`/`-C`/`--amend`
+const SKIPPED_SOURCES: &[&str] = &["message", "merge", "squash", "commit"];
+
+/// Line above which `git commit --verbose` appends the diff; everything below it is dropped from
+/// the message
+const SCISSORS: &str = "# ------------------------ >8 ------------------------";
+
+/// Writes a `prepare-commit-msg` hook into `hooks_dir` that runs `exe hook run`. A hook that
+/// ccc-jj didn't install is only replaced with `force`.
+pub fn install(hooks_dir: &Path, exe: &Path, force: bool) -> Result<PathBuf> {
+    let path = hooks_dir.join("prepare-commit-msg");
+    if let Ok(existing) = fs::read_to_string(&path)
+        && !existing.contains(MARKER)
+        && !force
+    {
+        bail!("'{}' already exists; pass --force to replace it", path.display());
+    }
+    fs::create_dir_all(hooks_dir)
+        .with_context(|| format!("Failed to create '{}'", hooks_dir.display()))?;
+    fs::write(&path, script(exe))
+        .with_context(|| format!("Failed to write '{}'", path.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("Failed to make '{}' executable", path.display()))?;
+    }
+    debug!(path = %path.display(), "Installed hook");
+    Ok(path)
+}
+
+/// Whether the hook should write a message: git has none for this commit and the file holds
+/// nothing but comments above the scissors line
+pub fn should_fill(content: &str, source: Option<&str>) -> bool {
+    let message = content.split(SCISSORS).next().unwrap_or_default();
+    !source.is_some_and(|source| SKIPPED_SOURCES.contains(&source))
+        && strip_comment_lines(message).is_empty()
+}
+
+/// The message file with `message` put above git's comments, which stay as they were
+pub fn fill(content: &str, message: &str) -> String {
+    format!("{}\n{content}", message.trim_end())
+}
+
+/// Drops git-style `#` comment lines and surrounding whitespace from a message
+pub fn strip_comment_lines(message: &str) -> String {
+    message
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+fn script(exe: &Path) -> String {
+    // Single-quoted for the shell; a `'` inside is closed, escaped, and reopened
+    let exe = exe.to_string_lossy().replace('\'', r"'\''");
+    format!("#!/bin/sh\n{MARKER}\nexec '{exe}' hook run \"$@\"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_fill() {
+        let template = "\n# Please enter the commit message for your changes.\n#\n";
+        assert!(should_fill(template, None));
+        assert!(should_fill(template, Some("template")));
+        assert!(!should_fill(template, Some("message")));
+        assert!(!should_fill(template, Some("merge")));
+        assert!(!should_fill("fix: typo\n# Please enter\n", None));
+
+        let verbose = format!("\n# Please enter\n{SCISSORS}\ndiff --git a/a b/a\n+added\n");
+        assert!(should_fill(&verbose, None));
+    }
+
+    #[test]
+    fn test_fill() {
+        let template = "\n# Please enter the commit message for your changes.\n";
+        assert_eq!(
+            fill(template, "feat: add x\n\nBody.\n"),
+            "feat: add x\n\nBody.\n\n# Please enter the commit message for your changes.\n"
+        );
+    }
+
+    #[test]
+    fn test_install() {
+        let dir = std::env::temp_dir().join(format!("ccc-jj-hook-test-{}", std::process::id()));
+        let exe = Path::new("/opt/it's/jc");
+        let path = install(&dir, exe, false).unwrap();
+        let script = fs::read_to_string(&path).unwrap();
+        assert!(script.contains(r"exec '/opt/it'\''s/jc' hook run"));
+        // Our own hook is replaced, anyone else's only with force
+        install(&dir, exe, false).unwrap();
+        fs::write(&path, "#!/bin/sh\nexit 0\n").unwrap();
+        assert!(install(&dir, exe, false).is_err());
+        install(&dir, exe, true).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,410 @@
+use std::{
+    env::var,
+    process::{Command, Stdio},
+};
+
+use serde_json::{Value, from_str, json};
+use tracing::{debug, trace, warn};
+
+use crate::{
+    claude_client::{extract_json_object, stream_lines},
+    generator::{GenerationRequest, Generator, GeneratorResponse},
+};
+
+/// Upper bound on the length of a generated answer
+const MAX_TOKENS: u32 = 1024;
+
+/// Generates with the Anthropic Messages API
+pub struct AnthropicApi {
+    pub url: String,
+    pub api_key_env: String,
+    /// Mark the stable start of the prompt for prompt caching
+    pub prompt_caching: bool,
+}
+
+impl AnthropicApi {
+    fn body(&self, request: &GenerationRequest<'_>, stream: bool) -> Value {
+        json!({
+            "model": request.model,
+            "max_tokens": MAX_TOKENS,
+            "stream": stream,
+            "messages": [{ "role": "user", "content": self.content(request) }],
+        })
+    }
+
+    /// The prompt as one text block, or split after its stable prefix into a cached block and the
+    /// part that changes
+    fn content(&self, request: &GenerationRequest<'_>) -> Value {
+        let prompt = schema_prompt(request);
+        let split = request.stable_prefix;
+        if !self.prompt_caching || split == 0 || !prompt.is_char_boundary(split) {
+            return Value::String(prompt);
+        }
+        let (stable, varying) = prompt.split_at(split);
+        json!([
+            { "type": "text", "text": stable, "cache_control": { "type": "ephemeral" } },
+            { "type": "text", "text": varying },
+        ])
+    }
+
+    fn headers(&self) -> [Header<'_>; 2] {
+        [
+            Header::Env {
+                name: "x-api-key",
+                prefix: "",
+                env: &self.api_key_env,
+            },
+            Header::Fixed("anthropic-version: 2023-06-01"),
+        ]
+    }
+}
+
+impl Generator for AnthropicApi {
+    fn generate(&self, request: &GenerationRequest<'_>) -> Option<GeneratorResponse> {
+        let raw_output = post_json(&self.url, &self.headers(), &self.body(request, false), |_| {})?;
+        let json = from_str::<Value>(&raw_output).ok();
+        if let Some(usage) = json.as_ref().and_then(|json| json.get("usage")) {
+            debug!(
+                cache_read = ?usage.get("cache_read_input_tokens"),
+                cache_write = ?usage.get("cache_creation_input_tokens"),
+                "Prompt cache usage"
+            );
+        }
+        let text = json.and_then(|json| {
+            json.get("content")?
+                .as_array()?
+                .iter()
+                .find_map(|block| block.get("text")?.as_str().map(str::to_string))
+        });
+        into_response(text, raw_output, request.model)
+    }
+
+    fn generate_streaming(
+        &self,
+        request: &GenerationRequest<'_>,
+        on_text: &mut dyn FnMut(&str),
+    ) -> Option<GeneratorResponse> {
+        // Text arrives in `content_block_delta` events
+        let delta = |line: &str| sse_data(line)?.pointer("/delta/text")?.as_str().map(String::from);
+        stream_answer(
+            &self.url,
+            &self.headers(),
+            &self.body(request, true),
+            request.model,
+            delta,
+            on_text,
+        )
+    }
+}
+
+/// Generates with an OpenAI-compatible chat completions endpoint
+pub struct OpenAiCompatible {
+    pub url: String,
+    /// Environment variable holding the API key. Local servers often need none.
+    pub api_key_env: Option<String>,
+}
+
+impl OpenAiCompatible {
+    fn body(request: &GenerationRequest<'_>, stream: bool) -> Value {
+        json!({
+            "model": request.model,
+            "max_tokens": MAX_TOKENS,
+            "stream": stream,
+            "messages": [{ "role": "user", "content": schema_prompt(request) }],
+        })
+    }
+
+    fn headers(&self) -> Vec<Header<'_>> {
+        self.api_key_env
+            .iter()
+            .map(|env| Header::Env { name: "authorization", prefix: "Bearer ", env })
+            .collect()
+    }
+}
+
+impl Generator for OpenAiCompatible {
+    fn generate(&self, request: &GenerationRequest<'_>) -> Option<GeneratorResponse> {
+        let raw_output =
+            post_json(&self.url, &self.headers(), &Self::body(request, false), |_| {})?;
+        let text = from_str::<Value>(&raw_output).ok().and_then(|json| {
+            json.pointer("/choices/0/message/content")?
+                .as_str()
+                .map(str::to_string)
+        });
+        into_response(text, raw_output, request.model)
+    }
+
+    fn generate_streaming(
+        &self,
+        request: &GenerationRequest<'_>,
+        on_text: &mut dyn FnMut(&str),
+    ) -> Option<GeneratorResponse> {
+        let delta = |line: &str| {
+            sse_data(line)?
+                .pointer("/choices/0/delta/content")?
+                .as_str()
+                .map(String::from)
+        };
+        stream_answer(
+            &self.url,
+            &self.headers(),
+            &Self::body(request, true),
+            request.model,
+            delta,
+            on_text,
+        )
+    }
+}
+
+/// Generates with a local Ollama server, which can constrain output to the JSON schema itself
+pub struct Ollama {
+    pub url: String,
+}
+
+impl Ollama {
+    fn body(request: &GenerationRequest<'_>, stream: bool) -> Value {
+        let schema = from_str::<Value>(request.json_schema).unwrap_or(Value::Null);
+        json!({
+            "model": request.model,
+            "stream": stream,
+            "format": schema,
+            "messages": [{ "role": "user", "content": schema_prompt(request) }],
+        })
+    }
+}
+
+impl Generator for Ollama {
+    fn generate(&self, request: &GenerationRequest<'_>) -> Option<GeneratorResponse> {
+        let raw_output = post_json(&self.url, &[], &Self::body(request, false), |_| {})?;
+        let text = from_str::<Value>(&raw_output)
+            .ok()
+            .and_then(|json| json.pointer("/message/content")?.as_str().map(str::to_string));
+        into_response(text, raw_output, request.model)
+    }
+
+    fn generate_streaming(
+        &self,
+        request: &GenerationRequest<'_>,
+        on_text: &mut dyn FnMut(&str),
+    ) -> Option<GeneratorResponse> {
+        // Ollama streams one JSON object per line rather than server-sent events
+        let delta = |line: &str| {
+            from_str::<Value>(line)
+                .ok()?
+                .pointer("/message/content")?
+                .as_str()
+                .map(String::from)
+        };
+        stream_answer(&self.url, &[], &Self::body(request, true), request.model, delta, on_text)
+    }
+}
+
+/// A request header
+pub(crate) enum Header<'a> {
+    Fixed(&'a str),
+    /// A header whose value comes from an environment variable, expanded by curl itself so the
+    /// secret never appears on the command line
+    Env {
+        name: &'a str,
+        prefix: &'a str,
+        env: &'a str,
+    },
+}
+
+/// Appends the JSON schema to the prompt for backends without native schema support
+fn schema_prompt(request: &GenerationRequest<'_>) -> String {
+    format!(
+        "{}\n\nRespond with only a JSON object matching this JSON schema, without any other text:\n{}",
+        request.prompt, request.json_schema
+    )
+}
+
+/// Uses the answer's embedded JSON object if it has one, and the free text otherwise
+fn into_response(
+    text: Option<String>,
+    raw_output: String,
+    model: &str,
+) -> Option<GeneratorResponse> {
+    let Some(text) = text.filter(|text| !text.trim().is_empty()) else {
+        warn!("Generator response has no text content");
+        return None;
+    };
+    let structured = extract_json_object(&text).unwrap_or(Value::String(text.trim().to_string()));
+    Some(GeneratorResponse { structured, raw_output, model: model.to_string() })
+}
+
+/// The JSON payload of a server-sent event line
+fn sse_data(line: &str) -> Option<Value> {
+    from_str(line.strip_prefix("data:")?.trim()).ok()
+}
+
+/// POSTs a streaming request and puts the answer together from the pieces `delta` finds in the
+/// lines of the response, passing each to `on_text` as it arrives
+fn stream_answer(
+    url: &str,
+    headers: &[Header<'_>],
+    body: &Value,
+    model: &str,
+    delta: impl Fn(&str) -> Option<String>,
+    on_text: &mut dyn FnMut(&str),
+) -> Option<GeneratorResponse> {
+    let mut text = String::new();
+    let raw_output = post_json(url, headers, body, |line| {
+        if let Some(piece) = delta(line) {
+            on_text(&piece);
+            text.push_str(&piece);
+        }
+    })?;
+    into_response(Some(text), raw_output, model)
+}
+
+/// Builds the curl arguments for posting JSON from stdin
+fn curl_args(url: &str, headers: &[Header<'_>]) -> Vec<String> {
+    let mut args: Vec<String> = ["-sS", "-N", "--fail-with-body", "-X", "POST"]
+        .into_iter()
+        .map(String::from)
+        .collect();
+    args.extend(["-H".to_string(), "content-type: application/json".to_string()]);
+    args.extend(header_args(headers));
+    // `--url` so that a value starting with `-` can't be read as another option
+    args.extend(["--data-binary", "@-", "--url", url].map(String::from));
+    args
+}
+
+/// The curl arguments that send the headers
+pub(crate) fn header_args(headers: &[Header<'_>]) -> Vec<String> {
+    let mut args = Vec::new();
+    for header in headers {
+        match header {
+            Header::Fixed(header) => args.extend(["-H".to_string(), header.to_string()]),
+            Header::Env { name, prefix, env } => args.extend([
+                "--variable".to_string(),
+                format!("%{env}"),
+                "--expand-header".to_string(),
+                format!("{name}: {prefix}{{{{{env}}}}}"),
+            ]),
+        }
+    }
+    args
+}
+
+/// POSTs the body with curl, passing each line of the response to `on_line` as it arrives, and
+/// returns the whole response body, or `None` on failure
+fn post_json(
+    url: &str,
+    headers: &[Header<'_>],
+    body: &Value,
+    on_line: impl FnMut(&str),
+) -> Option<String> {
+    for header in headers {
+        if let Header::Env { env, .. } = header
+            && var(env).is_err()
+        {
+            warn!(env = %env, "API key environment variable is not set");
+            return None;
+        }
+    }
+
+    debug!(url = %url, "Posting generation request with curl");
+    let result = Command::new("curl")
+        .args(curl_args(url, headers))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .and_then(|child| stream_lines(child, body.to_string().as_bytes(), on_line));
+
+    match result {
+        Ok(output) if output.status.success() => {
+            let raw_output = String::from_utf8_lossy(&output.stdout).into_owned();
+            trace!(raw_output = %raw_output, "Generator raw output");
+            Some(raw_output)
+        }
+        Ok(output) => {
+            warn!(
+                status = %output.status,
+                stdout = %String::from_utf8_lossy(&output.stdout),
+                stderr = %String::from_utf8_lossy(&output.stderr),
+                "Generator request failed"
+            );
+            None
+        }
+        Err(e) => {
+            warn!(error = %e, "Failed to execute curl");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_curl_args_expand_secrets_from_env() {
+        let headers = [
+            Header::Env {
+                name: "authorization",
+                prefix: "Bearer ",
+                env: "OPENAI_API_KEY",
+            },
+            Header::Fixed("x-extra: 1"),
+        ];
+        let args = curl_args("http://localhost/v1", &headers);
+        assert!(args.windows(2).any(|w| w == ["--variable", "%OPENAI_API_KEY"]));
+        assert!(
+            args.windows(2)
+                .any(|w| w == ["--expand-header", "authorization: Bearer {{OPENAI_API_KEY}}"])
+        );
+        assert!(args.windows(2).any(|w| w == ["-H", "x-extra: 1"]));
+        assert!(args.ends_with(&["--url".to_string(), "http://localhost/v1".to_string()]));
+    }
+
+    #[test]
+    fn test_sse_data() {
+        let line = r#"data: {"type":"content_block_delta","delta":{"text":"feat"}}"#;
+        assert_eq!(sse_data(line).unwrap()["delta"]["text"], "feat");
+        assert!(sse_data("event: content_block_delta").is_none());
+        assert!(sse_data("data: [DONE]").is_none());
+    }
+
+    #[test]
+    fn test_anthropic_content_caches_stable_prefix() {
+        let backend = AnthropicApi {
+            url: String::new(),
+            api_key_env: String::new(),
+            prompt_caching: true,
+        };
+        let request = GenerationRequest {
+            model: "haiku",
+            json_schema: "{}",
+            prompt: "Instructions\n\ndiff",
+            stable_prefix: "Instructions\n\n".len(),
+            spinner_message: "",
+        };
+        let content = backend.content(&request);
+        assert_eq!(content[0]["text"], "Instructions\n\n");
+        assert_eq!(content[0]["cache_control"]["type"], "ephemeral");
+        assert!(content[1]["text"].as_str().unwrap().starts_with("diff\n\nRespond"));
+
+        let request = GenerationRequest { stable_prefix: 0, ..request };
+        assert!(backend.content(&request).is_string());
+    }
+
+    #[test]
+    fn test_into_response_extracts_json() {
+        let text = Some("```json\n{\"title\": \"add x\"}\n```".to_string());
+        let response = into_response(text, "raw".to_string(), "haiku").unwrap();
+        assert_eq!(response.structured["title"], "add x");
+        assert_eq!(response.raw_output, "raw");
+    }
+
+    #[test]
+    fn test_into_response_keeps_free_text() {
+        let response =
+            into_response(Some(" feat: add x \n".to_string()), String::new(), "haiku").unwrap();
+        assert_eq!(response.structured, Value::String("feat: add x".to_string()));
+        assert!(into_response(Some("  ".to_string()), String::new(), "haiku").is_none());
+        assert!(into_response(None, String::new(), "haiku").is_none());
+    }
+}
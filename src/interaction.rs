@@ -0,0 +1,121 @@
+use std::{
+    env::{temp_dir, var},
+    fs::{read_to_string, remove_file, write},
+    process::Command,
+};
+
+use anyhow::{Context, Result, bail};
+use ccc_jj_lib::hook::strip_comment_lines;
+use colored::Colorize;
+use console::Term;
+use tracing::debug;
+
+/// Help text appended to the message opened in the editor
+const EDIT_HELP: &str = "\n# Edit the commit message above. Lines starting with '#' are ignored,\n# and an empty message aborts the commit.\n";
+
+/// What to do with a generated message
+#[derive(Debug, PartialEq, Eq)]
+pub enum Choice {
+    Accept,
+    Edit,
+    Regenerate,
+    Abort,
+}
+
+impl Choice {
+    fn from_key(key: char) -> Option<Self> {
+        match key.to_ascii_lowercase() {
+            'a' | 'y' | '\n' | '\r' => Some(Self::Accept),
+            'e' => Some(Self::Edit),
+            'r' => Some(Self::Regenerate),
+            'q' | 'n' | '\u{1b}' => Some(Self::Abort),
+            _ => None,
+        }
+    }
+}
+
+/// Asks whether to accept, edit, regenerate, or abort, re-prompting on unknown keys
+pub fn prompt_choice() -> Result<Choice> {
+    let term = Term::stderr();
+    loop {
+        term.write_str(&format!(
+            "{} {}ccept / {}dit / {}egenerate / {}uit: ",
+            "?".yellow(),
+            "[a]".bold(),
+            "[e]".bold(),
+            "[r]".bold(),
+            "[q]".bold(),
+        ))?;
+        let key = term.read_char().context("Failed to read choice")?;
+        term.write_line("")?;
+        if let Some(choice) = Choice::from_key(key) {
+            debug!(?choice, "User choice");
+            return Ok(choice);
+        }
+    }
+}
+
+/// Asks for optional feedback to guide regeneration. Returns `None` if left empty.
+pub fn read_feedback() -> Result<Option<String>> {
+    let term = Term::stderr();
+    term.write_str(&format!("{} Feedback for Claude (optional): ", "?".yellow()))?;
+    let feedback = term.read_line().context("Failed to read feedback")?;
+    let feedback = feedback.trim();
+    Ok((!feedback.is_empty()).then(|| feedback.to_string()))
+}
+
+/// Opens the message in `$VISUAL` or `$EDITOR` (falling back to `vi`) and returns the edited text
+/// with comment lines removed
+pub fn edit_message(message: &str) -> Result<String> {
+    let editor = var("VISUAL")
+        .or_else(|_| var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let path = temp_dir().join(format!("ccc-jj-message-{}.txt", std::process::id()));
+    write(&path, format!("{message}{EDIT_HELP}"))
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+
+    debug!(editor = %editor, path = %path.display(), "Opening editor");
+    // The editor may include arguments (e.g. `code --wait`), so let the shell split it
+    let status = if cfg!(windows) {
+        Command::new("cmd").arg("/C").arg(&editor).arg(&path).status()
+    } else {
+        Command::new("sh")
+            .arg("-c")
+            .arg(format!("{editor} \"$1\""))
+            .arg("sh")
+            .arg(&path)
+            .status()
+    };
+
+    let edited = read_to_string(&path);
+    let _ = remove_file(&path);
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => bail!("Editor `{editor}` exited with {status}"),
+        Err(e) => bail!("Failed to run editor `{editor}`: {e}"),
+    }
+    let edited = edited.context("Failed to read edited message")?;
+    Ok(strip_comment_lines(&edited))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choice_from_key() {
+        assert_eq!(Choice::from_key('a'), Some(Choice::Accept));
+        assert_eq!(Choice::from_key('\n'), Some(Choice::Accept));
+        assert_eq!(Choice::from_key('E'), Some(Choice::Edit));
+        assert_eq!(Choice::from_key('r'), Some(Choice::Regenerate));
+        assert_eq!(Choice::from_key('q'), Some(Choice::Abort));
+        assert_eq!(Choice::from_key('x'), None);
+    }
+
+    #[test]
+    fn test_strip_comment_lines() {
+        let message = format!("feat: add x\n\nBody.\n{EDIT_HELP}");
+        assert_eq!(strip_comment_lines(&message), "feat: add x\n\nBody.");
+        assert_eq!(strip_comment_lines(EDIT_HELP), "");
+    }
+}
@@ -0,0 +1,258 @@
+use std::{
+    collections::BTreeMap,
+    env::var_os,
+    process::Command,
+    sync::{LazyLock, Mutex},
+};
+
+use regex::Regex;
+use serde_json::{Value, from_slice};
+use tracing::{debug, warn};
+
+use crate::{
+    config::CONFIG,
+    http_backends::{Header, header_args},
+};
+
+/// Seconds to wait for the issue tracker before going on without the issue
+const TIMEOUT_SECS: &str = "10";
+
+static NAME_PATTERN: LazyLock<Option<Regex>> = LazyLock::new(|| {
+    match Regex::new(&CONFIG.ticket.issue_pattern) {
+        Ok(re) => Some(re),
+        Err(e) => {
+            warn!(pattern = %CONFIG.ticket.issue_pattern, error = %e, "Invalid issue pattern, ignoring");
+            None
+        }
+    }
+});
+
+/// A closing keyword or `Refs` followed by an issue number, e.g. `Fixes #123`
+static REFERENCE_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\b(?:fix(?:e[sd])?|close[sd]?|resolve[sd]?|refs?)\s+#(\d+)\b")
+        .expect("Failed to compile issue reference regex")
+});
+
+/// Issues already looked up in this run, by remote URL and number
+static FETCHED: Mutex<BTreeMap<(String, u64), Option<String>>> = Mutex::new(BTreeMap::new());
+
+/// Where a remote's issues live
+#[derive(Debug, PartialEq, Eq)]
+enum Tracker {
+    GitHub {
+        /// `owner/repo`
+        repo: String,
+    },
+    GitLab {
+        host: String,
+        /// `group/project`, possibly with subgroups
+        project: String,
+    },
+}
+
+impl Tracker {
+    /// Recognizes GitHub and GitLab remotes in HTTPS, SSH, and scp-like (`git@host:path`) form
+    fn from_remote(url: &str) -> Option<Self> {
+        let url = url.trim().trim_end_matches('/');
+        let url = url.strip_suffix(".git").unwrap_or(url);
+        let (host, path) = match url.split_once("://") {
+            Some((_, rest)) => {
+                let (authority, path) = rest.split_once('/')?;
+                let host = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+                (host.split(':').next()?, path)
+            }
+            None => {
+                let (authority, path) = url.split_once(':')?;
+                (authority.rsplit_once('@').map_or(authority, |(_, host)| host), path)
+            }
+        };
+        let path = path.trim_matches('/').to_string();
+        if !path.contains('/') {
+            return None;
+        }
+        if host == "github.com" {
+            Some(Self::GitHub { repo: path })
+        } else if host == CONFIG.ticket.gitlab_host
+            || host.split('.').any(|label| label == "gitlab")
+        {
+            Some(Self::GitLab { host: host.to_string(), project: path })
+        } else {
+            None
+        }
+    }
+
+    fn api_url(&self, number: u64) -> String {
+        match self {
+            Self::GitHub { repo } => format!("https://api.github.com/repos/{repo}/issues/{number}"),
+            Self::GitLab { host, project } => format!(
+                "https://{host}/api/v4/projects/{}/issues/{number}",
+                project.replace('/', "%2F")
+            ),
+        }
+    }
+
+    /// The token header, if its environment variable is set. Public issues can be read without.
+    fn auth_header(&self) -> Option<Header<'static>> {
+        let (name, prefix, env) = self.token_header()?;
+        var_os(env)
+            .is_some_and(|value| !value.is_empty())
+            .then_some(Header::Env { name, prefix, env })
+    }
+
+    /// The name, value prefix, and environment variable of the token header. GitLab's is only for
+    /// `ticket.gitlab_host`, so that a remote on another host never receives the token.
+    fn token_header(&self) -> Option<(&'static str, &'static str, &'static str)> {
+        Some(match self {
+            Self::GitHub { .. } => ("authorization", "Bearer ", &CONFIG.ticket.github_token_env),
+            Self::GitLab { host, .. } if *host == CONFIG.ticket.gitlab_host => {
+                ("private-token", "", &CONFIG.ticket.gitlab_token_env)
+            }
+            Self::GitLab { .. } => return None,
+        })
+    }
+}
+
+/// The title and labels of the issue the change is for, e.g. `#123 Login fails (labels: bug)`.
+///
+/// The issue number is taken from the first bookmark or branch name matching
+/// `ticket.issue_pattern`, or else from a reference like `Fixes #123` on an added line of the diff,
+/// and looked up with the API of the GitHub or GitLab instance `remote_url` points to. Returns
+/// `None` when there is no number, the remote is neither, or the lookup fails.
+pub fn linked_issue<'a>(
+    remote_url: &str,
+    names: impl IntoIterator<Item = &'a str>,
+    diff: &str,
+) -> Option<String> {
+    let tracker = Tracker::from_remote(remote_url)?;
+    let number = NAME_PATTERN
+        .as_ref()
+        .and_then(|pattern| number_in_names(names, pattern))
+        .or_else(|| number_in_diff(diff))?;
+    let key = (remote_url.to_string(), number);
+    if let Some(issue) = FETCHED.lock().ok()?.get(&key) {
+        return issue.clone();
+    }
+    let issue = fetch(&tracker, number);
+    debug!(?tracker, number, ?issue, "Looked up the linked issue");
+    FETCHED.lock().ok()?.insert(key, issue.clone());
+    issue
+}
+
+/// The issue number in the first name that matches, from the pattern's first capture group or, if
+/// it has none, the whole match
+fn number_in_names<'a>(names: impl IntoIterator<Item = &'a str>, pattern: &Regex) -> Option<u64> {
+    names.into_iter().find_map(|name| {
+        let captures = pattern.captures(name)?;
+        let number = captures.get(1).or_else(|| captures.get(0))?.as_str();
+        number.trim_start_matches('#').parse().ok()
+    })
+}
+
+/// The first issue referenced on an added line of the diff
+fn number_in_diff(diff: &str) -> Option<u64> {
+    diff.lines()
+        .filter(|line| line.starts_with('+') && !line.starts_with("+++"))
+        .find_map(|line| REFERENCE_RE.captures(line)?[1].parse().ok())
+}
+
+fn fetch(tracker: &Tracker, number: u64) -> Option<String> {
+    let url = tracker.api_url(number);
+    let headers: Vec<Header<'_>> = tracker.auth_header().into_iter().collect();
+    let output = Command::new("curl")
+        .args(["-sS", "--fail", "--max-time", TIMEOUT_SECS])
+        .args(["-H", "accept: application/json"])
+        .args(header_args(&headers))
+        .arg(&url)
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            format_issue(number, &from_slice(&output.stdout).ok()?)
+        }
+        Ok(output) => {
+            warn!(
+                url = %url,
+                stderr = %String::from_utf8_lossy(&output.stderr).trim(),
+                "Failed to fetch the linked issue"
+            );
+            None
+        }
+        Err(e) => {
+            warn!(error = %e, "Failed to execute curl");
+            None
+        }
+    }
+}
+
+/// Formats an issue from the GitHub or GitLab API. GitHub lists labels as objects with a name,
+/// GitLab as plain strings.
+fn format_issue(number: u64, issue: &Value) -> Option<String> {
+    let title = issue.get("title")?.as_str()?.trim();
+    let labels: Vec<&str> = issue
+        .get("labels")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|label| label.as_str().or_else(|| label.get("name")?.as_str()))
+        .collect();
+    Some(if labels.is_empty() {
+        format!("#{number} {title}")
+    } else {
+        format!("#{number} {title} (labels: {})", labels.join(", "))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_tracker_from_remote() {
+        let github = Tracker::GitHub { repo: "0x6b/ccc-jj".to_string() };
+        assert_eq!(Tracker::from_remote("git@github.com:0x6b/ccc-jj.git"), Some(github));
+        assert_eq!(
+            Tracker::from_remote("https://github.com/0x6b/ccc-jj/")
+                .unwrap()
+                .api_url(7),
+            "https://api.github.com/repos/0x6b/ccc-jj/issues/7"
+        );
+        assert_eq!(
+            Tracker::from_remote("ssh://git@gitlab.example.com:2222/group/sub/app.git")
+                .unwrap()
+                .api_url(7),
+            "https://gitlab.example.com/api/v4/projects/group%2Fsub%2Fapp/issues/7"
+        );
+        assert_eq!(Tracker::from_remote("https://example.com/repo.git"), None);
+        assert_eq!(Tracker::from_remote("/srv/git/repo.git"), None);
+    }
+
+    #[test]
+    fn test_token_only_for_configured_gitlab_host() {
+        let gitlab = Tracker::from_remote("git@gitlab.com:group/app.git").unwrap();
+        assert!(gitlab.token_header().is_some());
+        let other = Tracker::from_remote("https://gitlab.attacker.example/group/app").unwrap();
+        assert!(other.token_header().is_none());
+    }
+
+    #[test]
+    fn test_issue_number() {
+        let pattern = Regex::new(r"(?:^|/)(?:issue-|gh-)?(\d+)(?:[-_]|$)").unwrap();
+        assert_eq!(number_in_names(["main", "fix/123-login"], &pattern), Some(123));
+        assert_eq!(number_in_names(["issue-45"], &pattern), Some(45));
+        assert_eq!(number_in_names(["main", "v2-login", "release/2.0"], &pattern), None);
+
+        let diff = "+++ b/CHANGELOG.md\n-Fixes #1\n+- Fix the login redirect (fixes #42)\n";
+        assert_eq!(number_in_diff(diff), Some(42));
+        assert_eq!(number_in_diff("+color: #123456;\n"), None);
+    }
+
+    #[test]
+    fn test_format_issue() {
+        let github = json!({ "title": "Login fails ", "labels": [{ "name": "bug" }] });
+        assert_eq!(format_issue(3, &github).unwrap(), "#3 Login fails (labels: bug)");
+        let gitlab = json!({ "title": "Login fails", "labels": ["bug", "auth"] });
+        assert_eq!(format_issue(3, &gitlab).unwrap(), "#3 Login fails (labels: bug, auth)");
+        assert_eq!(format_issue(3, &json!({ "labels": [] })), None);
+    }
+}
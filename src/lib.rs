@@ -0,0 +1,89 @@
+//! Commit message generation for Jujutsu workspaces, as used by the `jc` CLI.
+//!
+//! The [`pipeline`] functions cover a whole commit: find the workspace, snapshot it, build the
+//! diff sent to the model, generate a message, and write the commit. [`pipeline::load_change`]
+//! and [`pipeline::Change::write`] bundle the steps around generation, [`workflow`] runs whole
+//! commands the way `jc` does, and [`bookmark`] names and sets bookmarks.
+//!
+//! ```no_run
+//! # async fn example() -> ccc_jj_lib::Result<()> {
+//! use std::path::Path;
+//!
+//! use ccc_jj_lib::{
+//!     commit_message_generator::FormatOptions, config, diff::get_file_change_summary, pipeline::*,
+//! };
+//!
+//! config::load(Path::new("."));
+//! let workspace = find_workspace(Path::new("."))?;
+//! let repo = load_repo(&workspace)?;
+//! let wc_commit = working_copy_commit(&repo, &workspace)?;
+//! let parent_tree = first_parent_tree(&repo, &wc_commit)?;
+//! let (tree, _summary) = snapshot_workspace(&workspace, 100 * 1024 * 1024, false).await?;
+//! let (diff, _masked) = diff_to_prompt(&repo, &parent_tree, &tree, true).await?;
+//! let file_changes = get_file_change_summary(&parent_tree, &tree).await;
+//! let context = prompt_context(&repo, &workspace)?;
+//! let options = MessageOptions {
+//!     language: "English",
+//!     model: "haiku",
+//!     format_options: FormatOptions::default(),
+//!     always_generate: false,
+//!     conflicts: None,
+//!     conflict_markers: None,
+//!     existing_description: None,
+//!     feedback: None,
+//!     examples: &[],
+//!     ticket: None,
+//!     trailers: &[],
+//!     context: &context,
+//! };
+//! let generated = generate_message(&diff, &file_changes, &options)?;
+//! let provenance = generated.provenance.as_ref();
+//! let message = &generated.message;
+//! write_commit(&workspace, wc_commit.id(), message, tree.clone(), tree, true, provenance).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod audit;
+pub mod bookmark;
+pub mod bookmark_generator;
+mod cache;
+pub mod chunk_summary;
+mod claude_client;
+pub mod commit_message_generator;
+pub mod config;
+pub mod conventions;
+pub mod dependency_update;
+pub mod description_template;
+pub mod diagnostics;
+pub mod diff;
+pub mod explain;
+pub mod generator;
+pub mod git;
+pub mod gitattributes;
+pub mod hook;
+mod http_backends;
+#[cfg(feature = "net")]
+pub mod issue;
+pub mod lint;
+pub mod metrics;
+pub mod offline;
+pub mod pipeline;
+pub mod pr_description;
+mod progress;
+pub mod project;
+pub mod prompt_template;
+pub mod provenance;
+mod records;
+pub mod redaction;
+mod renames;
+pub mod split;
+mod stream_preview;
+mod text_formatter;
+pub mod ticket;
+pub mod trailers;
+pub mod watch;
+pub mod workflow;
+mod workspace;
+
+pub use pipeline::{Error, Result};
@@ -0,0 +1,334 @@
+use std::{fmt, sync::LazyLock};
+
+use regex::Regex;
+
+use crate::{commit_message_generator::fit_subject, config::LintConfig};
+
+/// A conventional commit subject line, split into its parts
+static SUBJECT_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(?P<type>[A-Za-z]+)(?:\((?P<scope>[^)]*)\))?(?P<bang>!)?:\s*(?P<description>.*)$")
+        .expect("Failed to compile subject regex")
+});
+
+/// Verbs that commit subjects commonly start with, in the imperative mood. Other forms of these
+/// (`added`, `adds`, `adding`) are flagged; words not listed here are never flagged.
+const VERBS: &[&str] = &[
+    "add",
+    "adjust",
+    "allow",
+    "avoid",
+    "bump",
+    "change",
+    "check",
+    "clean",
+    "clarify",
+    "configure",
+    "convert",
+    "correct",
+    "create",
+    "delete",
+    "deprecate",
+    "disable",
+    "document",
+    "drop",
+    "enable",
+    "ensure",
+    "extract",
+    "fix",
+    "handle",
+    "hide",
+    "ignore",
+    "implement",
+    "improve",
+    "include",
+    "introduce",
+    "limit",
+    "load",
+    "merge",
+    "migrate",
+    "move",
+    "optimize",
+    "prevent",
+    "reduce",
+    "refactor",
+    "release",
+    "remove",
+    "rename",
+    "reorder",
+    "replace",
+    "require",
+    "restore",
+    "revert",
+    "rework",
+    "simplify",
+    "skip",
+    "sort",
+    "split",
+    "stop",
+    "support",
+    "switch",
+    "tidy",
+    "trim",
+    "update",
+    "upgrade",
+    "use",
+    "validate",
+    "wrap",
+];
+
+/// Past tense forms that don't follow the rules in [`verb_forms`]
+const IRREGULAR: &[(&str, &str)] = &[
+    ("built", "build"),
+    ("kept", "keep"),
+    ("made", "make"),
+    ("ran", "run"),
+    ("rewrote", "rewrite"),
+    ("wrote", "write"),
+];
+
+/// A lint rule that a commit message breaks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// The `[lint]` setting of the rule, e.g. `subject_max_length`
+    pub rule: &'static str,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.rule)
+    }
+}
+
+/// The parts of a subject line in `type(scope)!: description` form
+struct Subject<'a> {
+    commit_type: &'a str,
+    scope: Option<&'a str>,
+    bang: bool,
+    description: &'a str,
+}
+
+impl<'a> Subject<'a> {
+    fn parse(line: &'a str) -> Option<Self> {
+        let caps = SUBJECT_RE.captures(line)?;
+        let part = |name| caps.name(name).map(|m| m.as_str());
+        Some(Self {
+            commit_type: part("type")?,
+            scope: part("scope"),
+            bang: part("bang").is_some(),
+            description: part("description")?,
+        })
+    }
+
+    fn prefix(&self, scope: Option<&str>) -> String {
+        let scope = scope.map(|scope| format!("({scope})")).unwrap_or_default();
+        let bang = if self.bang { "!" } else { "" };
+        format!("{}{scope}{bang}: ", self.commit_type)
+    }
+}
+
+/// Checks a commit message against the rules in `[lint]`
+pub fn lint(message: &str, rules: &LintConfig) -> Vec<Violation> {
+    let subject = message.lines().next().unwrap_or("").trim();
+    let parsed = Subject::parse(subject);
+    let description = parsed.as_ref().map_or(subject, |parsed| parsed.description);
+    let mut violations = Vec::new();
+    let mut add = |rule, message| violations.push(Violation { rule, message });
+
+    let length = subject.chars().count();
+    if rules.subject_max_length > 0 && length > rules.subject_max_length {
+        add(
+            "subject_max_length",
+            format!("subject is {length} characters long, more than {}", rules.subject_max_length),
+        );
+    }
+    if rules.no_trailing_period && description.ends_with('.') {
+        add("no_trailing_period", "subject ends with a period".to_string());
+    }
+    if rules.imperative_mood
+        && let Some(word) = description.split_whitespace().next()
+        && let Some(imperative) = imperative(word)
+    {
+        add(
+            "imperative_mood",
+            format!("subject starts with '{word}' instead of the imperative '{imperative}'"),
+        );
+    }
+    if let Some(parsed) = &parsed {
+        if !rules.types.is_empty() && !rules.types.iter().any(|t| t == parsed.commit_type) {
+            add(
+                "types",
+                format!("type '{}' is not one of {}", parsed.commit_type, rules.types.join(", ")),
+            );
+        }
+        if let Some(scope) = parsed.scope
+            && !rules.scopes.is_empty()
+            && !rules.scopes.iter().any(|s| s == scope)
+        {
+            add("scopes", format!("scope '{scope}' is not one of {}", rules.scopes.join(", ")));
+        }
+    }
+    violations
+}
+
+/// Fixes what can be fixed without the model: a scope that isn't allowed is dropped, the first
+/// word is put in the imperative, trailing periods are removed, and a long subject is cut at a
+/// word boundary. A type that isn't allowed is left alone.
+pub fn fix(message: &str, rules: &LintConfig) -> String {
+    let (subject, rest) = message
+        .split_once('\n')
+        .map_or((message, None), |(s, r)| (s, Some(r)));
+    let subject = subject.trim();
+    let parsed = Subject::parse(subject);
+
+    let prefix = match &parsed {
+        Some(parsed) => {
+            let allowed =
+                |scope: &&str| rules.scopes.is_empty() || rules.scopes.iter().any(|s| s == scope);
+            parsed.prefix(parsed.scope.filter(allowed))
+        }
+        None => String::new(),
+    };
+    let mut description = parsed
+        .as_ref()
+        .map_or(subject, |parsed| parsed.description)
+        .to_string();
+    if rules.imperative_mood
+        && let Some(word) = description.split_whitespace().next()
+        && let Some(imperative) = imperative(word)
+    {
+        let imperative = match word.chars().next() {
+            Some(first) if first.is_uppercase() => capitalize(imperative),
+            _ => imperative.to_string(),
+        };
+        description = format!("{imperative}{}", &description[word.len()..]);
+    }
+    if rules.no_trailing_period {
+        description = description.trim_end_matches('.').to_string();
+    }
+    if rules.subject_max_length > 0 {
+        let max = rules.subject_max_length.saturating_sub(prefix.chars().count());
+        description = fit_subject(&description, max).to_string();
+    }
+
+    let subject = format!("{prefix}{description}");
+    match rest {
+        Some(rest) => format!("{subject}\n{rest}"),
+        None => subject,
+    }
+}
+
+/// Lists the violations for a log message or the model, one per line
+pub fn format_violations(violations: &[Violation]) -> String {
+    violations
+        .iter()
+        .map(|violation| format!("- {violation}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The imperative of a word that is another form of one of [`VERBS`], e.g. `add` for `Added`
+fn imperative(word: &str) -> Option<&'static str> {
+    let word = word.to_lowercase();
+    if let Some((_, verb)) = IRREGULAR.iter().find(|(past, _)| *past == word) {
+        return Some(verb);
+    }
+    VERBS
+        .iter()
+        .find(|verb| **verb != word && verb_forms(verb).contains(&word))
+        .copied()
+}
+
+/// The third person, past tense, and present participle of a regular verb
+fn verb_forms(verb: &str) -> [String; 3] {
+    let is_vowel = |c: char| "aeiou".contains(c);
+    let chars: Vec<char> = verb.chars().collect();
+    let (last, before) = (chars[chars.len() - 1], chars.get(chars.len().wrapping_sub(2)).copied());
+
+    if last == 'y' && before.is_some_and(|c| !is_vowel(c)) {
+        let stem = &verb[..verb.len() - 1];
+        return [format!("{stem}ies"), format!("{stem}ied"), format!("{verb}ing")];
+    }
+    if last == 'e' {
+        let stem = &verb[..verb.len() - 1];
+        return [format!("{verb}s"), format!("{verb}d"), format!("{stem}ing")];
+    }
+    let suffix =
+        if ["s", "x", "z", "ch", "sh"].iter().any(|end| verb.ends_with(end)) { "es" } else { "s" };
+    // One-syllable verbs ending in consonant-vowel-consonant double the consonant: drop, dropped
+    let vowel_groups = chars.windows(2).filter(|w| !is_vowel(w[0]) && is_vowel(w[1])).count()
+        + usize::from(is_vowel(chars[0]));
+    let doubles = vowel_groups == 1
+        && chars.len() >= 3
+        && !is_vowel(last)
+        && !"wxy".contains(last)
+        && before.is_some_and(is_vowel)
+        && !is_vowel(chars[chars.len() - 3]);
+    let stem = if doubles { format!("{verb}{last}") } else { verb.to_string() };
+    [format!("{verb}{suffix}"), format!("{stem}ed"), format!("{stem}ing")]
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    chars
+        .next()
+        .map(|first| first.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LintAction;
+
+    fn rules() -> LintConfig {
+        LintConfig {
+            on_failure: LintAction::Fix,
+            subject_max_length: 40,
+            imperative_mood: true,
+            no_trailing_period: true,
+            types: vec!["feat".to_string(), "fix".to_string()],
+            scopes: vec!["cli".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_lint() {
+        let rules = rules();
+        assert!(lint("feat(cli): add the --foo option\n\nBody.\n", &rules).is_empty());
+
+        let rules_broken: Vec<&str> =
+            lint("chore(core): Added a much longer subject line.\n", &rules)
+                .iter()
+                .map(|violation| violation.rule)
+                .collect();
+        assert_eq!(
+            rules_broken,
+            ["subject_max_length", "no_trailing_period", "imperative_mood", "types", "scopes"]
+        );
+        // Words that aren't verb forms are left alone
+        assert!(lint("fix: settings page crash", &rules).is_empty());
+    }
+
+    #[test]
+    fn test_fix() {
+        let rules = rules();
+        assert_eq!(
+            fix("feat(core)!: Dropped support for the old config file.\n\nBody.\n", &rules),
+            "feat!: Drop support for the old config\n\nBody.\n"
+        );
+        assert_eq!(fix("fix: simplified parsing", &rules), "fix: simplify parsing");
+    }
+
+    #[test]
+    fn test_imperative() {
+        assert_eq!(imperative("adds"), Some("add"));
+        assert_eq!(imperative("Fixed"), Some("fix"));
+        assert_eq!(imperative("dropping"), Some("drop"));
+        assert_eq!(imperative("removed"), Some("remove"));
+        assert_eq!(imperative("clarifies"), Some("clarify"));
+        assert_eq!(imperative("wrote"), Some("write"));
+        assert_eq!(imperative("add"), None);
+        assert_eq!(imperative("parser"), None);
+    }
+}
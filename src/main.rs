@@ -1,70 +1,334 @@
-mod bookmark_generator;
-mod claude_client;
-mod commit_message_generator;
-mod config;
-mod diff;
-mod text_formatter;
+mod error;
+mod interaction;
+mod multi;
+mod output;
+mod tui;
 
 use std::{
-    collections::{HashMap, HashSet},
-    env::{current_dir, var},
+    env::current_dir,
+    ffi::OsString,
+    io::{Read, stdin, stdout},
     path::{Path, PathBuf},
-    process::Command,
-    sync::Arc,
+    process::ExitCode,
+    time::Duration,
 };
 
 use anyhow::{Context, Result, bail};
-use bookmark_generator::BookmarkGenerator;
-use chrono::Local;
-use clap::{Parser, Subcommand};
+use ccc_jj_lib::{
+    audit,
+    bookmark::{Bookmark, BookmarkAction, plan_bookmark},
+    commit_message_generator::{FormatOptions, format_message, is_conventional},
+    config::{self, CONFIG, CommitStyle, parse_size},
+    conventions::{AUTO_LANGUAGE, MATCH_EXAMPLES, detect_language},
+    description_template::template_literal,
+    diagnostics::{Status, run_checks},
+    diff::{
+        FileChangeSummary, build_collapse_matcher, collapse_git_diff, collapsed_files, format_size,
+        summarize_unified_diff,
+    },
+    generator::{DEFAULT_MAX_CLAUDE_CALLS, set_max_claude_calls},
+    git,
+    gitattributes::collapse_patterns,
+    hook::{self, strip_comment_lines},
+    metrics,
+    pipeline::{
+        self, AUTO_COMMIT_DESCRIPTION, SnapshotSummary, find_named_workspace, find_workspace,
+        load_repo, prompt_context, recent_descriptions, set_lock_wait, undo_auto_commit,
+        working_copy_commit,
+    },
+    prompt_template::PromptContext,
+    ticket::{current_ticket, ticket_in},
+    trailers::is_trailer_line,
+    watch::AccumulateUntil,
+    workflow::{self, CommitOptions, Described, Frontend, MessageSettings, Outcome, Verdict},
+};
+use chrono::{DateTime, Local};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, parser::ValueSource};
+use clap_complete::{Shell, generate};
+use clap_mangen::Man;
 use colored::Colorize;
-use commit_message_generator::CommitMessageGenerator;
-use config::CONFIG;
 use console::strip_ansi_codes;
-use diff::{FileChangeSummary, build_collapse_matcher, get_file_change_summary, get_tree_diff};
-use dirs::{config_dir, home_dir};
-use gethostname::gethostname;
+use error::CcError;
+use interaction::{Choice, edit_message, prompt_choice, read_feedback};
 use jj_lib::{
-    backend::CommitId,
-    commit::Commit,
-    config::{ConfigLayer, ConfigResolutionContext, ConfigSource, StackedConfig, resolve},
-    dsl_util::AliasesMap,
-    git::{GitImportOptions, export_refs, import_refs},
-    gitignore::GitIgnoreFile,
-    merged_tree::MergedTree,
-    object_id::ObjectId,
-    op_store::RefTarget,
-    ref_name::RefName,
-    repo::{ReadonlyRepo, Repo, StoreFactories},
-    repo_path::RepoPathUiConverter,
-    revset::{
-        RevsetAliasesMap, RevsetDiagnostics, RevsetExtensions, RevsetParseContext,
-        RevsetWorkspaceContext, SymbolResolver, parse,
-    },
-    settings::UserSettings,
-    time_util::DatePatternContext,
-    working_copy::SnapshotOptions,
-    workspace::{Workspace, default_working_copy_factories},
+    commit::Commit, config::ConfigGetError, object_id::ObjectId, settings::UserSettings,
+    workspace::Workspace,
 };
-use tracing::{debug, info, trace, warn};
+use output::{CommitRecord, OutputFormat};
+use serde_json::{Value, json};
+use tracing::{debug, info, warn};
 use tracing_subscriber::fmt;
+use tui::{FileEntry, review_files};
 use unicode_width::UnicodeWidthStr;
 
 #[derive(Parser, Debug)]
-#[command(about, version)]
+#[command(about, version, args_conflicts_with_subcommands = true)]
 struct Args {
     /// Path to the workspace (defaults to current directory)
     #[arg(short, long, global = true)]
     path: Option<PathBuf>,
 
+    /// Operate on this workspace of the repository, as listed by `jj workspace list`, instead of
+    /// the one containing the path
+    #[arg(long, value_name = "NAME", global = true)]
+    workspace: Option<String>,
+
     /// Model to use for AI generation
     #[arg(short, long, default_value = "haiku", env = "CCC_JJ_MODEL", global = true)]
     model: String,
 
+    /// Generate with this command line instead of generator.command and generator.args, e.g.
+    /// "llm -m gpt-4o". It is split into words like a shell would, but not run through one
+    #[arg(long, value_name = "COMMAND", env = "CCC_JJ_GENERATOR_CMD", global = true)]
+    generator_cmd: Option<String>,
+
+    /// Unchanged lines shown around each change in the diff sent to the model [default:
+    /// diff.context_lines, 2]
+    #[arg(long, value_name = "N", global = true)]
+    context_lines: Option<usize>,
+
+    /// Lines shown of an added or deleted file before the rest is only counted, 0 for whole
+    /// files [default: diff.max_file_lines, 50]
+    #[arg(long, value_name = "N", global = true)]
+    max_file_lines: Option<usize>,
+
+    /// Print the effective configuration as TOML and exit
+    #[arg(long, global = true)]
+    dump_config: bool,
+
+    /// Fail before calling Claude if a prompt template has missing or unknown placeholders
+    /// (default)
+    #[arg(long, global = true, conflicts_with = "no_strict_placeholders")]
+    prompt_placeholder_strict: bool,
+
+    /// Don't check prompt templates for missing or unknown placeholders
+    #[arg(long, global = true)]
+    no_strict_placeholders: bool,
+
+    /// Abort once this many Claude calls have been made in one run (per commit in watch mode)
+    #[arg(long, default_value_t = DEFAULT_MAX_CLAUDE_CALLS, value_name = "N", global = true)]
+    max_claude_calls: usize,
+
+    /// Wait up to this many seconds for the working copy lock when another jj command holds it,
+    /// retrying with backoff, instead of failing at once
+    #[arg(long, default_value_t = 0, value_name = "SECS", global = true)]
+    lock_wait: u64,
+
+    /// Print how long the snapshot, diff, and model calls took, and how much was sent, at the end
+    #[arg(long, global = true)]
+    timings: bool,
+
+    /// Report results as text, or as one JSON object per line for scripts
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text, global = true)]
+    output: OutputFormat,
+
+    #[command(flatten)]
+    commit: CommitArgs,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
+#[derive(clap::Args, Debug)]
+struct CommitArgs {
+    /// Language to use for commit messages, or "auto" to match the language and style of the
+    /// repository's recent commit messages
+    #[arg(short, long, default_value = "English", env = "CCC_JJ_LANGUAGE")]
+    language: String,
+
+    /// Commit unresolved conflicts and ask Claude to note them in the message
+    #[arg(long)]
+    describe_conflicts: bool,
+
+    /// Commit unresolved conflicts as they are, telling Claude which files hold conflict markers
+    #[arg(long)]
+    allow_conflicts: bool,
+
+    /// Read a commit message from stdin, validate and format it, and print the result without
+    /// calling Claude or committing (usable as a commit-msg hook)
+    #[arg(long)]
+    stdin_message: bool,
+
+    /// Store the diff sent to Claude and its raw output under .jj/ccc-jj/records/<op-id>/
+    #[arg(long)]
+    record_context: bool,
+
+    /// Print the diff that would be sent to Claude and exit without generating or committing
+    #[arg(long, conflicts_with_all = ["stdin_message", "record_context"])]
+    diff_only: bool,
+
+    /// Generate the message and print it with the files that would be included, without
+    /// committing
+    #[arg(long, conflicts_with_all = ["watch", "record_context"])]
+    dry_run: bool,
+
+    /// Review the generated message before committing: accept, edit it in $EDITOR, regenerate
+    /// it with optional feedback, or abort
+    #[arg(short, long, conflicts_with_all = ["watch", "stdin_message", "diff_only"])]
+    interactive: bool,
+
+    /// Send the diff as is instead of masking secrets matching redaction.patterns
+    #[arg(long)]
+    no_redact: bool,
+
+    /// Don't reflow the message body to 72 columns
+    #[arg(long)]
+    no_wrap: bool,
+
+    /// Don't break lines longer than format.hard_wrap_width
+    #[arg(long)]
+    no_hard_wrap: bool,
+
+    /// How the subject starts: conventional (`feat: ...`) or gitmoji (`✨ ...`) [default:
+    /// format.style]
+    #[arg(long, value_name = "STYLE")]
+    style: Option<CommitStyle>,
+
+    /// Call Claude even when the diff is below diff.min_lines_for_claude
+    #[arg(long)]
+    always_claude: bool,
+
+    /// Keep running and commit working-copy changes once they settle
+    #[arg(long, conflicts_with_all = ["stdin_message", "diff_only"])]
+    watch: bool,
+
+    /// Seconds between working-copy snapshots in watch mode
+    #[arg(long, default_value_t = 2, value_name = "SECONDS", requires = "watch")]
+    watch_interval: u64,
+
+    /// Describe this revision's stored tree in place instead of snapshotting and committing the
+    /// working copy (implies --no-snapshot)
+    #[arg(short, long, value_name = "REVSET", conflicts_with = "watch")]
+    revision: Option<String>,
+
+    /// Describe every undescribed commit in this revset from its stored tree, in a single
+    /// transaction (e.g. 'mine() & description(exact:"")')
+    #[arg(long, value_name = "REVSET", conflicts_with_all = ["revision", "watch", "via_jj", "interactive", "record_context", "diff_only"])]
+    revset: Option<String>,
+
+    /// Use the stored tree instead of snapshotting the working copy, and describe the commit in
+    /// place without taking the working-copy lock
+    #[arg(long, conflicts_with = "watch")]
+    no_snapshot: bool,
+
+    /// Leave new files larger than this untracked, e.g. "1GiB" or "500MB"
+    /// [default: snapshot.max_new_file_size]
+    #[arg(long, value_name = "SIZE", value_parser = config::parse_size, conflicts_with_all = ["revision", "revset", "no_snapshot"])]
+    max_new_file_size: Option<u64>,
+
+    /// Show the number of files scanned while snapshotting the working copy
+    /// [default: snapshot.progress]
+    #[arg(long, conflicts_with_all = ["quiet", "revision", "revset", "no_snapshot"])]
+    progress: bool,
+
+    /// Don't show progress while snapshotting the working copy
+    #[arg(long)]
+    quiet: bool,
+
+    /// Hand the message to `jj describe --stdin` (followed by `jj new` when committing the working
+    /// copy) instead of rewriting commits with jj-lib, so jj's own behavior applies
+    #[arg(long)]
+    via_jj: bool,
+
+    /// In watch mode, wait until the pending change is commit-worthy: idle:<duration> (e.g.
+    /// idle:30s), lines:<n>, or files:<n>
+    #[arg(long, value_name = "CONDITION", requires = "watch")]
+    accumulate_until: Option<AccumulateUntil>,
+
+    /// After committing, point a bookmark at the commit: advance a bookmark from its parent, or
+    /// create one named by Claude from the commit's summary
+    #[arg(long, conflicts_with_all = ["dry_run", "diff_only", "stdin_message", "watch", "revset", "split"])]
+    bookmark: bool,
+
+    /// Only commit changes to paths matching this glob (repeatable); the rest stay in the new
+    /// working-copy commit
+    #[arg(long, value_name = "GLOB", conflicts_with_all = ["revision", "revset", "no_snapshot", "via_jj", "watch", "split"])]
+    only: Vec<String>,
+
+    /// Leave changes to paths matching this glob (repeatable) in the new working-copy commit
+    /// instead of committing them
+    #[arg(long, value_name = "GLOB", conflicts_with_all = ["revision", "revset", "no_snapshot", "via_jj", "watch", "split"])]
+    exclude: Vec<String>,
+
+    /// Update the commit's existing description to cover its current changes instead of skipping
+    /// commits that are already described
+    #[arg(long, conflicts_with_all = ["revset", "split", "stdin_message"])]
+    amend: bool,
+
+    /// Squash the working-copy changes into the parent commit, as `jj squash` does, and have
+    /// Claude merge the parent's description with them instead of creating a new commit
+    #[arg(long, conflicts_with_all = ["revision", "revset", "no_snapshot", "via_jj", "watch", "amend", "split", "stdin_message"])]
+    squash: bool,
+
+    /// Describe the cumulative change since this revision (e.g. 'trunk()') rather than the change
+    /// from the parent, e.g. to write one message for a series of commits before squashing them
+    #[arg(long, value_name = "REVSET", conflicts_with_all = ["revset", "watch", "squash", "split"])]
+    from: Option<String>,
+
+    /// Set the description of the working-copy commit and stay on it, as `jj describe` does,
+    /// instead of starting a new empty change on top of it as `jj commit` does
+    #[arg(long, visible_alias = "message-only", conflicts_with_all = ["revision", "revset", "no_snapshot", "watch", "only", "exclude", "squash", "split"])]
+    describe: bool,
+
+    /// Commit with git instead of jj: the staged changes, or every change to tracked files if
+    /// nothing is staged. Used automatically when there is a git repository but no Jujutsu
+    /// workspace
+    #[arg(long, conflicts_with_all = ["revision", "revset", "no_snapshot", "via_jj", "watch", "bookmark", "only", "exclude", "amend", "squash", "from", "describe", "split", "record_context"])]
+    git: bool,
+
+    /// Call Claude even if a message for the same trees and prompt is cached under
+    /// .jj/ccc-jj/cache
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Ask Claude to group the changed files into several logical commits and create each of them
+    #[arg(long, conflicts_with_all = ["revision", "revset", "no_snapshot", "via_jj", "interactive", "watch", "record_context", "diff_only"])]
+    split: bool,
+
+    /// Append a Signed-off-by trailer for the jj user (user.name and user.email)
+    #[arg(long, conflicts_with = "stdin_message")]
+    signoff: bool,
+
+    /// Append this trailer to the message, e.g. "Reviewed-by: Jane Doe <jane@example.com>"
+    /// (repeatable)
+    #[arg(long, value_name = "TRAILER", conflicts_with = "stdin_message")]
+    trailer: Vec<String>,
+
+    /// Ticket ID found in the bookmark or branch name, filled in by `run`
+    #[arg(skip)]
+    ticket: Option<String>,
+
+    /// The jj description template generated messages are put into, filled in by `run`
+    #[arg(skip)]
+    description_template: Option<String>,
+
+    /// Recent commit messages to match with `--language auto`, filled in by `run`
+    #[arg(skip)]
+    examples: Vec<String>,
+
+    /// Branch, recent commits, and repository name for the prompt template, filled in by `run`
+    #[arg(skip)]
+    prompt_context: PromptContext,
+
+    /// Print the prompt instead of generating a message, set by `jc prompt`
+    #[arg(skip)]
+    preview_prompt: bool,
+
+    /// Where `jc prompt` writes the prompt instead of printing it
+    #[arg(skip)]
+    prompt_file: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct PromptArgs {
+    /// Write the prompt to this file instead of printing it
+    #[arg(long, value_name = "PATH")]
+    file: Option<PathBuf>,
+
+    #[command(flatten)]
+    commit: CommitArgs,
+}
+
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Generate a bookmark name for commits between the current revision and a base
@@ -85,227 +349,284 @@ enum Commands {
         /// Only print the generated name, don't create the bookmark
         #[arg(long)]
         dry_run: bool,
+
+        /// Generate a new name from the commits even if the branch already has a bookmark, and
+        /// rename that bookmark to it
+        #[arg(long)]
+        rename: bool,
+    },
+    /// Generate a pull request title and description for the commits between a base and a target
+    #[command(alias = "pr")]
+    PrDescription {
+        /// Base revision to compare against (default: main@origin or main)
+        #[arg(short, long)]
+        from: Option<String>,
+
+        /// Target revision (default: @, or @- if @ is empty)
+        #[arg(short, long, default_value = "@")]
+        to: String,
+
+        /// Language to write the description in
+        #[arg(short, long, default_value = "English", env = "CCC_JJ_LANGUAGE")]
+        language: String,
+
+        /// Write the description to this file as markdown instead of printing it
+        #[arg(long, value_name = "PATH")]
+        file: Option<PathBuf>,
+    },
+    /// Explain the working-copy changes in plain language, without committing anything
+    Explain {
+        /// Language to write the explanation in
+        #[arg(short, long, default_value = "English", env = "CCC_JJ_LANGUAGE")]
+        language: String,
     },
     /// Generate a commit message and commit changes (default command)
     #[command(alias = "c")]
-    Commit {
-        /// Language to use for commit messages
+    Commit(Box<CommitArgs>),
+    /// Generate a commit message for a unified diff from stdin or a file and print it, without
+    /// looking for a repository (e.g. from a git hook or another VCS)
+    Message {
+        /// Read the diff from stdin
+        #[arg(long, required_unless_present = "diff_file")]
+        stdin: bool,
+
+        /// Read the diff from this file
+        #[arg(long, value_name = "PATH", conflicts_with = "stdin")]
+        diff_file: Option<PathBuf>,
+
+        /// Language to write the message in
         #[arg(short, long, default_value = "English", env = "CCC_JJ_LANGUAGE")]
         language: String,
+
+        /// Send the diff as is, without masking strings that look like secrets
+        #[arg(long)]
+        no_redact: bool,
+    },
+    /// Fill in empty commit messages from git's `prepare-commit-msg` hook
+    Hook {
+        #[command(subcommand)]
+        action: HookAction,
+    },
+    /// Review the changed files and their diffs in a terminal UI, choose which to include, then
+    /// generate a message and commit them
+    Tui(Box<CommitArgs>),
+    /// Print the full prompt a commit would send to the model (template, diff, and context),
+    /// without calling it or committing
+    Prompt(Box<PromptArgs>),
+    /// Roll back the most recent auto-commit by restoring the operation before it, keeping the
+    /// changes in the working copy
+    Undo {
+        /// Undo even if operations were made after the auto-commit, rolling them back as well
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print a shell completion script, e.g. `jc completions zsh > ~/.zfunc/_jc`
+    Completions {
+        /// Shell to complete for
+        shell: Shell,
+    },
+    /// Print the man page in roff format, e.g. `jc man > jc.1`
+    Man,
+    /// Check the environment: config files, prompt templates, the jj workspace, the backend and
+    /// its credentials, and git, printing how to fix any problem found
+    Doctor,
+    /// List the prompt and response files in the audit log (see `audit.enabled`), oldest first
+    Logs {
+        /// Remove the logs older than `audit.retention_days` instead
+        #[arg(long)]
+        prune: bool,
+    },
+    /// Commit in every jj workspace under a directory, one after another, and print a summary of
+    /// each, e.g. for a directory of repositories or a nightly cron job
+    Multi {
+        /// Directory to search for jj workspaces
+        dir: PathBuf,
+
+        /// How many directory levels below DIR to search
+        #[arg(long, default_value_t = 3, value_name = "N")]
+        max_depth: usize,
+
+        /// Options for each run, after `--`, e.g. `jc multi ~/src -- --language Japanese`
+        #[arg(last = true, allow_hyphen_values = true)]
+        args: Vec<OsString>,
     },
 }
 
-impl Default for Commands {
-    fn default() -> Self {
-        Commands::Commit { language: "English".to_string() }
-    }
-}
+#[derive(Subcommand, Debug)]
+enum HookAction {
+    /// Write a `prepare-commit-msg` hook that runs `hook run` into the git repository's hooks
+    /// directory
+    Install {
+        /// Replace an existing hook that ccc-jj didn't install
+        #[arg(long)]
+        force: bool,
+    },
+    /// Write a message for the staged changes into the message file if it has none; called by the
+    /// installed hook with git's arguments
+    Run {
+        /// The commit message file git passes to the hook
+        msg_file: PathBuf,
 
-/// Load user configuration from standard jj config locations
-fn load_user_config(config: &mut StackedConfig) -> Result<()> {
-    let home = home_dir();
-    let candidates: Vec<PathBuf> = [
-        home.as_ref().map(|h| h.join(".jjconfig.toml")),
-        home.as_ref().map(|h| h.join(".config/jj/config.toml")),
-        config_dir().map(|c| c.join("jj/config.toml")),
-    ]
-    .into_iter()
-    .flatten()
-    .collect();
+        /// Where git got the message from (`message`, `template`, `merge`, `squash`, `commit`)
+        source: Option<String>,
 
-    for path in candidates {
-        if path.exists() {
-            let layer = ConfigLayer::load_from_file(ConfigSource::User, path)?;
-            config.add_layer(layer);
-        }
-    }
-    Ok(())
-}
+        /// The commit being amended or reused, for `commit`
+        sha: Option<String>,
 
-/// Load gitignore files from global and workspace locations
-fn load_base_ignores(workspace_root: &Path) -> Result<Arc<GitIgnoreFile>> {
-    let mut git_ignores = GitIgnoreFile::empty();
+        /// Language to write the message in
+        #[arg(short, long, default_value = "English", env = "CCC_JJ_LANGUAGE")]
+        language: String,
+    },
+}
 
-    // Try to get global excludes file from git config
-    let global_excludes = get_global_git_excludes_file();
+/// The first 8 hex digits of an id
+fn short_hex(hex: &str) -> &str {
+    &hex[..8.min(hex.len())]
+}
 
-    if let Some(excludes_path) = global_excludes {
-        // Chain the global excludes file (ignore errors if file doesn't exist)
-        git_ignores = git_ignores.chain_with_file("", excludes_path).unwrap_or(git_ignores);
+/// Print the message in a box titled with the given id and the commit's author, if known,
+/// followed by the file changes
+fn print_commit_summary(action: &str, short_id: &str, described: &Described) {
+    let mut title = format!("{}{}", action.white().dimmed(), short_id.blue().dimmed());
+    if let Some(author) = &described.author {
+        title.push_str(&format!(" {} {}", "by".white().dimmed(), author.white().dimmed()));
     }
 
-    // Load workspace root .gitignore
-    let workspace_gitignore = workspace_root.join(".gitignore");
-    git_ignores = git_ignores
-        .chain_with_file("", workspace_gitignore)
-        .unwrap_or(git_ignores);
+    // Print the box with title in top border
+    output::print(&format_box_with_title(&title, &described.message, 72));
+
+    // Print file changes below the box (indented to align with box content)
+    print_file_changes(&described.file_changes);
+}
 
-    Ok(git_ignores)
+/// Print a message that was not written, in a box with the given title
+fn print_proposed(title: &str, described: &Described) {
+    let title = title.white().dimmed().to_string();
+    output::print(&format_box_with_title(&title, &described.message, 72));
+    print_file_changes(&described.file_changes);
 }
 
-/// Get the global git excludes file path
-fn get_global_git_excludes_file() -> Option<PathBuf> {
-    // First, try to get from git config
-    if let Ok(output) = Command::new("git")
-        .args(["config", "--global", "--get", "core.excludesFile"])
-        .output()
-        && output.status.success()
-        && let Ok(path_str) = std::str::from_utf8(&output.stdout)
-    {
-        let path_str = path_str.trim();
-        if !path_str.is_empty() {
-            // Expand ~ to home directory if present
-            let expanded = if let Some(stripped) = path_str.strip_prefix("~/") {
-                if let Some(home) = home_dir() {
-                    home.join(stripped)
-                } else {
-                    PathBuf::from(path_str)
-                }
-            } else {
-                PathBuf::from(path_str)
-            };
-            return Some(expanded);
-        }
-    }
+/// Emits the JSON record of a message in JSON mode
+fn emit_record(described: &Described, model: &str) {
+    let file_changes = &described.file_changes;
+    let files_changed: Vec<String> = file_changes
+        .paths()
+        .chain(file_changes.conflicted.iter().map(String::as_str))
+        .map(String::from)
+        .collect();
+    let collapsed_files = collapsed_files(&described.diff)
+        .into_iter()
+        .filter(|file| files_changed.contains(file))
+        .collect();
+    output::commit(&CommitRecord {
+        status: described.status.as_str(),
+        commit_id: described.commit_id.clone(),
+        change_id: described.change_id.clone(),
+        message: &described.message,
+        files_changed,
+        collapsed_files,
+        model,
+        duration_ms: output::elapsed().as_millis(),
+    });
+}
 
-    // Fall back to XDG_CONFIG_HOME/git/ignore or ~/.config/git/ignore
-    if let Ok(xdg_config) = var("XDG_CONFIG_HOME")
-        && !xdg_config.is_empty()
-    {
-        let path = PathBuf::from(xdg_config).join("git").join("ignore");
-        if path.exists() {
-            return Some(path);
+/// Prints how a commit run ended. `prompt_file` is where `jc prompt` writes the prompt, if
+/// anywhere.
+fn print_outcome(outcome: Outcome, model: &str, prompt_file: Option<&Path>) -> Result<()> {
+    match outcome {
+        Outcome::AlreadyDescribed => output::emit(
+            "",
+            || json!({ "status": "skipped", "reason": "Commit already has description" }),
+        ),
+        Outcome::Diff(diff) => output::emit(&diff, || json!({ "status": "diff", "diff": diff })),
+        Outcome::Prompt(prompt) => print_prompt(&prompt, prompt_file)?,
+        Outcome::Aborted => output::skipped("Aborted, nothing committed"),
+        Outcome::Message { described, bookmark } => {
+            let action = match described.status {
+                workflow::Status::DryRun => "",
+                // Only git commits come without a change id
+                workflow::Status::Committed if described.change_id.is_none() => "Committed ",
+                workflow::Status::Committed => "Committed change ",
+                workflow::Status::Described => "Described change ",
+                workflow::Status::Squashed => "Squashed into change ",
+            };
+            match &described.commit_id {
+                Some(commit_id) if !action.is_empty() => {
+                    print_commit_summary(action, short_hex(commit_id), &described);
+                }
+                _ => print_proposed("Proposed message (dry run, nothing committed)", &described),
+            }
+            emit_record(&described, model);
+            if let Some(bookmark) = bookmark {
+                print_bookmark(&bookmark?);
+            }
         }
-    }
-
-    // Final fallback: ~/.config/git/ignore
-    if let Some(home) = home_dir() {
-        let path = home.join(".config").join("git").join("ignore");
-        if path.exists() {
-            return Some(path);
+        Outcome::Split(commits) => {
+            for (i, described) in commits.iter().enumerate() {
+                match &described.commit_id {
+                    Some(commit_id) => {
+                        print_commit_summary("Committed change ", short_hex(commit_id), described);
+                    }
+                    None => {
+                        let title = format!(
+                            "Proposed commit {}/{} (dry run, nothing committed)",
+                            i + 1,
+                            commits.len()
+                        );
+                        print_proposed(&title, described);
+                    }
+                }
+                emit_record(described, model);
+            }
         }
     }
-
-    None
+    Ok(())
 }
 
-/// Discover the jj workspace starting from the given directory
-fn find_workspace(start_dir: &Path) -> Result<Workspace> {
-    // First, find the workspace root directory
-    let mut current_dir = start_dir;
-    let workspace_root = loop {
-        if current_dir.join(".jj").exists() {
-            break current_dir;
-        }
-
-        match current_dir.parent() {
-            Some(parent) => current_dir = parent,
-            None => bail!(
-                "No Jujutsu workspace found in '{}' or any parent directory",
-                start_dir.display()
-            ),
+/// Print the prompt, or write it to the file given to `jc prompt`
+fn print_prompt(prompt: &str, file: Option<&Path>) -> Result<()> {
+    match file {
+        Some(path) => {
+            std::fs::write(path, prompt)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            output::emit(
+                &format!("Wrote the prompt to {}\n", path.display()),
+                || json!({ "status": "written", "file": path.display().to_string() }),
+            );
         }
-    };
-
-    // Build config with proper layers (with_defaults includes operation.hostname/username)
-    let mut config = StackedConfig::with_defaults();
-
-    // Load user configuration
-    load_user_config(&mut config)?;
-
-    // Load repository-specific configuration
-    let repo_config_path = workspace_root.join(".jj").join("repo").join("config.toml");
-    if repo_config_path.exists() {
-        let layer = ConfigLayer::load_from_file(ConfigSource::Repo, repo_config_path)?;
-        config.add_layer(layer);
+        None => output::emit(prompt, || json!({ "status": "prompt", "prompt": prompt })),
     }
-
-    // Resolve conditional scopes (e.g., --when.repositories)
-    let hostname = gethostname().to_str().map(|s| s.to_owned()).unwrap_or_default();
-    let home_dir = home_dir();
-    let context = ConfigResolutionContext {
-        home_dir: home_dir.as_deref(),
-        repo_path: Some(workspace_root),
-        workspace_path: Some(workspace_root),
-        command: None,
-        hostname: hostname.as_str(),
-    };
-    let resolved_config = resolve(&config, &context)?;
-
-    // Now create settings with resolved config
-    let settings = UserSettings::from_config(resolved_config)?;
-    let store_factories = StoreFactories::default();
-    let working_copy_factories = default_working_copy_factories();
-
-    // Load the workspace with the complete settings
-    Workspace::load(&settings, workspace_root, &store_factories, &working_copy_factories)
-        .context("Failed to load workspace")
+    Ok(())
 }
 
-/// Create a commit with the generated message
-async fn create_commit(
-    workspace: &Workspace,
-    commit_message: &str,
-    tree: MergedTree,
-    file_changes: &FileChangeSummary,
-) -> Result<()> {
-    let repo = workspace.repo_loader().load_at_head()?;
-
-    // Start transaction
-    let mut tx = repo.start_transaction();
-    let mut_repo = tx.repo_mut();
-
-    let wc_commit_id = repo
-        .view()
-        .get_wc_commit_id(workspace.workspace_name())
-        .context("workspace should have a working-copy commit")?;
-    let wc_commit = repo.store().get_commit(wc_commit_id)?;
-
-    // Rewrite the working copy commit with the description and snapshotted tree
-    let commit_with_description = mut_repo
-        .rewrite_commit(&wc_commit)
-        .set_tree(tree.clone())
-        .set_description(commit_message)
-        .write()?;
-
-    // Rebase descendants (handles the rewrite)
-    mut_repo.rebase_descendants()?;
-
-    // Create a new empty working copy commit on top
-    let new_wc_commit = mut_repo
-        .new_commit(vec![commit_with_description.id().clone()], tree)
-        .write()?;
-
-    mut_repo.set_wc_commit(workspace.workspace_name().to_owned(), new_wc_commit.id().clone())?;
-
-    let new_repo = tx.commit("auto-commit via ccc-jj")?;
-
-    // Finish the working copy with the new state
-    let locked_wc = workspace.working_copy().start_mutation()?;
-    locked_wc.finish(new_repo.operation().id().clone()).await?;
-
-    let author = commit_with_description.author();
-    let commit_id = commit_with_description.id().hex();
-    let short_id = &commit_id[..8.min(commit_id.len())];
-    let title = format!(
-        "{}{} {} {}",
-        "Committed change ".white().dimmed(),
-        short_id.blue().dimmed(),
-        "by".white().dimmed(),
-        format!("{} <{}>", author.name, author.email).white().dimmed()
-    );
+/// Reports the progress of a workflow in the terminal and reviews messages with the user
+struct Terminal;
 
-    // Print the box with title in top border
-    print!("{}", format_box_with_title(&title, commit_message, 72));
+impl Frontend for Terminal {
+    fn snapshot(&mut self, summary: &SnapshotSummary, max_new_file_size: u64) {
+        report_snapshot(summary, max_new_file_size);
+    }
 
-    // Print file changes below the box (indented to align with box content)
-    print_file_changes(file_changes);
+    fn masked_secrets(&mut self, count: usize) {
+        report_masked_secrets(count);
+    }
 
-    Ok(())
+    fn review(&mut self, message: &str, file_changes: &FileChangeSummary) -> Result<Verdict> {
+        let title = "Proposed message".white().dimmed().to_string();
+        output::print(&format_box_with_title(&title, message, 72));
+        print_file_changes(file_changes);
+
+        Ok(match prompt_choice()? {
+            Choice::Accept => Verdict::Accept,
+            Choice::Edit => Verdict::Edit(edit_message(message)?),
+            Choice::Regenerate => Verdict::Regenerate(read_feedback()?),
+            Choice::Abort => Verdict::Abort,
+        })
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> ExitCode {
     fmt()
         .with_env_filter(
             tracing_subscriber::EnvFilter::from_default_env()
@@ -313,396 +634,1016 @@ async fn main() -> Result<()> {
         )
         .init();
 
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
     debug!(?args, "Parsed arguments");
+    output::init(args.output);
 
-    // Determine workspace path
-    let workspace_path = match args.path {
-        Some(p) => p,
-        None => current_dir().context("Failed to get current directory")?,
+    let timings = args.timings;
+    let result = run(args, &matches).await;
+    if timings {
+        report_timings();
+    }
+    let Err(error) = result else {
+        return ExitCode::SUCCESS;
     };
-    info!(?workspace_path, "Starting workspace discovery");
-
-    // Find workspace
-    let workspace = find_workspace(&workspace_path)?;
-    info!(workspace_root = ?workspace.workspace_root(), "Found workspace");
+    let class = CcError::of(&error);
+    if class == CcError::NothingToCommit {
+        output::skipped(&error.to_string());
+    } else if output::is_json() {
+        output::error(&error);
+    } else {
+        eprintln!("Error: {error:?}");
+    }
+    class.exit_code()
+}
 
-    match args.command.unwrap_or_default() {
-        Commands::Bookmark { from, to, prefix, dry_run } => {
-            run_bookmark(&workspace, &args.model, from, &to, prefix, dry_run).await
-        }
-        Commands::Commit { language } => run_commit(&workspace, &language, &args.model).await,
+/// Prints the `--timings` breakdown: to stderr in text mode so it stays out of piped output, or as
+/// a `timings` JSON line
+fn report_timings() {
+    let metrics = metrics::current();
+    let total = output::elapsed();
+    if output::is_json() {
+        let mut value = metrics.to_json(total);
+        value["status"] = json!("timings");
+        println!("{value}");
+    } else {
+        eprint!("{}", metrics.format(total));
     }
 }
 
-async fn run_bookmark(
-    workspace: &Workspace,
-    model: &str,
-    from: Option<String>,
-    to: &str,
-    prefix: Option<String>,
-    dry_run: bool,
-) -> Result<()> {
-    let repo = workspace.repo_loader().load_at_head()?;
-    debug!("Loaded repository at head");
+async fn run(mut args: Args, matches: &ArgMatches) -> Result<()> {
+    // Packaging output needs neither a repository nor the config
+    match args.command {
+        Some(Commands::Completions { shell }) => return print_completions(shell),
+        Some(Commands::Man) => return print_man_page(),
+        _ => {}
+    }
 
-    let from_rev = match from {
-        Some(rev) => rev,
-        None => find_default_base(&repo)?,
+    // Determine workspace path
+    let workspace_path = match &args.path {
+        Some(p) => p.clone(),
+        None => current_dir().context("Failed to get current directory")?,
     };
+    config::load(&workspace_path);
+    if let Some(command_line) = &args.generator_cmd {
+        config::override_generator_command(command_line).context("Invalid --generator-cmd")?;
+    }
+    config::override_diff_lines(args.context_lines, args.max_file_lines);
+
+    if args.dump_config {
+        // Always TOML, as it is meant to be copied into a config file
+        print!("{}", format_cli_values(&args, matches));
+        print!("{}", CONFIG.dump()?);
+        return Ok(());
+    }
 
-    // Resolve target revision, skipping empty @ if needed
-    let effective_to = resolve_bookmark_target(&repo, workspace, to)?;
-    let target_commit = resolve_single_commit(&repo, workspace, &effective_to)?;
+    // Before the placeholder check, so that a broken template is reported rather than fatal
+    if matches!(args.command, Some(Commands::Doctor)) {
+        return run_doctor(&workspace_path);
+    }
+    if let Some(Commands::Logs { prune }) = args.command {
+        return run_logs(&workspace_path, prune);
+    }
+    if let Some(Commands::Multi { dir, max_depth, args: run_args }) = &args.command {
+        return run_multi(dir, *max_depth, &forwarded_args(&args, matches, run_args));
+    }
+    if let Some(dir) = audit::log_dir(&workspace_path) {
+        audit::set_log_dir(dir);
+    }
 
-    // Check if any commit in the range already has a bookmark - if so, move it
-    if let Some(existing_name) =
-        find_existing_bookmark_in_range(&repo, workspace, &from_rev, &effective_to)?
+    // Placeholder checking is on by default; it's cheap and catches template typos before a
+    // Claude call is spent
+    if args.prompt_placeholder_strict || !args.no_strict_placeholders {
+        CONFIG.check_placeholders()?;
+    }
+
+    set_max_claude_calls(args.max_claude_calls);
+    set_lock_wait(Duration::from_secs(args.lock_wait));
+
+    let mut command = args
+        .command
+        .unwrap_or_else(|| Commands::Commit(Box::new(args.commit)));
+    // A prompt preview goes through the commit preparation and stops where the model would be
+    // called
+    if let Commands::Prompt(prompt) = command {
+        let PromptArgs { file, mut commit } = *prompt;
+        if commit.watch || commit.revset.is_some() {
+            bail!("jc prompt previews a single change, without --watch or --revset");
+        }
+        commit.preview_prompt = true;
+        commit.prompt_file = file;
+        command = Commands::Commit(Box::new(commit));
+    }
+    if let Commands::Commit(commit) = &command
+        && commit.stdin_message
     {
-        let final_name = match &prefix {
-            Some(p) if !existing_name.starts_with(&format!("{p}/")) => {
-                format!("{p}/{existing_name}")
+        return run_review(commit);
+    }
+    if let Commands::Message { diff_file, language, no_redact, .. } = &command {
+        return run_message(diff_file.as_deref(), language, *no_redact, &args.model).await;
+    }
+    if let Commands::Hook { action } = &command {
+        return match action {
+            HookAction::Install { force } => run_hook_install(&workspace_path, *force),
+            HookAction::Run { msg_file, source, language, .. } => {
+                run_hook(&workspace_path, msg_file, source.as_deref(), language, &args.model).await
             }
-            _ => existing_name.clone(),
         };
+    }
+
+    info!(?workspace_path, "Starting workspace discovery");
 
-        if dry_run {
-            println!("{final_name}");
-            return Ok(());
+    // Find workspace
+    let wants_git = matches!(&command, Commands::Commit(commit) if commit.git);
+    if wants_git && args.workspace.is_some() {
+        bail!("--workspace requires a Jujutsu workspace");
+    }
+    let workspace = if wants_git {
+        None
+    } else {
+        let found = match &args.workspace {
+            Some(name) => find_named_workspace(&workspace_path, name),
+            None => find_workspace(&workspace_path),
+        };
+        match found {
+            Ok(workspace) => Some(workspace),
+            // Fall back to plain git in repositories that don't use jj
+            Err(pipeline::Error::WorkspaceNotFound(_))
+                if matches!(command, Commands::Commit(_))
+                    && args.workspace.is_none()
+                    && git::find_root(&workspace_path).is_some() =>
+            {
+                info!("No Jujutsu workspace found, committing with git");
+                None
+            }
+            Err(e) => return Err(e.into()),
         }
+    };
+    let Some(workspace) = workspace else {
+        let Commands::Commit(mut commit) = command else {
+            unreachable!("git is only used for commits");
+        };
+        return run_git_commit(&workspace_path, &mut commit, &args.model).await;
+    };
+    info!(
+        workspace_root = ?workspace.workspace_root(),
+        workspace_name = workspace.workspace_name().as_str(),
+        "Found workspace"
+    );
 
-        let was_moved = set_bookmark(&repo, &final_name, &target_commit)?;
-        let action = if was_moved { "Moved bookmark" } else { "Created bookmark" };
-        println!(
-            "{} {} {} {}",
-            action.green(),
-            final_name.blue().bold(),
-            "at".white().dimmed(),
-            target_commit.id().hex()[..8].to_string().yellow()
-        );
-        return Ok(());
+    // `[ccc-jj]` in the jj config stands in for options left at their defaults, so a repository
+    // can pick its own model and language
+    let settings = workspace.repo_loader().settings();
+    let is_default =
+        |matches: &ArgMatches, id| matches.value_source(id) == Some(ValueSource::DefaultValue);
+    if is_default(matches, "model")
+        && let Some(model) = jj_setting(settings, "ccc-jj.model")?
+    {
+        info!(model = %model, "Using the model from the jj config");
+        args.model = model;
+    }
+    let command_matches = matches.subcommand().map_or(matches, |(_, sub)| sub);
+    let language = match &mut command {
+        Commands::Commit(commit) | Commands::Tui(commit) => Some(&mut commit.language),
+        Commands::PrDescription { language, .. } | Commands::Explain { language } => Some(language),
+        _ => None,
+    };
+    if let Some(language) = language
+        && is_default(command_matches, "language")
+        && let Some(configured) = jj_setting(settings, "ccc-jj.language")?
+    {
+        info!(language = %configured, "Using the language from the jj config");
+        *language = configured;
     }
 
-    // No existing bookmark - generate a new name
-    info!(from = %from_rev, to = %effective_to, "Resolving revset range");
+    // From here on `trailer` holds every trailer to append, including the config's and the sign-off
+    if let Commands::Commit(commit) | Commands::Tui(commit) = &mut command {
+        let repo = load_repo(&workspace)?;
+        let settings = repo.settings();
+        commit.trailer = requested_trailers(commit, settings.user_name(), settings.user_email())?;
+        commit.ticket = current_ticket(&repo, &workspace);
+        commit.description_template = description_template(settings)?;
+        commit.prompt_context = prompt_context(&repo, &workspace)?;
+        if commit.language.eq_ignore_ascii_case(AUTO_LANGUAGE) {
+            let wc_commit = working_copy_commit(&repo, &workspace)?;
+            let recent = recent_descriptions(&repo, &wc_commit, CONFIG.conventions.sample_size)?;
+            match_recent_messages(commit, recent);
+        }
+    }
 
-    let commit_summaries = get_commit_summaries(&repo, workspace, &from_rev, &effective_to)?;
-    if commit_summaries.is_empty() {
-        bail!("No commits found between {from_rev} and {effective_to}");
+    match command {
+        Commands::Bookmark { from, to, prefix, dry_run, rename } => {
+            let options = BookmarkOptions { prefix, dry_run, rename };
+            run_bookmark(&workspace, &args.model, from, &to, options).await
+        }
+        Commands::PrDescription { from, to, language, file } => {
+            run_pr_description(&workspace, &args.model, &language, from, &to, file.as_deref()).await
+        }
+        Commands::Explain { language } => run_explain(&workspace, &args.model, &language).await,
+        Commands::Commit(commit) if commit.watch => {
+            // Watch mode reloads the workspace for each commit, from its root so that a
+            // --workspace choice sticks
+            run_watch(workspace.workspace_root(), &commit, &args.model).await
+        }
+        Commands::Commit(commit) if commit.revset.is_some() => {
+            run_describe_all(&workspace, &commit, &args.model).await
+        }
+        Commands::Commit(commit) => run_commit(&workspace, &commit, &args.model).await,
+        Commands::Tui(mut commit) => run_tui(&workspace, &mut commit, &args.model).await,
+        Commands::Message { .. }
+        | Commands::Hook { .. }
+        | Commands::Completions { .. }
+        | Commands::Man
+        | Commands::Doctor
+        | Commands::Logs { .. }
+        | Commands::Multi { .. } => unreachable!("handled before workspace discovery"),
+        Commands::Prompt(_) => unreachable!("turned into a commit above"),
+        Commands::Undo { force } => run_undo(&workspace, force).await,
     }
-    debug!(commit_count = commit_summaries.lines().count(), "Found commits");
+}
 
-    info!(model = %model, "Generating bookmark name with Claude");
-    let generator = BookmarkGenerator::new(model);
-    let bookmark_name = match generator.generate(&commit_summaries) {
-        Some(name) => name,
-        None => bail!("Failed to generate bookmark name"),
-    };
+/// The description template from the jj config when `format.description_template` is set:
+/// `templates.draft_commit_description` if it is a plain string, otherwise the older
+/// `ui.default-description`
+fn description_template(settings: &UserSettings) -> Result<Option<String>> {
+    if !CONFIG.format.description_template {
+        return Ok(None);
+    }
+    if let Some(template) = jj_setting(settings, "templates.draft_commit_description")? {
+        match template_literal(&template) {
+            Some(text) => return Ok(Some(text)),
+            None => debug!(
+                template = %template,
+                "templates.draft_commit_description is not a plain string, ignoring it"
+            ),
+        }
+    }
+    let template = jj_setting(settings, "ui.default-description")?;
+    Ok(template.filter(|template| !template.trim().is_empty()))
+}
 
-    let final_name = match &prefix {
-        Some(p) => format!("{p}/{bookmark_name}"),
-        None => bookmark_name,
+/// A string setting from the jj config, with the repository's config over the user's, or `None`
+/// if it isn't set
+fn jj_setting(settings: &UserSettings, name: &str) -> Result<Option<String>> {
+    match settings.get_string(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(ConfigGetError::NotFound { .. }) => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Invalid {name} in the jj config")),
+    }
+}
+
+/// Resolves `--language auto` from the repository's recent commit messages, which are also shown to
+/// Claude as examples. Without any, messages are written in English.
+fn match_recent_messages(args: &mut CommitArgs, recent: Vec<String>) {
+    let language = match detect_language(&recent) {
+        Some(language) => language,
+        None if recent.is_empty() => "English",
+        None => MATCH_EXAMPLES,
     };
+    info!(language, examples = recent.len(), "Matching recent commit messages");
+    args.language = language.to_string();
+    args.examples = recent;
+}
 
-    if dry_run {
-        println!("{final_name}");
-        return Ok(());
+/// The full list of trailers to append to generated messages: `trailers.extra`, then the
+/// `--trailer` values, then the sign-off
+fn requested_trailers(args: &CommitArgs, user_name: &str, user_email: &str) -> Result<Vec<String>> {
+    let mut trailers: Vec<String> = CONFIG
+        .trailers
+        .extra
+        .iter()
+        .chain(&args.trailer)
+        .map(|t| t.trim().to_string())
+        .collect();
+    if let Some(invalid) = trailers.iter().find(|trailer| !is_trailer_line(trailer)) {
+        bail!(
+            "Invalid trailer '{invalid}': expected 'Key: value', e.g. 'Reviewed-by: Jane Doe <jane@example.com>'"
+        );
     }
+    if args.signoff || CONFIG.trailers.signoff {
+        if user_name.is_empty() || user_email.is_empty() {
+            bail!("Signing off requires user.name and user.email to be set");
+        }
+        trailers.push(format!("Signed-off-by: {user_name} <{user_email}>"));
+    }
+    Ok(trailers)
+}
 
-    set_bookmark(&repo, &final_name, &target_commit)?;
-    println!(
-        "{} {} {} {}",
-        "Created bookmark".green(),
-        final_name.blue().bold(),
-        "at".white().dimmed(),
-        target_commit.id().hex()[..8].to_string().yellow()
-    );
+/// Renders the CLI-derived settings as a TOML table, noting where each value came from.
+fn format_cli_values(args: &Args, matches: &ArgMatches) -> String {
+    // Commit options live either at the top level or under the `commit` subcommand
+    let (commit, commit_matches) = match (&args.command, matches.subcommand_matches("commit")) {
+        (Some(Commands::Commit(commit)), Some(sub)) => (&**commit, sub),
+        _ => (&args.commit, matches),
+    };
 
-    Ok(())
+    let mut values = vec![("model", args.model.clone(), value_source(matches, "model"))];
+    if let Some(path) = &args.path {
+        values.push(("path", path.display().to_string(), value_source(matches, "path")));
+    }
+    values.push(("language", commit.language.clone(), value_source(commit_matches, "language")));
+    if let Some(command_line) = &args.generator_cmd {
+        let source = value_source(matches, "generator_cmd");
+        values.push(("generator_cmd", command_line.clone(), source));
+    }
+
+    let mut output = String::from("[cli]\n");
+    for (key, value, source) in values {
+        output.push_str(&format!("{key} = {} # {source}\n", toml::Value::from(value)));
+    }
+    output.push('\n');
+    output
 }
 
-/// Find an existing local bookmark anywhere in the given revset range
-fn find_existing_bookmark_in_range(
-    repo: &Arc<ReadonlyRepo>,
-    workspace: &Workspace,
-    from: &str,
-    to: &str,
-) -> Result<Option<String>> {
-    let revset_str = format!("{from}..{to}");
-    let commit_ids: HashSet<_> =
-        evaluate_revset(repo, workspace, &revset_str)?.into_iter().collect();
-
-    for (name, target) in repo.view().local_bookmarks() {
-        if target.added_ids().any(|id| commit_ids.contains(id)) {
-            return Ok(Some(name.as_str().to_string()));
-        }
+/// Describes where clap obtained the value of an argument.
+fn value_source(matches: &ArgMatches, id: &str) -> &'static str {
+    match matches.value_source(id) {
+        Some(ValueSource::CommandLine) => "command line",
+        Some(ValueSource::EnvVariable) => "environment",
+        Some(ValueSource::DefaultValue) => "default",
+        _ => "unknown",
     }
-    Ok(None)
 }
 
-/// Resolve bookmark target, using @- if @ is empty (idiomatic jj behavior)
-fn resolve_bookmark_target(
-    repo: &Arc<ReadonlyRepo>,
+/// How `jc bookmark` names and sets the bookmark
+struct BookmarkOptions {
+    prefix: Option<String>,
+    dry_run: bool,
+    rename: bool,
+}
+
+async fn run_bookmark(
     workspace: &Workspace,
+    model: &str,
+    from: Option<String>,
     to: &str,
-) -> Result<String> {
-    if to != "@" {
-        return Ok(to.to_string());
+    options: BookmarkOptions,
+) -> Result<()> {
+    let BookmarkOptions { prefix, dry_run, rename } = options;
+    let plan =
+        plan_bookmark(workspace, model, from.as_deref(), to, prefix.as_deref(), rename).await?;
+    if dry_run {
+        print_bookmark_name(&plan.name);
+        return Ok(());
     }
+    print_bookmark(&plan.apply()?);
+    Ok(())
+}
 
-    let commit = resolve_single_commit(repo, workspace, "@")?;
-
-    // Check if @ is empty (no description and tree matches parent)
-    let is_empty = commit.description().is_empty() && {
-        if let Some(parent_id) = commit.parent_ids().first() {
-            let parent = repo.store().get_commit(parent_id)?;
-            commit.tree_ids() == parent.tree_ids()
-        } else {
-            false
+/// Prints the result of each environment check with its fix, failing if any check found an error
+fn run_doctor(start_dir: &Path) -> Result<()> {
+    let checks = run_checks(start_dir);
+    let mut text = String::new();
+    for check in &checks {
+        let mark = match check.status {
+            Status::Ok => "✓".green(),
+            Status::Warning => "!".yellow(),
+            Status::Error => "✗".red(),
+        };
+        text.push_str(&format!("{mark} {}: {}\n", check.name.bold(), check.detail));
+        if let Some(fix) = &check.fix {
+            text.push_str(&format!("  {} {fix}\n", "→".dimmed()));
         }
-    };
+    }
+    output::emit(&text, || json!({ "status": "doctor", "checks": checks }));
 
-    if is_empty {
-        debug!("@ is empty, using @- as bookmark target");
-        Ok("@-".to_string())
-    } else {
-        Ok("@".to_string())
+    let errors = checks.iter().filter(|check| check.status == Status::Error).count();
+    if errors > 0 {
+        bail!("{errors} check(s) found a problem");
     }
+    Ok(())
 }
 
-fn find_default_base(repo: &Arc<ReadonlyRepo>) -> Result<String> {
-    let view = repo.view();
-    let remote_name = jj_lib::ref_name::RemoteName::new("origin");
-    let main_ref = RefName::new("main");
+/// Commits in each workspace under `dir` with a `jc` run of its own, so that each picks up its
+/// repository's config, then prints a table of the results. Fails if any of the runs failed.
+fn run_multi(dir: &Path, max_depth: usize, run_args: &[OsString]) -> Result<()> {
+    let workspaces = multi::find_workspaces(dir, max_depth);
+    if workspaces.is_empty() {
+        bail!(pipeline::Error::WorkspaceNotFound(dir.to_path_buf()));
+    }
+    info!(count = workspaces.len(), dir = %dir.display(), "Found workspaces");
+    let results = multi::run_each(&workspaces, run_args)?;
+    output::emit(
+        &multi::format_table(dir, &results),
+        || json!({ "status": "multi", "results": results }),
+    );
+    let failed = results.iter().filter(|result| result.failed()).count();
+    if failed > 0 {
+        bail!("The run failed in {failed} of {} workspaces", results.len());
+    }
+    Ok(())
+}
 
-    let remote_symbol = main_ref.to_remote_symbol(remote_name);
-    let remote_ref = view.get_remote_bookmark(remote_symbol);
-    if remote_ref.target.is_present() {
-        debug!("Using main@origin as base");
-        return Ok("main@origin".to_string());
+/// The arguments for each run of `jc multi`: the options given after `--`, preceded by the global
+/// options set on the command line, which the runs would otherwise not see (those set through the
+/// environment are inherited)
+fn forwarded_args(args: &Args, matches: &ArgMatches, run_args: &[OsString]) -> Vec<OsString> {
+    let from_command_line = |id| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let mut forwarded: Vec<OsString> = Vec::new();
+    if from_command_line("model") {
+        forwarded.extend(["--model".into(), args.model.clone().into()]);
     }
+    if let Some(command_line) = args
+        .generator_cmd
+        .as_ref()
+        .filter(|_| from_command_line("generator_cmd"))
+    {
+        forwarded.extend(["--generator-cmd".into(), command_line.into()]);
+    }
+    if from_command_line("max_claude_calls") {
+        forwarded.extend(["--max-claude-calls".into(), args.max_claude_calls.to_string().into()]);
+    }
+    if let Some(lines) = args.context_lines {
+        forwarded.extend(["--context-lines".into(), lines.to_string().into()]);
+    }
+    if let Some(lines) = args.max_file_lines {
+        forwarded.extend(["--max-file-lines".into(), lines.to_string().into()]);
+    }
+    forwarded.extend(run_args.iter().cloned());
+    forwarded
+}
 
-    let local_ref = view.get_local_bookmark(main_ref);
-    if local_ref.is_present() {
-        debug!("Using main as base");
-        return Ok("main".to_string());
+/// Lists the audit log, or with `prune` removes the logs past `audit.retention_days`
+fn run_logs(start_dir: &Path, prune: bool) -> Result<()> {
+    let Some(dir) = audit::log_dir(start_dir) else {
+        bail!(pipeline::Error::WorkspaceNotFound(start_dir.to_path_buf()));
+    };
+    let retention_days = CONFIG.audit.retention_days;
+    if prune {
+        let removed = audit::prune(&dir, retention_days)?;
+        let text = if retention_days == 0 {
+            "Nothing pruned: audit.retention_days is 0, so logs are kept forever\n".to_string()
+        } else {
+            format!("Removed {removed} log(s) older than {retention_days} day(s)\n")
+        };
+        output::emit(&text, || json!({ "status": "pruned", "removed": removed }));
+        return Ok(());
     }
 
-    bail!("Could not find main@origin or main bookmark. Please specify --from explicitly.")
+    let logs = audit::list(&dir)?;
+    let modified = |log: &audit::LogFile| DateTime::<Local>::from(log.modified);
+    let mut text = String::new();
+    for log in &logs {
+        text.push_str(&format!(
+            "{}  {:>10}  {}\n",
+            modified(log).format("%Y-%m-%d %H:%M:%S").to_string().dimmed(),
+            format_size(log.size),
+            log.path.display()
+        ));
+    }
+    if logs.is_empty() {
+        text = format!("No audit logs in {}\n", dir.display());
+        if !CONFIG.audit.enabled {
+            text.push_str("Set audit.enabled = true in the config to log prompts and responses\n");
+        }
+    }
+    output::emit(&text, || {
+        let logs: Vec<Value> = logs
+            .iter()
+            .map(|log| {
+                json!({
+                    "path": log.path,
+                    "size": log.size,
+                    "modified": modified(log).to_rfc3339(),
+                })
+            })
+            .collect();
+        json!({ "status": "logs", "dir": dir, "logs": logs })
+    });
+    Ok(())
 }
 
-/// Evaluate a revset expression and return the matching commit IDs.
-fn evaluate_revset(
-    repo: &Arc<ReadonlyRepo>,
-    workspace: &Workspace,
-    revset_str: &str,
-) -> Result<Vec<CommitId>> {
-    let settings = repo.settings();
-    let extensions = RevsetExtensions::new();
-    let aliases_map: RevsetAliasesMap = AliasesMap::new();
-    let path_converter = RepoPathUiConverter::Fs {
-        cwd: workspace.workspace_root().to_path_buf(),
-        base: workspace.workspace_root().to_path_buf(),
-    };
-    let workspace_ctx = RevsetWorkspaceContext {
-        path_converter: &path_converter,
-        workspace_name: workspace.workspace_name(),
-    };
-    let context = RevsetParseContext {
-        aliases_map: &aliases_map,
-        local_variables: HashMap::new(),
-        user_email: settings.user_email(),
-        date_pattern_context: DatePatternContext::Local(Local::now()),
-        default_ignored_remote: None,
-        use_glob_by_default: false,
-        extensions: &extensions,
-        workspace: Some(workspace_ctx),
+/// Restore the operation before the most recent auto-commit and report what was rolled back
+async fn run_undo(workspace: &Workspace, force: bool) -> Result<()> {
+    let Some(undone) = undo_auto_commit(workspace, force).await? else {
+        bail!("No '{AUTO_COMMIT_DESCRIPTION}' operation found in the operation log");
     };
+    if undone.later_operations > 0 {
+        eprintln!(
+            "{}",
+            format!(
+                "Also rolled back {} later operation(s); see `jj op log` to redo them",
+                undone.later_operations
+            )
+            .yellow()
+        );
+    }
 
-    let mut diagnostics = RevsetDiagnostics::new();
-    let expression = parse(&mut diagnostics, revset_str, &context)?;
-    let symbol_resolver = SymbolResolver::new(repo.as_ref(), extensions.symbol_resolvers());
-    let resolved = expression.resolve_user_expression(repo.as_ref(), &symbol_resolver)?;
-    let revset = resolved.evaluate(repo.as_ref())?;
-    revset.iter().collect::<Result<Vec<_>, _>>().map_err(Into::into)
+    let operation_id = undone.operation.id().hex();
+    let restored_id = undone.restored.id().hex();
+    let text = format!(
+        "{} {} {} {}\n",
+        "Undid auto-commit".green(),
+        short_hex(&operation_id).yellow(),
+        "by restoring operation".white().dimmed(),
+        short_hex(&restored_id).yellow()
+    );
+    output::emit(&text, || {
+        json!({
+            "status": "undone",
+            "operation_id": operation_id,
+            "restored_operation_id": restored_id,
+            "later_operations": undone.later_operations,
+            "duration_ms": output::elapsed().as_millis(),
+        })
+    });
+    Ok(())
 }
 
-fn get_commit_summaries(
-    repo: &Arc<ReadonlyRepo>,
+/// Generate a pull request description from the messages and aggregate diff of the commits in
+/// `from..to`, then print it or write it to `file`
+async fn run_pr_description(
     workspace: &Workspace,
-    from: &str,
+    model: &str,
+    language: &str,
+    from: Option<String>,
     to: &str,
-) -> Result<String> {
-    let revset_str = format!("{from}..{to}");
-    let commit_ids = evaluate_revset(repo, workspace, &revset_str)?;
-
-    let mut summaries = Vec::new();
-    for commit_id in commit_ids {
-        let commit = repo.store().get_commit(&commit_id)?;
-        let desc = commit.description().trim();
-        if !desc.is_empty() {
-            summaries.push(format!("- {}", desc.lines().next().unwrap_or("")));
+    file: Option<&Path>,
+) -> Result<()> {
+    let description =
+        workflow::pr_description(workspace, model, language, from.as_deref(), to, &mut Terminal)
+            .await?;
+    let markdown = description.to_markdown();
+
+    match file {
+        Some(path) => {
+            std::fs::write(path, &markdown)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            output::emit(&format!("Wrote PR description to {}\n", path.display()), || {
+                json!({
+                    "status": "written",
+                    "title": description.title,
+                    "body": description.body,
+                    "file": path.display().to_string(),
+                })
+            });
         }
+        None => output::emit(
+            &markdown,
+            || json!({ "status": "generated", "title": description.title, "body": description.body }),
+        ),
     }
+    Ok(())
+}
 
-    Ok(summaries.join("\n"))
+/// Snapshot the working copy and print a plain-language explanation of its changes
+async fn run_explain(workspace: &Workspace, model: &str, language: &str) -> Result<()> {
+    let max_new_file_size = parse_size(&CONFIG.snapshot.max_new_file_size)
+        .context("Invalid snapshot.max_new_file_size")?;
+    let explanation =
+        workflow::explain(workspace, model, language, max_new_file_size, &mut Terminal).await?;
+    let Some(explanation) = explanation else {
+        output::skipped("No changes detected, nothing to explain");
+        return Ok(());
+    };
+    output::emit(
+        &format!("{explanation}\n"),
+        || json!({ "status": "explained", "explanation": explanation }),
+    );
+    Ok(())
 }
 
-fn resolve_single_commit(
-    repo: &Arc<ReadonlyRepo>,
-    workspace: &Workspace,
-    rev: &str,
-) -> Result<Commit> {
-    match evaluate_revset(repo, workspace, rev)?.as_slice() {
-        [id] => repo.store().get_commit(id).map_err(Into::into),
-        [] => bail!("Revset resolved to no commits"),
-        _ => bail!("Revset '{rev}' resolved to multiple commits, expected single commit"),
-    }
+fn print_bookmark(bookmark: &Bookmark) {
+    let Bookmark { name, commit, action } = bookmark;
+    let action = match action {
+        BookmarkAction::Created => "Created bookmark",
+        BookmarkAction::Moved => "Moved bookmark",
+        BookmarkAction::Kept => "Kept bookmark",
+        BookmarkAction::Renamed(old) => return print_renamed_bookmark(old, name, commit),
+    };
+    let text = format!(
+        "{} {} {} {}\n",
+        action.green(),
+        name.blue().bold(),
+        "at".white().dimmed(),
+        commit.id().hex()[..8].to_string().yellow()
+    );
+    output::emit(&text, || {
+        json!({
+            "status": action.to_lowercase().replace(' ', "_"),
+            "bookmark": name,
+            "commit_id": commit.id().hex(),
+            "duration_ms": output::elapsed().as_millis(),
+        })
+    });
 }
 
-/// Set bookmark to point to commit. Returns true if bookmark already existed (moved), false if
-/// created. Also exports the bookmark to git refs.
-fn set_bookmark(repo: &Arc<ReadonlyRepo>, name: &str, commit: &Commit) -> Result<bool> {
-    let ref_name = RefName::new(name);
-    let existed = repo.view().get_local_bookmark(ref_name).is_present();
+fn print_renamed_bookmark(old: &str, name: &str, commit: &Commit) {
+    let text = format!(
+        "{} {} {} {} {} {}\n",
+        "Renamed bookmark".green(),
+        old.blue(),
+        "to".white().dimmed(),
+        name.blue().bold(),
+        "at".white().dimmed(),
+        commit.id().hex()[..8].to_string().yellow()
+    );
+    output::emit(&text, || {
+        json!({
+            "status": "renamed_bookmark",
+            "previous_bookmark": old,
+            "bookmark": name,
+            "commit_id": commit.id().hex(),
+            "duration_ms": output::elapsed().as_millis(),
+        })
+    });
+}
 
-    let mut tx = repo.start_transaction();
-    let mut_repo = tx.repo_mut();
+/// Prints the bookmark name alone, for dry runs
+fn print_bookmark_name(name: &str) {
+    output::emit(&format!("{name}\n"), || json!({ "status": "dry_run", "bookmark": name }));
+}
 
-    // Import git refs first to sync state (prevents compare-and-swap failures)
-    let import_options = GitImportOptions {
-        auto_local_bookmark: false,
-        abandon_unreachable_commits: true,
-        remote_auto_track_bookmarks: HashMap::new(),
-    };
-    if let Err(e) = import_refs(mut_repo, &import_options) {
-        warn!(error = %e, "Failed to import git refs");
+/// Validate and format a commit message read from stdin, printing the cleaned message to stdout
+/// and the verdict to stderr. Fails if the message doesn't follow the conventional commit format.
+fn run_review(args: &CommitArgs) -> Result<()> {
+    let mut input = String::new();
+    stdin()
+        .read_to_string(&mut input)
+        .context("Failed to read commit message from stdin")?;
+
+    // Drop git comment lines so the output can be written back to a commit-msg file
+    let message = strip_comment_lines(&input);
+    if message.is_empty() {
+        bail!("Empty commit message");
     }
 
-    let target = RefTarget::normal(commit.id().clone());
-    mut_repo.set_local_bookmark_target(ref_name, target);
+    let formatted = format_message(&message, &format_options(args));
+    let conventional = is_conventional(&formatted);
+    output::emit(&formatted, || json!({ "message": formatted, "conventional": conventional }));
 
-    // Export to git refs - now should succeed since we imported first
-    match export_refs(mut_repo) {
-        Ok(stats) => {
-            if !stats.failed_bookmarks.is_empty() {
-                for (ref_name, reason) in &stats.failed_bookmarks {
-                    warn!(bookmark = %ref_name, reason = ?reason, "Failed to export bookmark");
-                }
-            }
+    if conventional {
+        if !output::is_json() {
+            eprintln!("{}", "Message follows the conventional commit format".green());
         }
-        Err(e) => {
-            warn!(error = %e, "Failed to export refs to git");
+        Ok(())
+    } else {
+        let subject = formatted.lines().next().unwrap_or("");
+        bail!("Subject does not follow the conventional commit format: {subject}")
+    }
+}
+
+/// Generate a message for a diff from stdin or `diff_file` and print just the message
+async fn run_message(
+    diff_file: Option<&Path>,
+    language: &str,
+    no_redact: bool,
+    model: &str,
+) -> Result<()> {
+    if language.eq_ignore_ascii_case(AUTO_LANGUAGE) {
+        bail!("--language auto needs a repository to match; pass a language instead");
+    }
+    let diff = match diff_file {
+        Some(path) => std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the diff from '{}'", path.display()))?,
+        None => {
+            let mut diff = String::new();
+            stdin()
+                .read_to_string(&mut diff)
+                .context("Failed to read the diff from stdin")?;
+            diff
         }
+    };
+    if diff.trim().is_empty() {
+        bail!("Empty diff, nothing to describe");
     }
 
-    let action = if existed { "move" } else { "create" };
-    tx.commit(format!("{action} bookmark '{name}' via ccc-jj"))?;
-    Ok(existed)
+    let file_changes = summarize_unified_diff(&diff);
+    debug!(files = file_changes.paths().count(), diff_len = diff.len(), "Read external diff");
+    let diff = match build_collapse_matcher(&collapse_patterns(None)) {
+        Some(matcher) => collapse_git_diff(&diff, &matcher),
+        None => diff,
+    };
+    let settings = describe_settings(language, model, PromptContext::default());
+    let message = workflow::describe_diff(diff, &file_changes, &settings, !no_redact).await?;
+    output::emit(&message, || json!({ "message": message }));
+    Ok(())
 }
 
-async fn run_commit(workspace: &Workspace, language: &str, model: &str) -> Result<()> {
-    let repo = workspace.repo_loader().load_at_head()?;
-    debug!("Loaded repository at head");
-
-    let wc_commit_id = repo
-        .view()
-        .get_wc_commit_id(workspace.workspace_name())
-        .context("workspace should have a working-copy commit")?;
-    let wc_commit = repo.store().get_commit(wc_commit_id)?;
-    debug!(wc_commit_id = %wc_commit_id.hex(), "Working copy commit");
+/// What `message` and `hook run` describe a diff outside of a commit with: the default formatting
+/// and the configured trailers
+fn describe_settings(language: &str, model: &str, context: PromptContext) -> MessageSettings {
+    MessageSettings {
+        language: language.to_string(),
+        model: model.to_string(),
+        trailers: CONFIG.trailers.extra.clone(),
+        context,
+        ..MessageSettings::default()
+    }
+}
 
-    // Scope the working copy lock - it's automatically released at the end of this block
-    let (current_tree, parent_tree, diff) = {
-        debug!("Starting working copy mutation");
-        let mut locked_wc = workspace.working_copy().start_mutation()?;
+/// Write the completion script for `shell` to stdout
+fn print_completions(shell: Shell) -> Result<()> {
+    generate(shell, &mut Args::command(), env!("CARGO_BIN_NAME"), &mut stdout().lock());
+    Ok(())
+}
 
-        let base_ignores = load_base_ignores(workspace.workspace_root())?;
-        debug!("Loaded base ignores");
+/// Write the man page to stdout
+fn print_man_page() -> Result<()> {
+    Man::new(Args::command().name(env!("CARGO_BIN_NAME")))
+        .render(&mut stdout().lock())
+        .context("Failed to write the man page")
+}
 
-        let snapshot_options = SnapshotOptions {
-            base_ignores,
-            progress: None,
-            start_tracking_matcher: &jj_lib::matchers::EverythingMatcher,
-            force_tracking_matcher: &jj_lib::matchers::NothingMatcher,
-            max_new_file_size: 1024 * 1024 * 100,
-        };
-        debug!("Taking snapshot of working copy");
-        let (current_tree, _stats) = locked_wc.snapshot(&snapshot_options).await?;
-        debug!("Snapshot complete");
+/// Install the `prepare-commit-msg` hook in the git repository containing `start`
+fn run_hook_install(start: &Path, force: bool) -> Result<()> {
+    let root = git::find_root(start)
+        .with_context(|| format!("No git repository found in '{}'", start.display()))?;
+    let exe = std::env::current_exe().context("Failed to find the ccc-jj executable")?;
+    let path = hook::install(&git::hooks_dir(&root)?, &exe, force)?;
+    output::emit(
+        &format!("Installed {}\n", path.display()),
+        || json!({ "status": "installed", "path": path }),
+    );
+    Ok(())
+}
 
-        let parent_tree = if !wc_commit.parent_ids().is_empty() {
-            let parent_commit = repo.store().get_commit(&wc_commit.parent_ids()[0])?;
-            parent_commit.tree()
-        } else {
-            jj_lib::merged_tree::MergedTree::resolved(
-                repo.store().clone(),
-                repo.store().empty_tree_id().clone(),
-            )
-        };
+/// Fill an empty commit message file with a message for the staged changes. Any failure only
+/// warns, leaving git to open the editor as usual rather than blocking the commit.
+async fn run_hook(
+    start: &Path,
+    msg_file: &Path,
+    source: Option<&str>,
+    language: &str,
+    model: &str,
+) -> Result<()> {
+    if let Err(e) = fill_commit_message(start, msg_file, source, language, model).await {
+        warn!(error = %e, "Failed to fill in the commit message, leaving it to the editor");
+    }
+    Ok(())
+}
 
-        if current_tree.tree_ids() == parent_tree.tree_ids() {
-            println!("No changes detected, nothing to commit");
-            return Ok(());
-        }
-        debug!("Changes detected in working copy");
+async fn fill_commit_message(
+    start: &Path,
+    msg_file: &Path,
+    source: Option<&str>,
+    language: &str,
+    model: &str,
+) -> Result<()> {
+    if language.eq_ignore_ascii_case(AUTO_LANGUAGE) {
+        bail!("--language auto is not supported in the hook; pass a language instead");
+    }
+    let root = git::find_root(start)
+        .with_context(|| format!("No git repository found in '{}'", start.display()))?;
+    let settings = describe_settings(language, model, git::prompt_context(&root));
+    workflow::fill_commit_message(&root, msg_file, source, &settings).await?;
+    Ok(())
+}
 
-        if !wc_commit.description().is_empty() {
-            warn!(description = %wc_commit.description(), "Working copy already has description, skipping");
-            return Ok(());
+/// Poll the working copy and commit accumulated changes once they settle (and, with
+/// `--accumulate-until`, once they're large or idle enough). Failures to commit are logged and
+/// watching continues.
+async fn run_watch(workspace_path: &Path, args: &CommitArgs, model: &str) -> Result<()> {
+    let interval = Duration::from_secs(args.watch_interval.max(1));
+    let options = commit_options(args, model)?;
+    output::print("Watching for changes (Ctrl-C to stop)\n");
+
+    let mut on_commit = |committed: ccc_jj_lib::Result<Outcome>| {
+        let printed = committed
+            .map_err(anyhow::Error::from)
+            .and_then(|outcome| print_outcome(outcome, model, None));
+        match printed {
+            Ok(()) => {}
+            // The changes were undone between the poll and the commit
+            Err(e) if CcError::of(&e) == CcError::NothingToCommit => {
+                output::skipped(&e.to_string());
+            }
+            Err(e) => warn!(error = %e, "Failed to commit accumulated changes"),
         }
+    };
+    let until = args.accumulate_until.clone();
+    workflow::watch(workspace_path, &options, interval, until, &mut Terminal, &mut on_commit).await;
+    Ok(())
+}
 
-        debug!("Generating diff");
-        let collapse_matcher = build_collapse_matcher(&CONFIG.diff.collapse_patterns);
-        let diff = get_tree_diff(
-            &repo,
-            &parent_tree,
-            &current_tree,
-            collapse_matcher.as_ref(),
-            CONFIG.diff.max_diff_lines,
-            CONFIG.diff.max_diff_bytes,
-        )
-        .await?;
-        debug!(diff_len = diff.len(), "Diff generated");
-        trace!(diff = %diff, "Full diff content");
+/// Message formatting selected by the command line
+fn format_options(args: &CommitArgs) -> FormatOptions {
+    FormatOptions {
+        wrap: !args.no_wrap,
+        hard_wrap_width: (!args.no_hard_wrap).then_some(CONFIG.format.hard_wrap_width),
+        wrap_cjk: CONFIG.format.wrap_cjk,
+        style: args.style.unwrap_or(CONFIG.format.style),
+    }
+}
 
-        if diff.trim().is_empty() {
-            println!("Empty diff, nothing to commit");
-            return Ok(());
-        }
+/// The message settings selected by the command line
+fn message_settings(args: &CommitArgs, model: &str) -> MessageSettings {
+    MessageSettings {
+        language: args.language.clone(),
+        model: model.to_string(),
+        format_options: format_options(args),
+        always_generate: args.always_claude,
+        describe_conflicts: args.describe_conflicts,
+        allow_conflicts: args.allow_conflicts,
+        examples: args.examples.clone(),
+        ticket: args.ticket.clone(),
+        trailers: args.trailer.clone(),
+        context: args.prompt_context.clone(),
+        description_template: args.description_template.clone(),
+    }
+}
 
-        let diff_lines = diff.lines().count();
-        let diff_bytes = diff.len();
-        let max_lines = CONFIG.diff.max_total_diff_lines;
-        let max_bytes = CONFIG.diff.max_total_diff_bytes;
+/// The commit options selected by the command line
+fn commit_options(args: &CommitArgs, model: &str) -> Result<CommitOptions> {
+    Ok(CommitOptions {
+        message: message_settings(args, model),
+        revision: args.revision.clone(),
+        no_snapshot: args.no_snapshot,
+        max_new_file_size: max_new_file_size(args)?,
+        show_progress: show_progress(args),
+        only: args.only.clone(),
+        exclude: args.exclude.clone(),
+        squash: args.squash,
+        from: args.from.clone(),
+        amend: args.amend,
+        describe: args.describe,
+        no_redact: args.no_redact,
+        diff_only: args.diff_only,
+        preview_prompt: args.preview_prompt,
+        split: args.split,
+        no_cache: args.no_cache,
+        interactive: args.interactive,
+        dry_run: args.dry_run,
+        via_jj: args.via_jj,
+        record_context: args.record_context,
+        bookmark: args.bookmark,
+    })
+}
 
-        if diff_lines > max_lines || diff_bytes > max_bytes {
-            bail!(
-                "Diff too large to generate commit message: {diff_lines} lines / {diff_bytes} bytes (limits: {max_lines} lines / {max_bytes} bytes). \
-                Consider committing in smaller chunks or using `jj describe` to set the message manually."
-            );
-        }
+/// Describe every undescribed commit matching `--revset` from its stored tree (see
+/// [`workflow::describe_revset`])
+async fn run_describe_all(workspace: &Workspace, args: &CommitArgs, model: &str) -> Result<()> {
+    let revset = args.revset.as_deref().context("--revset is required")?;
+    let options = commit_options(args, model)?;
+    let described = workflow::describe_revset(workspace, revset, &options, &mut Terminal).await?;
+
+    // Commit ids change as descendants are rebased, so identify commits by their change id
+    for described in &described {
+        let action = match described.status {
+            workflow::Status::DryRun => "Would describe change ",
+            _ => "Described change ",
+        };
+        let change_id = described.change_id.as_deref().unwrap_or_default();
+        print_commit_summary(action, short_hex(change_id), described);
+        emit_record(described, model);
+    }
+    Ok(())
+}
 
-        (current_tree, parent_tree, diff)
-    }; // locked_wc is automatically dropped here
+/// Let the user pick the changed files to commit in the terminal UI, then commit them the way
+/// `--only` does
+async fn run_tui(workspace: &Workspace, args: &mut CommitArgs, model: &str) -> Result<()> {
+    let incompatible = [
+        (args.watch, "--watch"),
+        (args.revset.is_some(), "--revset"),
+        (args.revision.is_some(), "--revision"),
+        (args.no_snapshot, "--no-snapshot"),
+        (args.stdin_message, "--stdin-message"),
+        (args.describe, "--describe"),
+        (args.from.is_some(), "--from"),
+    ];
+    if let Some((_, option)) = incompatible.iter().find(|(given, _)| *given) {
+        bail!("{option} can't be used with the tui command");
+    }
 
-    info!(language = %language, model = %model, "Generating commit message with Claude");
-    let generator = CommitMessageGenerator::new(language, model);
-    let commit_message = match generator.generate(&diff) {
-        Some(msg) => msg,
-        None => {
-            bail!("Failed to generate commit message, aborting commit");
-        }
+    let files: Vec<FileEntry> = workflow::file_diffs(workspace, &commit_options(args, model)?)
+        .await?
+        .into_iter()
+        .map(|(path, diff)| FileEntry::new(path, diff))
+        .collect();
+    let Some(paths) = review_files(files)? else {
+        output::skipped("Aborted, nothing committed");
+        return Ok(());
     };
-    debug!(commit_message = %commit_message, "Generated commit message");
+    if paths.is_empty() {
+        let reason = "No files selected, nothing to commit".to_string();
+        return Err(pipeline::Error::NothingToCommit(reason).into());
+    }
+    args.only = paths.iter().map(|path| globset::escape(path)).collect();
+    args.exclude.clear();
+    run_commit(workspace, args, model).await
+}
 
-    let file_changes = get_file_change_summary(&parent_tree, &current_tree).await;
+/// Generate a message for the change and write it, then print what became of it
+async fn run_commit(workspace: &Workspace, args: &CommitArgs, model: &str) -> Result<()> {
+    let options = commit_options(args, model)?;
+    let outcome = workflow::commit(workspace, &options, &mut Terminal).await?;
+    print_outcome(outcome, model, args.prompt_file.as_deref())
+}
 
-    info!("Creating commit");
-    create_commit(workspace, &commit_message, current_tree, &file_changes).await?;
-    info!("Commit created successfully");
+/// Commit the pending changes of a git repository without a Jujutsu workspace: the staged
+/// changes, or every change to tracked files if nothing is staged
+async fn run_git_commit(start: &Path, args: &mut CommitArgs, model: &str) -> Result<()> {
+    let root = git::find_root(start)
+        .with_context(|| format!("No git repository found in '{}'", start.display()))?;
+    if let Some(option) = jj_only_option(args) {
+        bail!("{option} requires a Jujutsu workspace");
+    }
 
-    Ok(())
+    let (user_name, user_email) = git::user(&root);
+    args.trailer = requested_trailers(args, &user_name, &user_email)?;
+    args.prompt_context = git::prompt_context(&root);
+    args.ticket = ticket_in([args.prompt_context.branch.as_str()]);
+    if args.language.eq_ignore_ascii_case(AUTO_LANGUAGE) {
+        let recent = git::recent_messages(&root, CONFIG.conventions.sample_size);
+        match_recent_messages(args, recent);
+    }
+
+    let options = commit_options(args, model)?;
+    let outcome = workflow::commit_git(&root, &options, &mut Terminal).await?;
+    print_outcome(outcome, model, args.prompt_file.as_deref())
+}
+
+/// The first option given that only works in a Jujutsu workspace
+fn jj_only_option(args: &CommitArgs) -> Option<&'static str> {
+    [
+        (args.revision.is_some(), "--revision"),
+        (args.revset.is_some(), "--revset"),
+        (args.no_snapshot, "--no-snapshot"),
+        (args.max_new_file_size.is_some(), "--max-new-file-size"),
+        (args.progress, "--progress"),
+        (args.via_jj, "--via-jj"),
+        (args.watch, "--watch"),
+        (args.bookmark, "--bookmark"),
+        (!args.only.is_empty(), "--only"),
+        (!args.exclude.is_empty(), "--exclude"),
+        (args.amend, "--amend"),
+        (args.squash, "--squash"),
+        (args.from.is_some(), "--from"),
+        (args.describe, "--describe"),
+        (args.split, "--split"),
+        (args.record_context, "--record-context"),
+    ]
+    .into_iter()
+    .find_map(|(given, option)| given.then_some(option))
+}
+
+/// The size limit for newly tracked files: `--max-new-file-size`, or `snapshot.max_new_file_size`
+fn max_new_file_size(args: &CommitArgs) -> Result<u64> {
+    match args.max_new_file_size {
+        Some(size) => Ok(size),
+        None => parse_size(&CONFIG.snapshot.max_new_file_size)
+            .context("Invalid snapshot.max_new_file_size"),
+    }
+}
+
+/// Whether snapshots show progress: `--progress` or `--quiet`, or `snapshot.progress`
+fn show_progress(args: &CommitArgs) -> bool {
+    if args.progress || args.quiet { args.progress } else { CONFIG.snapshot.progress }
+}
+
+/// Tells the user which files the snapshot started tracking or left untracked, so it's clear what
+/// will be committed before the diff goes to Claude
+fn report_snapshot(summary: &SnapshotSummary, max_new_file_size: u64) {
+    if !summary.newly_tracked.is_empty() {
+        output::print(&format!(
+            "Started tracking {} new file(s): {}\n",
+            summary.newly_tracked.len(),
+            list_paths(summary.newly_tracked.iter().cloned())
+        ));
+    }
+    if !summary.too_large.is_empty() {
+        let files = summary
+            .too_large
+            .iter()
+            .map(|(path, size)| format!("{path} ({})", format_size(*size)));
+        let notice = format!(
+            "Left {} file(s) larger than {} untracked (raise the limit with --max-new-file-size): {}",
+            summary.too_large.len(),
+            format_size(max_new_file_size),
+            list_paths(files)
+        );
+        output::print(&format!("{}\n", notice.yellow()));
+    }
+    if !summary.untracked.is_empty() {
+        output::print(&format!(
+            "Left {} new path(s) untracked: {}\n",
+            summary.untracked.len(),
+            list_paths(summary.untracked.iter().cloned())
+        ));
+    }
+}
+
+/// Joins up to ten paths with commas, noting how many more there are
+fn list_paths(paths: impl ExactSizeIterator<Item = String>) -> String {
+    const SHOWN: usize = 10;
+    let more = paths.len().saturating_sub(SHOWN);
+    let mut list = paths.take(SHOWN).collect::<Vec<_>>().join(", ");
+    if more > 0 {
+        list.push_str(&format!(" and {more} more"));
+    }
+    list
+}
+
+/// Tells the user how many secrets were masked in the diff
+fn report_masked_secrets(count: usize) {
+    if count > 0 {
+        let notice = format!(
+            "Masked {count} possible secret(s) in the diff (pass --no-redact to keep them)"
+        );
+        output::print(&format!("{}\n", notice.yellow()));
+    }
 }
 
 /// Formats text content inside a box with a title in the top border (with colors).
@@ -713,8 +1654,8 @@ fn format_box_with_title(title: &str, content: &str, width: usize) -> String {
     let mut result = String::new();
 
     // Top border with title: ╭─Title───...───╮
-    let remaining = width + 2 - title_width - 1; // -1 for the leading ─
-    let border = "─".repeat(remaining.max(0));
+    let remaining = (width + 1).saturating_sub(title_width); // -1 for the leading ─
+    let border = "─".repeat(remaining);
     result.push_str(&format!(
         "{}{title}{}{}\n",
         "╭─".white().dimmed(),
@@ -747,15 +1688,20 @@ fn format_box_with_title(title: &str, content: &str, width: usize) -> String {
 
 /// Prints file changes with colored status indicators.
 fn print_file_changes(changes: &FileChangeSummary) {
+    let mut text = String::new();
     for file in &changes.added {
-        println!("  {} {}", "A".green().dimmed(), file.dimmed());
+        text.push_str(&format!("  {} {}\n", "A".green().dimmed(), file.dimmed()));
     }
     for file in &changes.deleted {
-        println!("  {} {}", "D".red().dimmed(), file.dimmed());
+        text.push_str(&format!("  {} {}\n", "D".red().dimmed(), file.dimmed()));
     }
     for file in &changes.modified {
-        println!("  {} {}", "M".yellow().dimmed(), file.dimmed());
+        text.push_str(&format!("  {} {}\n", "M".yellow().dimmed(), file.dimmed()));
+    }
+    for file in &changes.conflicted {
+        text.push_str(&format!("  {} {}\n", "C".magenta().dimmed(), file.dimmed()));
     }
+    output::print(&text);
 }
 
 #[cfg(test)]
@@ -803,4 +1749,10 @@ mod tests {
         // width=72, plus 4 for borders and spaces = 76
         assert_eq!(first_line.width(), 76);
     }
+
+    #[test]
+    fn test_format_box_with_title_longer_than_width() {
+        let result = format_box_with_title("A title much wider than the box", "Hi", 10);
+        assert!(result.starts_with("╭─A title much wider than the box"));
+    }
 }
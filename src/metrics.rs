@@ -0,0 +1,136 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde_json::{Value, json};
+
+/// Timings and sizes collected over a run, reported by `--timings`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Time spent snapshotting the working copy
+    pub snapshot: Duration,
+    /// Time spent building diffs
+    pub diff: Duration,
+    /// Model calls made, including retries
+    pub model_calls: usize,
+    /// Time spent waiting for the model
+    pub model: Duration,
+    /// Bytes of prompt sent to the model
+    pub prompt_bytes: usize,
+    /// Estimated tokens of prompt sent to the model
+    pub prompt_tokens: usize,
+}
+
+/// Collector shared by the whole process
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics {
+    snapshot: Duration::ZERO,
+    diff: Duration::ZERO,
+    model_calls: 0,
+    model: Duration::ZERO,
+    prompt_bytes: 0,
+    prompt_tokens: 0,
+});
+
+/// A stage of the run whose duration is measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Snapshot,
+    Diff,
+    Model,
+}
+
+/// Measures a stage from its creation until it is dropped
+pub struct Timer {
+    stage: Stage,
+    started: Instant,
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        update(|metrics| match self.stage {
+            Stage::Snapshot => metrics.snapshot += elapsed,
+            Stage::Diff => metrics.diff += elapsed,
+            Stage::Model => {
+                metrics.model_calls += 1;
+                metrics.model += elapsed;
+            }
+        });
+    }
+}
+
+/// Starts timing `stage`; the time is added when the returned timer is dropped
+pub fn time(stage: Stage) -> Timer {
+    Timer { stage, started: Instant::now() }
+}
+
+/// Counts a prompt sent to the model
+pub fn record_prompt(bytes: usize, tokens: usize) {
+    update(|metrics| {
+        metrics.prompt_bytes += bytes;
+        metrics.prompt_tokens += tokens;
+    });
+}
+
+/// The metrics collected so far
+pub fn current() -> Metrics {
+    *METRICS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn update(f: impl FnOnce(&mut Metrics)) {
+    f(&mut METRICS.lock().unwrap_or_else(|e| e.into_inner()));
+}
+
+impl Metrics {
+    /// A table of the stages and the prompt size, ending with the `total` wall time
+    pub fn format(&self, total: Duration) -> String {
+        let ms = |duration: Duration| format!("{} ms", duration.as_millis());
+        let rows = [
+            ("snapshot", ms(self.snapshot)),
+            ("diff", ms(self.diff)),
+            ("model", format!("{} ({} calls)", ms(self.model), self.model_calls)),
+            ("prompt", format!("{} bytes (~{} tokens)", self.prompt_bytes, self.prompt_tokens)),
+            ("total", ms(total)),
+        ];
+        rows.iter()
+            .map(|(name, value)| format!("{name:<10}{value}\n"))
+            .collect()
+    }
+
+    /// The metrics as JSON, with durations in milliseconds
+    pub fn to_json(&self, total: Duration) -> Value {
+        json!({
+            "snapshot_ms": self.snapshot.as_millis(),
+            "diff_ms": self.diff.as_millis(),
+            "model_ms": self.model.as_millis(),
+            "model_calls": self.model_calls,
+            "prompt_bytes": self.prompt_bytes,
+            "prompt_tokens": self.prompt_tokens,
+            "total_ms": total.as_millis(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let metrics = Metrics {
+            snapshot: Duration::from_millis(12),
+            diff: Duration::from_millis(3),
+            model_calls: 2,
+            model: Duration::from_millis(1500),
+            prompt_bytes: 4096,
+            prompt_tokens: 1024,
+        };
+        assert_eq!(
+            metrics.format(Duration::from_millis(1600)),
+            "snapshot  12 ms\ndiff      3 ms\nmodel     1500 ms (2 calls)\n\
+             prompt    4096 bytes (~1024 tokens)\ntotal     1600 ms\n"
+        );
+        assert_eq!(metrics.to_json(Duration::ZERO)["model_calls"], 2);
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    env::current_exe,
+    ffi::OsString,
+    fs::read_dir,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use serde::Serialize;
+use serde_json::{Value, from_str};
+use unicode_width::UnicodeWidthStr;
+
+/// Directories that are not searched for workspaces, as they are large and never hold one
+const SKIPPED_DIRS: &[&str] = &["node_modules", "target"];
+
+/// How the run in one workspace ended
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct RepoResult {
+    pub path: PathBuf,
+    /// The `status` of the run's JSON result, e.g. `committed`, `skipped`, or `error`
+    pub status: String,
+    /// The subject of the commit, why nothing was committed, or the error
+    pub detail: String,
+    pub commit_id: Option<String>,
+}
+
+impl RepoResult {
+    pub fn failed(&self) -> bool {
+        self.status == "error"
+    }
+}
+
+/// The jj workspaces under `dir` (including `dir` itself), in path order, searching at most
+/// `max_depth` directory levels below it. Hidden directories and the directories inside a
+/// workspace are not searched.
+pub fn find_workspaces(dir: &Path, max_depth: usize) -> Vec<PathBuf> {
+    if dir.join(".jj").is_dir() {
+        return vec![dir.to_path_buf()];
+    }
+    if max_depth == 0 {
+        return Vec::new();
+    }
+    let mut subdirs: Vec<PathBuf> = read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        // Not following symlinks keeps the search out of cycles
+        .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_dir()))
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            !name.starts_with('.') && !SKIPPED_DIRS.contains(&name.as_ref())
+        })
+        .map(|entry| entry.path())
+        .collect();
+    subdirs.sort();
+    subdirs
+        .iter()
+        .flat_map(|subdir| find_workspaces(subdir, max_depth - 1))
+        .collect()
+}
+
+/// Runs `jc` with `args` in each workspace, one after another, and collects how each run ended.
+/// The runs report to this process as JSON, while their progress goes to stderr as usual.
+pub fn run_each(workspaces: &[PathBuf], args: &[OsString]) -> Result<Vec<RepoResult>> {
+    let exe = current_exe().context("Failed to find the jc executable")?;
+    let mut results = Vec::new();
+    for workspace in workspaces {
+        eprintln!("{} {}", "==>".blue().bold(), workspace.display());
+        let output = Command::new(&exe)
+            .arg("--path")
+            .arg(workspace)
+            .args(["--output", "json"])
+            .args(args)
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .output()
+            .with_context(|| format!("Failed to run {}", exe.display()))?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut result = parse_result(workspace, &stdout);
+        if result.status == "unknown" && !output.status.success() {
+            result.status = "error".to_string();
+            result.detail = format!("exited with {}", output.status);
+        }
+        results.push(result);
+    }
+    Ok(results)
+}
+
+/// Reads how a run ended from the last JSON status line it printed
+fn parse_result(workspace: &Path, stdout: &str) -> RepoResult {
+    let value = stdout
+        .lines()
+        .rev()
+        .filter_map(|line| from_str::<Value>(line).ok())
+        .find(|value| value["status"].is_string() && value["status"] != "timings")
+        .unwrap_or_default();
+    let text = |key: &str| value[key].as_str().unwrap_or_default();
+    let detail = match text("status") {
+        "error" => text("error"),
+        "skipped" => text("reason"),
+        _ => text("message").lines().next().unwrap_or_default(),
+    };
+    RepoResult {
+        path: workspace.to_path_buf(),
+        status: value["status"].as_str().unwrap_or("unknown").to_string(),
+        detail: detail.to_string(),
+        commit_id: value["commit_id"].as_str().map(String::from),
+    }
+}
+
+/// Formats the results as a table with a row per workspace, its path shown relative to `dir`
+pub fn format_table(dir: &Path, results: &[RepoResult]) -> String {
+    let rows: Vec<(String, &RepoResult)> = results
+        .iter()
+        .map(|result| {
+            let path = result.path.strip_prefix(dir).unwrap_or(&result.path);
+            let path = path.display().to_string();
+            (if path.is_empty() { ".".to_string() } else { path }, result)
+        })
+        .collect();
+    let path_width = rows.iter().map(|(path, _)| path.width()).max().unwrap_or(0);
+    let status_width = results.iter().map(|result| result.status.width()).max().unwrap_or(0);
+
+    let mut table = String::new();
+    for (path, result) in rows {
+        let status = format!("{:status_width$}", result.status);
+        let status = match result.status.as_str() {
+            "error" | "unknown" => status.red(),
+            "skipped" => status.dimmed(),
+            _ => status.green(),
+        };
+        let commit = match &result.commit_id {
+            Some(id) => format!("{} ", id.get(..8).unwrap_or(id).yellow()),
+            None => String::new(),
+        };
+        let padding = " ".repeat(path_width - path.width());
+        table.push_str(&format!("{path}{padding}  {status}  {commit}{}\n", result.detail));
+    }
+    let failed = results.iter().filter(|result| result.failed()).count();
+    table.push_str(&format!("\n{} workspaces, {failed} failed\n", results.len()));
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    use super::*;
+
+    #[test]
+    fn test_find_workspaces() {
+        let dir = std::env::temp_dir().join(format!("ccc-jj-multi-{}", std::process::id()));
+        for workspace in ["a/.jj", "a/nested/.jj", "b/c/.jj", ".hidden/.jj", "node_modules/d/.jj"] {
+            create_dir_all(dir.join(workspace)).unwrap();
+        }
+        assert_eq!(find_workspaces(&dir, 3), [dir.join("a"), dir.join("b/c")]);
+        assert_eq!(find_workspaces(&dir, 1), [dir.join("a")]);
+        assert_eq!(find_workspaces(&dir.join("a"), 0), [dir.join("a")]);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_result() {
+        let path = Path::new("repo");
+        let stdout = concat!(
+            r#"{"status":"committed","commit_id":"0123456789ab","message":"feat: add x\n\nBody"}"#,
+            "\n",
+            r#"{"status":"timings","total_ms":10}"#,
+            "\n"
+        );
+        let result = parse_result(path, stdout);
+        assert_eq!(result.status, "committed");
+        assert_eq!(result.detail, "feat: add x");
+        assert_eq!(result.commit_id.as_deref(), Some("0123456789ab"));
+
+        let result = parse_result(path, "{\"status\":\"skipped\",\"reason\":\"No changes\"}\n");
+        assert_eq!((result.status.as_str(), result.detail.as_str()), ("skipped", "No changes"));
+        assert_eq!(parse_result(path, "not json\n").status, "unknown");
+    }
+}
@@ -0,0 +1,197 @@
+use std::{path::Path, sync::LazyLock};
+
+use regex::Regex;
+
+use crate::diff::{FileChangeSummary, added_removed_lines, split_file_diffs};
+
+/// The line counts in a file summary, e.g. `modified (+12 -3 lines, collapsed: ...)`
+static SUMMARY_COUNTS_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\(\+(\d+) -(\d+) lines").expect("Failed to compile summary counts regex")
+});
+
+/// File names of build manifests and lock files, which make a change `build`
+const BUILD_FILES: &[&str] = &[
+    "Cargo.toml",
+    "Cargo.lock",
+    "build.rs",
+    "package.json",
+    "package-lock.json",
+    "yarn.lock",
+    "pnpm-lock.yaml",
+    "pyproject.toml",
+    "poetry.lock",
+    "uv.lock",
+    "setup.py",
+    "go.mod",
+    "go.sum",
+    "Gemfile",
+    "Gemfile.lock",
+    "composer.json",
+    "composer.lock",
+    "Makefile",
+    "CMakeLists.txt",
+    "Dockerfile",
+    "flake.nix",
+    "flake.lock",
+];
+
+/// Writes a commit message without a model, for when none can be reached: the type is guessed
+/// from the paths (`docs`, `test`, `ci`, `build`, otherwise `chore`), the scope is the top-level
+/// directory the files share, and the body lists the changed files with their line counts.
+pub fn offline_message(diff: &str, file_changes: &FileChangeSummary) -> String {
+    let paths: Vec<&str> = file_changes.paths().collect();
+    let commit_type = commit_type(&paths);
+    // A scope that only repeats the type, as in `docs(docs)`, is left out
+    let scope = scope(&paths)
+        .filter(|scope| path_type(&format!("{scope}/")) != Some(commit_type))
+        .map(|scope| format!("({scope})"))
+        .unwrap_or_default();
+    let mut message = format!("{commit_type}{scope}: {}", subject(file_changes, &paths));
+
+    let counts = line_counts(diff);
+    let lines: Vec<String> = paths
+        .iter()
+        .map(|path| match counts.iter().find(|(file, ..)| file == path) {
+            Some((_, added, removed)) => format!("- {path} (+{added} -{removed})"),
+            None => format!("- {path}"),
+        })
+        .collect();
+    if !lines.is_empty() {
+        message.push_str(&format!("\n\n{}", lines.join("\n")));
+    }
+    message
+}
+
+/// The type shared by every path, or `chore` when they differ or fit none
+fn commit_type(paths: &[&str]) -> &'static str {
+    let mut types = paths.iter().map(|path| path_type(path));
+    match types.next().flatten() {
+        Some(first) if types.all(|t| t == Some(first)) => first,
+        _ => "chore",
+    }
+}
+
+fn path_type(path: &str) -> Option<&'static str> {
+    let name = file_name(path);
+    let lower = path.to_lowercase();
+    let in_dir =
+        |dir: &str| lower.starts_with(&format!("{dir}/")) || lower.contains(&format!("/{dir}/"));
+
+    if lower.starts_with(".github/workflows/")
+        || lower.starts_with(".gitlab-ci")
+        || lower.starts_with(".circleci/")
+    {
+        Some("ci")
+    } else if in_dir("tests")
+        || in_dir("test")
+        || in_dir("spec")
+        || ["_test.", ".test.", "_spec.", ".spec."]
+            .iter()
+            .any(|part| name.contains(part))
+        || name.starts_with("test_")
+    {
+        Some("test")
+    } else if in_dir("docs")
+        || in_dir("doc")
+        || [".md", ".rst", ".adoc"].iter().any(|ext| lower.ends_with(ext))
+    {
+        Some("docs")
+    } else if BUILD_FILES.contains(&name) {
+        Some("build")
+    } else {
+        None
+    }
+}
+
+/// The top-level directory of every path, if they all have the same one
+fn scope(paths: &[&str]) -> Option<String> {
+    let mut dirs = paths.iter().map(|path| path.split_once('/').map(|(dir, _)| dir));
+    let first = dirs.next()??;
+    (dirs.all(|dir| dir == Some(first)) && !first.starts_with('.')).then(|| first.to_string())
+}
+
+/// `add`, `remove`, or `update`, followed by the file name or the number of files
+fn subject(file_changes: &FileChangeSummary, paths: &[&str]) -> String {
+    let only = |files: &[String]| !files.is_empty() && files.len() == paths.len();
+    let verb = if only(&file_changes.added) {
+        "add"
+    } else if only(&file_changes.deleted) {
+        "remove"
+    } else {
+        "update"
+    };
+    match paths {
+        [path] => format!("{verb} {}", file_name(path)),
+        _ => format!("{verb} {} files", paths.len()),
+    }
+}
+
+/// Added and removed lines of each file in the diff, read from its summary for files shown that
+/// way
+fn line_counts(diff: &str) -> Vec<(String, usize, usize)> {
+    split_file_diffs(diff)
+        .into_iter()
+        .map(|(path, file_diff)| {
+            let (added, removed) = match SUMMARY_COUNTS_RE.captures(&file_diff) {
+                Some(caps) if !file_diff.contains("\n+++ ") => {
+                    (caps[1].parse().unwrap_or(0), caps[2].parse().unwrap_or(0))
+                }
+                _ => added_removed_lines(&file_diff),
+            };
+            (path, added, removed)
+        })
+        .collect()
+}
+
+fn file_name(path: &str) -> &str {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|path| path.to_string()).collect()
+    }
+
+    #[test]
+    fn test_offline_message() {
+        let diff = "diff --git a/docs/a.md b/docs/a.md\n--- a/docs/a.md\n+++ b/docs/a.md\n\
+            @@ -1 +1,2 @@\n-old\n+new\n+more\n\
+            diff --git a/docs/b.md b/docs/b.md\nmodified (+40 -2 lines, collapsed: exceeds token budget)\n";
+        let file_changes = FileChangeSummary {
+            modified: strings(&["docs/a.md", "docs/b.md"]),
+            ..Default::default()
+        };
+        assert_eq!(
+            offline_message(diff, &file_changes),
+            "docs: update 2 files\n\n- docs/a.md (+2 -1)\n- docs/b.md (+40 -2)"
+        );
+
+        let file_changes = FileChangeSummary {
+            added: strings(&["src/lib.rs"]),
+            ..Default::default()
+        };
+        assert_eq!(offline_message("", &file_changes), "chore(src): add lib.rs\n\n- src/lib.rs");
+    }
+
+    #[test]
+    fn test_commit_type() {
+        assert_eq!(commit_type(&["tests/cli.rs", "src/parser_test.go"]), "test");
+        assert_eq!(commit_type(&["Cargo.toml", "Cargo.lock"]), "build");
+        assert_eq!(commit_type(&[".github/workflows/ci.yml"]), "ci");
+        assert_eq!(commit_type(&["README.md", "src/main.rs"]), "chore");
+        assert_eq!(commit_type(&[]), "chore");
+    }
+
+    #[test]
+    fn test_scope() {
+        assert_eq!(scope(&["src/a.rs", "src/b/c.rs"]).as_deref(), Some("src"));
+        assert_eq!(scope(&["src/a.rs", "README.md"]), None);
+        assert_eq!(scope(&[".github/workflows/ci.yml"]), None);
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use anyhow::Error;
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::{Value, json, to_string};
+
+/// How results are reported
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable text
+    #[default]
+    Text,
+    /// One JSON object per line on stdout; human-readable text goes to stderr
+    Json,
+}
+
+static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+/// Selects the output format for the rest of the run and starts the run's clock
+pub fn init(format: OutputFormat) {
+    let _ = FORMAT.set(format);
+    let _ = STARTED.set(Instant::now());
+    if format == OutputFormat::Json {
+        // Text on stderr is meant for logs then, not a terminal
+        colored::control::set_override(false);
+    }
+}
+
+pub fn is_json() -> bool {
+    FORMAT.get() == Some(&OutputFormat::Json)
+}
+
+/// Time since [`init`]
+pub fn elapsed() -> Duration {
+    STARTED.get().map(Instant::elapsed).unwrap_or_default()
+}
+
+/// Prints human-facing text such as progress notes and message previews. In JSON mode it goes to
+/// stderr so stdout stays machine-readable.
+pub fn print(text: &str) {
+    if is_json() {
+        eprint!("{text}");
+    } else {
+        print!("{text}");
+    }
+}
+
+/// Reports a result: `text` in text mode, or the JSON value on its own line in JSON mode
+pub fn emit(text: &str, json: impl FnOnce() -> Value) {
+    if is_json() {
+        println!("{}", json());
+    } else {
+        print!("{text}");
+    }
+}
+
+/// Reports that the run ended without doing anything, and why
+pub fn skipped(reason: &str) {
+    emit(&format!("{reason}\n"), || json!({ "status": "skipped", "reason": reason }));
+}
+
+/// A commit that was created, described, or (in a dry run) proposed
+#[derive(Debug, Serialize)]
+pub struct CommitRecord<'a> {
+    pub status: &'static str,
+    /// `None` for commits that only exist in a dry-run plan
+    pub commit_id: Option<String>,
+    pub change_id: Option<String>,
+    pub message: &'a str,
+    pub files_changed: Vec<String>,
+    pub collapsed_files: Vec<String>,
+    pub model: &'a str,
+    pub duration_ms: u128,
+}
+
+/// Emits the record in JSON mode; text mode shows commits as boxes instead
+pub fn commit(record: &CommitRecord<'_>) {
+    if is_json()
+        && let Ok(line) = to_string(record)
+    {
+        println!("{line}");
+    }
+}
+
+/// Emits a failed run as `{"status": "error", "error": ..., "causes": [...]}`
+pub fn error(error: &Error) {
+    println!("{}", error_value(error));
+}
+
+fn error_value(error: &Error) -> Value {
+    let causes: Vec<String> = error.chain().skip(1).map(ToString::to_string).collect();
+    json!({ "status": "error", "error": error.to_string(), "causes": causes })
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[test]
+    fn test_error_value_lists_causes() {
+        let error = anyhow!("curl exited with 7").context("Failed to generate commit message");
+        assert_eq!(
+            error_value(&error),
+            json!({
+                "status": "error",
+                "error": "Failed to generate commit message",
+                "causes": ["curl exited with 7"],
+            })
+        );
+    }
+}
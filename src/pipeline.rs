@@ -0,0 +1,1678 @@
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Write},
+    iter::once,
+    path::PathBuf,
+    process::{Command, ExitStatus, Stdio},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    thread,
+};
+
+use chrono::Local;
+use jj_lib::{
+    backend::CommitId,
+    commit::Commit,
+    dsl_util::AliasesMap,
+    merged_tree::MergedTree,
+    object_id::ObjectId,
+    op_store::OperationId,
+    op_walk::walk_ancestors,
+    operation::Operation,
+    repo::{ReadonlyRepo, Repo},
+    repo_path::{RepoPath, RepoPathUiConverter},
+    revset::{
+        self, RevsetAliasesMap, RevsetDiagnostics, RevsetExtensions, RevsetParseContext,
+        RevsetWorkspaceContext, SymbolResolver,
+    },
+    time_util::DatePatternContext,
+    transaction::Transaction,
+    working_copy::{LockedWorkingCopy, SnapshotOptions, SnapshotProgress, UntrackedReason},
+    workspace::Workspace,
+};
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+#[cfg(feature = "net")]
+use crate::issue::linked_issue;
+pub use crate::workspace::{
+    find_named_workspace, find_workspace, lock_working_copy, set_lock_wait,
+};
+use crate::{
+    chunk_summary::ChunkSummarizer,
+    commit_message_generator::{
+        CommitMessageGenerator, FormatOptions, GeneratedMessage, apply_style, format_message,
+        templated_message,
+    },
+    config::{CONFIG, LintAction},
+    conventions::{find_style_guide, format_examples, suggested_type},
+    dependency_update::{
+        format_dependency_changes, is_dependency_update, parse_dependency_changes,
+    },
+    diff::{
+        ConflictSummary, DiffLimits, DiffPriority, DiffStyle, FileChangeSummary, PathFilter,
+        WordDiff, build_collapse_matcher, count_changed_lines, escape_path, get_conflict_summaries,
+        get_file_change_summary, get_tree_diff, read_gitattributes,
+    },
+    generator::CallBudgetExhausted,
+    gitattributes::collapse_patterns,
+    lint::{fix, format_violations, lint},
+    metrics::{self, Stage},
+    offline::offline_message,
+    progress::Progress,
+    project::detect_stack,
+    prompt_template::PromptContext,
+    provenance::Provenance,
+    redaction::redact,
+    split::{
+        SplitCommit, SplitGenerator, TreeChange, apply_changes, changed_paths, collect_changes,
+    },
+    ticket::apply_ticket,
+    trailers::append_trailers,
+    workspace::{auto_track_matcher, git_head_branch, git_remote_url, load_base_ignores},
+};
+
+/// Description of the operations that [`write_commit`] records, which `ccc-jj undo` looks for
+pub const AUTO_COMMIT_DESCRIPTION: &str = "auto-commit via ccc-jj";
+
+/// Description of the operations that [`squash_into_parent`] records
+pub const SQUASH_DESCRIPTION: &str = "squash into parent via ccc-jj";
+
+/// Errors returned by the pipeline
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Neither the start directory nor any of its parents contains a `.jj` directory
+    #[error("No Jujutsu workspace found in '{}' or any parent directory", .0.display())]
+    WorkspaceNotFound(PathBuf),
+
+    /// The repository has no workspace of the name given with `--workspace`, or its directory is
+    /// gone
+    #[error("No workspace named '{0}' in this repository (see `jj workspace list`)")]
+    UnknownWorkspace(String),
+
+    /// The workspace has no working-copy commit
+    #[error("workspace should have a working-copy commit")]
+    NoWorkingCopyCommit,
+
+    /// The commit has no single parent that its changes can be squashed into
+    #[error("Cannot squash: {0}")]
+    CannotSquash(&'static str),
+
+    /// A revset could not be parsed, resolved, or evaluated
+    #[error("Invalid revset '{revset}'")]
+    Revset {
+        revset: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    /// A revision resolved to no commit
+    #[error("Revset '{0}' resolved to no commits")]
+    NoSuchCommit(String),
+
+    /// A revision resolved to several commits where one was expected
+    #[error("Revset '{0}' resolved to multiple commits, expected single commit")]
+    AmbiguousRevision(String),
+
+    /// A `from..to` range has no commits
+    #[error("No commits found between {from} and {to}")]
+    EmptyRange { from: String, to: String },
+
+    /// Neither `main@origin` nor `main` exists to start a range from
+    #[error("Could not find main@origin or main bookmark. Please specify --from explicitly.")]
+    NoBaseBookmark,
+
+    /// A jj-lib operation failed
+    #[error("{context}")]
+    Jj {
+        context: &'static str,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    /// The diff between the trees could not be built
+    #[error("Failed to generate diff: {0:#}")]
+    Diff(anyhow::Error),
+
+    /// The `--only` or `--exclude` globs could not be parsed
+    #[error("{0:#}")]
+    PathFilter(anyhow::Error),
+
+    /// A generator could not be run, e.g. because its prompt template failed to render
+    #[error("{0:#}")]
+    Generator(anyhow::Error),
+
+    /// The run made as many model calls as `--max-claude-calls` allows
+    #[error(transparent)]
+    CallBudgetExhausted(#[from] CallBudgetExhausted),
+
+    /// The prompt template could not be rendered for the change
+    #[error("{0:#}")]
+    Prompt(anyhow::Error),
+
+    /// Every generation attempt failed or returned unusable output
+    #[error("Failed to generate commit message")]
+    GenerationFailed,
+
+    /// The model returned no usable split plan
+    #[error("Failed to plan the split, aborting commit")]
+    SplitFailed,
+
+    /// The model returned no valid bookmark name
+    #[error("Failed to generate bookmark name")]
+    BookmarkNameFailed,
+
+    /// The model returned no usable pull request description
+    #[error("Failed to generate PR description")]
+    PrDescriptionFailed,
+
+    /// The model returned no explanation
+    #[error("Failed to generate an explanation")]
+    ExplanationFailed,
+
+    /// There were no changes to commit or describe. Callers may report this as a skipped run
+    /// rather than a failure.
+    #[error("{0}")]
+    NothingToCommit(String),
+
+    /// The changes have unresolved conflicts that weren't allowed to be committed
+    #[error("{0}")]
+    UnresolvedConflicts(String),
+
+    /// A `git` command failed in a repository without a Jujutsu workspace
+    #[error("{0:#}")]
+    Git(anyhow::Error),
+
+    /// The commit message file handed over by a git hook could not be read or written
+    #[error("Failed to access '{}'", path.display())]
+    MessageFile {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// Reviewing the message with the user failed
+    #[error("{0:#}")]
+    Review(anyhow::Error),
+
+    /// The diff exceeds `diff.max_total_diff_lines` or `diff.max_total_diff_bytes`
+    #[error(
+        "Diff too large to generate commit message: {lines} lines / {bytes} bytes (limits: \
+         {max_lines} lines / {max_bytes} bytes). Consider committing in smaller chunks or setting \
+         the message manually."
+    )]
+    DiffTooLarge { lines: usize, bytes: usize, max_lines: usize, max_bytes: usize },
+
+    /// The generated message breaks `[lint]` rules and `lint.on_failure` is `abort`
+    #[error("Generated commit message breaks the lint rules:\n{0}")]
+    Lint(String),
+
+    /// Undoing the auto-commit would also roll back the operations made after it
+    #[error(
+        "{count} operation(s) were made after auto-commit {operation} and would be rolled back \
+         with it. Pass --force to undo anyway, or `jj op revert {operation}` to revert only the \
+         auto-commit"
+    )]
+    LaterOperations { count: usize, operation: String },
+
+    /// The `jj` CLI, which `--via-jj` hands the message to, is not installed
+    #[error("`jj` was not found in PATH; install jj or drop --via-jj")]
+    JjNotFound,
+
+    /// The `jj` CLI could not be run
+    #[error("Failed to run jj")]
+    JjSpawn(#[source] std::io::Error),
+
+    /// A `jj` command exited with an error
+    #[error("`jj {command}` failed with {status}")]
+    JjFailed { command: String, status: ExitStatus },
+
+    /// Another process held the working copy lock for longer than `--lock-wait` allows
+    #[error("Failed to lock the working copy")]
+    WorkingCopyLocked(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    /// The working-copy commit changed between the snapshot and writing the commit, so the
+    /// snapshotted tree may no longer belong to it
+    #[error(
+        "The working copy moved from {expected} to {found} after it was snapshotted (e.g. by \
+         another jj command), so nothing was committed. Run again to commit its current state"
+    )]
+    WorkingCopyMoved { expected: String, found: String },
+
+    /// The operation was recorded, but the working copy still points at the one before it
+    #[error(
+        "Recorded operation {operation}, but failed to update the working copy to it. Run `jj \
+         workspace update-stale` to finish, or `jj op restore {previous}` to go back to before"
+    )]
+    WorkingCopyNotUpdated {
+        operation: String,
+        previous: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Wraps a jj-lib error with what was being done
+pub(crate) fn jj<E>(context: &'static str) -> impl FnOnce(E) -> Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    move |source| Error::Jj { context, source: Box::new(source) }
+}
+
+/// Wraps an error from a generator, telling an exhausted call budget apart from the rest
+pub(crate) fn generator_error(error: anyhow::Error) -> Error {
+    match error.downcast::<CallBudgetExhausted>() {
+        Ok(exhausted) => Error::CallBudgetExhausted(exhausted),
+        Err(error) => Error::Generator(error),
+    }
+}
+
+/// Runs a blocking model call on tokio's blocking threads, so that async callers don't hold up a
+/// runtime worker until the model answers
+pub(crate) async fn blocking<T, F>(call: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::task::spawn_blocking(call).await {
+        Ok(result) => result,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// What a snapshot did besides recording changes to tracked files
+#[derive(Debug, Default)]
+pub struct SnapshotSummary {
+    /// Files tracked for the first time
+    pub newly_tracked: Vec<String>,
+    /// New files left untracked for exceeding the size limit, with their size in bytes
+    pub too_large: Vec<(String, u64)>,
+    /// New files left untracked for other reasons
+    pub untracked: Vec<String>,
+}
+
+/// Snapshot the working copy and return its tree, along with what the snapshot started or
+/// declined to track. New files are tracked if they match jj's `snapshot.auto-track` setting and
+/// are no larger than `max_new_file_size` bytes. With `show_progress`, a spinner on stderr counts
+/// the files scanned and shows the latest one, so a large working copy doesn't look stuck; when
+/// stderr isn't a terminal, a line is logged at the start and end instead.
+///
+/// The working copy lock is only held for the snapshot itself and released before returning, so
+/// diffing and message generation don't block concurrent `jj` invocations.
+pub async fn snapshot_workspace(
+    workspace: &Workspace,
+    max_new_file_size: u64,
+    show_progress: bool,
+) -> Result<(MergedTree, SnapshotSummary)> {
+    debug!("Starting working copy mutation");
+    let mut locked_wc = lock_working_copy(workspace).await?;
+    let _timer = metrics::time(Stage::Snapshot);
+
+    let base_ignores = load_base_ignores(workspace);
+    debug!("Loaded base ignores");
+    let auto_track = auto_track_matcher(workspace)?;
+
+    let spinner = if show_progress {
+        Progress::start("Snapshotting the working copy...")
+    } else {
+        Progress::hidden()
+    };
+    let scanned = AtomicUsize::new(0);
+    let progress = |path: &RepoPath| {
+        let count = scanned.fetch_add(1, Ordering::Relaxed) + 1;
+        spinner.set_message(format!(
+            "Snapshotting the working copy ({count} files): {}",
+            path.as_internal_file_string()
+        ));
+    };
+    let snapshot_options = SnapshotOptions {
+        base_ignores,
+        progress: show_progress.then_some(&progress as &SnapshotProgress),
+        start_tracking_matcher: auto_track.as_ref(),
+        force_tracking_matcher: &jj_lib::matchers::NothingMatcher,
+        max_new_file_size,
+    };
+    debug!(max_new_file_size, "Taking snapshot of working copy");
+    let old_tree = locked_wc.old_tree().clone();
+    let (tree, stats) = locked_wc
+        .snapshot(&snapshot_options)
+        .await
+        .map_err(jj("Failed to snapshot the working copy"))?;
+    spinner.finish();
+    debug!(scanned = scanned.into_inner(), "Snapshot complete, releasing working copy lock");
+    drop(locked_wc);
+
+    let mut summary = SnapshotSummary {
+        newly_tracked: get_file_change_summary(&old_tree, &tree).await.added,
+        ..SnapshotSummary::default()
+    };
+    for (path, reason) in stats.untracked_paths {
+        let path = path.as_internal_file_string().to_string();
+        match reason {
+            UntrackedReason::FileTooLarge { size, .. } => summary.too_large.push((path, size)),
+            _ => summary.untracked.push(path),
+        }
+    }
+    debug!(?summary, "Snapshot summary");
+    Ok((tree, summary))
+}
+
+/// An auto-commit rolled back by [`undo_auto_commit`]
+#[derive(Debug)]
+pub struct UndoneAutoCommit {
+    /// The operation that made the commit
+    pub operation: Operation,
+    /// The operation before it, whose state was restored
+    pub restored: Operation,
+    /// Operations made after the auto-commit, which were rolled back with it (only with `force`)
+    pub later_operations: usize,
+}
+
+/// Restores the repository to the operation before the most recent [`AUTO_COMMIT_DESCRIPTION`]
+/// operation, as `jj op restore` does. The files in the working copy are left alone, so the
+/// committed changes show up in the working-copy commit again.
+///
+/// Restoring also rolls back every operation made after the auto-commit, so unless `force` is
+/// given, that fails with [`Error::LaterOperations`]. Returns `None` if the operation log has no
+/// auto-commit.
+pub async fn undo_auto_commit(
+    workspace: &Workspace,
+    force: bool,
+) -> Result<Option<UndoneAutoCommit>> {
+    let locked_wc = lock_working_copy(workspace).await?;
+    let repo = load_repo(workspace)?;
+
+    let mut later_operations = 0;
+    let mut found = None;
+    for op in walk_ancestors(std::slice::from_ref(repo.operation())) {
+        let op = op.map_err(jj("Failed to read the operation log"))?;
+        if op.metadata().description.starts_with(AUTO_COMMIT_DESCRIPTION) {
+            found = Some(op);
+            break;
+        }
+        later_operations += 1;
+    }
+    let Some(operation) = found else {
+        return Ok(None);
+    };
+    // Only the root operation has no parent, and it is never an auto-commit
+    let Some(restored) = operation.parents().next() else {
+        return Ok(None);
+    };
+    let restored = restored.map_err(jj("Failed to read the operation log"))?;
+    if later_operations > 0 && !force {
+        return Err(Error::LaterOperations {
+            count: later_operations,
+            operation: short_id(operation.id().hex()),
+        });
+    }
+    debug!(
+        operation = %operation.id().hex(),
+        restored = %restored.id().hex(),
+        later_operations,
+        "Undoing auto-commit"
+    );
+
+    let view = restored.view().map_err(jj("Failed to read the operation's view"))?;
+    let mut tx = repo.start_transaction();
+    tx.repo_mut().set_view(view.store_view().clone());
+    let new_repo = tx
+        .commit(format!("undo {AUTO_COMMIT_DESCRIPTION} operation {}", operation.id().hex()))
+        .map_err(jj("Failed to commit the transaction"))?;
+    finish_working_copy(locked_wc, &repo, &new_repo).await?;
+
+    Ok(Some(UndoneAutoCommit { operation, restored, later_operations }))
+}
+
+/// Points the locked working copy at the operation `new_repo` was committed as, releasing the
+/// lock. The lock should be taken before `repo` is loaded, so that no other process can move the
+/// working copy between the transaction and this update.
+///
+/// If the update fails, the operation is already recorded: the error says so and how to recover.
+pub async fn finish_working_copy(
+    locked_wc: Box<dyn LockedWorkingCopy>,
+    repo: &ReadonlyRepo,
+    new_repo: &ReadonlyRepo,
+) -> Result<()> {
+    let operation = new_repo.operation().id();
+    locked_wc
+        .finish(operation.clone())
+        .await
+        .map_err(|source| Error::WorkingCopyNotUpdated {
+            operation: short_id(operation.hex()),
+            previous: short_id(repo.operation().id().hex()),
+            source: Box::new(source),
+        })?;
+    Ok(())
+}
+
+/// The first 12 digits of a commit or operation ID, as `jj log` and `jj op log` show them
+fn short_id(hex: String) -> String {
+    hex.get(..12).map(String::from).unwrap_or(hex)
+}
+
+/// Loads the repository of the workspace at its latest operation
+pub fn load_repo(workspace: &Workspace) -> Result<Arc<ReadonlyRepo>> {
+    workspace
+        .repo_loader()
+        .load_at_head()
+        .map_err(jj("Failed to load the repository"))
+}
+
+/// The working-copy commit of the workspace
+pub fn working_copy_commit(repo: &ReadonlyRepo, workspace: &Workspace) -> Result<Commit> {
+    let wc_commit_id = repo
+        .view()
+        .get_wc_commit_id(workspace.workspace_name())
+        .ok_or(Error::NoWorkingCopyCommit)?;
+    repo.store()
+        .get_commit(wc_commit_id)
+        .map_err(jj("Failed to read the working-copy commit"))
+}
+
+/// The tree of the commit's first parent, or the empty tree for a root commit
+pub fn first_parent_tree(repo: &ReadonlyRepo, commit: &Commit) -> Result<MergedTree> {
+    Ok(match commit.parent_ids().first() {
+        Some(parent_id) => repo
+            .store()
+            .get_commit(parent_id)
+            .map_err(jj("Failed to read the parent commit"))?
+            .tree(),
+        None => MergedTree::resolved(repo.store().clone(), repo.store().empty_tree_id().clone()),
+    })
+}
+
+/// The parent that the commit's changes are squashed into, which must be its only parent and not
+/// the root commit
+pub fn squash_parent(repo: &ReadonlyRepo, commit: &Commit) -> Result<Commit> {
+    let [parent_id] = commit.parent_ids() else {
+        return Err(Error::CannotSquash("the commit is a merge"));
+    };
+    if parent_id == repo.store().root_commit_id() {
+        return Err(Error::CannotSquash("its parent is the root commit"));
+    }
+    repo.store()
+        .get_commit(parent_id)
+        .map_err(jj("Failed to read the parent commit"))
+}
+
+/// Evaluates a revset expression in the workspace and returns the matching commit IDs, newest
+/// first
+pub fn evaluate_revset(
+    repo: &ReadonlyRepo,
+    workspace: &Workspace,
+    revset_str: &str,
+) -> Result<Vec<CommitId>> {
+    let settings = repo.settings();
+    let extensions = RevsetExtensions::new();
+    let aliases_map: RevsetAliasesMap = AliasesMap::new();
+    let path_converter = RepoPathUiConverter::Fs {
+        cwd: workspace.workspace_root().to_path_buf(),
+        base: workspace.workspace_root().to_path_buf(),
+    };
+    let workspace_ctx = RevsetWorkspaceContext {
+        path_converter: &path_converter,
+        workspace_name: workspace.workspace_name(),
+    };
+    let context = RevsetParseContext {
+        aliases_map: &aliases_map,
+        local_variables: HashMap::new(),
+        user_email: settings.user_email(),
+        date_pattern_context: DatePatternContext::Local(Local::now()),
+        default_ignored_remote: None,
+        use_glob_by_default: false,
+        extensions: &extensions,
+        workspace: Some(workspace_ctx),
+    };
+
+    let mut diagnostics = RevsetDiagnostics::new();
+    let expression =
+        revset::parse(&mut diagnostics, revset_str, &context).map_err(invalid(revset_str))?;
+    let symbol_resolver = SymbolResolver::new(repo, extensions.symbol_resolvers());
+    let resolved = expression
+        .resolve_user_expression(repo, &symbol_resolver)
+        .map_err(invalid(revset_str))?;
+    let revset = resolved.evaluate(repo).map_err(invalid(revset_str))?;
+    revset
+        .iter()
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(invalid(revset_str))
+}
+
+/// Wraps an error from a step of [`evaluate_revset`] with the revset
+fn invalid<E>(revset: &str) -> impl FnOnce(E) -> Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let revset = revset.to_string();
+    move |source| Error::Revset { revset, source: Box::new(source) }
+}
+
+/// The one commit that a revision resolves to
+pub fn resolve_single_commit(
+    repo: &ReadonlyRepo,
+    workspace: &Workspace,
+    rev: &str,
+) -> Result<Commit> {
+    match evaluate_revset(repo, workspace, rev)?.as_slice() {
+        [id] => repo.store().get_commit(id).map_err(jj("Failed to read the commit")),
+        [] => Err(Error::NoSuchCommit(rev.to_string())),
+        _ => Err(Error::AmbiguousRevision(rev.to_string())),
+    }
+}
+
+/// The descriptions of up to `count` of the commit's nearest first-parent ancestors, newest first,
+/// skipping undescribed ones
+pub fn recent_descriptions(
+    repo: &ReadonlyRepo,
+    commit: &Commit,
+    count: usize,
+) -> Result<Vec<String>> {
+    let mut descriptions = Vec::with_capacity(count);
+    let mut next = commit.parent_ids().first().cloned();
+    // Bound the walk in case most of the history is undescribed
+    let mut remaining = count.saturating_mul(10);
+    while descriptions.len() < count
+        && remaining > 0
+        && let Some(id) = next.take().filter(|id| id != repo.store().root_commit_id())
+    {
+        let ancestor = repo
+            .store()
+            .get_commit(&id)
+            .map_err(jj("Failed to read an ancestor commit"))?;
+        if !ancestor.description().trim().is_empty() {
+            descriptions.push(ancestor.description().to_string());
+        }
+        next = ancestor.parent_ids().first().cloned();
+        remaining -= 1;
+    }
+    Ok(descriptions)
+}
+
+/// Names of the bookmarks on the working-copy commit and its parents, followed by the checked-out
+/// branch of a colocated git repository
+pub fn branch_names(repo: &ReadonlyRepo, workspace: &Workspace) -> Vec<String> {
+    let mut names = Vec::new();
+    if let Ok(commit) = working_copy_commit(repo, workspace) {
+        for id in once(commit.id()).chain(commit.parent_ids()) {
+            names.extend(
+                repo.view()
+                    .local_bookmarks_for_commit(id)
+                    .map(|(name, _)| name.as_str().to_string()),
+            );
+        }
+    }
+    names.extend(git_head_branch(workspace.repo_path()));
+    names
+}
+
+/// Gathers what the prompt template can use about the working-copy commit: its bookmark or
+/// branch, the descriptions of up to `prompt.recent_commits` ancestors, the repository name, and
+/// the project's commit style guide and tech stack
+pub fn prompt_context(repo: &ReadonlyRepo, workspace: &Workspace) -> Result<PromptContext> {
+    let commit = working_copy_commit(repo, workspace)?;
+    let root = workspace.workspace_root();
+    let stack = detect_stack(root);
+    Ok(PromptContext {
+        branch: branch_names(repo, workspace).into_iter().next().unwrap_or_default(),
+        recent_commits: recent_descriptions(repo, &commit, CONFIG.prompt.recent_commits)?,
+        repo_name: root
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        style_guide: find_style_guide(root),
+        project_type: stack.project_type,
+        frameworks: stack.frameworks,
+        remote_url: git_remote_url(workspace.repo_path()).unwrap_or_default(),
+        root: root.to_path_buf(),
+        issue: String::new(),
+    })
+}
+
+/// Builds the diff sent to the model for the change between two trees, following the `[diff]`
+/// settings (collapse patterns, priorities, token budget, size limits, rename detection). With
+/// `redact_secrets`, anything matching `redaction.patterns` is masked.
+///
+/// Returns the diff and the number of masked secrets.
+pub async fn diff_to_prompt(
+    repo: &ReadonlyRepo,
+    from_tree: &MergedTree,
+    to_tree: &MergedTree,
+    redact_secrets: bool,
+) -> Result<(String, usize)> {
+    let gitattributes = read_gitattributes(repo, to_tree).await;
+    let collapse_matcher = build_collapse_matcher(&collapse_patterns(gitattributes.as_deref()));
+    let diff = get_tree_diff(
+        repo,
+        from_tree,
+        to_tree,
+        collapse_matcher.as_ref(),
+        &DiffPriority::new(&CONFIG.diff.priority.first, &CONFIG.diff.priority.last),
+        DiffLimits {
+            token_budget: CONFIG.diff.token_budget,
+            max_lines: CONFIG.diff.max_total_diff_lines,
+            max_bytes: CONFIG.diff.max_total_diff_bytes,
+        },
+        &DiffStyle {
+            rename_similarity: CONFIG.diff.rename_similarity,
+            word_diff: WordDiff::new(
+                &CONFIG.diff.word_diff.patterns,
+                CONFIG.diff.word_diff.max_bytes,
+            ),
+            context_lines: CONFIG.diff.context_lines,
+            max_file_lines: CONFIG.diff.max_file_lines,
+        },
+    )
+    .await
+    .map_err(Error::Diff)?;
+    Ok(if redact_secrets { redact(&diff) } else { (diff, 0) })
+}
+
+/// Fails if the diff exceeds `diff.max_total_diff_lines` or `diff.max_total_diff_bytes`
+pub fn check_diff_size(diff: &str) -> Result<()> {
+    let (lines, bytes) = (diff.lines().count(), diff.len());
+    let max_lines = CONFIG.diff.max_total_diff_lines;
+    let max_bytes = CONFIG.diff.max_total_diff_bytes;
+    if lines > max_lines || bytes > max_bytes {
+        return Err(Error::DiffTooLarge { lines, bytes, max_lines, max_bytes });
+    }
+    Ok(())
+}
+
+/// The diff to generate a message from: the diff itself within `diff.max_total_diff_lines` and
+/// `diff.max_total_diff_bytes`, or else, with `summarize.enabled`, summaries of its chunks.
+///
+/// Summarizing blocks until the model answers; async code should run it with
+/// `tokio::task::spawn_blocking`.
+pub fn fit_diff(diff: String, language: &str, model: &str) -> Result<String> {
+    let Err(too_large) = check_diff_size(&diff) else {
+        return Ok(diff);
+    };
+    if !CONFIG.summarize.enabled {
+        return Err(too_large);
+    }
+    warn!(bytes = diff.len(), "Diff too large for one prompt, summarizing it in chunks");
+    ChunkSummarizer::new(language, model)
+        .summarize(&diff)
+        .map_err(generator_error)
+}
+
+/// How to generate a commit message
+pub struct MessageOptions<'a> {
+    pub language: &'a str,
+    pub model: &'a str,
+    pub format_options: FormatOptions,
+    /// Call the model even for diffs below `diff.min_lines_for_claude`
+    pub always_generate: bool,
+    /// Formatted list of unresolved conflicts to note in the message
+    pub conflicts: Option<&'a str>,
+    /// Formatted list of conflicted paths committed as is, whose conflict markers the model should
+    /// not describe as changes
+    pub conflict_markers: Option<&'a str>,
+    /// A description to update rather than replace
+    pub existing_description: Option<&'a str>,
+    /// The user's feedback on a previously generated message
+    pub feedback: Option<&'a str>,
+    /// Recent commit messages whose language and style to match, newest first
+    pub examples: &'a [String],
+    /// Ticket ID added to the message as configured by `ticket.placement`
+    pub ticket: Option<&'a str>,
+    /// `Key: value` trailers appended to the message, e.g. `Signed-off-by: ...`
+    pub trailers: &'a [String],
+    /// The branch, recent commits, and repository name for the prompt template
+    pub context: &'a PromptContext,
+}
+
+/// Generates a commit message for a diff from [`diff_to_prompt`].
+///
+/// Changes that only touch `dependency_update.lockfiles` get
+/// `dependency_update.lockfile_message_template`, and diffs below `diff.min_lines_for_claude`
+/// changed lines `diff.trivial_message_template`, without a model call, unless the options ask for
+/// more than a plain message. Changes that only touch dependency manifests and lock files use the
+/// dependency update prompt. Messages from the
+/// model are checked against the `[lint]` rules. If no model can be reached and
+/// `generator.offline_fallback` is set, a message is put together from the paths by
+/// [`offline_message`], or else with `generator.default_message_fallback`,
+/// `generator.default_commit_message` is used. The ticket and trailers are added last.
+///
+/// Blocks until the model answers; async code should run it with `tokio::task::spawn_blocking`.
+pub fn generate_message(
+    diff: &str,
+    file_changes: &FileChangeSummary,
+    options: &MessageOptions<'_>,
+) -> Result<GeneratedMessage> {
+    let mut generated = if let Some(template) = message_template(diff, file_changes, options) {
+        templated_message(template, file_changes.paths(), &options.format_options)
+    } else {
+        match generate_with_model(diff, file_changes, options) {
+            Err(Error::GenerationFailed) if CONFIG.generator.offline_fallback => {
+                warn!("No model could generate a message, writing one offline");
+                let message =
+                    apply_style(&offline_message(diff, file_changes), options.format_options.style);
+                GeneratedMessage {
+                    message: format_message(&message, &options.format_options),
+                    raw_output: String::new(),
+                    provenance: None,
+                }
+            }
+            Err(Error::GenerationFailed) if CONFIG.generator.default_message_fallback => {
+                warn!("No model could generate a message, using the default commit message");
+                let message = apply_style(
+                    &CONFIG.generator.default_commit_message,
+                    options.format_options.style,
+                );
+                GeneratedMessage {
+                    message: format_message(&message, &options.format_options),
+                    raw_output: String::new(),
+                    provenance: None,
+                }
+            }
+            generated => check_lint(generated?, diff, file_changes, options)?,
+        }
+    };
+    if let Some(ticket) = options.ticket {
+        generated.message = apply_ticket(&generated.message, ticket, CONFIG.ticket.placement);
+    }
+    let mut trailers = options.trailers.to_vec();
+    if CONFIG.trailers.generated_by
+        && let Some(provenance) = &generated.provenance
+    {
+        trailers.push(provenance.trailer());
+    }
+    generated.message = add_trailers(&generated.message, &trailers, &options.format_options);
+    Ok(generated)
+}
+
+/// Classifies the change before the prompt is assembled, returning the configured message template
+/// for changes that don't need a model: lock files only, or a trivial diff. Anything asking for
+/// more than a plain message goes to the model.
+fn message_template(
+    diff: &str,
+    file_changes: &FileChangeSummary,
+    options: &MessageOptions<'_>,
+) -> Option<&'static str> {
+    if options.always_generate
+        || options.conflicts.is_some()
+        || options.conflict_markers.is_some()
+        || options.existing_description.is_some()
+        || options.feedback.is_some()
+        || file_changes.paths().next().is_none()
+    {
+        return None;
+    }
+    if let Some(lockfiles) = build_collapse_matcher(&CONFIG.dependency_update.lockfiles)
+        && is_dependency_update(file_changes.paths(), &lockfiles)
+    {
+        info!("Only lock files changed, using templated message");
+        return Some(&CONFIG.dependency_update.lockfile_message_template);
+    }
+    let changed_lines = count_changed_lines(diff);
+    if changed_lines < CONFIG.diff.min_lines_for_claude {
+        info!(
+            changed_lines,
+            threshold = CONFIG.diff.min_lines_for_claude,
+            "Trivial change, using templated message"
+        );
+        return Some(&CONFIG.diff.trivial_message_template);
+    }
+    None
+}
+
+/// Checks a generated message against the `[lint]` rules and handles violations as
+/// `lint.on_failure` says
+fn check_lint(
+    generated: GeneratedMessage,
+    diff: &str,
+    file_changes: &FileChangeSummary,
+    options: &MessageOptions<'_>,
+) -> Result<GeneratedMessage> {
+    let rules = &CONFIG.lint;
+    if rules.on_failure == LintAction::Off {
+        return Ok(generated);
+    }
+    let violations = lint(&generated.message, rules);
+    if violations.is_empty() {
+        return Ok(generated);
+    }
+    let listed = format_violations(&violations);
+    warn!(violations = %listed, action = ?rules.on_failure, "Generated message breaks lint rules");
+
+    let generated = match rules.on_failure {
+        LintAction::Off | LintAction::Fix => generated,
+        LintAction::Abort => return Err(Error::Lint(listed)),
+        LintAction::Regenerate => {
+            let feedback = match options.feedback {
+                Some(feedback) => {
+                    format!("{feedback}\n\nAlso follow these commit message rules:\n{listed}")
+                }
+                None => format!("The message broke these commit message rules:\n{listed}"),
+            };
+            let options = MessageOptions { feedback: Some(&feedback), ..*options };
+            let regenerated = generate_with_model(diff, file_changes, &options)?;
+            if lint(&regenerated.message, rules).is_empty() {
+                return Ok(regenerated);
+            }
+            regenerated
+        }
+    };
+
+    let message = format_message(&fix(&generated.message, rules), &options.format_options);
+    let remaining = lint(&message, rules);
+    if !remaining.is_empty() {
+        warn!(violations = %format_violations(&remaining), "Lint violations left after fixing");
+    }
+    Ok(GeneratedMessage { message, ..generated })
+}
+
+/// The prompt context with the issue the change is for, looked up when `ticket.fetch_issues` is
+/// set and the `net` feature is enabled
+#[cfg_attr(not(feature = "net"), allow(unused_variables))]
+fn with_linked_issue(context: &PromptContext, diff: &str) -> PromptContext {
+    #[cfg(feature = "net")]
+    if CONFIG.ticket.fetch_issues
+        && context.issue.is_empty()
+        && let Some(issue) = linked_issue(&context.remote_url, [context.branch.as_str()], diff)
+    {
+        info!(issue = %issue, "Adding the linked issue to the prompt");
+        return PromptContext { issue, ..context.clone() };
+    }
+    context.clone()
+}
+
+/// Appends trailers to a message and formats the result, leaving the trailer block unwrapped
+pub fn add_trailers(message: &str, trailers: &[String], format_options: &FormatOptions) -> String {
+    if trailers.is_empty() {
+        return message.to_string();
+    }
+    format_message(&append_trailers(message, trailers), format_options)
+}
+
+/// The prompt [`generate_message`] would send to the model for the diff, assembled without calling
+/// it. A change that would get a message template instead is pointed out in the log.
+pub fn preview_prompt(
+    diff: &str,
+    file_changes: &FileChangeSummary,
+    options: &MessageOptions<'_>,
+) -> Result<String> {
+    if message_template(diff, file_changes, options).is_some() {
+        warn!("This change gets a templated message unless the model is asked for explicitly");
+    }
+    message_generator(diff, file_changes, options)
+        .prompt(diff)
+        .map_err(Error::Prompt)
+}
+
+fn generate_with_model(
+    diff: &str,
+    file_changes: &FileChangeSummary,
+    options: &MessageOptions<'_>,
+) -> Result<GeneratedMessage> {
+    info!(language = %options.language, model = %options.model, "Generating commit message with Claude");
+    message_generator(diff, file_changes, options)
+        .generate(diff)
+        .map_err(generator_error)?
+        .ok_or(Error::GenerationFailed)
+}
+
+/// The commit message generator set up for the change and the options
+fn message_generator(
+    diff: &str,
+    file_changes: &FileChangeSummary,
+    options: &MessageOptions<'_>,
+) -> CommitMessageGenerator {
+    let files = file_changes
+        .paths()
+        .map(|path| escape_path(path).into_owned())
+        .collect();
+    let mut generator = CommitMessageGenerator::new(options.language, options.model)
+        .with_format_options(options.format_options)
+        .with_prompt_context(files, with_linked_issue(options.context, diff));
+    if let Some(manifests) = build_collapse_matcher(&CONFIG.dependency_update.patterns)
+        && is_dependency_update(file_changes.paths(), &manifests)
+    {
+        let dependency_changes = parse_dependency_changes(diff);
+        info!(count = dependency_changes.len(), "Using dependency update prompt");
+        generator =
+            generator.for_dependency_update(&format_dependency_changes(&dependency_changes));
+    }
+    if let Some(commit_type) = suggested_type(file_changes.paths(), &CONFIG.types) {
+        info!(commit_type = %commit_type, "The changed paths call for a commit type");
+        generator = generator.with_suggested_type(&commit_type);
+    }
+    if let Some(conflicts) = options.conflicts {
+        generator = generator.with_conflicts(conflicts);
+    }
+    if let Some(conflicts) = options.conflict_markers {
+        generator = generator.with_conflict_markers(conflicts);
+    }
+    if !options.examples.is_empty() {
+        generator = generator.with_examples(&format_examples(options.examples));
+    }
+    if let Some(description) = options.existing_description {
+        info!("Updating the existing description");
+        generator = generator.with_existing_description(description);
+    }
+    if let Some(feedback) = options.feedback {
+        generator = generator.with_feedback(feedback);
+    }
+    generator
+}
+
+/// Where [`load_change`] takes the changes to commit from
+pub struct ChangeOptions<'a> {
+    /// Describe this revision rather than the working-copy commit
+    pub revision: Option<&'a str>,
+    /// Use the stored tree of the commit instead of snapshotting the working copy; implied by
+    /// `revision`
+    pub no_snapshot: bool,
+    /// New files larger than this many bytes are left untracked by the snapshot
+    pub max_new_file_size: u64,
+    /// Show the progress of the snapshot on stderr
+    pub show_progress: bool,
+    /// Only commit the changes to the paths this matches
+    pub filter: Option<&'a PathFilter>,
+    /// Squash the changes into the parent of the commit
+    pub squash: bool,
+    /// Describe everything since this revision rather than since the parent
+    pub from: Option<&'a str>,
+}
+
+/// The changes of a commit, ready to describe and write
+pub struct Change {
+    pub repo: Arc<ReadonlyRepo>,
+    /// The working-copy commit, or the revision being described
+    pub target: Commit,
+    /// What the snapshot did, or `None` if the stored tree of `target` was used
+    pub snapshot: Option<SnapshotSummary>,
+    /// The parent `target` is squashed into
+    pub squash_into: Option<Commit>,
+    /// The tree the changes are described against
+    pub base_tree: MergedTree,
+    /// The tree to commit: the snapshot or stored tree, with only the changes the filter selects
+    pub tree: MergedTree,
+    /// The whole snapshot, which the new working-copy commit keeps
+    pub working_copy_tree: MergedTree,
+    parent_tree: MergedTree,
+}
+
+/// How [`Change::write`] records the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteMode {
+    /// Describe the commit and start a new working-copy commit on top, as `jj commit` does
+    Commit,
+    /// Describe the commit in place, as `jj describe` does
+    Describe,
+    /// Squash the changes into the parent, as `jj squash` does
+    Squash,
+}
+
+/// What [`Change::write`] recorded
+pub struct Written {
+    /// The described commit, unless the message was handed to the `jj` CLI
+    pub commit: Option<Commit>,
+    pub operation: OperationId,
+}
+
+/// Loads the changes to commit: those of the working copy, snapshotted first, or the stored tree
+/// of a commit. With a filter only the selected changes are committed, and the rest stay in the
+/// new working-copy commit.
+pub async fn load_change(workspace: &Workspace, options: &ChangeOptions<'_>) -> Result<Change> {
+    let repo = load_repo(workspace)?;
+    debug!("Loaded repository at head");
+
+    let wc_commit = working_copy_commit(&repo, workspace)?;
+    debug!(wc_commit_id = %wc_commit.id().hex(), "Working copy commit");
+
+    // Describing a stored commit must not touch the working copy, so the snapshot (and its lock)
+    // is skipped entirely
+    let target = match options.revision {
+        Some(rev) => resolve_single_commit(&repo, workspace, rev)?,
+        None => wc_commit,
+    };
+    let (working_copy_tree, snapshot) = if options.no_snapshot || options.revision.is_some() {
+        debug!(commit_id = %target.id().hex(), "Using stored tree, skipping snapshot");
+        (target.tree(), None)
+    } else {
+        let (tree, summary) =
+            snapshot_workspace(workspace, options.max_new_file_size, options.show_progress).await?;
+        (tree, Some(summary))
+    };
+
+    let parent_tree = first_parent_tree(&repo, &target)?;
+    let tree = match options.filter {
+        Some(filter) => select_changes(&parent_tree, &working_copy_tree, filter).await?,
+        None => working_copy_tree.clone(),
+    };
+
+    // With squashing the message describes the parent together with the squashed changes, and
+    // with `from` everything since the given revision
+    let squash_into = if options.squash { Some(squash_parent(&repo, &target)?) } else { None };
+    let base_tree = match (&squash_into, options.from) {
+        (Some(parent), _) => first_parent_tree(&repo, parent)?,
+        (None, Some(from)) => {
+            let base = resolve_single_commit(&repo, workspace, from)?;
+            info!(base = %short_id(base.id().hex()), "Describing the changes since --from");
+            base.tree()
+        }
+        (None, None) => parent_tree.clone(),
+    };
+
+    Ok(Change {
+        repo,
+        target,
+        snapshot,
+        squash_into,
+        base_tree,
+        tree,
+        working_copy_tree,
+        parent_tree,
+    })
+}
+
+impl Change {
+    /// Whether there is nothing to commit: the tree to commit is the parent's
+    pub fn is_empty(&self) -> bool {
+        self.tree.tree_ids() == self.parent_tree.tree_ids()
+    }
+
+    /// The commit that gets the message: the parent when squashing, or else the target
+    pub fn described(&self) -> &Commit {
+        self.squash_into.as_ref().unwrap_or(&self.target)
+    }
+
+    /// The description for the model to update rather than replace: when squashing those of both
+    /// commits, and with `amend` that of the target
+    pub fn existing_description(&self, amend: bool) -> Option<String> {
+        match &self.squash_into {
+            Some(parent) => squashed_description(parent, &self.target),
+            None => Some(self.target.description().to_string())
+                .filter(|description| amend && !description.is_empty()),
+        }
+    }
+
+    /// The diff of the changes for the prompt, see [`diff_to_prompt`]
+    pub async fn diff(&self, redact_secrets: bool) -> Result<(String, usize)> {
+        diff_to_prompt(&self.repo, &self.base_tree, &self.tree, redact_secrets).await
+    }
+
+    /// The files changed, with their line counts
+    pub async fn file_changes(&self) -> FileChangeSummary {
+        get_file_change_summary(&self.base_tree, &self.tree).await
+    }
+
+    /// The unresolved conflicts in the tree to commit
+    pub fn conflicts(&self) -> Vec<ConflictSummary> {
+        get_conflict_summaries(&self.tree)
+    }
+
+    /// Records the message as `mode` says, or with `via_jj` by handing it to the `jj` CLI (see
+    /// [`describe_via_jj`]). A stored tree is described in place by [`describe_stored_commit`],
+    /// unless squashing.
+    pub async fn write(
+        self,
+        workspace: &Workspace,
+        message: &str,
+        mode: WriteMode,
+        via_jj: bool,
+        provenance: Option<&Provenance>,
+    ) -> Result<Written> {
+        let stored = self.snapshot.is_none();
+        if via_jj {
+            info!("Describing commit with jj");
+            let new_change = !stored && mode != WriteMode::Describe;
+            let commit = stored.then_some(&self.target);
+            let operation = describe_via_jj(workspace, commit, message, new_change)?;
+            return Ok(Written { commit: None, operation });
+        }
+        let commit = match mode {
+            WriteMode::Squash => {
+                info!("Squashing into the parent commit");
+                squash_into_parent(
+                    workspace,
+                    self.target.id(),
+                    message,
+                    self.tree,
+                    self.working_copy_tree,
+                    provenance,
+                )
+                .await?
+            }
+            _ if stored => {
+                info!("Describing commit");
+                let (commit, operation) =
+                    describe_stored_commit(&self.repo, &self.target, message, provenance)?;
+                return Ok(Written { commit: Some(commit), operation });
+            }
+            _ => {
+                info!(new_change = mode == WriteMode::Commit, "Creating commit");
+                write_commit(
+                    workspace,
+                    self.target.id(),
+                    message,
+                    self.tree,
+                    self.working_copy_tree,
+                    mode == WriteMode::Commit,
+                    provenance,
+                )
+                .await?
+            }
+        };
+        let operation = load_repo(workspace)?.operation().id().clone();
+        Ok(Written { commit: Some(commit), operation })
+    }
+}
+
+/// Builds the tree holding only the changes to paths selected by the filter
+async fn select_changes(
+    parent_tree: &MergedTree,
+    tree: &MergedTree,
+    filter: &PathFilter,
+) -> Result<MergedTree> {
+    let changes = collect_changes(parent_tree, tree).await;
+    let files: Vec<String> = changed_paths(&changes)
+        .into_iter()
+        .filter(|path| filter.matches(path))
+        .collect();
+    info!(selected = files.len(), changed = changes.len(), "Filtered changes by path");
+    apply_changes(parent_tree, &changes, &files).map_err(|e| Error::Jj {
+        context: "Failed to write the tree of the selected changes",
+        source: e.into(),
+    })
+}
+
+/// The descriptions of the parent and the working-copy commit squashed into it, for the model to
+/// merge with the new changes, or `None` if neither is described
+fn squashed_description(parent: &Commit, wc_commit: &Commit) -> Option<String> {
+    let descriptions: Vec<&str> = [parent.description(), wc_commit.description()]
+        .into_iter()
+        .map(str::trim)
+        .filter(|description| !description.is_empty())
+        .collect();
+    (!descriptions.is_empty()).then(|| descriptions.join("\n\n"))
+}
+
+/// Commits the working copy: its commit gets `message` and `tree`, and with `new_change` a new
+/// working-copy commit with `working_copy_tree` is started on top of it, as `jj commit` does. Both
+/// trees are normally the snapshot from [`snapshot_workspace`]; committing only part of it leaves
+/// the rest in the new working-copy commit. Without `new_change` the working copy stays on the
+/// described commit, as `jj describe` does, and `working_copy_tree` is not used.
+///
+/// The operation is described as [`AUTO_COMMIT_DESCRIPTION`], annotated with the `provenance` of
+/// a generated message and tagged with it.
+///
+/// The snapshot's lock is released while the message is generated, so another process may move
+/// the working copy before this runs. `snapshotted` is the working-copy commit the trees were
+/// taken from; if the working copy is now on another commit, nothing is written and
+/// [`Error::WorkingCopyMoved`] is returned. From loading the repository until the working copy
+/// points at the new operation it stays locked. Until the transaction is committed, a failure
+/// leaves the repository as it was; after that, see [`finish_working_copy`].
+///
+/// Returns the described commit.
+pub async fn write_commit(
+    workspace: &Workspace,
+    snapshotted: &CommitId,
+    message: &str,
+    tree: MergedTree,
+    working_copy_tree: MergedTree,
+    new_change: bool,
+    provenance: Option<&Provenance>,
+) -> Result<Commit> {
+    let locked_wc = lock_working_copy(workspace).await?;
+    let repo = load_repo(workspace)?;
+    let wc_commit = snapshotted_working_copy_commit(&repo, workspace, snapshotted)?;
+
+    let mut tx = repo.start_transaction();
+    let mut_repo = tx.repo_mut();
+
+    // Rewrite the working copy commit with the description and snapshotted tree
+    let described = mut_repo
+        .rewrite_commit(&wc_commit)
+        .set_tree(tree)
+        .set_description(message)
+        .write()
+        .map_err(jj("Failed to write the commit"))?;
+
+    // Rebase descendants (handles the rewrite, and moves the working copy to the described commit)
+    mut_repo
+        .rebase_descendants()
+        .map_err(jj("Failed to rebase descendants"))?;
+
+    // Create a new working copy commit on top
+    if new_change {
+        let new_wc_commit = mut_repo
+            .new_commit(vec![described.id().clone()], working_copy_tree)
+            .write()
+            .map_err(jj("Failed to write the new working-copy commit"))?;
+        mut_repo
+            .set_wc_commit(workspace.workspace_name().to_owned(), new_wc_commit.id().clone())
+            .map_err(jj("Failed to set the working-copy commit"))?;
+    }
+
+    let description = record_provenance(&mut tx, AUTO_COMMIT_DESCRIPTION, provenance);
+    let new_repo = tx
+        .commit(description)
+        .map_err(jj("Failed to commit the transaction"))?;
+    finish_working_copy(locked_wc, &repo, &new_repo).await?;
+
+    Ok(described)
+}
+
+/// Squashes the working copy into its parent, as `jj squash` does: the parent gets `message` and
+/// `tree`, the working-copy commit is abandoned, and a new one with `working_copy_tree` is started
+/// on the parent. `tree` is normally the snapshot from [`snapshot_workspace`]; squashing only part
+/// of it leaves the rest in the new working-copy commit. As in [`write_commit`], the working copy
+/// must still be on the `snapshotted` commit and is locked while it is rewritten.
+///
+/// Returns the rewritten parent.
+pub async fn squash_into_parent(
+    workspace: &Workspace,
+    snapshotted: &CommitId,
+    message: &str,
+    tree: MergedTree,
+    working_copy_tree: MergedTree,
+    provenance: Option<&Provenance>,
+) -> Result<Commit> {
+    let locked_wc = lock_working_copy(workspace).await?;
+    let repo = load_repo(workspace)?;
+    let wc_commit = snapshotted_working_copy_commit(&repo, workspace, snapshotted)?;
+    let parent = squash_parent(&repo, &wc_commit)?;
+
+    let mut tx = repo.start_transaction();
+    let mut_repo = tx.repo_mut();
+
+    let squashed = mut_repo
+        .rewrite_commit(&parent)
+        .set_tree(tree)
+        .set_description(message)
+        .write()
+        .map_err(jj("Failed to write the commit"))?;
+
+    // The new working-copy commit is set before the old one is abandoned, so that jj doesn't start
+    // one of its own
+    let new_wc_commit = mut_repo
+        .new_commit(vec![squashed.id().clone()], working_copy_tree)
+        .write()
+        .map_err(jj("Failed to write the new working-copy commit"))?;
+    mut_repo
+        .set_wc_commit(workspace.workspace_name().to_owned(), new_wc_commit.id().clone())
+        .map_err(jj("Failed to set the working-copy commit"))?;
+    mut_repo.record_abandoned_commit(&wc_commit);
+    mut_repo
+        .rebase_descendants()
+        .map_err(jj("Failed to rebase descendants"))?;
+
+    let description = record_provenance(&mut tx, SQUASH_DESCRIPTION, provenance);
+    let new_repo = tx
+        .commit(description)
+        .map_err(jj("Failed to commit the transaction"))?;
+    finish_working_copy(locked_wc, &repo, &new_repo).await?;
+
+    Ok(squashed)
+}
+
+/// The working-copy commit of `repo`, provided it is still the `snapshotted` one
+pub fn snapshotted_working_copy_commit(
+    repo: &ReadonlyRepo,
+    workspace: &Workspace,
+    snapshotted: &CommitId,
+) -> Result<Commit> {
+    let wc_commit = working_copy_commit(repo, workspace)?;
+    if wc_commit.id() != snapshotted {
+        return Err(Error::WorkingCopyMoved {
+            expected: short_id(snapshotted.hex()),
+            found: short_id(wc_commit.id().hex()),
+        });
+    }
+    Ok(wc_commit)
+}
+
+/// Sets the description of a stored commit in place, rebasing its descendants. The working copy is
+/// left untouched.
+///
+/// Returns the described commit and the operation that recorded it.
+pub fn describe_stored_commit(
+    repo: &Arc<ReadonlyRepo>,
+    commit: &Commit,
+    message: &str,
+    provenance: Option<&Provenance>,
+) -> Result<(Commit, OperationId)> {
+    let mut tx = repo.start_transaction();
+    let mut_repo = tx.repo_mut();
+
+    let described = mut_repo
+        .rewrite_commit(commit)
+        .set_description(message)
+        .write()
+        .map_err(jj("Failed to write the commit"))?;
+    mut_repo
+        .rebase_descendants()
+        .map_err(jj("Failed to rebase descendants"))?;
+
+    let description = record_provenance(&mut tx, "describe commit via ccc-jj", provenance);
+    let new_repo = tx
+        .commit(description)
+        .map_err(jj("Failed to commit the transaction"))?;
+    Ok((described, new_repo.operation().id().clone()))
+}
+
+/// Checks that the `jj` CLI can be executed, so that a message isn't generated for nothing
+pub fn ensure_jj_available() -> Result<()> {
+    match Command::new("jj").arg("--version").stdout(Stdio::null()).status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(Error::JjFailed { command: "--version".to_string(), status }),
+        Err(e) if e.kind() == ErrorKind::NotFound => Err(Error::JjNotFound),
+        Err(e) => Err(Error::JjSpawn(e)),
+    }
+}
+
+/// Describes a stored commit, or the working-copy commit as jj snapshots it when `commit` is
+/// `None`, by piping the message to `jj describe --stdin`, then starts a new change on top with
+/// `jj new` if requested. Returns the resulting operation.
+pub fn describe_via_jj(
+    workspace: &Workspace,
+    commit: Option<&Commit>,
+    message: &str,
+    new_change: bool,
+) -> Result<OperationId> {
+    let run_jj = |args: &[&str], input: Option<&str>| -> Result<()> {
+        debug!(?args, "Running jj");
+        let mut child = Command::new("jj")
+            .args(args)
+            .current_dir(workspace.workspace_root())
+            .stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() })
+            .spawn()
+            .map_err(|e| match e.kind() {
+                ErrorKind::NotFound => Error::JjNotFound,
+                _ => Error::JjSpawn(e),
+            })?;
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            stdin.write_all(input.as_bytes()).map_err(Error::JjSpawn)?;
+        }
+        let status = child.wait().map_err(Error::JjSpawn)?;
+        if !status.success() {
+            return Err(Error::JjFailed { command: args.join(" "), status });
+        }
+        Ok(())
+    };
+
+    // jj snapshots the working copy again, which rewrites @ under a new id, so the id seen before
+    // the snapshot would name a hidden commit
+    let revision = commit.map_or_else(|| "@".to_string(), |commit| commit.id().hex());
+    run_jj(&["describe", "--stdin", "-r", &revision], Some(message))?;
+    if new_change {
+        run_jj(&["new"], None)?;
+    }
+
+    Ok(load_repo(workspace)?.operation().id().clone())
+}
+
+/// Asks the model how to split the change into logical commits, given its diff and every changed
+/// path (see [`changed_paths`]). The messages are formatted and get the ticket and trailers of the
+/// options.
+///
+/// Blocks until the model answers; async code should run it with `tokio::task::spawn_blocking`.
+pub fn plan_split(
+    diff: &str,
+    changed_files: &[String],
+    options: &MessageOptions<'_>,
+) -> Result<Vec<SplitCommit>> {
+    let mut commits = SplitGenerator::new(options.language, options.model, options.format_options)
+        .generate(diff, changed_files)
+        .map_err(generator_error)?
+        .ok_or(Error::SplitFailed)?;
+    for commit in &mut commits {
+        if let Some(ticket) = options.ticket {
+            commit.message = apply_ticket(&commit.message, ticket, CONFIG.ticket.placement);
+        }
+        commit.message = add_trailers(&commit.message, options.trailers, &options.format_options);
+    }
+    debug!(commits = commits.len(), "Planned split");
+    Ok(commits)
+}
+
+/// Creates one commit per planned group, each adding its files on top of the previous one.
+///
+/// The first group rewrites the working-copy commit and the rest are stacked on it, so the last
+/// commit ends up with `tree`. A new empty working-copy commit is created on top. As in
+/// [`write_commit`], nothing is written unless the working copy is still on the `snapshotted`
+/// commit.
+pub async fn write_split_commits(
+    workspace: &Workspace,
+    snapshotted: &CommitId,
+    commits: &[SplitCommit],
+    parent_tree: MergedTree,
+    tree: MergedTree,
+    changes: &[TreeChange],
+) -> Result<Vec<Commit>> {
+    let locked_wc = lock_working_copy(workspace).await?;
+    let repo = load_repo(workspace)?;
+    let wc_commit = snapshotted_working_copy_commit(&repo, workspace, snapshotted)?;
+    let mut tx = repo.start_transaction();
+    let mut_repo = tx.repo_mut();
+
+    let mut commit_tree = parent_tree;
+    let mut written: Vec<Commit> = Vec::with_capacity(commits.len());
+    for (i, commit) in commits.iter().enumerate() {
+        // Every file is assigned to some group, so the last tree is the snapshot itself
+        commit_tree = if i + 1 == commits.len() {
+            tree.clone()
+        } else {
+            apply_changes(&commit_tree, changes, &commit.files).map_err(|e| Error::Jj {
+                context: "Failed to write the tree of a split commit",
+                source: e.into(),
+            })?
+        };
+        let new_commit = match written.last() {
+            None => {
+                let rewritten = mut_repo
+                    .rewrite_commit(&wc_commit)
+                    .set_tree(commit_tree.clone())
+                    .set_description(&commit.message)
+                    .write()
+                    .map_err(jj("Failed to write the commit"))?;
+                mut_repo
+                    .rebase_descendants()
+                    .map_err(jj("Failed to rebase descendants"))?;
+                rewritten
+            }
+            Some(previous) => mut_repo
+                .new_commit(vec![previous.id().clone()], commit_tree.clone())
+                .set_description(&commit.message)
+                .write()
+                .map_err(jj("Failed to write the commit"))?,
+        };
+        debug!(commit_id = %new_commit.id().hex(), files = commit.files.len(), "Wrote split commit");
+        written.push(new_commit);
+    }
+
+    let Some(last) = written.last() else {
+        return Err(Error::SplitFailed);
+    };
+    let new_wc_commit = mut_repo
+        .new_commit(vec![last.id().clone()], tree)
+        .write()
+        .map_err(jj("Failed to write the new working-copy commit"))?;
+    mut_repo
+        .set_wc_commit(workspace.workspace_name().to_owned(), new_wc_commit.id().clone())
+        .map_err(jj("Failed to set the working-copy commit"))?;
+
+    let new_repo = tx
+        .commit(format!("split into {} commits via ccc-jj", written.len()))
+        .map_err(jj("Failed to commit the transaction"))?;
+    finish_working_copy(locked_wc, &repo, &new_repo).await?;
+    Ok(written)
+}
+
+/// A commit without a description found by [`undescribed_commits`], with its changes
+pub struct UndescribedCommit {
+    pub commit: Commit,
+    pub diff: String,
+    /// Secrets masked in the diff
+    pub masked: usize,
+    pub file_changes: FileChangeSummary,
+    pub conflicts: Vec<ConflictSummary>,
+}
+
+/// The commits matching `revset` that have no description yet, with the diffs of their stored
+/// trees. Commits with an empty or too large diff are skipped, and so are commits with unresolved
+/// conflicts unless `allow_conflicts` is set.
+pub async fn undescribed_commits(
+    repo: &ReadonlyRepo,
+    workspace: &Workspace,
+    revset: &str,
+    redact_secrets: bool,
+    allow_conflicts: bool,
+) -> Result<Vec<UndescribedCommit>> {
+    let commit_ids = evaluate_revset(repo, workspace, revset)?;
+    info!(count = commit_ids.len(), revset = %revset, "Resolved commits to describe");
+
+    let mut undescribed = Vec::new();
+    for commit_id in &commit_ids {
+        if commit_id == repo.store().root_commit_id() {
+            continue;
+        }
+        let commit = repo
+            .store()
+            .get_commit(commit_id)
+            .map_err(jj("Failed to read a commit to describe"))?;
+        let short_id = short_id(commit_id.hex());
+        if !commit.description().is_empty() {
+            info!(commit = %short_id, "Commit already has description, skipping");
+            continue;
+        }
+
+        let tree = commit.tree();
+        let parent_tree = first_parent_tree(repo, &commit)?;
+        let (diff, masked) = diff_to_prompt(repo, &parent_tree, &tree, redact_secrets).await?;
+        if diff.trim().is_empty() {
+            info!(commit = %short_id, "Empty diff, skipping");
+            continue;
+        }
+        if check_diff_size(&diff).is_err() {
+            warn!(commit = %short_id, "Diff too large to generate commit message, skipping");
+            continue;
+        }
+
+        let conflicts = get_conflict_summaries(&tree);
+        if !conflicts.is_empty() && !allow_conflicts {
+            warn!(
+                commit = %short_id,
+                count = conflicts.len(),
+                "Commit has unresolved conflicts, skipping; pass --allow-conflicts to describe it"
+            );
+            continue;
+        }
+
+        let file_changes = get_file_change_summary(&parent_tree, &tree).await;
+        undescribed.push(UndescribedCommit { commit, diff, masked, file_changes, conflicts });
+    }
+    Ok(undescribed)
+}
+
+/// Sets the descriptions of several stored commits in a single operation, so a failure leaves the
+/// repository untouched. The commits must be listed as a revset yields them, descendants first.
+pub fn describe_commits(
+    repo: &Arc<ReadonlyRepo>,
+    described: &[(&Commit, &str)],
+) -> Result<OperationId> {
+    // Each commit is rewritten before its parents, and the final rebase moves the rewritten
+    // descendants onto the rewritten parents
+    let mut tx = repo.start_transaction();
+    let mut_repo = tx.repo_mut();
+    for (commit, message) in described {
+        mut_repo
+            .rewrite_commit(commit)
+            .set_description(*message)
+            .write()
+            .map_err(jj("Failed to write the commit"))?;
+    }
+    mut_repo
+        .rebase_descendants()
+        .map_err(jj("Failed to rebase descendants"))?;
+    let new_repo = tx
+        .commit(format!("describe {} commits via ccc-jj", described.len()))
+        .map_err(jj("Failed to commit the transaction"))?;
+    Ok(new_repo.operation().id().clone())
+}
+
+/// Runs `generate` on every item, up to `generator.parallel_calls` at a time, and returns the
+/// results in the order of `items`. After a failure no further items are started, and the first
+/// error is returned once the calls under way are done.
+pub fn generate_in_parallel<T, R, E>(
+    items: &[T],
+    generate: impl Fn(&T) -> std::result::Result<R, E> + Sync,
+) -> std::result::Result<Vec<R>, E>
+where
+    T: Sync,
+    R: Send,
+    E: Send,
+{
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let workers = CONFIG.generator.parallel_calls.clamp(1, items.len().max(1));
+    info!(items = items.len(), workers, "Generating in parallel");
+
+    let mut results: Vec<(usize, std::result::Result<R, E>)> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    while !failed.load(Ordering::Relaxed) {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(i) else {
+                            break;
+                        };
+                        let generated = generate(item);
+                        if generated.is_err() {
+                            failed.store(true, Ordering::Relaxed);
+                        }
+                        results.push((i, generated));
+                    }
+                    results
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("generation panicked"))
+            .collect()
+    });
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, generated)| generated).collect()
+}
+
+/// Snapshots the working copy without showing progress and returns its diff against the parent,
+/// unredacted, with the files changed. Meant for polling the working copy for changes.
+pub async fn working_copy_diff(
+    workspace: &Workspace,
+    max_new_file_size: u64,
+) -> Result<(String, FileChangeSummary)> {
+    let repo = load_repo(workspace)?;
+    let wc_commit = working_copy_commit(&repo, workspace)?;
+    let (tree, _) = snapshot_workspace(workspace, max_new_file_size, false).await?;
+    let parent_tree = first_parent_tree(&repo, &wc_commit)?;
+    let (diff, _) = diff_to_prompt(&repo, &parent_tree, &tree, false).await?;
+    let file_changes = get_file_change_summary(&parent_tree, &tree).await;
+    Ok((diff, file_changes))
+}
+
+/// Tags the transaction with the provenance of a generated message and returns `description`
+/// annotated with it, for the operation. Without provenance the description is left as it is.
+pub fn record_provenance(
+    tx: &mut Transaction,
+    description: &str,
+    provenance: Option<&Provenance>,
+) -> String {
+    let Some(provenance) = provenance else {
+        return description.to_string();
+    };
+    for (key, value) in provenance.tags() {
+        tx.set_tag(key, value);
+    }
+    provenance.annotate(description)
+}
@@ -0,0 +1,188 @@
+use anyhow::Result;
+use jj_lib::{commit::Commit, object_id::ObjectId, repo::ReadonlyRepo};
+use serde::Deserialize;
+use serde_json::{Value, from_value};
+use tracing::{debug, trace, warn};
+
+use crate::{
+    claude_client::{extract_json_object, strip_code_fence},
+    config::CONFIG,
+    generator::{GenerationRequest, Generator, backend_from_config, invoke},
+    pipeline::{diff_to_prompt, first_parent_tree},
+};
+
+const JSON_SCHEMA: &str = r#"{"type":"object","properties":{"title":{"type":"string","description":"Pull request title, max 72 chars, imperative mood"},"body":{"type":"string","description":"Pull request description in markdown"}},"required":["title","body"]}"#;
+
+/// A generated pull request title and markdown body
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub struct PrDescription {
+    pub title: String,
+    #[serde(default)]
+    pub body: String,
+}
+
+impl PrDescription {
+    /// Renders the description as markdown with the title as a heading
+    pub fn to_markdown(&self) -> String {
+        let body = self.body.trim();
+        if body.is_empty() {
+            format!("# {}\n", self.title)
+        } else {
+            format!("# {}\n\n{body}\n", self.title)
+        }
+    }
+}
+
+/// Generates a pull request description for a range of commits
+pub struct PrDescriptionGenerator {
+    prompt_template: String,
+    backend: Box<dyn Generator + Send + Sync>,
+    language: String,
+    model: String,
+}
+
+impl PrDescriptionGenerator {
+    /// Creates a new pull request description generator
+    ///
+    /// # Arguments
+    /// - `language` - The language to write the description in
+    /// - `model` - The model to use for generation
+    pub fn new(language: &str, model: &str) -> Self {
+        Self {
+            prompt_template: CONFIG.pr_description.prompt_template.clone(),
+            backend: backend_from_config(&CONFIG.generator),
+            language: language.to_string(),
+            model: model.to_string(),
+        }
+    }
+
+    /// Generates the description from the commit messages and the aggregate diff of the range
+    ///
+    /// # Returns
+    /// `Some(description)` if generation succeeds, `None` if it fails, or an error if the call
+    /// budget is exhausted.
+    pub fn generate(
+        &self,
+        commit_messages: &str,
+        diff_content: &str,
+    ) -> Result<Option<PrDescription>> {
+        let prompt = self
+            .prompt_template
+            .replace("{language}", &self.language)
+            .replace("{commit_messages}", commit_messages)
+            .replace("{diff_content}", diff_content);
+        trace!(prompt_len = prompt.len(), "Prepared PR description prompt");
+
+        let request = GenerationRequest {
+            model: &self.model,
+            json_schema: JSON_SCHEMA,
+            prompt: &prompt,
+            stable_prefix: 0,
+            spinner_message: "Generating PR description with Claude...",
+        };
+        let Some(response) = invoke(self.backend.as_ref(), &request)? else {
+            return Ok(None);
+        };
+        let description = parse_description(response.structured);
+        if description.is_none() {
+            warn!("Claude returned an empty PR description");
+        }
+        Ok(description)
+    }
+}
+
+/// Reads the description from structured output, or from free text whose first line is taken as
+/// the title when the model ignored the schema
+fn parse_description(structured: Value) -> Option<PrDescription> {
+    let structured = match structured {
+        Value::String(text) => extract_json_object(&text).unwrap_or(Value::String(text)),
+        value => value,
+    };
+    let description = match structured {
+        Value::String(text) => {
+            let text = strip_code_fence(&text).trim();
+            let (title, body) = text.split_once('\n').unwrap_or((text, ""));
+            PrDescription {
+                title: title.trim_start_matches('#').trim().to_string(),
+                body: body.trim().to_string(),
+            }
+        }
+        value => match from_value::<PrDescription>(value) {
+            Ok(description) => description,
+            Err(e) => {
+                warn!(error = %e, "PR description does not match the expected schema");
+                return None;
+            }
+        },
+    };
+    (!description.title.trim().is_empty()).then_some(description)
+}
+
+/// Lists the full messages of the commits, oldest first, skipping undescribed ones
+pub fn format_commit_messages(commits: &[Commit]) -> String {
+    commits
+        .iter()
+        .rev()
+        .filter_map(|commit| {
+            let description = commit.description().trim();
+            (!description.is_empty()).then(|| format!("- {}", description.replace('\n', "\n  ")))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Builds the diff of the whole range: from the first parent of `oldest` to the tree of `head`,
+/// so the model sees the net change rather than one diff per commit
+pub async fn aggregate_diff(
+    repo: &ReadonlyRepo,
+    oldest: &Commit,
+    head: &Commit,
+) -> crate::Result<(String, usize)> {
+    let base_tree = first_parent_tree(repo, oldest)?;
+    debug!(base = %oldest.id().hex(), head = %head.id().hex(), "Aggregating diff of the range");
+    diff_to_prompt(repo, &base_tree, &head.tree(), true).await
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_description_structured() {
+        let description =
+            parse_description(json!({"title": "Add PR descriptions", "body": "## Summary"}));
+        assert_eq!(
+            description,
+            Some(PrDescription {
+                title: "Add PR descriptions".to_string(),
+                body: "## Summary".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_description_free_text() {
+        let description = parse_description(Value::String(
+            "# Add PR descriptions\n\nGenerates a title and body.\n".to_string(),
+        ));
+        assert_eq!(
+            description,
+            Some(PrDescription {
+                title: "Add PR descriptions".to_string(),
+                body: "Generates a title and body.".to_string()
+            })
+        );
+        assert_eq!(parse_description(json!({"title": " ", "body": "x"})), None);
+    }
+
+    #[test]
+    fn test_to_markdown() {
+        let description = PrDescription {
+            title: "Title".to_string(),
+            body: "Body\n".to_string(),
+        };
+        assert_eq!(description.to_markdown(), "# Title\n\nBody\n");
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use console::Term;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+/// Draws the spinners of steps running at the same time, e.g. messages generated in parallel,
+/// below one another instead of over each other
+static SPINNERS: LazyLock<MultiProgress> = LazyLock::new(MultiProgress::new);
+
+/// Shows that a long step is running: a spinner on a terminal, or plain lines on stderr when it
+/// is redirected (CI, cron, watch-mode logs), where a spinner's control characters would end up
+/// in the log
+pub(crate) struct Progress {
+    kind: Kind,
+    message: String,
+    started: Instant,
+}
+
+enum Kind {
+    Spinner(ProgressBar),
+    Lines,
+    Hidden,
+}
+
+impl Progress {
+    /// Starts showing `message`
+    pub fn start(message: &str) -> Self {
+        let kind = if Term::stderr().is_term() {
+            Kind::Spinner(spinner(message))
+        } else {
+            eprintln!("{message}");
+            Kind::Lines
+        };
+        Self {
+            kind,
+            message: message.to_string(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Shows nothing, for steps that run too often to report, such as watch-mode polls
+    pub fn hidden() -> Self {
+        Self {
+            kind: Kind::Hidden,
+            message: String::new(),
+            started: Instant::now(),
+        }
+    }
+
+    /// The spinner, if the progress is drawn on a terminal
+    pub fn spinner(&self) -> Option<&ProgressBar> {
+        match &self.kind {
+            Kind::Spinner(spinner) => Some(spinner),
+            _ => None,
+        }
+    }
+
+    /// Updates the spinner message. Plain lines keep only the first message, as the updates can
+    /// come once per file.
+    pub fn set_message(&self, message: String) {
+        if let Kind::Spinner(spinner) = &self.kind {
+            spinner.set_message(message);
+        }
+    }
+
+    /// Clears the spinner, or logs how long the step took
+    pub fn finish(self) {
+        match self.kind {
+            Kind::Spinner(spinner) => {
+                spinner.finish_and_clear();
+                SPINNERS.remove(&spinner);
+            }
+            Kind::Lines => eprintln!("{}", done_line(&self.message, self.started.elapsed())),
+            Kind::Hidden => {}
+        }
+    }
+}
+
+fn spinner(message: &str) -> ProgressBar {
+    let spinner = SPINNERS.add(ProgressBar::new_spinner());
+    if let Ok(style) = ProgressStyle::default_spinner()
+        .tick_chars("✶✸✹✺✹✷")
+        .template("{spinner:.yellow} {msg}")
+    {
+        spinner.set_style(style);
+    }
+    spinner.set_message(message.to_string());
+    spinner.enable_steady_tick(Duration::from_millis(200));
+    spinner
+}
+
+/// The line logged when a step finishes, e.g. `Snapshotting the working copy... done (1.2s)`
+fn done_line(message: &str, elapsed: Duration) -> String {
+    format!("{message} done ({:.1}s)", elapsed.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_done_line() {
+        assert_eq!(
+            done_line("Snapshotting the working copy...", Duration::from_millis(1234)),
+            "Snapshotting the working copy... done (1.2s)"
+        );
+    }
+}
@@ -0,0 +1,259 @@
+use std::{fs, path::Path};
+
+use serde_json::Value;
+use tracing::debug;
+
+/// An ecosystem recognized by the manifest in the workspace root
+struct Ecosystem {
+    manifest: &'static str,
+    /// What a project of the ecosystem is called, e.g. "Rust crate"
+    project_type: fn(&str) -> &'static str,
+    dependencies: fn(&str) -> Vec<String>,
+    /// Dependencies worth naming, with their display names, in the order they are reported
+    frameworks: &'static [(&'static str, &'static str)],
+}
+
+const ECOSYSTEMS: &[Ecosystem] = &[
+    Ecosystem {
+        manifest: "Cargo.toml",
+        project_type: |manifest| {
+            let workspace_only = toml::from_str::<toml::Table>(manifest).is_ok_and(|table| {
+                table.contains_key("workspace") && !table.contains_key("package")
+            });
+            if workspace_only { "Rust workspace" } else { "Rust crate" }
+        },
+        dependencies: cargo_dependencies,
+        frameworks: &[
+            ("tokio", "Tokio"),
+            ("axum", "Axum"),
+            ("actix-web", "Actix Web"),
+            ("rocket", "Rocket"),
+            ("bevy", "Bevy"),
+            ("tauri", "Tauri"),
+            ("leptos", "Leptos"),
+            ("clap", "clap"),
+            ("ratatui", "Ratatui"),
+            ("diesel", "Diesel"),
+            ("sqlx", "SQLx"),
+        ],
+    },
+    Ecosystem {
+        manifest: "package.json",
+        project_type: |manifest| {
+            if package_json_dependencies(manifest)
+                .iter()
+                .any(|name| name == "typescript")
+            {
+                "TypeScript package"
+            } else {
+                "JavaScript package"
+            }
+        },
+        dependencies: package_json_dependencies,
+        frameworks: &[
+            ("react", "React"),
+            ("vue", "Vue"),
+            ("svelte", "Svelte"),
+            ("@angular/core", "Angular"),
+            ("next", "Next.js"),
+            ("nuxt", "Nuxt"),
+            ("express", "Express"),
+            ("@nestjs/core", "NestJS"),
+            ("electron", "Electron"),
+        ],
+    },
+    Ecosystem {
+        manifest: "go.mod",
+        project_type: |_| "Go module",
+        dependencies: go_mod_dependencies,
+        frameworks: &[
+            ("github.com/gin-gonic/gin", "Gin"),
+            ("github.com/labstack/echo", "Echo"),
+            ("github.com/gofiber/fiber", "Fiber"),
+            ("github.com/spf13/cobra", "Cobra"),
+            ("gorm.io/gorm", "GORM"),
+        ],
+    },
+    Ecosystem {
+        manifest: "pyproject.toml",
+        project_type: |_| "Python package",
+        dependencies: pyproject_dependencies,
+        frameworks: &[
+            ("django", "Django"),
+            ("flask", "Flask"),
+            ("fastapi", "FastAPI"),
+            ("numpy", "NumPy"),
+            ("pandas", "pandas"),
+            ("torch", "PyTorch"),
+        ],
+    },
+];
+
+/// The tech stack of a project, as shown to the model so that messages use the ecosystem's terms
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ProjectStack {
+    /// E.g. "Rust crate", or a comma-separated list for a project with several manifests. Empty
+    /// if no manifest was found.
+    pub project_type: String,
+    /// Comma-separated well-known frameworks the project depends on, e.g. "Tokio, clap"
+    pub frameworks: String,
+}
+
+/// Detects the tech stack from the manifests (`Cargo.toml`, `package.json`, `go.mod`,
+/// `pyproject.toml`) in the workspace root
+pub fn detect_stack(root: &Path) -> ProjectStack {
+    let mut types = Vec::new();
+    let mut frameworks = Vec::new();
+    for ecosystem in ECOSYSTEMS {
+        let Ok(manifest) = fs::read_to_string(root.join(ecosystem.manifest)) else {
+            continue;
+        };
+        types.push((ecosystem.project_type)(&manifest));
+        let dependencies = (ecosystem.dependencies)(&manifest);
+        frameworks.extend(
+            ecosystem
+                .frameworks
+                .iter()
+                .filter(|(name, _)| dependencies.iter().any(|dependency| dependency == name))
+                .map(|(_, display)| *display),
+        );
+    }
+    let stack = ProjectStack {
+        project_type: types.join(", "),
+        frameworks: frameworks.join(", "),
+    };
+    debug!(?stack, "Detected the project's tech stack");
+    stack
+}
+
+/// Names of the dependencies in a `Cargo.toml`, including dev, build, and workspace dependencies
+fn cargo_dependencies(manifest: &str) -> Vec<String> {
+    let Ok(table) = toml::from_str::<toml::Table>(manifest) else {
+        return Vec::new();
+    };
+    let workspace = table.get("workspace").and_then(toml::Value::as_table);
+    ["dependencies", "dev-dependencies", "build-dependencies"]
+        .iter()
+        .filter_map(|section| table.get(*section))
+        .chain(workspace.and_then(|workspace| workspace.get("dependencies")))
+        .filter_map(toml::Value::as_table)
+        .flat_map(|dependencies| dependencies.keys().cloned())
+        .collect()
+}
+
+/// Names of the dependencies and dev dependencies in a `package.json`
+fn package_json_dependencies(manifest: &str) -> Vec<String> {
+    let Ok(package) = serde_json::from_str::<Value>(manifest) else {
+        return Vec::new();
+    };
+    ["dependencies", "devDependencies"]
+        .iter()
+        .filter_map(|section| package.get(*section).and_then(Value::as_object))
+        .flat_map(|dependencies| dependencies.keys().cloned())
+        .collect()
+}
+
+/// Module paths required by a `go.mod`, without their major version suffixes (`/v2`)
+fn go_mod_dependencies(manifest: &str) -> Vec<String> {
+    let mut in_block = false;
+    let mut modules = Vec::new();
+    for line in manifest.lines().map(str::trim) {
+        let requirement = if in_block {
+            if line == ")" {
+                in_block = false;
+                continue;
+            }
+            line
+        } else if let Some(rest) = line.strip_prefix("require") {
+            let rest = rest.trim();
+            if rest == "(" {
+                in_block = true;
+                continue;
+            }
+            rest
+        } else {
+            continue;
+        };
+        let Some(module) = requirement.split_whitespace().next().filter(|m| !m.starts_with("//"))
+        else {
+            continue;
+        };
+        let module = match module.rsplit_once("/v") {
+            Some((path, major))
+                if !major.is_empty() && major.chars().all(|c| c.is_ascii_digit()) =>
+            {
+                path
+            }
+            _ => module,
+        };
+        modules.push(module.to_string());
+    }
+    modules
+}
+
+/// Lower-cased names of the dependencies in a `pyproject.toml`, from `[project]` or Poetry
+fn pyproject_dependencies(manifest: &str) -> Vec<String> {
+    let Ok(table) = toml::from_str::<toml::Table>(manifest) else {
+        return Vec::new();
+    };
+    let pep621 = table
+        .get("project")
+        .and_then(|project| project.get("dependencies"))
+        .and_then(toml::Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(toml::Value::as_str)
+        .map(|requirement| {
+            requirement
+                .split(|c: char| !(c.is_ascii_alphanumeric() || "-_.".contains(c)))
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        });
+    let poetry = table
+        .get("tool")
+        .and_then(|tool| tool.get("poetry"))
+        .and_then(|poetry| poetry.get("dependencies"))
+        .and_then(toml::Value::as_table)
+        .into_iter()
+        .flat_map(|dependencies| dependencies.keys().cloned());
+    pep621.chain(poetry).map(|name| name.to_lowercase()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cargo_dependencies() {
+        let manifest = "[package]\nname = \"a\"\n\n[dependencies]\ntokio = { version = \"1\" }\n\
+            clap = \"4\"\n\n[dev-dependencies]\ninsta = \"1\"\n";
+        assert_eq!(cargo_dependencies(manifest), ["clap", "tokio", "insta"]);
+        assert_eq!((ECOSYSTEMS[0].project_type)(manifest), "Rust crate");
+        assert_eq!((ECOSYSTEMS[0].project_type)("[workspace]\nmembers = []\n"), "Rust workspace");
+    }
+
+    #[test]
+    fn test_package_json_dependencies() {
+        let manifest = r#"{"dependencies":{"react":"^18"},"devDependencies":{"typescript":"^5"}}"#;
+        assert_eq!(package_json_dependencies(manifest), ["react", "typescript"]);
+        assert_eq!((ECOSYSTEMS[1].project_type)(manifest), "TypeScript package");
+    }
+
+    #[test]
+    fn test_go_mod_dependencies() {
+        let manifest = "module example.com/a\n\ngo 1.22\n\nrequire github.com/spf13/cobra v1.8.0\n\n\
+            require (\n\tgithub.com/labstack/echo/v4 v4.11.4\n\t// comment\n\tgolang.org/x/net v0.1.0 // indirect\n)\n";
+        assert_eq!(
+            go_mod_dependencies(manifest),
+            ["github.com/spf13/cobra", "github.com/labstack/echo", "golang.org/x/net"]
+        );
+    }
+
+    #[test]
+    fn test_pyproject_dependencies() {
+        let manifest = "[project]\nname = \"a\"\ndependencies = [\"Django>=4.2\", \"numpy\"]\n\n\
+            [tool.poetry.dependencies]\nFastAPI = \"^0.110\"\n";
+        assert_eq!(pyproject_dependencies(manifest), ["django", "numpy", "fastapi"]);
+    }
+}
@@ -0,0 +1,200 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::LazyLock,
+};
+
+use anyhow::{Context, Result, bail};
+use minijinja::{Environment, ErrorKind};
+use regex::{Captures, Regex};
+use serde::Serialize;
+
+/// A `{name}` placeholder of the templates written before they were rendered with minijinja
+static LEGACY_PLACEHOLDER_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(\{)?\{([A-Za-z_][A-Za-z0-9_]*)\}(\})?")
+        .expect("Failed to compile legacy placeholder regex")
+});
+
+/// Facts about the repository that commit message prompts can use besides the diff
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct PromptContext {
+    /// The bookmark or branch the change is on, empty if there is none
+    pub branch: String,
+    /// Descriptions of the nearest ancestors, newest first, as many as `prompt.recent_commits`
+    pub recent_commits: Vec<String>,
+    /// Name of the workspace's root directory
+    pub repo_name: String,
+    /// The project's documented commit message rules, empty if it has none
+    pub style_guide: String,
+    /// The kind of project, e.g. "Rust crate", empty if no manifest was recognized
+    pub project_type: String,
+    /// Well-known frameworks the project depends on, comma-separated
+    pub frameworks: String,
+    /// URL of the `origin` remote, empty if there is none. Left out of records, as it may hold
+    /// credentials.
+    #[serde(skip)]
+    pub remote_url: String,
+    /// The workspace root, which `{% include %}` and `{% import %}` paths are relative to
+    #[serde(skip)]
+    pub root: PathBuf,
+    /// Title and labels of the linked issue, e.g. `#123 Login fails (labels: bug)`, empty unless
+    /// looked up with `ticket.fetch_issues`
+    pub issue: String,
+}
+
+/// Renders a minijinja template with `vars`.
+///
+/// `{name}` placeholders from older configurations are still understood for the variables in
+/// `names`. Templates pulled in with `{% include "path" %}` are read from below `root`.
+pub(crate) fn render(
+    template: &str,
+    names: &[&str],
+    vars: impl Serialize,
+    root: &Path,
+) -> Result<String> {
+    let source = upgrade_placeholders(template, names);
+    let mut environment = environment();
+    let root = root.to_path_buf();
+    environment.set_loader(move |name| load(&root, name));
+    Ok(environment.render_str(&source, vars)?)
+}
+
+/// Ensures a template parses and uses all `required` variables and nothing outside `required` and
+/// `optional`, so that a typo'd `{{ diff_contents }}` doesn't silently render as nothing
+pub(crate) fn check(
+    name: &str,
+    template: &str,
+    required: &[&str],
+    optional: &[&str],
+) -> Result<()> {
+    let names: Vec<&str> = required.iter().chain(optional).copied().collect();
+    let source = upgrade_placeholders(template, &names);
+    let environment = environment();
+    let parsed = environment
+        .template_from_str(&source)
+        .with_context(|| format!("{name} is not a valid template"))?;
+    let found: HashSet<String> = parsed.undeclared_variables(false);
+
+    if let Some(missing) = required.iter().find(|v| !found.contains(**v)) {
+        bail!("{name} is missing the required variable {{{{ {missing} }}}}");
+    }
+    let mut unknown: Vec<&String> = found.iter().filter(|v| !names.contains(&v.as_str())).collect();
+    unknown.sort();
+    if let Some(unknown) = unknown.first() {
+        bail!("{name} uses unknown variable {{{{ {unknown} }}}} (known: {})", names.join(", "));
+    }
+    Ok(())
+}
+
+fn environment<'source>() -> Environment<'source> {
+    let mut environment = Environment::new();
+    // Keep prompts byte-for-byte as written, apart from the line after a `{% ... %}` tag
+    environment.set_keep_trailing_newline(true);
+    environment.set_trim_blocks(true);
+    environment
+}
+
+/// Reads the included template `name` from below `root`, refusing absolute paths and `..` so
+/// that a prompt can't pull in files from outside the workspace
+fn load(root: &Path, name: &str) -> Result<Option<String>, minijinja::Error> {
+    let path = Path::new(name);
+    if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(minijinja::Error::new(
+            ErrorKind::InvalidOperation,
+            format!("{name} is not a path inside the workspace"),
+        ));
+    }
+    match fs::read_to_string(root.join(path)) {
+        Ok(source) => Ok(Some(source)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(minijinja::Error::new(
+            ErrorKind::InvalidOperation,
+            format!("Failed to read {name}"),
+        )
+        .with_source(e)),
+    }
+}
+
+/// Rewrites `{name}` placeholders of known variables to `{{ name }}`, leaving anything already
+/// inside `{{ }}` alone
+fn upgrade_placeholders(template: &str, names: &[&str]) -> String {
+    LEGACY_PLACEHOLDER_RE
+        .replace_all(template, |caps: &Captures<'_>| {
+            let name = &caps[2];
+            if caps.get(1).is_none() && caps.get(3).is_none() && names.contains(&name) {
+                format!("{{{{ {name} }}}}")
+            } else {
+                caps[0].to_string()
+            }
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let template = "Write in {{ language }}.\n{% if branch %}\nBranch: {{ branch }}\n{% endif %}\n\
+            {% for file in files %}\n- {{ file }}\n{% endfor %}\n";
+        let vars = json!({ "language": "English", "branch": "", "files": ["a.rs", "b.rs"] });
+        assert_eq!(
+            render(template, &[], vars, Path::new("")).unwrap(),
+            "Write in English.\n- a.rs\n- b.rs\n"
+        );
+    }
+
+    #[test]
+    fn test_render_include() {
+        let root = std::env::temp_dir().join(format!("ccc-jj-include-{}", std::process::id()));
+        fs::create_dir_all(root.join(".jj-prompts")).unwrap();
+        fs::write(root.join(".jj-prompts/rules.txt"), "Use {{ language }}.\n").unwrap();
+        let vars = json!({ "language": "English" });
+
+        let rendered = render("{% include \".jj-prompts/rules.txt\" %}", &[], &vars, &root);
+        assert_eq!(rendered.unwrap(), "Use English.\n");
+        assert!(render("{% include \"../secret\" %}", &[], &vars, &root).is_err());
+        assert!(render("{% include \"missing.txt\" %}", &[], &vars, &root).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_legacy_placeholders() {
+        let names = ["language", "diff_content"];
+        assert_eq!(
+            upgrade_placeholders("In {language}: {diff_content} {{ language }} {other}", &names),
+            "In {{ language }}: {{ diff_content }} {{ language }} {other}"
+        );
+        let vars = json!({ "language": "English", "diff_content": "+x" });
+        assert_eq!(
+            render("{language}\n{diff_content}\n", &names, vars, Path::new("")).unwrap(),
+            "English\n+x\n"
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let known = ["language", "branch"];
+        check(
+            "t",
+            "{{ diff_content }} {% if branch %}{{ branch }}{% endif %}",
+            &["diff_content"],
+            &known,
+        )
+        .unwrap();
+        check("t", "{diff_content} {language}", &["diff_content"], &known).unwrap();
+
+        let err = check("t", "{{ language }}", &["diff_content"], &known).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("missing the required variable {{ diff_content }}")
+        );
+        let err =
+            check("t", "{{ diff_content }} {{ brnch }}", &["diff_content"], &known).unwrap_err();
+        assert!(err.to_string().contains("unknown variable {{ brnch }}"));
+    }
+}
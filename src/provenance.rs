@@ -0,0 +1,86 @@
+use serde::{Deserialize, Serialize};
+
+/// Version of ccc-jj recorded with generated commits
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Key of the trailer that `trailers.generated_by` adds to generated messages
+pub const GENERATED_BY_KEY: &str = "X-Generated-By";
+
+/// Where a generated commit message came from, so that machine-described commits can be audited
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// The model that answered, which may be one of `generator.fallback_models`
+    pub model: String,
+    /// Hash of the prompt sent to the model, from [`hash_prompt`]
+    pub prompt_hash: String,
+}
+
+impl Provenance {
+    pub fn new(model: &str, prompt: &str) -> Self {
+        Self {
+            model: model.to_string(),
+            prompt_hash: hash_prompt(prompt),
+        }
+    }
+
+    /// The trailer naming the tool and model, e.g. `X-Generated-By: ccc-jj/0.10.4 haiku`
+    pub fn trailer(&self) -> String {
+        format!("{GENERATED_BY_KEY}: ccc-jj/{VERSION} {}", self.model)
+    }
+
+    /// The operation description with the model, prompt hash, and version appended, e.g.
+    /// `auto-commit via ccc-jj (haiku, prompt 0123456789abcdef, ccc-jj 0.10.4)`. The description
+    /// stays at the start so that operations can still be found by it.
+    pub fn annotate(&self, description: &str) -> String {
+        format!("{description} ({}, prompt {}, ccc-jj {VERSION})", self.model, self.prompt_hash)
+    }
+
+    /// Key-value tags recorded on the operation
+    pub fn tags(&self) -> [(String, String); 3] {
+        [
+            ("ccc-jj.model".to_string(), self.model.clone()),
+            ("ccc-jj.prompt-hash".to_string(), self.prompt_hash.clone()),
+            ("ccc-jj.version".to_string(), VERSION.to_string()),
+        ]
+    }
+}
+
+/// The 16-digit hex [`fnv1a`] hash of a prompt
+pub fn hash_prompt(prompt: &str) -> String {
+    format!("{:016x}", fnv1a(&[prompt]))
+}
+
+/// 64-bit FNV-1a over the parts, which unlike `DefaultHasher` is stable across Rust releases
+pub fn fnv1a(parts: &[impl AsRef<str>]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for part in parts {
+        // A separator keeps ("ab", "c") and ("a", "bc") apart
+        for byte in part.as_ref().bytes().chain([0]) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fnv1a() {
+        assert_eq!(fnv1a(&[""; 0]), 0xcbf2_9ce4_8422_2325);
+        assert_ne!(fnv1a(&["ab", "c"]), fnv1a(&["a", "bc"]));
+        assert_eq!(fnv1a(&["diff", "English"]), fnv1a(&["diff", "English"]));
+    }
+
+    #[test]
+    fn test_provenance() {
+        let provenance = Provenance::new("haiku", "prompt");
+        assert_eq!(provenance.prompt_hash.len(), 16);
+        assert_eq!(provenance.trailer(), format!("X-Generated-By: ccc-jj/{VERSION} haiku"));
+        let description = provenance.annotate("auto-commit via ccc-jj");
+        assert!(description.starts_with("auto-commit via ccc-jj (haiku, prompt "));
+        assert!(description.ends_with(&format!(", ccc-jj {VERSION})")));
+    }
+}
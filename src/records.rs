@@ -0,0 +1,65 @@
+use std::{
+    cmp::Reverse,
+    fs::{create_dir_all, read_dir, remove_dir_all, write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use tracing::{debug, warn};
+
+/// Directory holding context records, relative to the workspace's `.jj` directory
+const RECORDS_DIR: &str = "ccc-jj/records";
+
+/// Store the diff sent to Claude, its raw output, and the final message for an operation under
+/// `.jj/ccc-jj/records/<op-id>/`, then prune the oldest records beyond `max_records`.
+pub fn write_record(
+    workspace_root: &Path,
+    op_id: &str,
+    diff: &str,
+    raw_output: &str,
+    message: &str,
+    max_records: usize,
+) -> Result<PathBuf> {
+    let records_dir = workspace_root.join(".jj").join(RECORDS_DIR);
+    let record_dir = records_dir.join(op_id);
+    create_dir_all(&record_dir)
+        .with_context(|| format!("Failed to create {}", record_dir.display()))?;
+
+    write(record_dir.join("diff.patch"), diff)?;
+    write(record_dir.join("response.json"), raw_output)?;
+    write(record_dir.join("message.txt"), message)?;
+    debug!(path = %record_dir.display(), "Wrote context record");
+
+    prune_records(&records_dir, max_records)?;
+    Ok(record_dir)
+}
+
+/// Remove the oldest record directories so that at most `max_records` remain
+fn prune_records(records_dir: &Path, max_records: usize) -> Result<()> {
+    let mut records: Vec<(SystemTime, PathBuf)> = read_dir(records_dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| {
+            let modified = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            (modified, entry.path())
+        })
+        .collect();
+
+    if records.len() <= max_records {
+        return Ok(());
+    }
+
+    // Newest first, so everything past `max_records` is pruned
+    records.sort_by_key(|(modified, _)| Reverse(*modified));
+    for (_, path) in records.into_iter().skip(max_records) {
+        debug!(path = %path.display(), "Pruning old context record");
+        if let Err(e) = remove_dir_all(&path) {
+            warn!(path = %path.display(), error = %e, "Failed to prune context record");
+        }
+    }
+    Ok(())
+}
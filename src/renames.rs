@@ -0,0 +1,186 @@
+use std::cmp::Reverse;
+
+use similar::TextDiff;
+use tracing::debug;
+
+/// Upper bound on content comparisons, above which detection is skipped (like git's
+/// `diff.renameLimit`) to keep huge changes fast
+const MAX_COMPARISONS: usize = 10_000;
+
+/// A file an added file may have been renamed or copied from
+pub struct Source {
+    pub path: String,
+    /// Content before the change
+    pub content: Vec<u8>,
+    /// Deleted files can be renamed; modified files can only be copied
+    pub deleted: bool,
+}
+
+/// An added file
+pub struct Target {
+    pub path: String,
+    pub content: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Rename,
+    Copy,
+}
+
+/// An added file recognized as a rename or copy of a source
+#[derive(Debug, PartialEq, Eq)]
+pub struct Detected {
+    pub source: usize,
+    pub target: usize,
+    pub kind: Kind,
+    /// Content similarity in percent
+    pub similarity: u8,
+}
+
+/// Percentage of lines the two contents have in common. Binary contents only match when they are
+/// identical.
+pub fn similarity(before: &[u8], after: &[u8]) -> u8 {
+    if before == after {
+        return 100;
+    }
+    match (std::str::from_utf8(before), std::str::from_utf8(after)) {
+        (Ok(before), Ok(after)) => {
+            let ratio = TextDiff::from_lines(before, after).ratio();
+            // Never report a changed file as identical
+            ((ratio * 100.0).floor() as u8).min(99)
+        }
+        _ => 0,
+    }
+}
+
+/// Pairs added files with the deleted or modified files they most resemble.
+///
+/// Best matches are taken first. Each added file is matched at most once; a deleted file becomes
+/// the source of one rename and of copies for any further matches, while modified files only ever
+/// yield copies. Pairs below `min_similarity` percent are ignored, and `0` disables detection.
+pub fn detect(sources: &[Source], targets: &[Target], min_similarity: u8) -> Vec<Detected> {
+    if min_similarity == 0 || sources.is_empty() || targets.is_empty() {
+        return Vec::new();
+    }
+    if sources.len() * targets.len() > MAX_COMPARISONS {
+        debug!(
+            sources = sources.len(),
+            targets = targets.len(),
+            "Too many candidates, skipping rename detection"
+        );
+        return Vec::new();
+    }
+
+    let mut scored = Vec::new();
+    for (s, source) in sources.iter().enumerate() {
+        for (t, target) in targets.iter().enumerate() {
+            // Files of very different sizes can't reach the threshold, so skip the line diff
+            let (small, large) = if source.content.len() < target.content.len() {
+                (source.content.len(), target.content.len())
+            } else {
+                (target.content.len(), source.content.len())
+            };
+            if large > 0 && small * 100 / large < min_similarity as usize {
+                continue;
+            }
+            let score = similarity(&source.content, &target.content);
+            if score >= min_similarity {
+                scored.push((score, s, t));
+            }
+        }
+    }
+    // Stable sort keeps the tree order among equally good matches
+    scored.sort_by_key(|&(score, ..)| Reverse(score));
+
+    let mut target_used = vec![false; targets.len()];
+    let mut source_renamed = vec![false; sources.len()];
+    let mut detected = Vec::new();
+    for (score, s, t) in scored {
+        if target_used[t] {
+            continue;
+        }
+        target_used[t] = true;
+        let kind = if sources[s].deleted && !source_renamed[s] {
+            source_renamed[s] = true;
+            Kind::Rename
+        } else {
+            Kind::Copy
+        };
+        detected.push(Detected { source: s, target: t, kind, similarity: score });
+    }
+    debug!(count = detected.len(), "Detected renames and copies");
+    detected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(path: &str, content: &str, deleted: bool) -> Source {
+        Source {
+            path: path.to_string(),
+            content: content.as_bytes().to_vec(),
+            deleted,
+        }
+    }
+
+    fn target(path: &str, content: &str) -> Target {
+        Target {
+            path: path.to_string(),
+            content: content.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_similarity() {
+        assert_eq!(similarity(b"a\nb\n", b"a\nb\n"), 100);
+        assert_eq!(similarity(b"a\nb\nc\nd\n", b"a\nb\nc\nx\n"), 75);
+        assert_eq!(similarity(b"a\n", b"z\n"), 0);
+        assert_eq!(similarity(&[0xff, 0x00], &[0xff, 0x01]), 0);
+        assert_eq!(similarity(&[0xff, 0x00], &[0xff, 0x00]), 100);
+    }
+
+    #[test]
+    fn test_detect_rename_and_copy() {
+        let sources = [source("old.rs", "a\nb\nc\nd\n", true), source("lib.rs", "x\ny\n", false)];
+        let targets = [
+            target("new.rs", "a\nb\nc\nd\n"),
+            target("other.rs", "a\nb\nc\ne\n"),
+            target("lib_copy.rs", "x\ny\n"),
+            target("unrelated.rs", "q\n"),
+        ];
+        assert_eq!(
+            detect(&sources, &targets, 50),
+            vec![
+                Detected {
+                    source: 0,
+                    target: 0,
+                    kind: Kind::Rename,
+                    similarity: 100
+                },
+                Detected {
+                    source: 1,
+                    target: 2,
+                    kind: Kind::Copy,
+                    similarity: 100
+                },
+                Detected {
+                    source: 0,
+                    target: 1,
+                    kind: Kind::Copy,
+                    similarity: 75
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_detect_respects_threshold() {
+        let sources = [source("old.rs", "a\nb\nc\nd\n", true)];
+        let targets = [target("new.rs", "a\nb\nc\nx\n")];
+        assert!(detect(&sources, &targets, 80).is_empty());
+        assert!(detect(&sources, &targets, 0).is_empty());
+        assert_eq!(detect(&sources, &targets, 75).len(), 1);
+    }
+}
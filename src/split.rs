@@ -0,0 +1,302 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+use futures::StreamExt;
+use jj_lib::{
+    matchers::EverythingMatcher,
+    merge::MergedTreeValue,
+    merged_tree::{MergedTree, MergedTreeBuilder},
+    repo_path::RepoPathBuf,
+};
+use serde::Deserialize;
+use serde_json::{from_value, json};
+use tracing::{debug, trace, warn};
+
+use crate::{
+    commit_message_generator::{FormatOptions, apply_style, format_message, message_from_output},
+    config::CONFIG,
+    diff::escape_path,
+    generator::{GenerationRequest, Generator, backend_from_config, invoke},
+};
+
+const JSON_SCHEMA: &str = r#"{"type":"object","properties":{"commits":{"type":"array","description":"Logical commits in the order they should be created","items":{"type":"object","properties":{"files":{"type":"array","items":{"type":"string"},"description":"Paths of the changed files belonging to this commit"},"commit_type":{"type":"string","enum":["feat","fix","refactor","docs","test","chore","style","perf","build","ci"],"description":"Conventional commit type"},"title":{"type":"string","description":"Commit description without type prefix, max 50 chars, imperative mood"},"body":{"type":"string","description":"Optional commit body explaining what and why"}},"required":["files","commit_type","title"]}}},"required":["commits"]}"#;
+
+/// A commit proposed by the model
+#[derive(Debug, Deserialize)]
+pub struct PlannedCommit {
+    pub files: Vec<String>,
+    pub commit_type: String,
+    pub title: String,
+    #[serde(default)]
+    pub body: String,
+}
+
+/// The model's proposal for splitting a change into logical commits
+#[derive(Debug, Deserialize)]
+pub struct SplitPlan {
+    pub commits: Vec<PlannedCommit>,
+}
+
+/// A commit to create: its formatted message and the files it contains
+#[derive(Debug, PartialEq, Eq)]
+pub struct SplitCommit {
+    pub message: String,
+    pub files: Vec<String>,
+}
+
+/// Asks the model how to group the changed files of a large change into logical commits
+pub struct SplitGenerator {
+    prompt_template: String,
+    backend: Box<dyn Generator + Send + Sync>,
+    language: String,
+    model: String,
+    format_options: FormatOptions,
+}
+
+impl SplitGenerator {
+    /// Creates a new split generator
+    ///
+    /// # Arguments
+    /// - `language` - The language to use for the commit messages
+    /// - `model` - The model to use for generation
+    /// - `format_options` - How to format the commit messages
+    pub fn new(language: &str, model: &str, format_options: FormatOptions) -> Self {
+        Self {
+            prompt_template: CONFIG.split.prompt_template.clone(),
+            backend: backend_from_config(&CONFIG.generator),
+            language: language.to_string(),
+            model: model.to_string(),
+            format_options,
+        }
+    }
+
+    /// Generates the commits to split the change into
+    ///
+    /// # Arguments
+    /// - `diff_content` - The diff of the whole change
+    /// - `changed_files` - Every changed path; each ends up in exactly one commit
+    ///
+    /// # Returns
+    /// `Some(commits)` if generation succeeds, `None` if it fails, or an error if the call budget
+    /// is exhausted.
+    pub fn generate(
+        &self,
+        diff_content: &str,
+        changed_files: &[String],
+    ) -> Result<Option<Vec<SplitCommit>>> {
+        let files = changed_files
+            .iter()
+            .map(|f| format!("- {}", escape_path(f)))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let prompt = self
+            .prompt_template
+            .replace("{language}", &self.language)
+            .replace("{files}", &files)
+            .replace("{diff_content}", diff_content);
+        trace!(prompt_len = prompt.len(), "Prepared split prompt");
+
+        let request = GenerationRequest {
+            model: &self.model,
+            json_schema: JSON_SCHEMA,
+            prompt: &prompt,
+            stable_prefix: 0,
+            spinner_message: "Planning commits with Claude...",
+        };
+        let Some(response) = invoke(self.backend.as_ref(), &request)? else {
+            return Ok(None);
+        };
+        let plan = match from_value::<SplitPlan>(response.structured) {
+            Ok(plan) => plan,
+            Err(e) => {
+                warn!(error = %e, "Split plan does not match the expected schema");
+                return Ok(None);
+            }
+        };
+        debug!(commits = plan.commits.len(), "Received split plan");
+        Ok(Some(assign_files(plan, changed_files, &self.format_options)))
+    }
+}
+
+/// A changed path with its value in the snapshot (absent when the file was deleted)
+pub struct TreeChange {
+    pub path: RepoPathBuf,
+    pub after: MergedTreeValue,
+}
+
+impl TreeChange {
+    fn path_string(&self) -> String {
+        self.path.as_internal_file_string().to_string()
+    }
+}
+
+/// Collects every path that differs between the two trees
+pub async fn collect_changes(from_tree: &MergedTree, to_tree: &MergedTree) -> Vec<TreeChange> {
+    let mut changes = Vec::new();
+    let mut stream = from_tree.diff_stream(to_tree, &EverythingMatcher);
+    while let Some(entry) = stream.next().await {
+        match entry.values {
+            Ok(values) => changes.push(TreeChange { path: entry.path, after: values.after }),
+            Err(e) => warn!(path = ?entry.path, error = %e, "Failed to read tree diff entry"),
+        }
+    }
+    changes
+}
+
+/// Lists the changed paths, in the form used in prompts and split plans
+pub fn changed_paths(changes: &[TreeChange]) -> Vec<String> {
+    changes.iter().map(TreeChange::path_string).collect()
+}
+
+/// Builds the tree of one split commit: `base_tree` with the changes to `files` applied
+pub fn apply_changes(
+    base_tree: &MergedTree,
+    changes: &[TreeChange],
+    files: &[String],
+) -> Result<MergedTree> {
+    let mut builder = MergedTreeBuilder::new(base_tree.clone());
+    for change in changes.iter().filter(|change| files.contains(&change.path_string())) {
+        builder.set_or_remove(change.path.clone(), change.after.clone());
+    }
+    Ok(builder.write_tree()?)
+}
+
+/// Turns the plan into commits so that every changed file lands in exactly one commit.
+///
+/// Files claimed by several commits stay in the first, unknown paths are dropped, files the plan
+/// forgot go into the last commit, and commits left without files are skipped. The plan names
+/// files as the prompt showed them, with [`escape_path`] applied.
+fn assign_files(
+    plan: SplitPlan,
+    changed_files: &[String],
+    format_options: &FormatOptions,
+) -> Vec<SplitCommit> {
+    let changed: HashMap<String, &String> = changed_files
+        .iter()
+        .map(|file| (escape_path(file).into_owned(), file))
+        .collect();
+    let mut assigned: HashSet<String> = HashSet::new();
+    let mut commits = Vec::new();
+
+    for planned in plan.commits {
+        let files: Vec<String> = planned
+            .files
+            .into_iter()
+            .filter_map(|file| {
+                let Some(&path) = changed.get(&file) else {
+                    warn!(file = %file, "Split plan references an unchanged file, ignoring");
+                    return None;
+                };
+                assigned.insert(path.clone()).then(|| path.clone())
+            })
+            .collect();
+        if files.is_empty() {
+            continue;
+        }
+        let output = json!({
+            "commit_type": planned.commit_type,
+            "subject": planned.title,
+            "body": planned.body,
+        });
+        let Some(message) = message_from_output(output) else {
+            continue;
+        };
+        commits.push(SplitCommit {
+            message: format_message(&apply_style(&message, format_options.style), format_options),
+            files,
+        });
+    }
+
+    let unassigned: Vec<String> = changed_files
+        .iter()
+        .filter(|file| !assigned.contains(*file))
+        .cloned()
+        .collect();
+    if !unassigned.is_empty() {
+        warn!(
+            count = unassigned.len(),
+            "Split plan missed some files, adding them to the last commit"
+        );
+        match commits.last_mut() {
+            Some(last) => last.files.extend(unassigned),
+            None => commits.push(SplitCommit {
+                message: format_message(
+                    &apply_style(&CONFIG.generator.default_commit_message, format_options.style),
+                    format_options,
+                ),
+                files: unassigned,
+            }),
+        }
+    }
+    commits
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::config::CommitStyle;
+
+    fn options() -> FormatOptions {
+        FormatOptions {
+            wrap: true,
+            hard_wrap_width: None,
+            wrap_cjk: true,
+            style: CommitStyle::Conventional,
+        }
+    }
+
+    fn plan(value: Value) -> SplitPlan {
+        from_value(value).unwrap()
+    }
+
+    fn files(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn test_assign_files_follows_plan() {
+        let plan = plan(json!({"commits": [
+            {"files": ["src/a.rs"], "commit_type": "feat", "title": "add a"},
+            {"files": ["README.md"], "commit_type": "docs", "title": "document a"},
+        ]}));
+        let commits = assign_files(plan, &files(&["src/a.rs", "README.md"]), &options());
+        assert_eq!(
+            commits,
+            vec![
+                SplitCommit {
+                    message: "feat: add a\n".to_string(),
+                    files: files(&["src/a.rs"])
+                },
+                SplitCommit {
+                    message: "docs: document a\n".to_string(),
+                    files: files(&["README.md"])
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assign_files_fixes_up_plan() {
+        let plan = plan(json!({"commits": [
+            {"files": ["a", "ghost"], "commit_type": "feat", "title": "add a"},
+            {"files": ["a"], "commit_type": "fix", "title": "only duplicates"},
+            {"files": ["b"], "commit_type": "fix", "title": "fix b"},
+        ]}));
+        let commits = assign_files(plan, &files(&["a", "b", "c"]), &options());
+        assert_eq!(commits.len(), 2);
+        assert_eq!(commits[0].files, files(&["a"]));
+        assert_eq!(commits[1].message, "fix: fix b\n");
+        assert_eq!(commits[1].files, files(&["b", "c"]));
+    }
+
+    #[test]
+    fn test_assign_files_maps_escaped_paths() {
+        let plan = plan(json!({"commits": [
+            {"files": ["new\\nline.txt"], "commit_type": "feat", "title": "add a file"},
+        ]}));
+        let commits = assign_files(plan, &files(&["new\nline.txt"]), &options());
+        assert_eq!(commits[0].files, files(&["new\nline.txt"]));
+    }
+}
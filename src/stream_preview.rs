@@ -0,0 +1,104 @@
+use console::{Term, style, truncate_str};
+use indicatif::ProgressBar;
+
+/// Number of answer lines shown under the spinner
+const PREVIEW_LINES: usize = 6;
+
+/// Shows the answer of a streaming generation under the spinner as it arrives
+pub struct StreamPreview<'a> {
+    spinner: &'a ProgressBar,
+    message: &'a str,
+    text: String,
+}
+
+impl<'a> StreamPreview<'a> {
+    /// # Arguments
+    /// - `message` - The spinner message the preview is shown under
+    pub fn new(spinner: &'a ProgressBar, message: &'a str) -> Self {
+        Self { spinner, message, text: String::new() }
+    }
+
+    /// Adds a piece of the answer and redraws the last lines of it
+    pub fn push(&mut self, text: &str) {
+        self.text.push_str(text);
+
+        // Lines wider than the terminal would wrap and break the spinner's redraw
+        let width = usize::from(Term::stderr().size().1).saturating_sub(4).max(10);
+        let readable = readable_text(&self.text);
+        let lines: Vec<&str> = readable.lines().filter(|line| !line.trim().is_empty()).collect();
+
+        let mut message = self.message.to_string();
+        for line in &lines[lines.len().saturating_sub(PREVIEW_LINES)..] {
+            message.push_str(&format!("\n  {}", style(truncate_str(line, width, "…")).dim()));
+        }
+        self.spinner.set_message(message);
+    }
+}
+
+/// Turns a partial answer into something readable. A JSON answer shows the values of its string
+/// fields, one per line, including the one still being written; free text is shown as it is.
+fn readable_text(partial: &str) -> String {
+    let trimmed = partial.trim_start();
+    let json = trimmed
+        .strip_prefix("```json")
+        .or_else(|| trimmed.strip_prefix("```"))
+        .unwrap_or(trimmed)
+        .trim_start();
+    if !json.starts_with('{') {
+        return partial.to_string();
+    }
+    json_string_values(json).join("\n")
+}
+
+/// The string values in possibly incomplete JSON, leaving out object keys
+fn json_string_values(json: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    let mut chars = json.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '"' {
+            continue;
+        }
+        let mut value = String::new();
+        let mut closed = false;
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => {
+                    closed = true;
+                    break;
+                }
+                '\\' => match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('r' | 'b' | 'f') | None => {}
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        value.extend(u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32));
+                    }
+                    Some(escaped) => value.push(escaped),
+                },
+                c => value.push(c),
+            }
+        }
+
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let is_key = closed && chars.peek() == Some(&':');
+        if !is_key {
+            values.push(value);
+        }
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readable_text() {
+        let partial = r#"{"commit_type": "feat", "subject": "add \"x\"", "body": "First\nSec"#;
+        assert_eq!(readable_text(partial), "feat\nadd \"x\"\nFirst\nSec");
+        let fenced = "```json\n{\"issues\": [\"#1\"], \"subject\": \"fix \\u00e9\"}\n```";
+        assert_eq!(readable_text(fenced), "#1\nfix é");
+        assert_eq!(readable_text("feat: add x\n\nBo"), "feat: add x\n\nBo");
+    }
+}
@@ -1,4 +1,7 @@
-use textwrap::{Options, wrap};
+use textwrap::{Options, WordSeparator, wrap};
+use unicode_width::UnicodeWidthStr;
+
+use crate::trailers::{is_trailer_line, split_trailers};
 
 /// Formats text with proper line wrapping and list-aware indentation.
 ///
@@ -7,7 +10,11 @@ use textwrap::{Options, wrap};
 /// - Preserves list formatting with proper hanging indents:
 ///   - Bullet lists (`- `) continue with 2-space indent
 ///   - Numbered lists (`1. `, `10. `) continue with matching indent
+/// - Keeps a trailing trailer block (`Signed-off-by: ...`) as is, one trailer per line
 pub fn format_text(text: &str, width: usize) -> String {
+    if let (body, Some(trailers)) = split_trailers(text) {
+        return format!("{}\n\n{trailers}", format_text(body, width));
+    }
     let paragraphs = split_into_paragraphs(text);
     let mut result = String::new();
 
@@ -29,6 +36,87 @@ pub fn format_text(text: &str, width: usize) -> String {
     result
 }
 
+/// Breaks lines longer than `width` at whitespace, leaving shorter lines untouched.
+///
+/// This is a safety net for unwrapped output rather than a formatter: lists and paragraphs are
+/// not reflowed, and single words longer than `width` (e.g. URLs) are kept whole. Trailer lines
+/// are never broken, since a continuation line would change how git reads them. Widths are display
+/// columns, so double-width characters count twice, and CJK lines, which have no spaces between
+/// words, are broken where Unicode line breaking allows.
+pub fn hard_wrap(text: &str, width: usize) -> String {
+    text.lines()
+        .map(|line| {
+            if line.width() <= width || is_trailer_line(line) {
+                return line.to_string();
+            }
+            let trimmed = line.trim_start();
+            let leading_ws = &line[..line.len() - trimmed.len()];
+            let separator = if is_cjk(trimmed) {
+                WordSeparator::UnicodeBreakProperties
+            } else {
+                WordSeparator::AsciiSpace
+            };
+            let opts = Options::new(width)
+                .initial_indent(leading_ws)
+                .subsequent_indent(leading_ws)
+                .break_words(false)
+                .word_separator(separator);
+            wrap(trimmed, opts).join("\n")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Whether the text contains Chinese, Japanese, or Korean script (Han, kana, or Hangul)
+pub fn is_cjk(text: &str) -> bool {
+    text.chars().any(|c| {
+        matches!(c,
+            '\u{3040}'..='\u{30ff}' // Hiragana and Katakana
+            | '\u{3400}'..='\u{4dbf}' // CJK Unified Ideographs Extension A
+            | '\u{4e00}'..='\u{9fff}' // CJK Unified Ideographs
+            | '\u{ac00}'..='\u{d7af}' // Hangul Syllables
+            | '\u{f900}'..='\u{faff}' // CJK Compatibility Ideographs
+            | '\u{ff66}'..='\u{ff9f}' // Halfwidth Katakana
+        )
+    })
+}
+
+/// Wraps markdown at `width` like [`format_text`], except that the first paragraph is wrapped too.
+///
+/// Headings, fenced code blocks, and blank lines are kept as they are, so only prose and list
+/// items are reflowed.
+pub fn format_markdown(text: &str, width: usize) -> String {
+    let mut result = Vec::new();
+    let mut prose = Vec::new();
+    let mut in_fence = false;
+
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        let is_fence = trimmed.starts_with("```");
+        if in_fence || is_fence || trimmed.starts_with('#') || trimmed.is_empty() {
+            wrap_prose(&mut prose, &mut result, width);
+            result.push(line.to_string());
+            in_fence ^= is_fence;
+        } else {
+            prose.push(line);
+        }
+    }
+    wrap_prose(&mut prose, &mut result, width);
+
+    result.join("\n")
+}
+
+/// Reflows a run of prose lines without blank lines in it, one list item at a time
+fn wrap_prose(lines: &mut Vec<&str>, result: &mut Vec<String>, width: usize) {
+    if lines.is_empty() {
+        return;
+    }
+    for para in split_into_paragraphs(&lines.join("\n")) {
+        result.push(format_line(&para.content, width));
+    }
+    lines.clear();
+}
+
 struct Paragraph {
     content: String,
     trailing_blank_lines: usize,
@@ -145,6 +233,38 @@ fn detect_list_indent(line: &str) -> &'static str {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_hard_wrap_only_touches_long_lines() {
+        let long = "word ".repeat(30);
+        let text = format!("short line\n{}", long.trim_end());
+        let wrapped = hard_wrap(&text, 40);
+        let lines: Vec<&str> = wrapped.lines().collect();
+        assert_eq!(lines[0], "short line");
+        assert!(lines.len() > 2);
+        assert!(lines.iter().all(|line| line.len() <= 40));
+    }
+
+    #[test]
+    fn test_hard_wrap_keeps_long_words() {
+        let url = format!("https://example.com/{}", "a".repeat(60));
+        assert_eq!(hard_wrap(&format!("See {url}"), 40), format!("See\n{url}"));
+    }
+
+    #[test]
+    fn test_trailers_are_not_wrapped() {
+        let trailer = format!("Reviewed-by: {} <r@example.com>", "Reviewer ".repeat(8).trim_end());
+        let input = format!("Title\n\nBody.\n\n{trailer}\nSigned-off-by: A <a@example.com>");
+        assert_eq!(format_text(&input, 40), input);
+        assert_eq!(hard_wrap(&input, 40), input);
+    }
+
+    #[test]
+    fn test_format_markdown() {
+        let input = "## Summary\n\nThis change adds an explain command that describes the working copy in plain words.\n\n- A bullet that is long enough to need wrapping onto a second line.\n\n```rust\nfn unchanged_code_that_is_longer_than_the_width() {}\n```";
+        let expected = "## Summary\n\nThis change adds an explain command that describes the working\ncopy in plain words.\n\n- A bullet that is long enough to need wrapping onto a second\n  line.\n\n```rust\nfn unchanged_code_that_is_longer_than_the_width() {}\n```";
+        assert_eq!(format_markdown(input, 64), expected);
+    }
+
     #[test]
     fn test_simple_text_no_wrap_needed() {
         let input = "Short line.";
@@ -266,6 +386,19 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_hard_wrap_measures_display_width() {
+        // 30 characters, 60 columns
+        let line = "あいうえおかきくけこさしすせそたちつてとなにぬねのはひふへほ";
+        assert_eq!(
+            hard_wrap(line, 40),
+            "あいうえおかきくけこさしすせそたちつてと\nなにぬねのはひふへほ"
+        );
+        assert_eq!(hard_wrap(line, 60), line);
+        assert!(is_cjk("fix: 修正"));
+        assert!(!is_cjk("fix: café"));
+    }
+
     #[test]
     fn test_japanese_wrap() {
         // 全角文字は幅2としてカウントされるべき
@@ -0,0 +1,112 @@
+use std::sync::LazyLock;
+
+use jj_lib::{repo::ReadonlyRepo, workspace::Workspace};
+use regex::Regex;
+use tracing::{debug, warn};
+
+use crate::{
+    config::{CONFIG, TicketPlacement},
+    pipeline::branch_names,
+    trailers::append_trailers,
+};
+
+static PATTERN: LazyLock<Option<Regex>> =
+    LazyLock::new(|| match Regex::new(&CONFIG.ticket.pattern) {
+        Ok(re) => Some(re),
+        Err(e) => {
+            warn!(pattern = %CONFIG.ticket.pattern, error = %e, "Invalid ticket pattern, ignoring");
+            None
+        }
+    });
+
+/// A conventional commit type prefix, e.g. `feat(cli)!: `
+static TYPE_PREFIX_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^[a-z]+(?:\([^)]+\))?!?:\s+").expect("Failed to compile type prefix regex")
+});
+
+/// Finds a ticket ID in the names of the bookmarks on the working-copy commit or its parents, or
+/// in the checked-out branch of a colocated git repository
+pub fn current_ticket(repo: &ReadonlyRepo, workspace: &Workspace) -> Option<String> {
+    ticket_in(branch_names(repo, workspace).iter().map(String::as_str))
+}
+
+/// Finds a ticket ID matching `ticket.pattern` in bookmark or branch names.
+///
+/// Returns `None` when `ticket.placement` is `none`.
+pub fn ticket_in<'a>(names: impl IntoIterator<Item = &'a str> + Clone) -> Option<String> {
+    if CONFIG.ticket.placement == TicketPlacement::None {
+        return None;
+    }
+    let ticket = find_ticket(names.clone(), PATTERN.as_ref()?);
+    debug!(names = ?names.into_iter().collect::<Vec<_>>(), ?ticket, "Looked for a ticket ID");
+    ticket
+}
+
+/// The first match of `pattern` in the names
+fn find_ticket<'a>(names: impl IntoIterator<Item = &'a str>, pattern: &Regex) -> Option<String> {
+    names
+        .into_iter()
+        .find_map(|name| pattern.find(name).map(|m| m.as_str().to_string()))
+}
+
+/// Adds the ticket ID to the message as configured by `ticket.placement`: after the type prefix
+/// of the subject, at the end of the subject, or as a `Refs:` trailer. Messages that already
+/// mention the ticket are left alone.
+pub fn apply_ticket(message: &str, ticket: &str, placement: TicketPlacement) -> String {
+    if message.contains(ticket) {
+        return message.to_string();
+    }
+    let (subject, rest) = match message.split_once('\n') {
+        Some((subject, rest)) => (subject, Some(rest)),
+        None => (message, None),
+    };
+    let subject = match placement {
+        TicketPlacement::None => return message.to_string(),
+        TicketPlacement::Trailer => return append_trailers(message, &[format!("Refs: {ticket}")]),
+        TicketPlacement::Prefix => {
+            let at = TYPE_PREFIX_RE.find(subject).map_or(0, |m| m.end());
+            format!("{}{ticket} {}", &subject[..at], &subject[at..])
+        }
+        TicketPlacement::Suffix => format!("{subject} ({ticket})"),
+    };
+    match rest {
+        Some(rest) => format!("{subject}\n{rest}"),
+        None => subject,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_ticket() {
+        let pattern = Regex::new(r"\b[A-Z][A-Z0-9]+-\d+\b").unwrap();
+        assert_eq!(
+            find_ticket(["main", "feature/JIRA-1234-login"], &pattern),
+            Some("JIRA-1234".to_string())
+        );
+        assert_eq!(find_ticket(["main", "fix-123"], &pattern), None);
+    }
+
+    #[test]
+    fn test_apply_ticket() {
+        let message = "feat(auth): add login\n\nBody.\n";
+        assert_eq!(
+            apply_ticket(message, "JIRA-1", TicketPlacement::Prefix),
+            "feat(auth): JIRA-1 add login\n\nBody.\n"
+        );
+        assert_eq!(
+            apply_ticket(message, "JIRA-1", TicketPlacement::Suffix),
+            "feat(auth): add login (JIRA-1)\n\nBody.\n"
+        );
+        assert_eq!(
+            apply_ticket(message, "JIRA-1", TicketPlacement::Trailer),
+            "feat(auth): add login\n\nBody.\n\nRefs: JIRA-1\n"
+        );
+        assert_eq!(
+            apply_ticket("fix: JIRA-1 x", "JIRA-1", TicketPlacement::Suffix),
+            "fix: JIRA-1 x"
+        );
+    }
+}
@@ -0,0 +1,155 @@
+use std::{collections::HashSet, sync::LazyLock};
+
+use regex::Regex;
+
+/// `Key: value` trailer lines, plus the conventional commits `BREAKING CHANGE` footer
+static TRAILER_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(BREAKING CHANGE|[A-Za-z0-9][A-Za-z0-9-]*):\s+\S")
+        .expect("Failed to compile trailer regex")
+});
+
+/// Returns true if the line looks like a `Key: value` trailer
+pub fn is_trailer_line(line: &str) -> bool {
+    TRAILER_RE.is_match(line)
+}
+
+/// Splits a message into its body and trailing trailer block.
+///
+/// The trailer block is the last paragraph if every line in it is a trailer (or an indented
+/// continuation of one). The first paragraph is the subject and is never treated as trailers.
+pub fn split_trailers(message: &str) -> (&str, Option<&str>) {
+    let trimmed = message.trim_end();
+    let Some(start) = trimmed.rfind("\n\n").map(|pos| pos + 2) else {
+        return (trimmed, None);
+    };
+
+    let block = trimmed[start..].trim_start_matches('\n');
+    let mut lines = block.lines();
+    let first_is_trailer = lines.next().is_some_and(is_trailer_line);
+    let rest_are_trailers =
+        lines.all(|line| is_trailer_line(line) || line.starts_with([' ', '\t']));
+
+    if first_is_trailer && rest_are_trailers {
+        (trimmed[..start].trim_end(), Some(block))
+    } else {
+        (trimmed, None)
+    }
+}
+
+/// Appends trailers to the message's trailer block, starting a new paragraph if it has none.
+/// Trailers already in the message keep their place and are not repeated.
+pub fn append_trailers(message: &str, trailers: &[String]) -> String {
+    if trailers.is_empty() {
+        return message.to_string();
+    }
+    let (body, block) = split_trailers(message);
+    let mut lines: Vec<&str> = block.into_iter().collect();
+    lines.extend(trailers.iter().map(|trailer| trailer.trim()));
+    format!("{body}\n\n{}\n", dedupe_trailer_block(&lines.join("\n")))
+}
+
+/// Collapses identical trailers in a trailer block, keeping the first occurrence. Keys are
+/// compared case-insensitively and values exactly.
+pub fn dedupe_trailer_block(block: &str) -> String {
+    let mut seen = HashSet::new();
+    let mut trailers: Vec<String> = Vec::new();
+
+    for line in block.lines() {
+        // Continuation lines belong to the preceding trailer
+        if line.starts_with([' ', '\t'])
+            && let Some(last) = trailers.last_mut()
+        {
+            last.push('\n');
+            last.push_str(line);
+        } else {
+            trailers.push(line.to_string());
+        }
+    }
+
+    trailers
+        .into_iter()
+        .filter(|trailer| {
+            let (key, value) = trailer.split_once(':').unwrap_or((trailer, ""));
+            seen.insert((key.trim().to_lowercase(), value.trim().to_string()))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dedupe_trailers(message: &str) -> String {
+        match split_trailers(message) {
+            (body, Some(block)) => format!("{body}\n\n{}", dedupe_trailer_block(block)),
+            (body, None) => body.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_split_trailers() {
+        let message = "feat: add x\n\nBody text.\n\nSigned-off-by: A <a@example.com>\nRefs: #1";
+        let (body, trailers) = split_trailers(message);
+        assert_eq!(body, "feat: add x\n\nBody text.");
+        assert_eq!(trailers, Some("Signed-off-by: A <a@example.com>\nRefs: #1"));
+    }
+
+    #[test]
+    fn test_subject_is_not_a_trailer() {
+        assert_eq!(split_trailers("feat: add x"), ("feat: add x", None));
+        assert_eq!(split_trailers("feat: add x\n"), ("feat: add x", None));
+    }
+
+    #[test]
+    fn test_mixed_paragraph_is_not_a_trailer_block() {
+        let message = "feat: add x\n\nNote: this is prose\nand continues here.";
+        assert_eq!(split_trailers(message), (message, None));
+    }
+
+    #[test]
+    fn test_dedupe_signoff() {
+        let message =
+            "fix: y\n\nSigned-off-by: A <a@example.com>\nsigned-off-by: A <a@example.com>";
+        assert_eq!(dedupe_trailers(message), "fix: y\n\nSigned-off-by: A <a@example.com>");
+    }
+
+    #[test]
+    fn test_dedupe_coauthors_preserves_order() {
+        let message = "feat: z\n\nBody.\n\nCo-authored-by: B <b@example.com>\nSigned-off-by: A <a@example.com>\nCo-authored-by: C <c@example.com>\nCo-Authored-By: B <b@example.com>";
+        assert_eq!(
+            dedupe_trailers(message),
+            "feat: z\n\nBody.\n\nCo-authored-by: B <b@example.com>\nSigned-off-by: A <a@example.com>\nCo-authored-by: C <c@example.com>"
+        );
+    }
+
+    #[test]
+    fn test_dedupe_keeps_distinct_values() {
+        let message = "feat: z\n\nRefs: #1\nRefs: #2";
+        assert_eq!(dedupe_trailers(message), message);
+    }
+
+    #[test]
+    fn test_append_trailers() {
+        let signoff = "Signed-off-by: A <a@example.com>".to_string();
+        assert_eq!(
+            append_trailers("feat: add x\n\nBody.\n", std::slice::from_ref(&signoff)),
+            "feat: add x\n\nBody.\n\nSigned-off-by: A <a@example.com>\n"
+        );
+        assert_eq!(
+            append_trailers(
+                "feat: add x\n\nRefs: #1\nSigned-off-by: A <a@example.com>\n",
+                &["Reviewed-by: B <b@example.com>".to_string(), signoff]
+            ),
+            "feat: add x\n\nRefs: #1\nSigned-off-by: A <a@example.com>\nReviewed-by: B <b@example.com>\n"
+        );
+        assert_eq!(append_trailers("feat: add x\n", &[]), "feat: add x\n");
+    }
+
+    #[test]
+    fn test_breaking_change_footer() {
+        let message =
+            "feat!: z\n\nBREAKING CHANGE: the API changed\nBREAKING CHANGE: the API changed";
+        assert_eq!(dedupe_trailers(message), "feat!: z\n\nBREAKING CHANGE: the API changed");
+    }
+}
@@ -0,0 +1,189 @@
+use anyhow::{Context, Result};
+use ccc_jj_lib::diff::added_removed_lines;
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, List, ListItem, ListState, Paragraph},
+};
+use tracing::debug;
+
+/// Lines the diff preview moves per page
+const PAGE_LINES: u16 = 10;
+
+/// A changed file on the review screen
+pub struct FileEntry {
+    pub path: String,
+    pub added: usize,
+    pub removed: usize,
+    pub diff: String,
+    /// Whether the file's changes go into the commit
+    pub included: bool,
+}
+
+impl FileEntry {
+    /// An included file with the +/- counts of its part of the diff
+    pub fn new(path: String, diff: String) -> Self {
+        let (added, removed) = added_removed_lines(&diff);
+        Self { path, added, removed, diff, included: true }
+    }
+}
+
+/// Shows the changed files with their diffs and lets the user pick the ones to commit.
+///
+/// Returns the included paths, or `None` if the user quit.
+pub fn review_files(files: Vec<FileEntry>) -> Result<Option<Vec<String>>> {
+    let mut terminal = ratatui::try_init().context("Failed to start the terminal UI")?;
+    let result = Review::new(files).run(&mut terminal);
+    ratatui::restore();
+    let paths = result.context("Terminal UI failed")?;
+    debug!(?paths, "Reviewed files");
+    Ok(paths)
+}
+
+struct Review {
+    files: Vec<FileEntry>,
+    list: ListState,
+    /// First line of the selected file's diff shown in the preview
+    scroll: u16,
+}
+
+impl Review {
+    fn new(files: Vec<FileEntry>) -> Self {
+        let list = ListState::default().with_selected((!files.is_empty()).then_some(0));
+        Self { files, list, scroll: 0 }
+    }
+
+    fn run(mut self, terminal: &mut DefaultTerminal) -> std::io::Result<Option<Vec<String>>> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(None),
+                KeyCode::Char('c') | KeyCode::Enter => return Ok(Some(self.included())),
+                KeyCode::Up | KeyCode::Char('k') => self.select(|list| list.select_previous()),
+                KeyCode::Down | KeyCode::Char('j') => self.select(|list| list.select_next()),
+                KeyCode::Char(' ') => self.toggle(),
+                KeyCode::Char('a') => self.toggle_all(),
+                KeyCode::PageDown | KeyCode::Char('J') => {
+                    self.scroll = self.scroll.saturating_add(PAGE_LINES);
+                }
+                KeyCode::PageUp | KeyCode::Char('K') => {
+                    self.scroll = self.scroll.saturating_sub(PAGE_LINES);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Moves the selection, showing the new file's diff from the top
+    fn select(&mut self, move_selection: impl FnOnce(&mut ListState)) {
+        move_selection(&mut self.list);
+        self.scroll = 0;
+    }
+
+    fn toggle(&mut self) {
+        if let Some(file) = self.list.selected().and_then(|i| self.files.get_mut(i)) {
+            file.included = !file.included;
+        }
+    }
+
+    /// Excludes every file if all are included, and includes every file otherwise
+    fn toggle_all(&mut self) {
+        let include = !self.files.iter().all(|file| file.included);
+        for file in &mut self.files {
+            file.included = include;
+        }
+    }
+
+    fn included(&self) -> Vec<String> {
+        self.files
+            .iter()
+            .filter(|file| file.included)
+            .map(|file| file.path.clone())
+            .collect()
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, help] =
+            Layout::vertical([Constraint::Fill(1), Constraint::Length(1)]).areas(frame.area());
+        let [files_area, diff_area] =
+            Layout::horizontal([Constraint::Percentage(35), Constraint::Fill(1)]).areas(main);
+
+        let items: Vec<ListItem> = self
+            .files
+            .iter()
+            .map(|file| {
+                ListItem::new(Line::from(vec![
+                    Span::raw(if file.included { "[x] " } else { "[ ] " }),
+                    format!("+{}", file.added).green(),
+                    Span::raw(" "),
+                    format!("-{}", file.removed).red(),
+                    Span::raw(" "),
+                    Span::raw(file.path.as_str()),
+                ]))
+            })
+            .collect();
+        let title = format!(" Files ({}/{} included) ", self.included().len(), self.files.len());
+        let list = List::new(items)
+            .block(Block::bordered().title(title))
+            .highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(list, files_area, &mut self.list);
+
+        let selected = self.list.selected().and_then(|i| self.files.get(i));
+        let (title, lines) = match selected {
+            Some(file) => (format!(" {} ", file.path), file.diff.lines().map(diff_line).collect()),
+            None => (String::new(), Vec::new()),
+        };
+        let preview = Paragraph::new(lines)
+            .block(Block::bordered().title(title))
+            .scroll((self.scroll, 0));
+        frame.render_widget(preview, diff_area);
+
+        let keys =
+            " ↑/↓ select  space toggle  a toggle all  PgUp/PgDn scroll  enter commit  q quit";
+        frame.render_widget(Line::from(keys).dim(), help);
+    }
+}
+
+/// A diff line colored as in `jj diff --git`
+fn diff_line(line: &str) -> Line<'_> {
+    if line.starts_with("diff --git") || line.starts_with("+++ ") || line.starts_with("--- ") {
+        Line::from(line).bold()
+    } else if line.starts_with("@@") {
+        Line::from(line).cyan()
+    } else if line.starts_with('+') {
+        Line::from(line).green()
+    } else if line.starts_with('-') {
+        Line::from(line).red()
+    } else {
+        Line::from(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_toggle() {
+        let files = vec![
+            FileEntry::new("a.rs".to_string(), "diff --git a/a.rs b/a.rs\n".to_string()),
+            FileEntry::new("b.rs".to_string(), "diff --git a/b.rs b/b.rs\n".to_string()),
+        ];
+        let mut review = Review::new(files);
+        review.toggle();
+        assert_eq!(review.included(), ["b.rs"]);
+        review.toggle_all();
+        assert_eq!(review.included(), ["a.rs", "b.rs"]);
+        review.toggle_all();
+        assert!(review.included().is_empty());
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Error, Result, bail};
+
+/// When accumulated watch-mode changes are considered worth a commit
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AccumulateUntil {
+    /// No change has been seen for the given duration
+    Idle(Duration),
+    /// At least this many lines have changed
+    Lines(usize),
+    /// At least this many files have changed
+    Files(usize),
+}
+
+impl FromStr for AccumulateUntil {
+    type Err = Error;
+
+    /// Parses `idle:<duration>`, `lines:<n>`, or `files:<n>`. Durations are seconds, optionally
+    /// suffixed with `s` or `m` (e.g. `idle:90`, `idle:30s`, `idle:2m`).
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, value) = s
+            .split_once(':')
+            .context("expected idle:<duration>, lines:<n>, or files:<n>")?;
+        let value = value.trim();
+        match kind.trim() {
+            "idle" => parse_duration(value).map(Self::Idle),
+            "lines" => Ok(Self::Lines(value.parse().context("invalid line count")?)),
+            "files" => Ok(Self::Files(value.parse().context("invalid file count")?)),
+            other => bail!("unknown condition `{other}`, expected idle, lines, or files"),
+        }
+    }
+}
+
+fn parse_duration(value: &str) -> Result<Duration> {
+    let (number, scale) = if let Some(minutes) = value.strip_suffix('m') {
+        (minutes, 60)
+    } else {
+        (value.strip_suffix('s').unwrap_or(value), 1)
+    };
+    let seconds: u64 = number.parse().context("invalid duration")?;
+    Ok(Duration::from_secs(seconds * scale))
+}
+
+/// Size of the uncommitted change observed in a watch-mode poll
+#[derive(Debug, Default)]
+pub struct PendingChange {
+    pub lines: usize,
+    pub files: usize,
+}
+
+impl PendingChange {
+    pub fn is_empty(&self) -> bool {
+        self.files == 0
+    }
+}
+
+/// Tracks the pending change across debounced snapshots and decides when to commit.
+///
+/// A change is never committed in the same poll it was modified in, so a burst of saves settles
+/// first. Without a condition, the first quiet poll with pending changes commits.
+pub struct Accumulator {
+    until: Option<AccumulateUntil>,
+    last_change: Option<Instant>,
+    armed: bool,
+}
+
+impl Accumulator {
+    pub fn new(until: Option<AccumulateUntil>) -> Self {
+        Self { until, last_change: None, armed: true }
+    }
+
+    /// Records a poll and returns true if the pending change should be committed now
+    ///
+    /// # Arguments
+    /// - `changed` - Whether the working copy changed since the previous poll
+    /// - `pending` - The size of the uncommitted change
+    /// - `now` - The time of the poll
+    pub fn observe(&mut self, changed: bool, pending: &PendingChange, now: Instant) -> bool {
+        if changed {
+            self.armed = true;
+            self.last_change = Some(now);
+            return false;
+        }
+        if !self.armed || pending.is_empty() {
+            return false;
+        }
+        let last_change = *self.last_change.get_or_insert(now);
+
+        match &self.until {
+            None => true,
+            Some(AccumulateUntil::Idle(idle)) => now.duration_since(last_change) >= *idle,
+            Some(AccumulateUntil::Lines(lines)) => pending.lines >= *lines,
+            Some(AccumulateUntil::Files(files)) => pending.files >= *files,
+        }
+    }
+
+    /// Waits for the next working-copy change before committing again
+    pub fn reset(&mut self) {
+        self.armed = false;
+        self.last_change = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(lines: usize, files: usize) -> PendingChange {
+        PendingChange { lines, files }
+    }
+
+    #[test]
+    fn test_parse_accumulate_until() {
+        assert_eq!(
+            "idle:30s".parse::<AccumulateUntil>().unwrap(),
+            AccumulateUntil::Idle(Duration::from_secs(30))
+        );
+        assert_eq!(
+            "idle:2m".parse::<AccumulateUntil>().unwrap(),
+            AccumulateUntil::Idle(Duration::from_secs(120))
+        );
+        assert_eq!("lines:40".parse::<AccumulateUntil>().unwrap(), AccumulateUntil::Lines(40));
+        assert_eq!("files:3".parse::<AccumulateUntil>().unwrap(), AccumulateUntil::Files(3));
+        assert!("idle".parse::<AccumulateUntil>().is_err());
+        assert!("bytes:10".parse::<AccumulateUntil>().is_err());
+        assert!("lines:many".parse::<AccumulateUntil>().is_err());
+    }
+
+    #[test]
+    fn test_commits_after_a_quiet_poll_by_default() {
+        let mut acc = Accumulator::new(None);
+        let now = Instant::now();
+        assert!(!acc.observe(true, &pending(1, 1), now));
+        assert!(acc.observe(false, &pending(1, 1), now));
+    }
+
+    #[test]
+    fn test_waits_for_line_threshold() {
+        let mut acc = Accumulator::new(Some(AccumulateUntil::Lines(10)));
+        let now = Instant::now();
+        assert!(!acc.observe(true, &pending(4, 1), now));
+        assert!(!acc.observe(false, &pending(4, 1), now));
+        assert!(!acc.observe(true, &pending(12, 2), now));
+        assert!(acc.observe(false, &pending(12, 2), now));
+    }
+
+    #[test]
+    fn test_waits_for_idle_interval() {
+        let mut acc = Accumulator::new(Some(AccumulateUntil::Idle(Duration::from_secs(30))));
+        let start = Instant::now();
+        assert!(!acc.observe(true, &pending(1, 1), start));
+        assert!(!acc.observe(false, &pending(1, 1), start + Duration::from_secs(10)));
+        assert!(acc.observe(false, &pending(1, 1), start + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_reset_waits_for_next_change() {
+        let mut acc = Accumulator::new(None);
+        let now = Instant::now();
+        assert!(acc.observe(false, &pending(1, 1), now));
+        acc.reset();
+        assert!(!acc.observe(false, &pending(1, 1), now));
+        assert!(!acc.observe(true, &pending(2, 1), now));
+        assert!(acc.observe(false, &pending(2, 1), now));
+    }
+}
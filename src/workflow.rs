@@ -0,0 +1,1024 @@
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use jj_lib::{commit::Commit, object_id::ObjectId, workspace::Workspace};
+use tracing::{debug, info, trace, warn};
+
+use crate::{
+    Error, Result,
+    bookmark::{Bookmark, bookmark_commit, default_base, range_commits, resolve_bookmark_target},
+    cache::{self, CacheKey},
+    commit_message_generator::{FormatOptions, GeneratedMessage, format_message},
+    config::CONFIG,
+    description_template::merge_into_template,
+    diff::{
+        DiffLimits, DiffPriority, DiffStyle, FileChangeSummary, PathFilter, count_changed_lines,
+        format_conflicts, get_tree_diff, split_file_diffs,
+    },
+    explain::ExplanationGenerator,
+    generator::reset_claude_calls,
+    git, hook,
+    pipeline::{
+        Change, ChangeOptions, MessageOptions, SnapshotSummary, UndescribedCommit, WriteMode,
+        blocking, check_diff_size, describe_commits, diff_to_prompt, ensure_jj_available,
+        find_workspace, first_parent_tree, fit_diff, generate_in_parallel, generate_message,
+        generator_error, load_change, load_repo, plan_split, preview_prompt, resolve_single_commit,
+        snapshot_workspace, undescribed_commits, working_copy_commit, working_copy_diff,
+        write_split_commits,
+    },
+    pr_description::{
+        PrDescription, PrDescriptionGenerator, aggregate_diff, format_commit_messages,
+    },
+    prompt_template::PromptContext,
+    records::write_record,
+    redaction::redact,
+    split::{changed_paths, collect_changes},
+    watch::{AccumulateUntil, Accumulator, PendingChange},
+};
+
+/// What shapes a generated message besides the change itself. It is owned, so that the workflows
+/// can hand it to the blocking model calls.
+#[derive(Debug, Clone, Default)]
+pub struct MessageSettings {
+    pub language: String,
+    pub model: String,
+    pub format_options: FormatOptions,
+    /// Call the model even for diffs below `diff.min_lines_for_claude`
+    pub always_generate: bool,
+    /// Commit unresolved conflicts and note them in the message
+    pub describe_conflicts: bool,
+    /// Commit unresolved conflicts as they are, pointing out their conflict markers to the model
+    pub allow_conflicts: bool,
+    /// Recent commit messages whose language and style to match, newest first
+    pub examples: Vec<String>,
+    /// Ticket ID added to the message as configured by `ticket.placement`
+    pub ticket: Option<String>,
+    /// `Key: value` trailers appended to the message, e.g. `Signed-off-by: ...`
+    pub trailers: Vec<String>,
+    /// The branch, recent commits, and repository name for the prompt template
+    pub context: PromptContext,
+    /// The jj description template the message is put into
+    pub description_template: Option<String>,
+}
+
+impl MessageSettings {
+    /// Whether unresolved conflicts may be committed or described
+    pub fn conflicts_allowed(&self) -> bool {
+        self.allow_conflicts || self.describe_conflicts
+    }
+
+    /// The options for [`generate_message`]. `conflicts` is the formatted list of unresolved
+    /// conflicts, which the message notes with `describe_conflicts`.
+    pub fn options<'a>(
+        &'a self,
+        conflicts: Option<&'a str>,
+        existing_description: Option<&'a str>,
+        feedback: Option<&'a str>,
+    ) -> MessageOptions<'a> {
+        MessageOptions {
+            language: &self.language,
+            model: &self.model,
+            format_options: self.format_options,
+            always_generate: self.always_generate,
+            conflicts: conflicts.filter(|_| self.describe_conflicts),
+            conflict_markers: conflicts.filter(|_| !self.describe_conflicts),
+            existing_description,
+            feedback,
+            examples: &self.examples,
+            ticket: self.ticket.as_deref(),
+            trailers: &self.trailers,
+            context: &self.context,
+        }
+    }
+
+    /// Generates a message for the diff with [`generate_message`] and puts it into the
+    /// description template, if any.
+    ///
+    /// Blocks until the model answers; async code should run it with `tokio::task::spawn_blocking`.
+    pub fn generate(
+        &self,
+        diff: &str,
+        file_changes: &FileChangeSummary,
+        conflicts: Option<&str>,
+        existing_description: Option<&str>,
+        feedback: Option<&str>,
+    ) -> Result<GeneratedMessage> {
+        let options = self.options(conflicts, existing_description, feedback);
+        let mut generated = generate_message(diff, file_changes, &options)?;
+        if let Some(template) = &self.description_template {
+            generated.message = merge_into_template(&generated.message, template);
+        }
+        Ok(generated)
+    }
+
+    /// Everything besides the trees and the model that shapes the generated message, so a change
+    /// to any of them (including the configuration) misses the cache
+    fn cache_inputs(
+        &self,
+        diff: &str,
+        conflicts: Option<&str>,
+        existing_description: Option<&str>,
+    ) -> Vec<String> {
+        vec![
+            diff.to_string(),
+            self.language.clone(),
+            format!(
+                "conflicts={} describe={} allow={}",
+                conflicts.unwrap_or_default(),
+                self.describe_conflicts,
+                self.allow_conflicts
+            ),
+            existing_description.unwrap_or_default().to_string(),
+            self.ticket.clone().unwrap_or_default(),
+            self.description_template.clone().unwrap_or_default(),
+            self.examples.join("\n---\n"),
+            format!("{:?}", self.context),
+            self.trailers.join("\n"),
+            format!("{:?} always_claude={}", self.format_options, self.always_generate),
+            toml::to_string(&*CONFIG).unwrap_or_default(),
+        ]
+    }
+}
+
+/// How [`commit`] finds the change, and what it does with the message
+#[derive(Debug, Clone, Default)]
+pub struct CommitOptions {
+    pub message: MessageSettings,
+    /// Describe this revision's stored tree in place instead of the working copy
+    pub revision: Option<String>,
+    /// Use the stored tree of the working-copy commit instead of snapshotting it
+    pub no_snapshot: bool,
+    /// New files larger than this many bytes are left untracked by the snapshot
+    pub max_new_file_size: u64,
+    /// Show the files scanned while snapshotting
+    pub show_progress: bool,
+    /// Globs of the paths to commit; every path when empty
+    pub only: Vec<String>,
+    /// Globs of the paths to leave in the working copy
+    pub exclude: Vec<String>,
+    /// Squash the changes into the parent commit
+    pub squash: bool,
+    /// Describe the cumulative change since this revision rather than the change from the parent
+    pub from: Option<String>,
+    /// Update an existing description instead of skipping commits that are already described
+    pub amend: bool,
+    /// Set the description of the working-copy commit and stay on it
+    pub describe: bool,
+    /// Send the diff as is instead of masking secrets matching `redaction.patterns`
+    pub no_redact: bool,
+    /// Stop once the diff is built, returning it in [`Outcome::Diff`]
+    pub diff_only: bool,
+    /// Stop before calling the model, returning the prompt in [`Outcome::Prompt`]
+    pub preview_prompt: bool,
+    /// Have the model group the changed files into several commits
+    pub split: bool,
+    /// Call the model even if a message for the same trees and prompt is cached
+    pub no_cache: bool,
+    /// Have the user review the message with [`Frontend::review`] before it is written
+    pub interactive: bool,
+    /// Generate the message without writing anything
+    pub dry_run: bool,
+    /// Hand the message to the `jj` CLI instead of rewriting commits with jj-lib
+    pub via_jj: bool,
+    /// Store the diff, the raw model output, and the message under `.jj/ccc-jj/records/`
+    pub record_context: bool,
+    /// Point a bookmark at the commit once it is written
+    pub bookmark: bool,
+}
+
+/// What to do with a generated message after reviewing it
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    /// Use this message instead; an empty one aborts
+    Edit(String),
+    /// Generate the message again, with the user's feedback if any
+    Regenerate(Option<String>),
+    Abort,
+}
+
+/// The program running a workflow, which tells the user what is going on and reviews messages
+/// with them. Every method does nothing by default, and messages are accepted as they are.
+pub trait Frontend: Send {
+    /// The snapshot started tracking new files or left some untracked
+    fn snapshot(&mut self, _summary: &SnapshotSummary, _max_new_file_size: u64) {}
+
+    /// This many possible secrets were masked in the diff
+    fn masked_secrets(&mut self, _count: usize) {}
+
+    /// Shows the message with the files it covers and asks what to do with it, with
+    /// [`CommitOptions::interactive`]
+    fn review(
+        &mut self,
+        _message: &str,
+        _file_changes: &FileChangeSummary,
+    ) -> anyhow::Result<Verdict> {
+        Ok(Verdict::Accept)
+    }
+}
+
+/// What became of a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Nothing was written
+    DryRun,
+    /// The message went on a new commit
+    Committed,
+    /// The message was set on an existing commit
+    Described,
+    /// The changes and the message were squashed into the parent
+    Squashed,
+}
+
+impl Status {
+    /// The name of the status in JSON output
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::DryRun => "dry_run",
+            Self::Committed => "committed",
+            Self::Described => "described",
+            Self::Squashed => "squashed",
+        }
+    }
+}
+
+/// A message and the commit it is for
+#[derive(Debug)]
+pub struct Described {
+    pub status: Status,
+    /// The commit the message was written to, or for a dry run the one it would describe. `None`
+    /// for planned split commits and git dry runs
+    pub commit_id: Option<String>,
+    /// The change id, which identifies the commit across rewrites. `None` with git
+    pub change_id: Option<String>,
+    /// `Name <email>` of the commit's author, when there is a commit
+    pub author: Option<String>,
+    pub message: String,
+    pub file_changes: FileChangeSummary,
+    /// The diff the message was generated from
+    pub diff: String,
+}
+
+impl Described {
+    fn new(
+        status: Status,
+        commit: Option<&Commit>,
+        message: String,
+        file_changes: FileChangeSummary,
+        diff: String,
+    ) -> Self {
+        Self {
+            status,
+            commit_id: commit.map(|commit| commit.id().hex()),
+            change_id: commit.map(|commit| commit.change_id().reverse_hex()),
+            author: commit.map(|commit| {
+                let author = commit.author();
+                format!("{} <{}>", author.name, author.email)
+            }),
+            message,
+            file_changes,
+            diff,
+        }
+    }
+}
+
+/// How a run of [`commit`] or [`commit_git`] ended
+#[derive(Debug)]
+pub enum Outcome {
+    /// The commit already has a description, and neither `amend` nor `squash` was given
+    AlreadyDescribed,
+    /// The diff that would be sent, with `diff_only`
+    Diff(String),
+    /// The prompt that would be sent, with `preview_prompt`
+    Prompt(String),
+    /// The user aborted the review, so nothing was written
+    Aborted,
+    /// The message was generated, and written unless this is a dry run
+    Message {
+        described: Box<Described>,
+        /// The bookmark set with `bookmark`. Setting it is attempted after the commit is written,
+        /// so a failure leaves the commit in place.
+        bookmark: Option<Result<Bookmark>>,
+    },
+    /// The commits the change was split into, with `split`
+    Split(Vec<Described>),
+}
+
+/// Generates a message for the change selected by the options and writes it, as `jc` does
+/// without a subcommand.
+///
+/// The change is the working copy after a fresh snapshot unless the options pick a stored tree. A
+/// message cached for the same trees and prompt is reused, and a newly generated one is cached.
+/// Fails with [`Error::NothingToCommit`] if there are no changes, and with
+/// [`Error::UnresolvedConflicts`] if they have conflicts that the options don't allow.
+pub async fn commit(
+    workspace: &Workspace,
+    options: &CommitOptions,
+    frontend: &mut dyn Frontend,
+) -> Result<Outcome> {
+    // Fail before spending a model call if the message can't be handed over
+    if options.via_jj {
+        ensure_jj_available()?;
+    }
+
+    let filter = PathFilter::new(&options.only, &options.exclude).map_err(Error::PathFilter)?;
+    let change = load_change(
+        workspace,
+        &ChangeOptions {
+            revision: options.revision.as_deref(),
+            no_snapshot: options.no_snapshot,
+            max_new_file_size: options.max_new_file_size,
+            show_progress: options.show_progress,
+            filter: filter.as_ref(),
+            squash: options.squash,
+            from: options.from.as_deref(),
+        },
+    )
+    .await?;
+    if let Some(summary) = &change.snapshot {
+        frontend.snapshot(summary, options.max_new_file_size);
+    }
+
+    if change.is_empty() {
+        let reason = if filter.is_some() {
+            "No changes match --only/--exclude, nothing to commit"
+        } else {
+            "No changes detected, nothing to commit"
+        };
+        return Err(Error::NothingToCommit(reason.to_string()));
+    }
+    debug!("Changes detected");
+
+    let target = &change.target;
+    if !options.diff_only && !options.amend && !options.squash && !target.description().is_empty() {
+        warn!(description = %target.description(), "Commit already has description, skipping");
+        return Ok(Outcome::AlreadyDescribed);
+    }
+
+    debug!("Generating diff");
+    let (diff, masked) = change.diff(!options.no_redact).await?;
+    frontend.masked_secrets(masked);
+    debug!(diff_len = diff.len(), "Diff generated");
+    trace!(diff = %diff, "Full diff content");
+
+    if options.diff_only {
+        return Ok(Outcome::Diff(diff));
+    }
+
+    let settings = &options.message;
+    let conflicts = change.conflicts();
+    if !conflicts.is_empty() && !settings.conflicts_allowed() {
+        return Err(Error::UnresolvedConflicts(format!(
+            "Working copy has unresolved conflicts in:\n{}\n\
+            Resolve them, or pass --allow-conflicts to commit them as they are \
+            (--describe-conflicts to also note them in the message)",
+            format_conflicts(&conflicts)
+        )));
+    }
+
+    if diff.trim().is_empty() {
+        return Err(Error::NothingToCommit("Empty diff, nothing to commit".to_string()));
+    }
+
+    let conflicts = (!conflicts.is_empty()).then(|| {
+        info!(count = conflicts.len(), "Committing unresolved conflicts");
+        format_conflicts(&conflicts)
+    });
+    let conflicts = conflicts.as_deref();
+    let file_changes = change.file_changes().await;
+    let existing_description = change.existing_description(options.amend);
+    let existing_description = existing_description.as_deref();
+
+    if options.preview_prompt {
+        let prompt = preview(settings, &diff, &file_changes, conflicts, existing_description)?;
+        return Ok(Outcome::Prompt(prompt));
+    }
+    if options.split {
+        // Splitting assigns the hunks of the diff, so it can't work from chunk summaries
+        check_diff_size(&diff)?;
+        return Ok(Outcome::Split(split(workspace, options, diff, change).await?));
+    }
+    let diff = fit(diff, settings).await?;
+    let generated = cached_or_generated(
+        workspace,
+        options,
+        &change,
+        &diff,
+        &file_changes,
+        conflicts,
+        existing_description,
+    )
+    .await?;
+    let generated = if options.interactive {
+        let reviewed = review(
+            frontend,
+            settings,
+            generated,
+            &diff,
+            &file_changes,
+            conflicts,
+            existing_description,
+        )
+        .await?;
+        match reviewed {
+            Some(generated) => generated,
+            None => return Ok(Outcome::Aborted),
+        }
+    } else {
+        generated
+    };
+    debug!(commit_message = %generated.message, "Generated commit message");
+
+    if options.dry_run {
+        let described = Described::new(
+            Status::DryRun,
+            Some(change.described()),
+            generated.message,
+            file_changes,
+            diff,
+        );
+        return Ok(Outcome::Message { described: Box::new(described), bookmark: None });
+    }
+
+    // Rewriting keeps the change id, so it identifies the described commit after writing too
+    let change_id = change.described().change_id().reverse_hex();
+    let (mode, status) = if options.squash {
+        (WriteMode::Squash, Status::Squashed)
+    } else if change.snapshot.is_none() || options.describe {
+        let mode = if options.describe { WriteMode::Describe } else { WriteMode::Commit };
+        (mode, Status::Described)
+    } else {
+        (WriteMode::Commit, Status::Committed)
+    };
+    let provenance = generated.provenance.as_ref();
+    let written = change
+        .write(workspace, &generated.message, mode, options.via_jj, provenance)
+        .await?;
+    info!(status = status.as_str(), "Commit written");
+
+    if options.record_context {
+        match write_record(
+            workspace.workspace_root(),
+            &written.operation.hex(),
+            &diff,
+            &generated.raw_output,
+            &generated.message,
+            CONFIG.records.max_records,
+        ) {
+            Ok(path) => info!(path = %path.display(), "Recorded generation context"),
+            Err(e) => warn!(error = %e, "Failed to record generation context"),
+        }
+    }
+
+    // Without jj-lib writing it, the commit is only known by its change id
+    let commit = match written.commit {
+        Some(commit) => commit,
+        None => {
+            let repo = load_repo(workspace)?;
+            resolve_single_commit(&repo, workspace, &change_id)?
+        }
+    };
+    let bookmark = match options.bookmark {
+        true => Some(bookmark_commit(workspace, &settings.model, &change_id).await),
+        false => None,
+    };
+    let described = Described::new(status, Some(&commit), generated.message, file_changes, diff);
+    Ok(Outcome::Message { described: Box::new(described), bookmark })
+}
+
+/// Plans how to split the change into logical commits and, unless this is a dry run, creates them
+async fn split(
+    workspace: &Workspace,
+    options: &CommitOptions,
+    diff: String,
+    change: Change,
+) -> Result<Vec<Described>> {
+    let changes = collect_changes(&change.base_tree, &change.tree).await;
+    let file_changes = change.file_changes().await;
+    let settings = options.message.clone();
+    let paths = changed_paths(&changes);
+    let (commits, diff) = blocking(move || {
+        let commits = plan_split(&diff, &paths, &settings.options(None, None, None))?;
+        Ok((commits, diff))
+    })
+    .await?;
+
+    if options.dry_run {
+        let planned = commits.into_iter().map(|commit| {
+            let file_changes = file_changes.subset(&commit.files);
+            Described::new(Status::DryRun, None, commit.message, file_changes, diff.clone())
+        });
+        return Ok(planned.collect());
+    }
+
+    info!(commits = commits.len(), "Creating split commits");
+    let written = write_split_commits(
+        workspace,
+        change.target.id(),
+        &commits,
+        change.base_tree,
+        change.tree,
+        &changes,
+    )
+    .await?;
+    let written = written.iter().zip(commits).map(|(commit, planned)| {
+        let file_changes = file_changes.subset(&planned.files);
+        Described::new(Status::Committed, Some(commit), planned.message, file_changes, diff.clone())
+    });
+    Ok(written.collect())
+}
+
+/// The message cached for the change and prompt, or else a newly generated one, which is then
+/// cached
+async fn cached_or_generated(
+    workspace: &Workspace,
+    options: &CommitOptions,
+    change: &Change,
+    diff: &str,
+    file_changes: &FileChangeSummary,
+    conflicts: Option<&str>,
+    existing_description: Option<&str>,
+) -> Result<GeneratedMessage> {
+    let settings = &options.message;
+    let key = (!options.no_cache).then(|| {
+        let inputs = settings.cache_inputs(diff, conflicts, existing_description);
+        CacheKey::new(&change.base_tree, &change.tree, &settings.model, &inputs)
+    });
+    if let Some(cached) = key
+        .as_ref()
+        .and_then(|key| cache::read(workspace.workspace_root(), key))
+    {
+        info!("Using the cached message for this change; pass --no-cache to regenerate it");
+        return Ok(cached);
+    }
+
+    let generated =
+        generate(settings, diff, file_changes, conflicts, existing_description, None).await?;
+    // Offline and other model-free messages are cheap to rebuild and would otherwise stand in for
+    // a real one on the next run
+    if let Some(key) = key.filter(|_| generated.provenance.is_some())
+        && let Err(e) = cache::write(workspace.workspace_root(), key, &generated)
+    {
+        warn!(error = %e, "Failed to cache the generated message");
+    }
+    Ok(generated)
+}
+
+/// Lets the user accept, edit, or regenerate the message. Returns `None` if they abort.
+async fn review(
+    frontend: &mut dyn Frontend,
+    settings: &MessageSettings,
+    mut generated: GeneratedMessage,
+    diff: &str,
+    file_changes: &FileChangeSummary,
+    conflicts: Option<&str>,
+    existing_description: Option<&str>,
+) -> Result<Option<GeneratedMessage>> {
+    loop {
+        match frontend
+            .review(&generated.message, file_changes)
+            .map_err(Error::Review)?
+        {
+            Verdict::Accept => return Ok(Some(generated)),
+            Verdict::Edit(edited) if edited.is_empty() => return Ok(None),
+            Verdict::Edit(edited) => {
+                generated.message = format_message(&edited, &settings.format_options);
+            }
+            Verdict::Regenerate(feedback) => {
+                let feedback = feedback.as_deref();
+                generated = generate(
+                    settings,
+                    diff,
+                    file_changes,
+                    conflicts,
+                    existing_description,
+                    feedback,
+                )
+                .await?;
+            }
+            Verdict::Abort => return Ok(None),
+        }
+    }
+}
+
+/// The prompt the model would get for the diff
+fn preview(
+    settings: &MessageSettings,
+    diff: &str,
+    file_changes: &FileChangeSummary,
+    conflicts: Option<&str>,
+    existing_description: Option<&str>,
+) -> Result<String> {
+    if check_diff_size(diff).is_err() {
+        warn!("The diff is too large for one prompt; a commit would send summaries of its parts");
+    }
+    let options = settings.options(conflicts, existing_description, None);
+    preview_prompt(diff, file_changes, &options)
+}
+
+/// [`MessageSettings::generate`] on tokio's blocking threads
+async fn generate(
+    settings: &MessageSettings,
+    diff: &str,
+    file_changes: &FileChangeSummary,
+    conflicts: Option<&str>,
+    existing_description: Option<&str>,
+    feedback: Option<&str>,
+) -> Result<GeneratedMessage> {
+    let settings = settings.clone();
+    let (diff, file_changes) = (diff.to_string(), file_changes.clone());
+    let conflicts = conflicts.map(String::from);
+    let existing_description = existing_description.map(String::from);
+    let feedback = feedback.map(String::from);
+    blocking(move || {
+        settings.generate(
+            &diff,
+            &file_changes,
+            conflicts.as_deref(),
+            existing_description.as_deref(),
+            feedback.as_deref(),
+        )
+    })
+    .await
+}
+
+/// [`fit_diff`] on tokio's blocking threads
+async fn fit(diff: String, settings: &MessageSettings) -> Result<String> {
+    let (language, model) = (settings.language.clone(), settings.model.clone());
+    blocking(move || fit_diff(diff, &language, &model)).await
+}
+
+/// Describes every undescribed commit matching `revset` from its stored tree, as `jc --revset`
+/// does. The messages are generated in parallel (see [`generate_in_parallel`]) and then written
+/// in a single transaction, so a failure leaves the repository untouched.
+///
+/// Of the options, only the message settings, `no_redact`, and `dry_run` apply. Fails with
+/// [`Error::NothingToCommit`] if no commit needs a description.
+pub async fn describe_revset(
+    workspace: &Workspace,
+    revset: &str,
+    options: &CommitOptions,
+    frontend: &mut dyn Frontend,
+) -> Result<Vec<Described>> {
+    let repo = load_repo(workspace)?;
+    let settings = &options.message;
+    let undescribed = undescribed_commits(
+        &repo,
+        workspace,
+        revset,
+        !options.no_redact,
+        settings.conflicts_allowed(),
+    )
+    .await?;
+    if undescribed.is_empty() {
+        return Err(Error::NothingToCommit(format!(
+            "No undescribed commits with changes in {revset}"
+        )));
+    }
+    for commit in &undescribed {
+        frontend.masked_secrets(commit.masked);
+    }
+
+    let changes: Vec<_> = undescribed
+        .iter()
+        .map(|commit| {
+            let conflicts =
+                (!commit.conflicts.is_empty()).then(|| format_conflicts(&commit.conflicts));
+            (commit.diff.clone(), commit.file_changes.clone(), conflicts)
+        })
+        .collect();
+    let settings = settings.clone();
+    let messages = blocking(move || {
+        generate_in_parallel(&changes, |(diff, file_changes, conflicts)| {
+            let generated = settings.generate(diff, file_changes, conflicts.as_deref(), None, None);
+            generated.map(|generated| generated.message)
+        })
+    })
+    .await?;
+
+    if options.dry_run {
+        let planned = undescribed.into_iter().zip(messages).map(|(commit, message)| {
+            let UndescribedCommit { commit, file_changes, diff, .. } = commit;
+            Described::new(Status::DryRun, Some(&commit), message, file_changes, diff)
+        });
+        return Ok(planned.collect());
+    }
+
+    // Revsets yield descendants before their ancestors, as describe_commits expects
+    let pairs: Vec<_> = undescribed
+        .iter()
+        .zip(&messages)
+        .map(|(commit, message)| (&commit.commit, message.as_str()))
+        .collect();
+    describe_commits(&repo, &pairs)?;
+
+    // Commit ids change as descendants are rebased, so find the commits by their change id
+    let repo = load_repo(workspace)?;
+    undescribed
+        .into_iter()
+        .zip(messages)
+        .map(|(commit, message)| {
+            let UndescribedCommit { commit, file_changes, diff, .. } = commit;
+            let change_id = commit.change_id().reverse_hex();
+            let commit = resolve_single_commit(&repo, workspace, &change_id)?;
+            Ok(Described::new(Status::Described, Some(&commit), message, file_changes, diff))
+        })
+        .collect()
+}
+
+/// Polls the working copy every `interval` and commits the accumulated changes with [`commit`]
+/// once they settle (and, with `until`, once they're large or idle enough), passing what became
+/// of each commit to `on_commit`. Never returns.
+///
+/// The workspace is reloaded every poll so that commits made by ccc-jj or by `jj` itself are
+/// picked up. A failed poll (e.g. while jj holds the working-copy lock) is retried on the next
+/// one, and each commit gets a fresh model call budget.
+pub async fn watch(
+    workspace_path: &Path,
+    options: &CommitOptions,
+    interval: Duration,
+    until: Option<AccumulateUntil>,
+    frontend: &mut dyn Frontend,
+    on_commit: &mut (dyn FnMut(Result<Outcome>) + Send),
+) {
+    let mut accumulator = Accumulator::new(until);
+    let mut last_diff: Option<String> = None;
+
+    loop {
+        let polled = match find_workspace(workspace_path) {
+            Ok(workspace) => working_copy_diff(&workspace, options.max_new_file_size)
+                .await
+                .map(|polled| (workspace, polled)),
+            Err(e) => Err(e),
+        };
+        let (workspace, (diff, file_changes)) = match polled {
+            Ok(polled) => polled,
+            Err(e) => {
+                warn!(error = %e, "Failed to poll the working copy");
+                tokio::time::sleep(interval).await;
+                continue;
+            }
+        };
+        let pending = PendingChange {
+            lines: count_changed_lines(&diff),
+            files: file_changes.paths().count(),
+        };
+        let changed = last_diff.as_ref() != Some(&diff);
+        trace!(changed, lines = pending.lines, files = pending.files, "Polled working copy");
+        last_diff = Some(diff);
+
+        if accumulator.observe(changed, &pending, Instant::now()) {
+            info!(lines = pending.lines, files = pending.files, "Committing accumulated changes");
+            reset_claude_calls();
+            on_commit(commit(&workspace, options, frontend).await);
+            accumulator.reset();
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Snapshots the working copy and returns the diff of each changed file selected by `only` and
+/// `exclude`, in full and without rename detection, so that each is one path to pick. Fails with
+/// [`Error::NothingToCommit`] if no file changed.
+pub async fn file_diffs(
+    workspace: &Workspace,
+    options: &CommitOptions,
+) -> Result<Vec<(String, String)>> {
+    let repo = load_repo(workspace)?;
+    let wc_commit = working_copy_commit(&repo, workspace)?;
+    let (tree, _summary) =
+        snapshot_workspace(workspace, options.max_new_file_size, options.show_progress).await?;
+    let parent_tree = first_parent_tree(&repo, &wc_commit)?;
+
+    let diff = get_tree_diff(
+        &repo,
+        &parent_tree,
+        &tree,
+        None,
+        &DiffPriority::default(),
+        DiffLimits::UNLIMITED,
+        &DiffStyle {
+            context_lines: CONFIG.diff.context_lines,
+            max_file_lines: CONFIG.diff.max_file_lines,
+            ..DiffStyle::default()
+        },
+    )
+    .await
+    .map_err(Error::Diff)?;
+    let filter = PathFilter::new(&options.only, &options.exclude).map_err(Error::PathFilter)?;
+    let files: Vec<_> = split_file_diffs(&diff)
+        .into_iter()
+        .filter(|(path, _)| filter.as_ref().is_none_or(|filter| filter.matches(path)))
+        .collect();
+    if files.is_empty() {
+        return Err(Error::NothingToCommit("No changes detected, nothing to commit".to_string()));
+    }
+    Ok(files)
+}
+
+/// Commits the pending changes of the git repository at `root`, which has no Jujutsu workspace:
+/// the staged changes, or every change to tracked files if nothing is staged. The options that
+/// only work with jj are ignored.
+pub async fn commit_git(
+    root: &Path,
+    options: &CommitOptions,
+    frontend: &mut dyn Frontend,
+) -> Result<Outcome> {
+    info!(root = %root.display(), "Committing with git");
+    let pending = git::pending_changes(root).map_err(Error::Git)?;
+    let (diff, masked) = if options.no_redact { (pending.diff, 0) } else { redact(&pending.diff) };
+    frontend.masked_secrets(masked);
+    let file_changes = pending.file_changes;
+
+    if options.diff_only {
+        return Ok(Outcome::Diff(diff));
+    }
+    if !file_changes.conflicted.is_empty() {
+        return Err(Error::UnresolvedConflicts(format!(
+            "The index has unresolved conflicts in: {}",
+            file_changes.conflicted.join(", ")
+        )));
+    }
+    if diff.trim().is_empty() {
+        return Err(Error::NothingToCommit("No changes to commit".to_string()));
+    }
+    let settings = &options.message;
+    if options.preview_prompt {
+        return Ok(Outcome::Prompt(preview(settings, &diff, &file_changes, None, None)?));
+    }
+    let diff = fit(diff, settings).await?;
+
+    let generated = generate(settings, &diff, &file_changes, None, None, None).await?;
+    let generated = if options.interactive {
+        match review(frontend, settings, generated, &diff, &file_changes, None, None).await? {
+            Some(generated) => generated,
+            None => return Ok(Outcome::Aborted),
+        }
+    } else {
+        generated
+    };
+
+    if options.dry_run {
+        let described = Described::new(Status::DryRun, None, generated.message, file_changes, diff);
+        return Ok(Outcome::Message { described: Box::new(described), bookmark: None });
+    }
+
+    let commit_id = git::commit(root, pending.scope, &generated.message).map_err(Error::Git)?;
+    info!(commit = %commit_id, "Git commit created");
+    let (user_name, user_email) = git::user(root);
+    let described = Described {
+        status: Status::Committed,
+        commit_id: Some(commit_id),
+        change_id: None,
+        author: Some(format!("{user_name} <{user_email}>")),
+        message: generated.message,
+        file_changes,
+        diff,
+    };
+    Ok(Outcome::Message { described: Box::new(described), bookmark: None })
+}
+
+/// Generates a message for a diff that doesn't come from a jj commit, e.g. one read from a file.
+/// With `redact_secrets`, secrets in it are masked first.
+pub async fn describe_diff(
+    diff: String,
+    file_changes: &FileChangeSummary,
+    settings: &MessageSettings,
+    redact_secrets: bool,
+) -> Result<String> {
+    let diff = if redact_secrets {
+        let (redacted, masked) = redact(&diff);
+        if masked > 0 {
+            warn!(masked, "Masked possible secrets in the diff (pass --no-redact to keep them)");
+        }
+        redacted
+    } else {
+        diff
+    };
+    let diff = fit(diff, settings).await?;
+    let generated = generate(settings, &diff, file_changes, None, None, None).await?;
+    Ok(generated.message)
+}
+
+/// Fills the commit message file that git hands its `prepare-commit-msg` hook with a message for
+/// the staged changes of the repository at `root`. `source` is the hook's second argument; a
+/// commit whose message git already has is left alone, and so is one without staged changes.
+pub async fn fill_commit_message(
+    root: &Path,
+    msg_file: &Path,
+    source: Option<&str>,
+    settings: &MessageSettings,
+) -> Result<()> {
+    let message_file = |e| Error::MessageFile { path: msg_file.to_path_buf(), source: e };
+    let content = std::fs::read_to_string(msg_file).map_err(message_file)?;
+    if !hook::should_fill(&content, source) {
+        debug!(?source, "Commit message already given, leaving it alone");
+        return Ok(());
+    }
+    let staged = git::staged_changes(root).map_err(Error::Git)?;
+    if staged.diff.trim().is_empty() {
+        return Ok(());
+    }
+
+    let message = describe_diff(staged.diff, &staged.file_changes, settings, true).await?;
+    std::fs::write(msg_file, hook::fill(&content, &message)).map_err(message_file)
+}
+
+/// Generates a pull request description from the messages and aggregate diff of the commits in
+/// `from..to`. `from` defaults to [`default_base`], and `to` skips an empty `@` (see
+/// [`resolve_bookmark_target`]).
+pub async fn pr_description(
+    workspace: &Workspace,
+    model: &str,
+    language: &str,
+    from: Option<&str>,
+    to: &str,
+    frontend: &mut dyn Frontend,
+) -> Result<PrDescription> {
+    let repo = load_repo(workspace)?;
+    let from = match from {
+        Some(rev) => rev.to_string(),
+        None => default_base(&repo)?,
+    };
+    let to = resolve_bookmark_target(&repo, workspace, to)?;
+    info!(from = %from, to = %to, "Resolving revset range");
+
+    let commits = range_commits(&repo, workspace, &from, &to)?;
+    // Revsets list the newest commit first
+    let (Some(head), Some(oldest)) = (commits.first(), commits.last()) else {
+        return Err(Error::EmptyRange { from, to });
+    };
+    debug!(commit_count = commits.len(), "Found commits");
+
+    let (diff, masked) = aggregate_diff(&repo, oldest, head).await?;
+    frontend.masked_secrets(masked);
+    let commit_messages = format_commit_messages(&commits);
+
+    info!(model = %model, "Generating PR description with Claude");
+    let generator = PrDescriptionGenerator::new(language, model);
+    blocking(move || {
+        generator
+            .generate(&commit_messages, &diff)
+            .map_err(generator_error)?
+            .ok_or(Error::PrDescriptionFailed)
+    })
+    .await
+}
+
+/// Snapshots the working copy and explains its changes in plain language. Returns `None` if there
+/// are no changes to explain.
+pub async fn explain(
+    workspace: &Workspace,
+    model: &str,
+    language: &str,
+    max_new_file_size: u64,
+    frontend: &mut dyn Frontend,
+) -> Result<Option<String>> {
+    let repo = load_repo(workspace)?;
+    let wc_commit = working_copy_commit(&repo, workspace)?;
+    let (tree, summary) =
+        snapshot_workspace(workspace, max_new_file_size, CONFIG.snapshot.progress).await?;
+    frontend.snapshot(&summary, max_new_file_size);
+    let parent_tree = first_parent_tree(&repo, &wc_commit)?;
+    if tree.tree_ids() == parent_tree.tree_ids() {
+        return Ok(None);
+    }
+
+    let (diff, masked) = diff_to_prompt(&repo, &parent_tree, &tree, true).await?;
+    frontend.masked_secrets(masked);
+
+    info!(model = %model, "Generating explanation with Claude");
+    let generator = ExplanationGenerator::new(language, model);
+    blocking(move || {
+        generator
+            .generate(&diff)
+            .map_err(generator_error)?
+            .ok_or(Error::ExplanationFailed)
+            .map(Some)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_options_route_conflicts() {
+        let mut settings = MessageSettings {
+            allow_conflicts: true,
+            ..MessageSettings::default()
+        };
+        let options = settings.options(Some("a.rs"), None, None);
+        assert_eq!((options.conflicts, options.conflict_markers), (None, Some("a.rs")));
+
+        settings.describe_conflicts = true;
+        let options = settings.options(Some("a.rs"), None, None);
+        assert_eq!((options.conflicts, options.conflict_markers), (Some("a.rs"), None));
+        assert!(settings.conflicts_allowed());
+        assert!(!MessageSettings::default().conflicts_allowed());
+    }
+}
@@ -0,0 +1,486 @@
+use std::{
+    env::{split_paths, var, var_os},
+    fs::read_dir,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use dirs::{config_dir, home_dir};
+use gethostname::gethostname;
+use jj_lib::{
+    config::{
+        ConfigGetError, ConfigLayer, ConfigResolutionContext, ConfigSource, StackedConfig, resolve,
+    },
+    fileset::{self, FilesetDiagnostics},
+    gitignore::GitIgnoreFile,
+    matchers::Matcher,
+    ref_name::WorkspaceName,
+    repo::StoreFactories,
+    repo_path::RepoPathUiConverter,
+    settings::UserSettings,
+    working_copy::LockedWorkingCopy,
+    workspace::{Workspace, default_working_copy_factories},
+    workspace_store::{SimpleWorkspaceStore, WorkspaceStore},
+};
+use tracing::{debug, warn};
+
+use crate::pipeline::{Error, Result, jj};
+
+/// Candidate user configuration files in standard jj config locations
+fn user_config_paths(home: Option<&Path>, config_dir: Option<&Path>) -> Vec<PathBuf> {
+    [
+        home.map(|h| h.join(".jjconfig.toml")),
+        home.map(|h| h.join(".config/jj/config.toml")),
+        config_dir.map(|c| c.join("jj/config.toml")),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// Load user configuration from standard jj config locations, or, as jj does, from the files and
+/// directories listed in `JJ_CONFIG` instead, which works without a home directory
+fn load_user_config(config: &mut StackedConfig) {
+    if let Some(paths) = var_os("JJ_CONFIG").filter(|paths| !paths.is_empty()) {
+        for path in split_paths(&paths).flat_map(config_files_at) {
+            add_config_file(config, ConfigSource::User, path);
+        }
+        return;
+    }
+
+    let home = home_dir();
+    if home.is_none() {
+        debug!("No home directory available, skipping home-relative user config");
+    }
+
+    for path in user_config_paths(home.as_deref(), config_dir().as_deref()) {
+        add_config_file(config, ConfigSource::User, path);
+    }
+}
+
+/// The config file at `path`, or the `.toml` files in it in name order if it is a directory
+fn config_files_at(path: PathBuf) -> Vec<PathBuf> {
+    if !path.is_dir() {
+        return vec![path];
+    }
+    let mut files: Vec<PathBuf> = read_dir(&path)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|file| file.is_file() && file.extension().is_some_and(|ext| ext == "toml"))
+        .collect();
+    files.sort();
+    files
+}
+
+/// The host name that `--when.hostnames` scopes in the jj config are matched against, falling
+/// back to `$HOSTNAME` when the system reports none (as in some minimal containers)
+fn hostname() -> String {
+    let name = gethostname().to_string_lossy().trim().to_string();
+    if !name.is_empty() {
+        return name;
+    }
+    let name = var("HOSTNAME").unwrap_or_default();
+    if name.is_empty() {
+        debug!("No host name available for conditional jj config");
+    }
+    name
+}
+
+/// Add a config file as a layer if it exists. Unreadable or malformed files are logged and
+/// skipped rather than aborting the run. Returns true if the layer was added.
+fn add_config_file(config: &mut StackedConfig, source: ConfigSource, path: PathBuf) -> bool {
+    if !path.exists() {
+        return false;
+    }
+
+    match ConfigLayer::load_from_file(source, path.clone()) {
+        Ok(layer) => {
+            config.add_layer(layer);
+            true
+        }
+        Err(e) => {
+            warn!(path = %path.display(), error = %e, "Skipping unreadable config file");
+            false
+        }
+    }
+}
+
+/// Load the ignore rules that apply on top of the `.gitignore` files in the working copy: git's
+/// global excludes file and the backing git repository's `info/exclude`, as `jj` itself does.
+///
+/// The `.gitignore` files at the root and in every subdirectory are chained by jj-lib as it walks
+/// the working copy during the snapshot, so they aren't loaded here.
+pub(crate) fn load_base_ignores(workspace: &Workspace) -> Arc<GitIgnoreFile> {
+    let mut git_ignores = GitIgnoreFile::empty();
+
+    // Missing files are skipped, so chaining errors are ignored
+    let excludes_files = [
+        get_global_git_excludes_file(),
+        git_repo_path(workspace.repo_path()).map(|git_repo| git_repo.join("info").join("exclude")),
+    ];
+    for path in excludes_files.into_iter().flatten() {
+        debug!(path = %path.display(), "Loading ignore file");
+        git_ignores = git_ignores.chain_with_file("", path).unwrap_or(git_ignores);
+    }
+
+    git_ignores
+}
+
+/// The files a snapshot may start tracking: jj's `snapshot.auto-track` fileset, or every file when
+/// it isn't set, so that ccc-jj tracks the same new files as `jj status`. Paths in the fileset are
+/// relative to the workspace root.
+pub(crate) fn auto_track_matcher(workspace: &Workspace) -> Result<Box<dyn Matcher>> {
+    let fileset = match workspace.repo_loader().settings().get_string("snapshot.auto-track") {
+        Ok(fileset) => fileset,
+        Err(ConfigGetError::NotFound { .. }) => "all()".to_string(),
+        Err(e) => return Err(jj("Invalid snapshot.auto-track in the jj config")(e)),
+    };
+    debug!(fileset = %fileset, "Loaded snapshot.auto-track");
+    let path_converter = RepoPathUiConverter::Fs { cwd: PathBuf::new(), base: PathBuf::new() };
+    let expression = fileset::parse(&mut FilesetDiagnostics::new(), &fileset, &path_converter)
+        .map_err(jj("Invalid snapshot.auto-track in the jj config"))?;
+    Ok(expression.to_matcher())
+}
+
+/// How long [`lock_working_copy`] keeps retrying while another process holds the lock, in
+/// milliseconds
+static LOCK_WAIT_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Delay before the second attempt to take the working copy lock, doubled for each one after it up
+/// to [`MAX_LOCK_RETRY_DELAY`]
+const LOCK_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_LOCK_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Sets how long to wait for a working copy lock held by another process, e.g. a concurrent `jj`
+/// command, before failing. The default of zero fails at once.
+pub fn set_lock_wait(wait: Duration) {
+    LOCK_WAIT_MS.store(wait.as_millis().try_into().unwrap_or(u64::MAX), Ordering::Relaxed);
+}
+
+/// Locks the working copy for a mutation. While the lock can't be taken, the attempt is retried
+/// with exponential backoff for as long as [`set_lock_wait`] allows, warning once about who holds
+/// the lock.
+pub async fn lock_working_copy(workspace: &Workspace) -> Result<Box<dyn LockedWorkingCopy>> {
+    let deadline = Instant::now() + Duration::from_millis(LOCK_WAIT_MS.load(Ordering::Relaxed));
+    let mut delay = LOCK_RETRY_BACKOFF;
+    let mut warned = false;
+    loop {
+        let error = match workspace.working_copy().start_mutation() {
+            Ok(locked_wc) => return Ok(locked_wc),
+            Err(e) => e,
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(Error::WorkingCopyLocked(Box::new(error)));
+        }
+        if !warned {
+            let lock_path = workspace.workspace_root().join(".jj/working_copy/working_copy.lock");
+            let holder = lock_holders(&lock_path).unwrap_or_else(|| "another process".to_string());
+            warn!(
+                holder = %holder,
+                wait_secs = remaining.as_secs(),
+                "The working copy is locked, waiting for it"
+            );
+            warned = true;
+        }
+        debug!(error = %error, delay_ms = delay.as_millis(), "Retrying the working copy lock");
+        tokio::time::sleep(delay.min(remaining)).await;
+        delay = (delay * 2).min(MAX_LOCK_RETRY_DELAY);
+    }
+}
+
+/// The other processes that have the lock file open, e.g. `jj (pid 4242)`, found through `/proc`.
+/// `None` where there is no `/proc` or no process could be identified.
+fn lock_holders(lock_path: &Path) -> Option<String> {
+    let holders: Vec<String> = std::fs::read_dir("/proc")
+        .ok()?
+        .flatten()
+        .filter_map(|process| {
+            let pid: u32 = process.file_name().to_str()?.parse().ok()?;
+            let holds_lock = pid != std::process::id()
+                && std::fs::read_dir(process.path().join("fd"))
+                    .ok()?
+                    .flatten()
+                    .any(|fd| {
+                        std::fs::read_link(fd.path()).is_ok_and(|target| target == lock_path)
+                    });
+            let name = std::fs::read_to_string(process.path().join("comm")).unwrap_or_default();
+            holds_lock.then(|| format!("{} (pid {pid})", name.trim()))
+        })
+        .collect();
+    (!holders.is_empty()).then(|| holders.join(", "))
+}
+
+/// The git repository backing a jj repository, from the `store/git_target` file the git backend
+/// writes (relative to the store directory). `None` for non-git backends.
+fn git_repo_path(repo_path: &Path) -> Option<PathBuf> {
+    let store = repo_path.join("store");
+    let target = std::fs::read_to_string(store.join("git_target")).ok()?;
+    let target = target.trim();
+    (!target.is_empty()).then(|| store.join(target))
+}
+
+/// The branch checked out in the workspace's backing git repository, if it's on one
+pub(crate) fn git_head_branch(repo_path: &Path) -> Option<String> {
+    let head = std::fs::read_to_string(git_repo_path(repo_path)?.join("HEAD")).ok()?;
+    head.trim().strip_prefix("ref: refs/heads/").map(str::to_string)
+}
+
+/// The URL of the `origin` remote of the workspace's backing git repository
+pub(crate) fn git_remote_url(repo_path: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .arg("--git-dir")
+        .arg(git_repo_path(repo_path)?)
+        .args(["config", "--get", "remote.origin.url"])
+        .output()
+        .ok()?;
+    let url = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !url.is_empty()).then_some(url)
+}
+
+/// The home directory git resolves `~` and `~/.config` against. Git for Windows uses `%HOME%` when
+/// it is set, which may differ from the user profile directory.
+fn git_home() -> Option<PathBuf> {
+    #[cfg(windows)]
+    if let Some(home) = std::env::var_os("HOME").filter(|home| !home.is_empty()) {
+        return Some(PathBuf::from(home));
+    }
+    home_dir()
+}
+
+/// Expands a leading `~/` (or `~\` on Windows) in a path from the git config to the home directory.
+/// `None` if there is a `~` to expand but no home directory.
+fn expand_home(path: &str, home: Option<&Path>) -> Option<PathBuf> {
+    let separators: &[char] = if cfg!(windows) { &['/', '\\'] } else { &['/'] };
+    match path.strip_prefix('~').filter(|rest| rest.starts_with(separators)) {
+        Some(rest) => Some(home?.join(rest.trim_start_matches(separators))),
+        None => Some(PathBuf::from(path)),
+    }
+}
+
+/// Get the global git excludes file path
+fn get_global_git_excludes_file() -> Option<PathBuf> {
+    // First, try to get from git config
+    if let Ok(output) = Command::new("git")
+        .args(["config", "--global", "--get", "core.excludesFile"])
+        .output()
+        && output.status.success()
+        && let Ok(path_str) = std::str::from_utf8(&output.stdout)
+    {
+        let path_str = path_str.trim();
+        if !path_str.is_empty() {
+            let expanded = expand_home(path_str, git_home().as_deref());
+            if expanded.is_none() {
+                debug!(path = %path_str, "No home directory to expand excludes file path");
+            }
+            return expanded;
+        }
+    }
+
+    // Fall back to XDG_CONFIG_HOME/git/ignore or ~/.config/git/ignore
+    if let Ok(xdg_config) = var("XDG_CONFIG_HOME")
+        && !xdg_config.is_empty()
+    {
+        let path = PathBuf::from(xdg_config).join("git").join("ignore");
+        if path.exists() {
+            return Some(path);
+        }
+    }
+
+    // Final fallback: ~/.config/git/ignore
+    if let Some(home) = git_home() {
+        let path = home.join(".config").join("git").join("ignore");
+        if path.exists() {
+            return Some(path);
+        }
+    }
+
+    None
+}
+
+/// The repository directory of a workspace: `.jj/repo`, or in a workspace added with
+/// `jj workspace add`, the directory that file points to (relative to `.jj`)
+fn repo_dir(workspace_root: &Path) -> PathBuf {
+    let jj_dir = workspace_root.join(".jj");
+    let repo = jj_dir.join("repo");
+    match std::fs::read_to_string(&repo) {
+        Ok(target) => jj_dir.join(target.trim()),
+        Err(_) => repo,
+    }
+}
+
+/// Discover the jj workspace starting from the given directory
+pub fn find_workspace(start_dir: &Path) -> Result<Workspace> {
+    // First, find the workspace root directory
+    let mut current_dir = start_dir;
+    let workspace_root = loop {
+        if current_dir.join(".jj").exists() {
+            break current_dir;
+        }
+
+        match current_dir.parent() {
+            Some(parent) => current_dir = parent,
+            None => return Err(Error::WorkspaceNotFound(start_dir.to_path_buf())),
+        }
+    };
+    load_workspace(workspace_root)
+}
+
+/// Discover the jj workspace starting from the given directory, then switch to the workspace
+/// called `name` in the same repository, as added by `jj workspace add`
+pub fn find_named_workspace(start_dir: &Path, name: &str) -> Result<Workspace> {
+    let workspace = find_workspace(start_dir)?;
+    if workspace.workspace_name().as_str() == name {
+        return Ok(workspace);
+    }
+
+    let unknown = || Error::UnknownWorkspace(name.to_string());
+    let store = SimpleWorkspaceStore::load(workspace.repo_path())
+        .map_err(jj("Failed to read the workspace store"))?;
+    let path = store
+        .get_workspace_path(WorkspaceName::new(name))
+        .map_err(jj("Failed to read the workspace store"))?
+        .ok_or_else(unknown)?;
+    // Paths are stored relative to the repository directory
+    let root = workspace.repo_path().join(path);
+    if !root.join(".jj").exists() {
+        return Err(unknown());
+    }
+    debug!(name, root = %root.display(), "Found named workspace");
+    load_workspace(&root)
+}
+
+/// Load the workspace rooted at `workspace_root` with the user's and the repository's jj config
+fn load_workspace(workspace_root: &Path) -> Result<Workspace> {
+    // Build config with proper layers (with_defaults includes operation.hostname/username)
+    let mut config = StackedConfig::with_defaults();
+
+    // Load user configuration
+    load_user_config(&mut config);
+
+    // Load repository-specific configuration
+    let repo_config_path = repo_dir(workspace_root).join("config.toml");
+    add_config_file(&mut config, ConfigSource::Repo, repo_config_path);
+
+    // Resolve conditional scopes (e.g., --when.repositories)
+    let hostname = hostname();
+    let home_dir = home_dir();
+    let context = ConfigResolutionContext {
+        home_dir: home_dir.as_deref(),
+        repo_path: Some(workspace_root),
+        workspace_path: Some(workspace_root),
+        command: None,
+        hostname: hostname.as_str(),
+    };
+    let resolved_config = resolve(&config, &context).map_err(jj("Failed to resolve jj config"))?;
+
+    // Now create settings with resolved config
+    let settings = UserSettings::from_config(resolved_config).map_err(jj("Invalid jj config"))?;
+    let store_factories = StoreFactories::default();
+    let working_copy_factories = default_working_copy_factories();
+
+    // Load the workspace with the complete settings
+    Workspace::load(&settings, workspace_root, &store_factories, &working_copy_factories)
+        .map_err(jj("Failed to load workspace"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_config_paths_without_home() {
+        assert!(user_config_paths(None, None).is_empty());
+
+        let paths = user_config_paths(None, Some(Path::new("/etc/xdg")));
+        assert_eq!(paths, vec![PathBuf::from("/etc/xdg/jj/config.toml")]);
+    }
+
+    #[test]
+    fn test_expand_home() {
+        let home = Path::new("/home/me");
+        assert_eq!(expand_home("~/.gitignore", Some(home)), Some(home.join(".gitignore")));
+        assert_eq!(expand_home("/etc/gitignore", None), Some(PathBuf::from("/etc/gitignore")));
+        assert_eq!(expand_home("~user/.gitignore", None), Some(PathBuf::from("~user/.gitignore")));
+        assert_eq!(expand_home("~/.gitignore", None), None);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_expand_home_with_backslash() {
+        let home = Path::new(r"C:\Users\me");
+        assert_eq!(
+            expand_home(r"~\.config\git\ignore", Some(home)),
+            Some(PathBuf::from(r"C:\Users\me\.config\git\ignore"))
+        );
+    }
+
+    #[test]
+    fn test_config_files_at() {
+        let dir = std::env::temp_dir().join(format!("ccc-jj-config-files-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["b.toml", "a.toml", "notes.txt"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        assert_eq!(config_files_at(dir.clone()), [dir.join("a.toml"), dir.join("b.toml")]);
+        let file = dir.join("missing.toml");
+        assert_eq!(config_files_at(file.clone()), [file]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_git_repo_path() {
+        let dir = std::env::temp_dir().join(format!("ccc-jj-git-target-{}", std::process::id()));
+        let store = dir.join("store");
+        std::fs::create_dir_all(&store).unwrap();
+        assert_eq!(git_repo_path(&dir), None);
+
+        std::fs::write(store.join("git_target"), "../../../.git\n").unwrap();
+        assert_eq!(git_repo_path(&dir), Some(store.join("../../../.git")));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_repo_dir() {
+        let dir = std::env::temp_dir().join(format!("ccc-jj-repo-dir-{}", std::process::id()));
+        let main = dir.join("main");
+        std::fs::create_dir_all(main.join(".jj/repo")).unwrap();
+        assert_eq!(repo_dir(&main), main.join(".jj/repo"));
+
+        let secondary = dir.join("secondary");
+        std::fs::create_dir_all(secondary.join(".jj")).unwrap();
+        std::fs::write(secondary.join(".jj/repo"), "../../main/.jj/repo").unwrap();
+        assert_eq!(repo_dir(&secondary), secondary.join(".jj/../../main/.jj/repo"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_add_config_file_skips_unreadable_files() {
+        let dir = std::env::temp_dir().join(format!("ccc-jj-config-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let valid = dir.join("valid.toml");
+        std::fs::write(&valid, "[user]\nname = \"Test\"\n").unwrap();
+        let malformed = dir.join("malformed.toml");
+        std::fs::write(&malformed, "[user\nname = ").unwrap();
+        // A directory exists but can't be read as a file
+        let unreadable = dir.join("unreadable.toml");
+        std::fs::create_dir_all(&unreadable).unwrap();
+
+        let mut config = StackedConfig::with_defaults();
+        assert!(add_config_file(&mut config, ConfigSource::User, valid));
+        assert!(!add_config_file(&mut config, ConfigSource::User, malformed));
+        assert!(!add_config_file(&mut config, ConfigSource::User, unreadable));
+        assert!(!add_config_file(&mut config, ConfigSource::User, dir.join("missing.toml")));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}